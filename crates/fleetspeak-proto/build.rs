@@ -5,32 +5,124 @@
 
 use std::path::PathBuf;
 
-const PROTOS: &'static [&'static str] = &[
+/// Protos of the `fleetspeak` and `fleetspeak.channel` packages, generated into
+/// the root module of the crate.
+const PROTOS: &[&str] = &[
     "vendor/fleetspeak/fleetspeak/src/common/proto/fleetspeak/common.proto",
+    "vendor/fleetspeak/fleetspeak/src/common/proto/fleetspeak/system.proto",
     "vendor/fleetspeak/fleetspeak/src/client/channel/proto/fleetspeak_channel/channel.proto",
 ];
 
+/// Protos of the other packages, generated into a submodule each.
+///
+/// They cannot all go into the root module, as rust-protobuf names modules
+/// after the files and some files of different packages share a name.
+const PACKAGES: &[(&str, &[&str])] = &[
+    ("daemonservice", &[
+        "vendor/fleetspeak/fleetspeak/src/client/daemonservice/proto/fleetspeak_daemonservice/config.proto",
+    ]),
+    ("monitoring", &[
+        "vendor/fleetspeak/fleetspeak/src/common/proto/fleetspeak_monitoring/resource.proto",
+    ]),
+    ("server", &[
+        "vendor/fleetspeak/fleetspeak/src/server/proto/fleetspeak_server/admin.proto",
+        "vendor/fleetspeak/fleetspeak/src/server/proto/fleetspeak_server/broadcasts.proto",
+        "vendor/fleetspeak/fleetspeak/src/server/proto/fleetspeak_server/resource.proto",
+    ]),
+];
+
 fn main() {
     let outdir: PathBuf = std::env::var("OUT_DIR")
         .expect("no output directory")
         .into();
 
-    for proto in PROTOS {
+    let protos = PROTOS.iter()
+        .chain(PACKAGES.iter().flat_map(|(_, protos)| protos.iter()));
+
+    for proto in protos.clone() {
         println!("cargo:rerun-if-changed={}", proto);
     }
 
     let proto_out_dir = outdir.join("proto");
-    std::fs::create_dir_all(&proto_out_dir).unwrap();
+    generate(&proto_out_dir, PROTOS);
+
+    for (package, protos) in PACKAGES {
+        generate(&proto_out_dir.join(package), protos);
+    }
+
+    #[cfg(feature = "prost")]
+    generate_prost(&outdir.join("prost"), protos);
+}
+
+/// Generates the rust-protobuf bindings of the given protos into the given
+/// directory.
+fn generate(out_dir: &std::path::Path, protos: &[&str]) {
+    std::fs::create_dir_all(out_dir).unwrap();
 
     let customize = protobuf_codegen::Customize::default()
         .gen_mod_rs(true)
         .generate_accessors(true);
 
-    protobuf_codegen::Codegen::new()
+    // Fleetspeak protos import each other with paths relative to the root of
+    // the repository.
+    let mut codegen = protobuf_codegen::Codegen::new();
+    codegen
+        .pure()
+        .out_dir(out_dir)
+        .include("vendor/fleetspeak")
+        .inputs(protos)
+        .customize(customize);
+
+    #[cfg(feature = "serde")]
+    codegen.customize_callback(SerdeCallback);
+
+    codegen.run().unwrap();
+}
+
+/// Code generation callback implementing Serde traits for all messages.
+#[cfg(feature = "serde")]
+struct SerdeCallback;
+
+#[cfg(feature = "serde")]
+impl protobuf_codegen::CustomizeCallback for SerdeCallback {
+
+    fn message(&self, message: &protobuf::reflect::MessageDescriptor) -> protobuf_codegen::Customize {
+        protobuf_codegen::Customize::default()
+            .before(&format!("crate::serde_reflect::impl_serde!({});", message.name()))
+    }
+}
+
+/// Generates the [prost] bindings into the given directory.
+///
+/// The descriptors are obtained with the pure Rust parser that the rust-protobuf
+/// code generator uses as well, so that `protoc` is not needed.
+///
+/// [prost]: https://github.com/tokio-rs/prost
+#[cfg(feature = "prost")]
+fn generate_prost<'a, I>(out_dir: &std::path::Path, protos: I)
+where
+    I: IntoIterator<Item = &'a &'static str>,
+{
+    std::fs::create_dir_all(out_dir).unwrap();
+
+    let parsed = protobuf_parse::Parser::new()
         .pure()
-        .out_dir(&proto_out_dir)
-        .include("vendor/fleetspeak/fleetspeak/src")
-        .inputs(PROTOS)
-        .customize(customize)
-        .run().unwrap();
+        .include("vendor/fleetspeak")
+        .inputs(protos)
+        .parse_and_typecheck()
+        .unwrap();
+
+    // Unlike `Parser::file_descriptor_set`, this keeps the descriptors of the
+    // dependencies (e.g. well-known types), which prost needs to resolve them.
+    let mut fds = protobuf::descriptor::FileDescriptorSet::new();
+    fds.file = parsed.file_descriptors;
+
+    let fds = protobuf::Message::write_to_bytes(&fds).unwrap();
+    let fds = <prost_types::FileDescriptorSet as prost::Message>::decode(&fds[..]).unwrap();
+
+    prost_build::Config::new()
+        .out_dir(out_dir)
+        .include_file("mod.rs")
+        .compile_fds(fds)
+        .unwrap();
 }
@@ -3,3 +3,65 @@
 // Use of this source code is governed by an MIT-style license that can be found
 // in the LICENSE file or at https://opensource.org/licenses/MIT.
 include!(concat!(env!("OUT_DIR"), "/proto/mod.rs"));
+
+#[cfg(feature = "serde")]
+mod serde_reflect;
+
+/// Messages of the `fleetspeak.daemonservice` package.
+pub mod daemonservice {
+    // Generated code refers to messages of other files through `super`.
+    #[allow(unused_imports)]
+    use super::{common, system};
+
+    include!(concat!(env!("OUT_DIR"), "/proto/daemonservice/mod.rs"));
+}
+
+/// Messages of the `fleetspeak.monitoring` package.
+pub mod monitoring {
+    // Generated code refers to messages of other files through `super`.
+    #[allow(unused_imports)]
+    use super::{common, system};
+
+    include!(concat!(env!("OUT_DIR"), "/proto/monitoring/mod.rs"));
+}
+
+/// Messages of the `fleetspeak.server` package.
+pub mod server {
+    // Generated code refers to messages of other files through `super`.
+    #[allow(unused_imports)]
+    use super::{common, system};
+
+    include!(concat!(env!("OUT_DIR"), "/proto/server/mod.rs"));
+}
+
+/// Bindings generated with [prost] instead of rust-protobuf.
+///
+/// The modules follow the Protocol Buffers packages, i.e. messages of the
+/// `fleetspeak` package are in the [`prost::fleetspeak`](self::prost::fleetspeak)
+/// module and messages of the `fleetspeak.channel` package are in its `channel`
+/// submodule.
+///
+/// [prost]: https://github.com/tokio-rs/prost
+#[cfg(feature = "prost")]
+pub mod prost {
+    include!(concat!(env!("OUT_DIR"), "/prost/mod.rs"));
+}
+
+#[cfg(all(test, feature = "prost"))]
+mod tests {
+
+    #[test]
+    fn prost_decodes_protobuf() {
+        let mut message = crate::common::Message::new();
+        message.message_type = String::from("foo");
+        message.mut_destination().service_name = String::from("bar");
+        message.mut_data().value = b"baz".to_vec();
+
+        let buf = protobuf::Message::write_to_bytes(&message).unwrap();
+        let message = <crate::prost::fleetspeak::Message as prost::Message>::decode(&buf[..]).unwrap();
+
+        assert_eq!(message.message_type, "foo");
+        assert_eq!(message.destination.unwrap().service_name, "bar");
+        assert_eq!(message.data.unwrap().value, b"baz");
+    }
+}
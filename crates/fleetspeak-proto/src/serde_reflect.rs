@@ -0,0 +1,477 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! [Serde] support for the generated messages.
+//!
+//! rust-protobuf does not support Serde, so messages are (de)serialized using
+//! reflection instead. A message is represented as a map from names of its
+//! fields to their values, where fields with default values are omitted.
+//! Enums are represented by the names of their values (or by numbers, if the
+//! value is not known) and bytes are represented as bytes of the data format.
+//!
+//! Note that for JSON this is close to but not the same as the canonical JSON
+//! mapping of Protocol Buffers (e.g. bytes are not encoded as base64).
+//!
+//! [Serde]: https://serde.rs
+
+use protobuf::reflect::{
+    EnumDescriptor,
+    MessageDescriptor,
+    ReflectFieldRef,
+    ReflectValueBox,
+    ReflectValueRef,
+    RuntimeFieldType,
+    RuntimeType,
+};
+use protobuf::{MessageDyn, MessageFull};
+use serde::de::{DeserializeSeed, Error as _};
+use serde::ser::{SerializeMap as _, SerializeSeq as _};
+
+/// Implements [`serde::Serialize`] and [`serde::Deserialize`] for a message.
+///
+/// Invocations of this macro are inserted into the generated code by the build
+/// script.
+macro_rules! impl_serde {
+    ($message:ident) => {
+        impl ::serde::Serialize for $message {
+
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: ::serde::Serializer,
+            {
+                crate::serde_reflect::serialize(self, serializer)
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $message {
+
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: ::serde::Deserializer<'de>,
+            {
+                crate::serde_reflect::deserialize(deserializer)
+            }
+        }
+    };
+}
+
+pub(crate) use impl_serde;
+
+/// Serializes the message using reflection.
+pub(crate) fn serialize<M, S>(message: &M, serializer: S) -> Result<S::Ok, S::Error>
+where
+    M: MessageFull,
+    S: serde::Serializer,
+{
+    serde::Serialize::serialize(&Message(message), serializer)
+}
+
+/// Deserializes a message using reflection.
+pub(crate) fn deserialize<'de, M, D>(deserializer: D) -> Result<M, D::Error>
+where
+    M: MessageFull,
+    D: serde::Deserializer<'de>,
+{
+    let message = MessageSeed(M::descriptor()).deserialize(deserializer)?;
+
+    match message.downcast_box::<M>() {
+        Ok(message) => Ok(*message),
+        // The instance is created from the descriptor of `M`, so it is always
+        // of the right type.
+        Err(_) => unreachable!("unexpected message type"),
+    }
+}
+
+/// Serialization wrapper of a message of any type.
+struct Message<'a>(&'a dyn MessageDyn);
+
+impl serde::Serialize for Message<'_> {
+
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+
+        for field in self.0.descriptor_dyn().fields() {
+            match field.get_reflect(self.0) {
+                ReflectFieldRef::Optional(value) => {
+                    if let Some(value) = value.value() {
+                        map.serialize_entry(field.name(), &Value(value))?;
+                    }
+                }
+                ReflectFieldRef::Repeated(values) => {
+                    if !values.is_empty() {
+                        map.serialize_entry(field.name(), &Repeated(values))?;
+                    }
+                }
+                ReflectFieldRef::Map(entries) => {
+                    if !entries.is_empty() {
+                        map.serialize_entry(field.name(), &Map(entries))?;
+                    }
+                }
+            }
+        }
+
+        map.end()
+    }
+}
+
+/// Serialization wrapper of a value of any type.
+struct Value<'a>(ReflectValueRef<'a>);
+
+impl serde::Serialize for Value<'_> {
+
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match &self.0 {
+            ReflectValueRef::U32(value) => serializer.serialize_u32(*value),
+            ReflectValueRef::U64(value) => serializer.serialize_u64(*value),
+            ReflectValueRef::I32(value) => serializer.serialize_i32(*value),
+            ReflectValueRef::I64(value) => serializer.serialize_i64(*value),
+            ReflectValueRef::F32(value) => serializer.serialize_f32(*value),
+            ReflectValueRef::F64(value) => serializer.serialize_f64(*value),
+            ReflectValueRef::Bool(value) => serializer.serialize_bool(*value),
+            ReflectValueRef::String(value) => serializer.serialize_str(value),
+            ReflectValueRef::Bytes(value) => serializer.serialize_bytes(value),
+            ReflectValueRef::Enum(descriptor, number) => {
+                match descriptor.value_by_number(*number) {
+                    Some(value) => serializer.serialize_str(value.name()),
+                    None => serializer.serialize_i32(*number),
+                }
+            }
+            ReflectValueRef::Message(message) => {
+                serde::Serialize::serialize(&Message(&**message), serializer)
+            }
+        }
+    }
+}
+
+/// Serialization wrapper of values of a repeated field.
+struct Repeated<'a>(protobuf::reflect::ReflectRepeatedRef<'a>);
+
+impl serde::Serialize for Repeated<'_> {
+
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for value in &self.0 {
+            seq.serialize_element(&Value(value))?;
+        }
+        seq.end()
+    }
+}
+
+/// Serialization wrapper of entries of a map field.
+struct Map<'a>(protobuf::reflect::ReflectMapRef<'a>);
+
+impl serde::Serialize for Map<'_> {
+
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(&Value(key), &Value(value))?;
+        }
+        map.end()
+    }
+}
+
+/// Deserialization seed of a message of the described type.
+struct MessageSeed(MessageDescriptor);
+
+impl<'de> DeserializeSeed<'de> for MessageSeed {
+    type Value = Box<dyn MessageDyn>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for MessageSeed {
+    type Value = Box<dyn MessageDyn>;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "a '{}' message", self.0.full_name())
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        let mut message = self.0.new_instance();
+
+        while let Some(name) = map.next_key::<String>()? {
+            let field = self.0.field_by_name_or_json_name(&name)
+                .ok_or_else(|| A::Error::custom({
+                    format!("unknown field '{name}' of '{}'", self.0.full_name())
+                }))?;
+
+            match field.runtime_field_type() {
+                RuntimeFieldType::Singular(kind) => {
+                    let value = map.next_value_seed(ValueSeed(kind))?;
+                    field.set_singular_field(&mut *message, value);
+                }
+                RuntimeFieldType::Repeated(kind) => {
+                    let values = map.next_value_seed(RepeatedSeed(kind))?;
+
+                    let mut repeated = field.mut_repeated(&mut *message);
+                    for value in values {
+                        repeated.push(value);
+                    }
+                }
+                RuntimeFieldType::Map(key_kind, value_kind) => {
+                    let entries = map.next_value_seed(MapSeed(key_kind, value_kind))?;
+
+                    let mut entries_mut = field.mut_map(&mut *message);
+                    for (key, value) in entries {
+                        entries_mut.insert(key, value);
+                    }
+                }
+            }
+        }
+
+        Ok(message)
+    }
+}
+
+/// Deserialization seed of a value of the given type.
+struct ValueSeed(RuntimeType);
+
+impl<'de> DeserializeSeed<'de> for ValueSeed {
+    type Value = ReflectValueBox;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::Deserialize as _;
+
+        Ok(match self.0 {
+            RuntimeType::I32 => ReflectValueBox::I32(i32::deserialize(deserializer)?),
+            RuntimeType::I64 => ReflectValueBox::I64(i64::deserialize(deserializer)?),
+            RuntimeType::U32 => ReflectValueBox::U32(u32::deserialize(deserializer)?),
+            RuntimeType::U64 => ReflectValueBox::U64(u64::deserialize(deserializer)?),
+            RuntimeType::F32 => ReflectValueBox::F32(f32::deserialize(deserializer)?),
+            RuntimeType::F64 => ReflectValueBox::F64(f64::deserialize(deserializer)?),
+            RuntimeType::Bool => ReflectValueBox::Bool(bool::deserialize(deserializer)?),
+            RuntimeType::String => ReflectValueBox::String(String::deserialize(deserializer)?),
+            RuntimeType::VecU8 => ReflectValueBox::Bytes(deserializer.deserialize_byte_buf(Bytes)?),
+            RuntimeType::Enum(descriptor) => {
+                let number = deserializer.deserialize_any(EnumVisitor(descriptor.clone()))?;
+                ReflectValueBox::Enum(descriptor, number)
+            }
+            RuntimeType::Message(descriptor) => {
+                ReflectValueBox::Message(MessageSeed(descriptor).deserialize(deserializer)?)
+            }
+        })
+    }
+}
+
+/// Deserialization seed of values of a repeated field of the given type.
+struct RepeatedSeed(RuntimeType);
+
+impl<'de> DeserializeSeed<'de> for RepeatedSeed {
+    type Value = Vec<ReflectValueBox>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for RepeatedSeed {
+    type Value = Vec<ReflectValueBox>;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "a sequence of values")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element_seed(ValueSeed(self.0.clone()))? {
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+}
+
+/// Deserialization seed of entries of a map field of the given types.
+struct MapSeed(RuntimeType, RuntimeType);
+
+impl<'de> DeserializeSeed<'de> for MapSeed {
+    type Value = Vec<(ReflectValueBox, ReflectValueBox)>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for MapSeed {
+    type Value = Vec<(ReflectValueBox, ReflectValueBox)>;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "a map of values")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        let mut entries = Vec::new();
+        while let Some(key) = map.next_key_seed(ValueSeed(self.0.clone()))? {
+            let value = map.next_value_seed(ValueSeed(self.1.clone()))?;
+            entries.push((key, value));
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Visitor of bytes that also accepts sequences (as produced by formats
+/// without native support for bytes).
+struct Bytes;
+
+impl<'de> serde::de::Visitor<'de> for Bytes {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "bytes")
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut bytes = Vec::new();
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Visitor of an enum value given either by its name or by its number.
+struct EnumVisitor(EnumDescriptor);
+
+impl<'de> serde::de::Visitor<'de> for EnumVisitor {
+    type Value = i32;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "a '{}' value", self.0.full_name())
+    }
+
+    fn visit_str<E>(self, name: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        match self.0.value_by_name(name) {
+            Some(value) => Ok(value.value()),
+            None => Err(E::custom({
+                format!("unknown value '{name}' of '{}'", self.0.full_name())
+            })),
+        }
+    }
+
+    fn visit_i64<E>(self, number: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        i32::try_from(number)
+            .map_err(|_| E::custom(format!("enum value out of range: {number}")))
+    }
+
+    fn visit_u64<E>(self, number: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        i32::try_from(number)
+            .map_err(|_| E::custom(format!("enum value out of range: {number}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{message::Priority, Message};
+
+    fn message() -> Message {
+        let mut message = Message::new();
+        message.message_type = String::from("foo");
+        message.mut_source().service_name = String::from("bar");
+        message.mut_data().value = b"baz".to_vec();
+        message.set_priority(Priority::HIGH);
+
+        let mut entry = crate::common::annotations::Entry::new();
+        entry.key = String::from("quux");
+        entry.value = String::from("norf");
+        message.mut_annotations().entries.push(entry);
+
+        message
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let json = serde_json::to_string(&message()).unwrap();
+        let roundtrip = serde_json::from_str::<Message>(&json).unwrap();
+
+        assert_eq!(roundtrip, message());
+    }
+
+    #[test]
+    fn json_format() {
+        let json = serde_json::to_value(message()).unwrap();
+
+        assert_eq!(json["message_type"], "foo");
+        assert_eq!(json["source"]["service_name"], "bar");
+        assert_eq!(json["priority"], "HIGH");
+        assert_eq!(json["annotations"]["entries"][0]["key"], "quux");
+        assert!(json.get("destination").is_none());
+    }
+
+    #[test]
+    fn json_unknown_field() {
+        assert!(serde_json::from_str::<Message>(r#"{"foo": 42}"#).is_err());
+    }
+
+    #[test]
+    fn json_enum_number() {
+        let message = serde_json::from_str::<Message>(r#"{"priority": 2}"#).unwrap();
+        assert_eq!(message.priority.value(), 2);
+    }
+}
@@ -0,0 +1,525 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use fleetspeak::{Message, Priority};
+use fleetspeak_proto::common::message::Priority as ProtoPriority;
+
+use crate::pipe::{PipeReader, PipeWriter};
+
+/// Magic number that both parties exchange during the handshake and that
+/// terminates every message frame.
+const MAGIC: u32 = 0xf1ee1001;
+
+/// A test double playing the part of the Fleetspeak client.
+///
+/// The fake performs the handshake with the service, records all messages that
+/// the service sends (keeping track of heartbeats and startup information
+/// separately) and allows to inject messages that the service then receives as
+/// if they came from the Fleetspeak server.
+///
+/// Note that messages are recorded and injected verbatim: payload
+/// transformations (like compression) enabled on the service side are not
+/// applied by the fake.
+///
+/// # Examples
+///
+/// ```
+/// use fleetspeak::Message;
+/// use fleetspeak_test::FakeFleetspeak;
+///
+/// let (fake, mut conn) = FakeFleetspeak::in_memory();
+/// conn.handshake().unwrap();
+///
+/// fake.inject(Message {
+///     service: String::from("example"),
+///     kind: None,
+///     data: b"ping".to_vec(),
+///     ..Default::default()
+/// }).unwrap();
+///
+/// let message = conn.receive().unwrap();
+/// conn.send(Message {
+///     service: String::from("example"),
+///     kind: None,
+///     data: message.data,
+///     ..Default::default()
+/// }).unwrap();
+///
+/// let message = fake.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+/// assert_eq!(message.data, b"ping");
+/// ```
+pub struct FakeFleetspeak {
+    /// Stream to which messages for the service are written.
+    output: Mutex<Box<dyn Write + Send>>,
+    /// State updated by the thread reading from the service.
+    shared: Arc<Shared>,
+}
+
+impl FakeFleetspeak {
+
+    /// Creates a fake that talks to the service over the given streams.
+    ///
+    /// `input` is the stream the service writes to and `output` is the stream
+    /// the service reads from. Our side of the handshake is sent immediately
+    /// and the messages are then read from `input` in a background thread.
+    pub fn new<R, W>(input: R, output: W) -> std::io::Result<FakeFleetspeak>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let mut output = output;
+        output.write_all(&MAGIC.to_le_bytes())?;
+        output.flush()?;
+
+        let shared = Arc::new(Shared {
+            inbox: Mutex::new(Inbox {
+                messages: VecDeque::new(),
+                heartbeats: 0,
+                version: None,
+                handshake: false,
+                error: None,
+            }),
+            update: Condvar::new(),
+        });
+
+        std::thread::spawn({
+            let shared = shared.clone();
+            move || read_all(input, &shared)
+        });
+
+        Ok(FakeFleetspeak {
+            output: Mutex::new(Box::new(output)),
+            shared,
+        })
+    }
+
+    /// Creates a fake together with a connection of a service that talks to it
+    /// over an in-memory transport.
+    pub fn in_memory() -> (FakeFleetspeak, fleetspeak::Connection<PipeReader, PipeWriter>) {
+        let (service, fake) = crate::pipe::duplex();
+        let (service_input, service_output) = service.into_split();
+        let (fake_input, fake_output) = fake.into_split();
+
+        let fake = FakeFleetspeak::new(fake_input, fake_output)
+            .expect("in-memory pipes do not fail");
+
+        (fake, fleetspeak::Connection::new(service_input, service_output))
+    }
+
+    /// Creates a fake that the global connection of this process talks to.
+    ///
+    /// This creates a pair of pipes and advertises their service ends through
+    /// the environment variables that the Fleetspeak client normally sets up,
+    /// so the free functions of the `fleetspeak` crate connect to the fake.
+    ///
+    /// Because the global connection is established only once per process,
+    /// this function has to be called before any other use of the connector
+    /// and at most once per test binary.
+    pub fn install() -> std::io::Result<FakeFleetspeak> {
+        let (service_input, fake_output) = std::io::pipe()?;
+        let (fake_input, service_output) = std::io::pipe()?;
+
+        // The service ends are owned by the global connection from now on, so
+        // we deliberately leak them.
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::fd::IntoRawFd as _;
+
+            std::env::set_var("FLEETSPEAK_COMMS_CHANNEL_INFD", service_input.into_raw_fd().to_string());
+            std::env::set_var("FLEETSPEAK_COMMS_CHANNEL_OUTFD", service_output.into_raw_fd().to_string());
+        }
+        #[cfg(target_family = "windows")]
+        {
+            use std::os::windows::io::IntoRawHandle as _;
+
+            std::env::set_var("FLEETSPEAK_COMMS_CHANNEL_INFD", (service_input.into_raw_handle() as usize).to_string());
+            std::env::set_var("FLEETSPEAK_COMMS_CHANNEL_OUTFD", (service_output.into_raw_handle() as usize).to_string());
+        }
+
+        FakeFleetspeak::new(fake_input, fake_output)
+    }
+
+    /// Sends a message to the service as if it came from the Fleetspeak server.
+    ///
+    /// The `service` field of the message is used as its source.
+    pub fn inject(&self, message: Message) -> std::io::Result<()> {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_source().set_service_name(message.service);
+        if let Some(kind) = message.kind {
+            proto.set_message_type(kind);
+        }
+        proto.mut_data().value = message.data;
+        if let Some(type_url) = message.data_type_url {
+            proto.mut_data().type_url = type_url;
+        }
+        proto.set_priority(match message.priority {
+            Priority::Low => ProtoPriority::LOW,
+            Priority::Medium => ProtoPriority::MEDIUM,
+            Priority::High => ProtoPriority::HIGH,
+        });
+        proto.set_background(message.background);
+        for (key, value) in message.annotations {
+            let mut entry = fleetspeak_proto::common::annotations::Entry::new();
+            entry.key = key;
+            entry.value = value;
+
+            proto.mut_annotations().entries.push(entry);
+        }
+
+        let mut output = self.output.lock()
+            .expect("poisoned output mutex");
+        write_proto(&mut *output, &proto)
+    }
+
+    /// Feeds the inbound traffic of a capture back into the service.
+    ///
+    /// All inbound frames recorded in the capture are sent to the service
+    /// verbatim, except for the handshake (which the fake has already done on
+    /// its own). This allows reproducing issues observed in the field with the
+    /// global connection of the service. Returns the number of sent frames.
+    ///
+    /// See the [`fleetspeak::capture`] module for more details on captures.
+    pub fn replay<R: Read>(&self, reader: fleetspeak::capture::Reader<R>) -> std::io::Result<usize> {
+        use fleetspeak::capture::Direction;
+
+        let mut output = self.output.lock()
+            .expect("poisoned output mutex");
+
+        let mut count = 0;
+        for record in reader {
+            let record = record?;
+            if record.direction != Direction::Inbound || record.frame == MAGIC.to_le_bytes() {
+                continue;
+            }
+
+            output.write_all(&record.frame)?;
+            count += 1;
+        }
+        output.flush()?;
+
+        Ok(count)
+    }
+
+    /// Waits for the next message sent by the service.
+    ///
+    /// Heartbeats and startup information are not returned by this method (see
+    /// [`heartbeats`] and [`version`] instead).
+    ///
+    /// An error is returned if no message arrives within the `timeout` or the
+    /// connection with the service is broken.
+    ///
+    /// [`heartbeats`]: FakeFleetspeak::heartbeats
+    /// [`version`]: FakeFleetspeak::version
+    pub fn recv_timeout(&self, timeout: Duration) -> std::io::Result<Message> {
+        self.wait(timeout, |inbox| inbox.messages.pop_front())
+    }
+
+    /// Waits until the service completes its side of the handshake.
+    ///
+    /// An error is returned if the handshake does not happen within the
+    /// `timeout` or it fails.
+    pub fn wait_handshake(&self, timeout: Duration) -> std::io::Result<()> {
+        self.wait(timeout, |inbox| inbox.handshake.then_some(()))
+    }
+
+    /// Waits until the service sends its startup information and returns the
+    /// reported version.
+    ///
+    /// An error is returned if the information does not arrive within the
+    /// `timeout` or the connection with the service is broken.
+    pub fn wait_version(&self, timeout: Duration) -> std::io::Result<String> {
+        self.wait(timeout, |inbox| inbox.version.clone())
+    }
+
+    /// Returns all messages sent by the service that have not been received
+    /// yet.
+    pub fn take_messages(&self) -> Vec<Message> {
+        self.shared.inbox.lock()
+            .expect("poisoned inbox mutex")
+            .messages.drain(..).collect()
+    }
+
+    /// Returns the number of heartbeats sent by the service so far.
+    pub fn heartbeats(&self) -> usize {
+        self.shared.inbox.lock()
+            .expect("poisoned inbox mutex")
+            .heartbeats
+    }
+
+    /// Returns the version reported by the service in its startup information
+    /// (if it has been sent already).
+    pub fn version(&self) -> Option<String> {
+        self.shared.inbox.lock()
+            .expect("poisoned inbox mutex")
+            .version.clone()
+    }
+
+    /// Returns whether the service has completed its side of the handshake.
+    pub fn is_handshake_done(&self) -> bool {
+        self.shared.inbox.lock()
+            .expect("poisoned inbox mutex")
+            .handshake
+    }
+
+    /// Waits until `f` returns a value or the connection breaks.
+    fn wait<T, F>(&self, timeout: Duration, mut f: F) -> std::io::Result<T>
+    where
+        F: FnMut(&mut Inbox) -> Option<T>,
+    {
+        let deadline = Instant::now() + timeout;
+
+        let mut inbox = self.shared.inbox.lock()
+            .expect("poisoned inbox mutex");
+        loop {
+            if let Some(value) = f(&mut inbox) {
+                return Ok(value);
+            }
+            if let Some(error) = &inbox.error {
+                return Err(std::io::Error::new(error.kind(), error.to_string()));
+            }
+
+            let timeout = match deadline.checked_duration_since(Instant::now()) {
+                Some(timeout) => timeout,
+                None => return Err(std::io::ErrorKind::TimedOut.into()),
+            };
+            inbox = self.shared.update.wait_timeout(inbox, timeout)
+                .expect("poisoned inbox mutex")
+                .0;
+        }
+    }
+}
+
+/// State shared with the thread reading from the service.
+struct Shared {
+    inbox: Mutex<Inbox>,
+    /// Notified whenever the inbox changes.
+    update: Condvar,
+}
+
+/// Everything the service has sent so far.
+struct Inbox {
+    messages: VecDeque<Message>,
+    heartbeats: usize,
+    version: Option<String>,
+    handshake: bool,
+    /// Error that ended reading from the service.
+    error: Option<std::io::Error>,
+}
+
+/// Reads everything the service sends until the stream breaks.
+fn read_all<R: Read>(mut input: R, shared: &Shared) {
+    if let Err(error) = read_frames(&mut input, shared) {
+        shared.record(|inbox| inbox.error = Some(error));
+    }
+}
+
+/// Reads the handshake and then message frames until an error occurs.
+fn read_frames<R: Read>(input: &mut R, shared: &Shared) -> std::io::Result<()> {
+    read_magic(input)?;
+    shared.record(|inbox| inbox.handshake = true);
+
+    loop {
+        let proto = read_proto(input)?;
+        shared.record(|inbox| inbox.push(proto))?;
+    }
+}
+
+impl Shared {
+
+    /// Updates the inbox and notifies all the waiters.
+    fn record<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Inbox) -> T,
+    {
+        let mut inbox = self.inbox.lock()
+            .expect("poisoned inbox mutex");
+        let result = f(&mut inbox);
+        drop(inbox);
+
+        self.update.notify_all();
+
+        result
+    }
+}
+
+impl Inbox {
+
+    /// Records a message sent by the service.
+    fn push(&mut self, proto: fleetspeak_proto::common::Message) -> std::io::Result<()> {
+        let service = proto.destination().service_name();
+        let kind = proto.message_type();
+
+        match (service, kind) {
+            ("system", "Heartbeat") => {
+                self.heartbeats += 1;
+            }
+            ("system", "StartupData") => {
+                let data = proto.data()
+                    .unpack::<fleetspeak_proto::channel::StartupData>()?
+                    .ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid startup data")
+                    })?;
+
+                self.version = Some(data.version);
+            }
+            _ => {
+                self.messages.push_back(Message {
+                    service: String::from(service),
+                    kind: if kind.is_empty() { None } else { Some(String::from(kind)) },
+                    data: proto.data().value.clone(),
+                    priority: priority(&proto),
+                    annotations: proto.annotations.entries.iter()
+                        .map(|entry| (entry.key.clone(), entry.value.clone()))
+                        .collect(),
+                    background: proto.background,
+                    data_type_url: Some(proto.data().type_url.clone())
+                        .filter(|type_url| !type_url.is_empty()),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the priority of the message.
+fn priority(proto: &fleetspeak_proto::common::Message) -> Priority {
+    match proto.priority() {
+        ProtoPriority::LOW => Priority::Low,
+        ProtoPriority::MEDIUM => Priority::Medium,
+        ProtoPriority::HIGH => Priority::High,
+    }
+}
+
+/// Reads and verifies the magic number.
+fn read_magic<R: Read>(input: &mut R) -> std::io::Result<()> {
+    let magic = read_u32(input)?;
+    if magic != MAGIC {
+        let error = format!("invalid magic: {magic:#x}");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error));
+    }
+
+    Ok(())
+}
+
+/// Reads a single message frame.
+fn read_proto<R: Read>(input: &mut R) -> std::io::Result<fleetspeak_proto::common::Message> {
+    let len = read_u32(input)? as usize;
+    let mut buf = vec![0; len];
+    input.read_exact(&mut buf[..])?;
+    read_magic(input)?;
+
+    Ok(protobuf::Message::parse_from_bytes(&buf[..])?)
+}
+
+/// Writes a single message frame.
+fn write_proto<W: Write + ?Sized>(output: &mut W, proto: &fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let buf = protobuf::Message::write_to_bytes(proto)?;
+    let len = u32::try_from(buf.len())
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+    output.write_all(&len.to_le_bytes())?;
+    output.write_all(&buf[..])?;
+    output.write_all(&MAGIC.to_le_bytes())?;
+    output.flush()
+}
+
+fn read_u32<R: Read>(input: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn records_messages() {
+        let (fake, mut conn) = FakeFleetspeak::in_memory();
+        conn.handshake().unwrap();
+        conn.startup("1.2.3").unwrap();
+        conn.heartbeat().unwrap();
+        conn.send(Message {
+            service: String::from("foo"),
+            kind: Some(String::from("bar")),
+            data: b"baz".to_vec(),
+            ..Default::default()
+        }).unwrap();
+
+        let message = fake.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(message.service, "foo");
+        assert_eq!(message.kind.as_deref(), Some("bar"));
+        assert_eq!(message.data, b"baz");
+
+        assert!(fake.is_handshake_done());
+        assert_eq!(fake.heartbeats(), 1);
+        assert_eq!(fake.version().as_deref(), Some("1.2.3"));
+    }
+
+    #[test]
+    fn replays_capture() {
+        let (fake, mut conn) = FakeFleetspeak::in_memory();
+        conn.handshake().unwrap();
+
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_source().set_service_name(String::from("foo"));
+        proto.set_message_type(String::from("bar"));
+
+        let mut frame = Vec::new();
+        write_proto(&mut frame, &proto).unwrap();
+
+        let mut capture = fleetspeak::capture::HEADER.to_vec();
+        for (direction, frame) in [(0, &MAGIC.to_le_bytes()[..]), (1, b"quux"), (0, &frame[..])] {
+            capture.push(direction);
+            capture.extend_from_slice(&0u64.to_le_bytes());
+            capture.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            capture.extend_from_slice(frame);
+        }
+
+        let reader = fleetspeak::capture::Reader::new(&capture[..]).unwrap();
+        assert_eq!(fake.replay(reader).unwrap(), 1);
+
+        let message = conn.receive().unwrap();
+        assert_eq!(message.service, "foo");
+        assert_eq!(message.kind.as_deref(), Some("bar"));
+    }
+
+    #[test]
+    fn injects_messages() {
+        let (fake, mut conn) = FakeFleetspeak::in_memory();
+        conn.handshake().unwrap();
+
+        fake.inject(Message {
+            service: String::from("foo"),
+            kind: Some(String::from("bar")),
+            data: b"baz".to_vec(),
+            ..Default::default()
+        }).unwrap();
+
+        let message = conn.receive().unwrap();
+        assert_eq!(message.service, "foo");
+        assert_eq!(message.kind.as_deref(), Some("bar"));
+        assert_eq!(message.data, b"baz");
+    }
+
+    #[test]
+    fn recv_closed() {
+        let (fake, conn) = FakeFleetspeak::in_memory();
+        drop(conn);
+
+        let error = fake.recv_timeout(TIMEOUT).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
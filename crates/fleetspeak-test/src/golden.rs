@@ -0,0 +1,163 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Canonical byte sequences of the Fleetspeak wire format.
+//!
+//! These are the exact bytes exchanged between a service and the Fleetspeak
+//! client, as produced and expected by the Go implementation of the client
+//! (`daemonservice`). Comparing frames written by the connector against them
+//! ensures that changes to the framing code stay compatible byte-for-byte.
+//!
+//! Every frame consists of the length of the serialized message (as a 32-bit
+//! little-endian integer), the message itself and the magic number (the same
+//! one that is exchanged in the [handshake](HANDSHAKE)).
+//!
+//! # Examples
+//!
+//! ```
+//! let mut conn = fleetspeak::Connection::new(std::io::empty(), Vec::new());
+//! conn.heartbeat().unwrap();
+//!
+//! let (_, output) = conn.into_inner();
+//! fleetspeak_test::golden::assert_bytes_eq(&output, fleetspeak_test::golden::HEARTBEAT);
+//! ```
+
+/// The magic number sent by both parties in the handshake.
+pub const HANDSHAKE: [u8; 4] = [0x01, 0x10, 0xee, 0xf1];
+
+/// A heartbeat sent by the service.
+pub const HEARTBEAT: &[u8] = &[
+    // Length of the message.
+    0x15, 0x00, 0x00, 0x00,
+    // Destination: service `system`.
+    0x22, 0x08, 0x12, 0x06, b's', b'y', b's', b't', b'e', b'm',
+    // Message type: `Heartbeat`.
+    0x2a, 0x09, b'H', b'e', b'a', b'r', b't', b'b', b'e', b'a', b't',
+    // Magic number.
+    0x01, 0x10, 0xee, 0xf1,
+];
+
+/// Process identifier reported in the [`STARTUP`] frame.
+pub const STARTUP_PID: i64 = 1234;
+
+/// Version reported in the [`STARTUP`] frame.
+pub const STARTUP_VERSION: &str = "1.2.3";
+
+/// Startup information sent by the service.
+///
+/// The frame reports [`STARTUP_PID`] and [`STARTUP_VERSION`] and carries no
+/// annotations (the Fleetspeak client ignores them in startup messages).
+pub const STARTUP: &[u8] = &[
+    // Length of the message.
+    0x59, 0x00, 0x00, 0x00,
+    // Destination: service `system`.
+    0x22, 0x08, 0x12, 0x06, b's', b'y', b's', b't', b'e', b'm',
+    // Message type: `StartupData`.
+    0x2a, 0x0b, b'S', b't', b'a', b'r', b't', b'u', b'p', b'D', b'a', b't', b'a',
+    // Data: `google.protobuf.Any` wrapping `fleetspeak.channel.StartupData`.
+    0x3a, 0x40,
+    0x0a, 0x32,
+    b't', b'y', b'p', b'e', b'.', b'g', b'o', b'o', b'g', b'l', b'e', b'a',
+    b'p', b'i', b's', b'.', b'c', b'o', b'm', b'/', b'f', b'l', b'e', b'e',
+    b't', b's', b'p', b'e', b'a', b'k', b'.', b'c', b'h', b'a', b'n', b'n',
+    b'e', b'l', b'.', b'S', b't', b'a', b'r', b't', b'u', b'p', b'D', b'a',
+    b't', b'a',
+    0x12, 0x0a,
+    // Process identifier: 1234.
+    0x08, 0xd2, 0x09,
+    // Version: `1.2.3`.
+    0x12, 0x05, b'1', b'.', b'2', b'.', b'3',
+    // Magic number.
+    0x01, 0x10, 0xee, 0xf1,
+];
+
+/// A message of kind `greeting` with data `Hello` sent by the service to the
+/// server-side service `example`.
+pub const MESSAGE_OUT: &[u8] = &[
+    // Length of the message.
+    0x1e, 0x00, 0x00, 0x00,
+    // Destination: service `example`.
+    0x22, 0x09, 0x12, 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+    // Message type: `greeting`.
+    0x2a, 0x08, b'g', b'r', b'e', b'e', b't', b'i', b'n', b'g',
+    // Data: `google.protobuf.Any` without type URL and with value `Hello`.
+    0x3a, 0x07, 0x12, 0x05, b'H', b'e', b'l', b'l', b'o',
+    // Magic number.
+    0x01, 0x10, 0xee, 0xf1,
+];
+
+/// A message of kind `greeting` with data `Hello` received by the service from
+/// the server-side service `example`.
+pub const MESSAGE_IN: &[u8] = &[
+    // Length of the message.
+    0x1e, 0x00, 0x00, 0x00,
+    // Source: service `example`.
+    0x12, 0x09, 0x12, 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+    // Message type: `greeting`.
+    0x2a, 0x08, b'g', b'r', b'e', b'e', b't', b'i', b'n', b'g',
+    // Data: `google.protobuf.Any` without type URL and with value `Hello`.
+    0x3a, 0x07, 0x12, 0x05, b'H', b'e', b'l', b'l', b'o',
+    // Magic number.
+    0x01, 0x10, 0xee, 0xf1,
+];
+
+/// Asserts that the bytes are equal to the expected ones.
+///
+/// Unlike [`assert_eq!`], the panic message reports the offset of the first
+/// differing byte along with both sequences in hexadecimal form, which makes
+/// mismatches of long frames easy to track down.
+#[track_caller]
+pub fn assert_bytes_eq(actual: &[u8], expected: &[u8]) {
+    if actual == expected {
+        return;
+    }
+
+    let offset = actual.iter().zip(expected)
+        .position(|(actual, expected)| actual != expected)
+        .unwrap_or(std::cmp::min(actual.len(), expected.len()));
+
+    panic! {
+        "bytes differ at offset {offset}:\n  actual:   {}\n  expected: {}",
+        hex(actual),
+        hex(expected),
+    };
+}
+
+/// Asserts that the bytes consist of the given frames in the given order.
+///
+/// See [`assert_bytes_eq`] for more details.
+#[track_caller]
+pub fn assert_frames_eq(actual: &[u8], expected: &[&[u8]]) {
+    assert_bytes_eq(actual, &expected.concat());
+}
+
+/// Formats the bytes in hexadecimal form.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn frame_lengths() {
+        for frame in [HEARTBEAT, STARTUP, MESSAGE_OUT, MESSAGE_IN] {
+            let len = u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize;
+            assert_eq!(frame.len(), 4 + len + 4);
+            assert_eq!(frame[frame.len() - 4..], HANDSHAKE);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "bytes differ at offset 2")]
+    fn assert_bytes_eq_mismatch() {
+        assert_bytes_eq(&[0x00, 0x01, 0x02], &[0x00, 0x01, 0x03]);
+    }
+}
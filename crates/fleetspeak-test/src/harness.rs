@@ -0,0 +1,138 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::ffi::OsStr;
+use std::time::Duration;
+
+use fleetspeak::Message;
+
+use crate::FakeFleetspeak;
+
+#[cfg(target_family = "unix")]
+use crate::unix as sys;
+#[cfg(target_family = "windows")]
+use crate::windows as sys;
+
+/// How long to wait for the service to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A service process driven by a fake Fleetspeak client.
+///
+/// This allows end-to-end tests of real service binaries: the harness spawns
+/// the service with the communication channels wired to pipes it owns, acts as
+/// the Fleetspeak client on the other end and kills the service when dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use fleetspeak::Message;
+///
+/// let harness = fleetspeak_test::Harness::spawn("./echo", std::iter::empty::<&str>())
+///     .expect("failed to spawn the service");
+///
+/// harness.send(Message {
+///     service: String::from("echo"),
+///     kind: None,
+///     data: b"ping".to_vec(),
+///     ..Default::default()
+/// }).expect("failed to send the message");
+///
+/// let message = harness.expect(Duration::from_secs(5))
+///     .expect("no reply from the service");
+/// assert_eq!(message.data, b"ping");
+/// ```
+pub struct Harness {
+    service: sys::Service,
+    fake: FakeFleetspeak,
+}
+
+impl Harness {
+
+    /// Spawns the service at `path` with the given `args` and performs the
+    /// handshake with it.
+    pub fn spawn<P, I, S>(path: P, args: I) -> std::io::Result<Harness>
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let service = sys::spawn(path, args)?;
+
+        let mut harness = Harness {
+            fake: FakeFleetspeak::new(service.output.try_clone()?, service.input.try_clone()?)?,
+            service,
+        };
+
+        if let Err(error) = harness.fake.wait_handshake(HANDSHAKE_TIMEOUT) {
+            // The service is killed when the harness is dropped, but we want to
+            // report the more relevant error.
+            let _ = harness.kill();
+            return Err(error);
+        }
+
+        Ok(harness)
+    }
+
+    /// Returns the identifier of the service process.
+    pub fn id(&self) -> u32 {
+        self.service.id()
+    }
+
+    /// Sends a message to the service as if it came from the Fleetspeak server.
+    ///
+    /// See documentation for [`FakeFleetspeak::inject`] for more details.
+    pub fn send(&self, message: Message) -> std::io::Result<()> {
+        self.fake.inject(message)
+    }
+
+    /// Waits for the next message sent by the service.
+    ///
+    /// See documentation for [`FakeFleetspeak::recv_timeout`] for more details.
+    pub fn expect(&self, timeout: Duration) -> std::io::Result<Message> {
+        self.fake.recv_timeout(timeout)
+    }
+
+    /// Waits for the startup information sent by the service and returns the
+    /// reported version.
+    pub fn expect_startup(&self, timeout: Duration) -> std::io::Result<String> {
+        self.fake.wait_version(timeout)
+    }
+
+    /// Returns the fake Fleetspeak client the service talks to.
+    pub fn fake(&self) -> &FakeFleetspeak {
+        &self.fake
+    }
+
+    /// Waits for the service process to exit and returns its exit status.
+    pub fn wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
+        #[cfg(target_family = "unix")]
+        {
+            self.service.wait()
+        }
+        #[cfg(target_family = "windows")]
+        {
+            use std::os::windows::process::ExitStatusExt as _;
+
+            self.service.wait().map(std::process::ExitStatus::from_raw)
+        }
+    }
+
+    /// Forcibly terminates the service process.
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        self.service.kill()
+    }
+}
+
+impl Drop for Harness {
+
+    fn drop(&mut self) {
+        // The service might have exited already, in which case there is nothing
+        // to do and the errors are expected.
+        let _ = self.service.kill();
+        let _ = self.wait();
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for testing Fleetspeak services.
+//!
+//! Fleetspeak services expect to be spawned by the Fleetspeak client with the
+//! communication channels set up in a platform-specific way. This crate allows
+//! tests to play the part of the client.
+//!
+//! [`FakeFleetspeak`] is a test double that services can talk to either over
+//! an in-memory transport (see [`pipe::duplex`]) or, through the environment,
+//! using the global connection of the `fleetspeak` crate. [`Harness`] spawns
+//! a real service binary and drives it through such a fake. The [`golden`]
+//! module provides canonical frames to check the wire format against.
+
+mod fake;
+pub mod golden;
+mod harness;
+pub mod pipe;
+
+pub use self::fake::FakeFleetspeak;
+pub use self::harness::Harness;
+
+#[cfg(target_family = "unix")]
+pub mod unix;
+
+#[cfg(target_family = "windows")]
+pub mod windows;
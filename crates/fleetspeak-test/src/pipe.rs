@@ -0,0 +1,180 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Creates an in-memory unidirectional pipe.
+///
+/// Data written to the returned writer can be read from the returned reader.
+/// Reading blocks until some data is available or the writer is dropped (in
+/// which case the end of file is reported).
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buf: VecDeque::new(),
+            closed: false,
+        }),
+        ready: Condvar::new(),
+    });
+
+    (PipeReader { shared: shared.clone() }, PipeWriter { shared })
+}
+
+/// Creates an in-memory bidirectional transport.
+///
+/// Data written to one of the returned ends can be read from the other one.
+pub fn duplex() -> (Duplex, Duplex) {
+    let (reader_a, writer_a) = pipe();
+    let (reader_b, writer_b) = pipe();
+
+    let a = Duplex {
+        reader: reader_a,
+        writer: writer_b,
+    };
+    let b = Duplex {
+        reader: reader_b,
+        writer: writer_a,
+    };
+
+    (a, b)
+}
+
+/// Reading end of an in-memory [`pipe`].
+pub struct PipeReader {
+    shared: Arc<Shared>,
+}
+
+/// Writing end of an in-memory [`pipe`].
+pub struct PipeWriter {
+    shared: Arc<Shared>,
+}
+
+/// An end of an in-memory bidirectional transport (see [`duplex`]).
+pub struct Duplex {
+    reader: PipeReader,
+    writer: PipeWriter,
+}
+
+impl Duplex {
+
+    /// Splits the transport into its reading and writing halves.
+    ///
+    /// This is useful for APIs that expect separate input and output streams
+    /// (like [`fleetspeak::Connection`]).
+    pub fn into_split(self) -> (PipeReader, PipeWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+impl Read for PipeReader {
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let state = self.shared.state.lock()
+            .expect("poisoned pipe mutex");
+        let mut state = self.shared.ready.wait_while(state, |state| {
+            state.buf.is_empty() && !state.closed
+        }).expect("poisoned pipe mutex");
+
+        state.buf.read(buf)
+    }
+}
+
+impl Write for PipeWriter {
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.shared.state.lock()
+            .expect("poisoned pipe mutex");
+        state.buf.extend(buf);
+        drop(state);
+
+        self.shared.ready.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+
+    fn drop(&mut self) {
+        self.shared.state.lock()
+            .expect("poisoned pipe mutex")
+            .closed = true;
+
+        self.shared.ready.notify_all();
+    }
+}
+
+impl Read for Duplex {
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Duplex {
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// State shared between the ends of a pipe.
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when data is written or the writer is dropped.
+    ready: Condvar,
+}
+
+struct State {
+    buf: VecDeque<u8>,
+    /// Whether the writer has been dropped.
+    closed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn pipe_eof() {
+        let (mut reader, mut writer) = pipe();
+        writer.write_all(b"foo").unwrap();
+        drop(writer);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"foo");
+    }
+
+    #[test]
+    fn duplex_thread() {
+        let (mut a, mut b) = duplex();
+
+        let thread = std::thread::spawn(move || {
+            let mut buf = [0; 3];
+            b.read_exact(&mut buf).unwrap();
+            b.write_all(&buf).unwrap();
+        });
+
+        a.write_all(b"foo").unwrap();
+
+        let mut buf = [0; 3];
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"foo");
+
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Spawning of Fleetspeak services on Unix.
+//!
+//! On Unix, the Fleetspeak client passes the communication channels to the
+//! service as inherited pipe descriptors. Their numbers are given to the
+//! service through the `FLEETSPEAK_COMMS_CHANNEL_INFD` and
+//! `FLEETSPEAK_COMMS_CHANNEL_OUTFD` environment variables.
+//!
+//! Pipes created by the standard library are closed on `exec`, so that they do
+//! not leak into unrelated children (e.g. ones spawned by tests running in
+//! parallel). [`spawn`] keeps only the ends that belong to the service open in
+//! the service process.
+
+use std::ffi::OsStr;
+use std::os::fd::{AsRawFd as _, OwnedFd};
+use std::os::unix::process::CommandExt as _;
+
+/// Name of the environment variable with the descriptor of the input channel.
+const INFD_ENV: &str = "FLEETSPEAK_COMMS_CHANNEL_INFD";
+
+/// Name of the environment variable with the descriptor of the output channel.
+const OUTFD_ENV: &str = "FLEETSPEAK_COMMS_CHANNEL_OUTFD";
+
+/// A service process spawned with the Fleetspeak communication channels.
+pub struct Service {
+    /// The spawned process.
+    child: std::process::Child,
+    /// Channel for writing messages to the service.
+    pub input: std::fs::File,
+    /// Channel for reading messages sent by the service.
+    pub output: std::fs::File,
+}
+
+impl Service {
+
+    /// Returns the identifier of the service process.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Waits for the service process to exit and returns its exit status.
+    pub fn wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
+        self.child.wait()
+    }
+
+    /// Forcibly terminates the service process.
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        self.child.kill()
+    }
+}
+
+/// Spawns the service at `path` with the given `args` as a Fleetspeak client
+/// would.
+///
+/// The service inherits the environment of the current process (with the
+/// communication variables added) and, apart from the standard streams, only
+/// the ends of the communication pipes that belong to it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::io::Read as _;
+///
+/// let mut service = fleetspeak_test::unix::spawn("./service", ["--verbose"])
+///     .expect("failed to spawn the service");
+///
+/// let mut magic = [0; 4];
+/// service.output.read_exact(&mut magic)
+///     .expect("failed to read the handshake");
+/// ```
+pub fn spawn<P, I, S>(path: P, args: I) -> std::io::Result<Service>
+where
+    P: AsRef<OsStr>,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    // The service reads from the first pipe and writes to the second one.
+    let (child_input, parent_input) = std::io::pipe()?;
+    let (parent_output, child_output) = std::io::pipe()?;
+
+    let child_input_fd = child_input.as_raw_fd();
+    let child_output_fd = child_output.as_raw_fd();
+
+    let mut command = std::process::Command::new(path);
+    command
+        .args(args)
+        .env(INFD_ENV, child_input_fd.to_string())
+        .env(OUTFD_ENV, child_output_fd.to_string());
+
+    // SAFETY: The closure runs in the forked process before `exec` and calls
+    // only `fcntl` which is async-signal-safe [1].
+    //
+    // [1]: https://man7.org/linux/man-pages/man7/signal-safety.7.html
+    unsafe {
+        command.pre_exec(move || {
+            clear_cloexec(child_input_fd)?;
+            clear_cloexec(child_output_fd)
+        });
+    }
+
+    let child = command.spawn()?;
+
+    // Our copies of the child ends must be closed, otherwise we would never
+    // see the end of file once the child exits.
+    drop(child_input);
+    drop(child_output);
+
+    Ok(Service {
+        child,
+        input: OwnedFd::from(parent_input).into(),
+        output: OwnedFd::from(parent_output).into(),
+    })
+}
+
+/// Makes the descriptor survive `exec` calls.
+fn clear_cloexec(fd: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: `F_GETFD` and `F_SETFD` only operate on descriptor flags and do
+    // not touch any memory [1]. We verify the results afterwards.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/fcntl.2.html
+    let flags = unsafe {
+        libc::fcntl(fd, libc::F_GETFD)
+    };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: See the comment above.
+    let status = unsafe {
+        libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC)
+    };
+    if status < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
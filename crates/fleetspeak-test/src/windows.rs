@@ -0,0 +1,394 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Spawning of Fleetspeak services on Windows.
+//!
+//! On Windows, the Fleetspeak client passes the communication channels to the
+//! service as inheritable pipe handles. Their values are given to the service
+//! through the `FLEETSPEAK_COMMS_CHANNEL_INFD` and `FLEETSPEAK_COMMS_CHANNEL_OUTFD`
+//! environment variables.
+//!
+//! Getting this right is subtle: handles have to be inheritable for the child
+//! to see them, but by default a child inherits *all* inheritable handles of
+//! its parent. This includes pipes created for other children (e.g. by tests
+//! running in parallel) which are then kept open by the wrong process and make
+//! the tests hang. To avoid this, [`spawn`] passes an explicit list of handles
+//! that the child is allowed to inherit.
+
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt as _;
+use std::os::windows::io::{AsRawHandle as _, FromRawHandle as _, OwnedHandle};
+
+use windows_sys::Win32::Foundation::*;
+use windows_sys::Win32::System::Pipes::CreatePipe;
+use windows_sys::Win32::System::Threading::*;
+
+/// Name of the environment variable with the handle of the input channel.
+const INFD_ENV: &str = "FLEETSPEAK_COMMS_CHANNEL_INFD";
+
+/// Name of the environment variable with the handle of the output channel.
+const OUTFD_ENV: &str = "FLEETSPEAK_COMMS_CHANNEL_OUTFD";
+
+/// A service process spawned with the Fleetspeak communication channels.
+pub struct Service {
+    /// Handle of the spawned process.
+    process: OwnedHandle,
+    /// Identifier of the spawned process.
+    id: u32,
+    /// Channel for writing messages to the service.
+    pub input: std::fs::File,
+    /// Channel for reading messages sent by the service.
+    pub output: std::fs::File,
+}
+
+impl Service {
+
+    /// Returns the identifier of the service process.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Waits for the service process to exit and returns its exit code.
+    pub fn wait(&self) -> std::io::Result<u32> {
+        // SAFETY: The process handle is owned by us and thus valid.
+        let status = unsafe {
+            WaitForSingleObject(self.process.as_raw_handle(), INFINITE)
+        };
+        if status != WAIT_OBJECT_0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut code = std::mem::MaybeUninit::uninit();
+
+        // SAFETY: The process handle is owned by us and we pass a valid pointer
+        // for the exit code. We verify the status after the call.
+        let status = unsafe {
+            GetExitCodeProcess(self.process.as_raw_handle(), code.as_mut_ptr())
+        };
+        if status == FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // SAFETY: We verified that the call to `GetExitCodeProcess` succeeded
+        // and thus `code` is guaranteed to be initialized.
+        Ok(unsafe { code.assume_init() })
+    }
+
+    /// Forcibly terminates the service process.
+    pub fn kill(&self) -> std::io::Result<()> {
+        // SAFETY: The process handle is owned by us and thus valid. Terminating
+        // a process that has already exited fails gracefully.
+        let status = unsafe {
+            TerminateProcess(self.process.as_raw_handle(), 1)
+        };
+        if status == FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Spawns the service at `path` with the given `args` as a Fleetspeak client
+/// would.
+///
+/// The service inherits the environment of the current process (with the
+/// communication variables added) and, apart from the standard handles, only
+/// the ends of the communication pipes that belong to it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::io::Read as _;
+///
+/// let mut service = fleetspeak_test::windows::spawn("service.exe", ["--verbose"])
+///     .expect("failed to spawn the service");
+///
+/// let mut magic = [0; 4];
+/// service.output.read_exact(&mut magic)
+///     .expect("failed to read the handshake");
+/// ```
+pub fn spawn<P, I, S>(path: P, args: I) -> std::io::Result<Service>
+where
+    P: AsRef<OsStr>,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    // The service reads from the first pipe and writes to the second one.
+    let (child_input, parent_input) = pipe()?;
+    let (parent_output, child_output) = pipe()?;
+
+    // Only the ends that belong to the child should be inheritable. The other
+    // ends must be closed when the child exits, otherwise we would never see
+    // the end of file.
+    set_inheritable(&child_input)?;
+    set_inheritable(&child_output)?;
+
+    let mut env = std::env::vars_os()
+        .filter(|(key, _)| key != INFD_ENV && key != OUTFD_ENV)
+        .collect::<Vec<_>>();
+    env.push((INFD_ENV.into(), (child_input.as_raw_handle() as usize).to_string().into()));
+    env.push((OUTFD_ENV.into(), (child_output.as_raw_handle() as usize).to_string().into()));
+
+    let mut command_line = Vec::new();
+    push_arg(&mut command_line, path.as_ref());
+    for arg in args {
+        command_line.push(u16::from(b' '));
+        push_arg(&mut command_line, arg.as_ref());
+    }
+    command_line.push(0);
+
+    let process = create_process(command_line, env_block(env), &[
+        child_input.as_raw_handle(),
+        child_output.as_raw_handle(),
+    ])?;
+
+    // The child has its own copies of its ends now, we have to close ours.
+    drop(child_input);
+    drop(child_output);
+
+    // SAFETY: The thread handle is valid and owned by us. We do not need it.
+    unsafe {
+        CloseHandle(process.hThread);
+    }
+
+    Ok(Service {
+        // SAFETY: The process handle is valid and owned by us.
+        process: unsafe { OwnedHandle::from_raw_handle(process.hProcess) },
+        id: process.dwProcessId,
+        input: std::fs::File::from(parent_input),
+        output: std::fs::File::from(parent_output),
+    })
+}
+
+/// Creates an anonymous pipe and returns its read and write ends.
+///
+/// Both of the ends are created as non-inheritable.
+fn pipe() -> std::io::Result<(OwnedHandle, OwnedHandle)> {
+    let mut read = std::mem::MaybeUninit::uninit();
+    let mut write = std::mem::MaybeUninit::uninit();
+
+    // SAFETY: We pass valid pointers for the handles and no attributes (which
+    // makes the handles non-inheritable) as described in the docs [1]. We
+    // verify the status after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/namedpipeapi/nf-namedpipeapi-createpipe
+    let status = unsafe {
+        CreatePipe(read.as_mut_ptr(), write.as_mut_ptr(), std::ptr::null(), 0)
+    };
+    if status == FALSE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: We verified that the call to `CreatePipe` succeeded and thus both
+    // handles are initialized, valid and owned by us.
+    unsafe {
+        Ok((
+            OwnedHandle::from_raw_handle(read.assume_init()),
+            OwnedHandle::from_raw_handle(write.assume_init()),
+        ))
+    }
+}
+
+/// Marks the given handle as inheritable by child processes.
+fn set_inheritable(handle: &OwnedHandle) -> std::io::Result<()> {
+    // SAFETY: The handle is owned by us and thus valid. We verify the status
+    // after the call.
+    let status = unsafe {
+        SetHandleInformation(handle.as_raw_handle(), HANDLE_FLAG_INHERIT, HANDLE_FLAG_INHERIT)
+    };
+    if status == FALSE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Creates a process that inherits only the given `handles`.
+///
+/// The `command_line` and `env` have to be null-terminated as expected by the
+/// `CreateProcessW` function.
+fn create_process(
+    mut command_line: Vec<u16>,
+    env: Vec<u16>,
+    handles: &[HANDLE],
+) -> std::io::Result<PROCESS_INFORMATION> {
+    let mut size = 0;
+
+    // SAFETY: We query the size of the attribute list with a single attribute
+    // as described in the docs [1]. This call is expected to fail with the
+    // `ERROR_INSUFFICIENT_BUFFER` error, so we do not verify its status.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-initializeprocthreadattributelist
+    unsafe {
+        InitializeProcThreadAttributeList(std::ptr::null_mut(), 1, 0, &mut size);
+    }
+
+    // We use a buffer of `usize` to guarantee pointer alignment of the list.
+    let mut buf = vec![0usize; size.div_ceil(std::mem::size_of::<usize>())];
+    let list: LPPROC_THREAD_ATTRIBUTE_LIST = buf.as_mut_ptr().cast();
+
+    // SAFETY: The buffer is big enough to hold the attribute list as reported
+    // by the previous call. We verify the status after the call.
+    let status = unsafe {
+        InitializeProcThreadAttributeList(list, 1, 0, &mut size)
+    };
+    if status == FALSE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let result = (|| {
+        // SAFETY: The list has been initialized with room for one attribute.
+        // The handle list outlives the list, as required by the docs [1]. We
+        // verify the status after the call.
+        //
+        // [1]: https://learn.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-updateprocthreadattribute
+        let status = unsafe {
+            UpdateProcThreadAttribute(
+                list,
+                0,
+                PROC_THREAD_ATTRIBUTE_HANDLE_LIST as usize,
+                handles.as_ptr().cast(),
+                std::mem::size_of_val(handles),
+                std::ptr::null_mut(),
+                std::ptr::null(),
+            )
+        };
+        if status == FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // SAFETY: `STARTUPINFOEXW` is a plain C structure for which all zeros
+        // is a valid value.
+        let mut startup_info = unsafe {
+            std::mem::zeroed::<STARTUPINFOEXW>()
+        };
+        startup_info.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
+        startup_info.lpAttributeList = list;
+
+        let mut process = std::mem::MaybeUninit::uninit();
+
+        // SAFETY: We pass a mutable null-terminated command line, a Unicode
+        // environment block (and the flag that says so) and the extended
+        // startup info (and the flag that says so) as described in the docs
+        // [1]. Handle inheritance has to be enabled for the handle list to
+        // take effect. We verify the status after the call.
+        //
+        // [1]: https://learn.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-createprocessw
+        let status = unsafe {
+            CreateProcessW(
+                std::ptr::null(),
+                command_line.as_mut_ptr(),
+                std::ptr::null(),
+                std::ptr::null(),
+                TRUE,
+                EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
+                env.as_ptr().cast(),
+                std::ptr::null(),
+                &startup_info.StartupInfo,
+                process.as_mut_ptr(),
+            )
+        };
+        if status == FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // SAFETY: We verified that the call to `CreateProcessW` succeeded and
+        // thus `process` is guaranteed to be initialized.
+        Ok(unsafe { process.assume_init() })
+    })();
+
+    // SAFETY: The list has been successfully initialized above.
+    unsafe {
+        DeleteProcThreadAttributeList(list);
+    }
+
+    result
+}
+
+/// Builds a Unicode environment block for the `CreateProcessW` function.
+fn env_block(mut env: Vec<(std::ffi::OsString, std::ffi::OsString)>) -> Vec<u16> {
+    // The block is expected to be sorted by names (case-insensitively).
+    env.sort_by_cached_key(|(key, _)| key.to_ascii_uppercase());
+
+    let mut block = Vec::new();
+    for (key, value) in env {
+        block.extend(key.encode_wide());
+        block.push(u16::from(b'='));
+        block.extend(value.encode_wide());
+        block.push(0);
+    }
+    block.push(0);
+
+    block
+}
+
+/// Appends the argument to the command line, quoting it if necessary.
+///
+/// The quoting follows the rules used by the Microsoft C runtime [1].
+///
+/// [1]: https://learn.microsoft.com/en-us/cpp/c-language/parsing-c-command-line-arguments
+fn push_arg(command_line: &mut Vec<u16>, arg: &OsStr) {
+    const QUOTE: u16 = b'"' as u16;
+    const BACKSLASH: u16 = b'\\' as u16;
+
+    let arg = arg.encode_wide().collect::<Vec<_>>();
+
+    let needs_quotes = arg.is_empty() || arg.iter().any(|&c| {
+        c == u16::from(b' ') || c == u16::from(b'\t') || c == QUOTE
+    });
+    if !needs_quotes {
+        command_line.extend(arg);
+        return;
+    }
+
+    command_line.push(QUOTE);
+
+    let mut backslashes = 0;
+    for c in arg {
+        match c {
+            BACKSLASH => backslashes += 1,
+            QUOTE => {
+                // Backslashes preceding a quote have to be escaped as well as
+                // the quote itself.
+                command_line.extend(std::iter::repeat_n(BACKSLASH, backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        command_line.push(c);
+    }
+
+    // Trailing backslashes would escape the closing quote.
+    command_line.extend(std::iter::repeat_n(BACKSLASH, backslashes));
+    command_line.push(QUOTE);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn command_line(arg: &str) -> String {
+        let mut command_line = Vec::new();
+        push_arg(&mut command_line, OsStr::new(arg));
+
+        String::from_utf16(&command_line).unwrap()
+    }
+
+    #[test]
+    fn push_arg_plain() {
+        assert_eq!(command_line(r"C:\foo\bar.exe"), r"C:\foo\bar.exe");
+    }
+
+    #[test]
+    fn push_arg_quoted() {
+        assert_eq!(command_line(""), r#""""#);
+        assert_eq!(command_line("foo bar"), r#""foo bar""#);
+        assert_eq!(command_line(r#"foo "bar""#), r#""foo \"bar\"""#);
+        assert_eq!(command_line(r"C:\foo bar\"), r#""C:\foo bar\\""#);
+        assert_eq!(command_line(r#"foo\"bar"#), r#""foo\\\"bar""#);
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use std::time::Duration;
+
+#[test]
+fn receive_cancellable_errors() {
+    let fake = common::install();
+
+    let token = fleetspeak::CancelToken::new().unwrap();
+
+    // Data compressed with an unknown algorithm cannot be decoded.
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"bar".to_vec(),
+        annotations: vec![(String::from(fleetspeak::compression::ANNOTATION), String::from("bogus"))],
+        ..Default::default()
+    }).unwrap();
+
+    let error = fleetspeak::receive_cancellable(&token).unwrap_err();
+    assert!(error.is_malformed());
+
+    let canceller = std::thread::spawn({
+        let token = token.clone();
+        move || {
+            std::thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        }
+    });
+
+    let error = fleetspeak::receive_cancellable(&token).unwrap_err();
+    assert!(error.is_cancelled());
+    canceller.join().unwrap();
+
+    // Neither of the errors broke the connection.
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"baz".to_vec(),
+        ..Default::default()
+    }).unwrap();
+
+    let message = fleetspeak::try_receive().unwrap();
+    assert_eq!(message.data, b"baz");
+}
@@ -0,0 +1,60 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use fleetspeak::capture::{Direction, Reader};
+
+use common::TIMEOUT;
+
+#[test]
+fn capture_records_frames() {
+    let dir = std::env::temp_dir().join(format!("fleetspeak-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_var(fleetspeak::env::CAPTURE_DIR_VAR, &dir);
+
+    let fake = common::install();
+
+    fleetspeak::send(fleetspeak::Message {
+        service: String::from("foo"),
+        kind: Some(String::from("bar")),
+        data: b"quux".to_vec(),
+        ..Default::default()
+    });
+    assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().kind.as_deref(), Some("bar"));
+
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        kind: Some(String::from("baz")),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(fleetspeak::receive().kind.as_deref(), Some("baz"));
+
+    let path = std::fs::read_dir(&dir).unwrap()
+        .next().unwrap().unwrap()
+        .path();
+    let file = std::fs::File::open(path).unwrap();
+
+    let records = Reader::new(std::io::BufReader::new(file)).unwrap()
+        .collect::<std::io::Result<Vec<_>>>()
+        .unwrap();
+
+    // The handshake magic in both directions, the sent and the received frame.
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0].frame.len(), 4);
+    assert_eq!(records[1].frame.len(), 4);
+
+    let outbound = records.iter()
+        .find(|record| record.direction == Direction::Outbound && record.frame.len() > 4)
+        .unwrap();
+    assert!(outbound.frame.windows(4).any(|window| window == b"quux"));
+
+    let inbound = records.iter()
+        .find(|record| record.direction == Direction::Inbound && record.frame.len() > 4)
+        .unwrap();
+    assert!(inbound.frame.windows(3).any(|window| window == b"baz"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
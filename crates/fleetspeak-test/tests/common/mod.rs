@@ -0,0 +1,24 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Setup shared by the integration tests.
+//!
+//! The global connection is established once per process, so every binary
+//! setting it up has to contain exactly one test.
+
+// Not every test binary uses every item.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use fleetspeak_test::FakeFleetspeak;
+
+/// Time to wait for the fake to observe something written by the service.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Installs a fake Fleetspeak instance as the global connection.
+pub fn install() -> FakeFleetspeak {
+    FakeFleetspeak::install().expect("failed to install the fake")
+}
@@ -0,0 +1,66 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use fleetspeak::compression::{Compressor, ANNOTATION};
+
+use common::TIMEOUT;
+
+/// A "compressor" reversing the data, which makes its effect easy to verify.
+struct Reverse;
+
+impl Compressor for Reverse {
+
+    fn algorithm(&self) -> &str {
+        "reverse"
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(data.iter().rev().copied().collect())
+    }
+
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.compress(data)
+    }
+}
+
+#[test]
+fn compress_above_threshold() {
+    let fake = common::install();
+
+    fleetspeak::compression::install(Reverse);
+    fleetspeak::compression::set_threshold(8);
+
+    fleetspeak::send(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"small".to_vec(),
+        ..Default::default()
+    });
+    fleetspeak::send(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"0123456789".to_vec(),
+        ..Default::default()
+    });
+
+    let message = fake.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(message.data, b"small");
+    assert!(message.annotations.is_empty());
+
+    let message = fake.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(message.data, b"9876543210");
+    assert_eq!(message.annotations, vec![(String::from(ANNOTATION), String::from("reverse"))]);
+
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"rab".to_vec(),
+        annotations: vec![(String::from(ANNOTATION), String::from("reverse"))],
+        ..Default::default()
+    }).unwrap();
+
+    let message = fleetspeak::receive();
+    assert_eq!(message.data, b"bar");
+    assert!(message.annotations.is_empty());
+}
@@ -0,0 +1,38 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use common::TIMEOUT;
+
+#[test]
+fn concurrent_senders() {
+    let fake = common::install();
+
+    let threads = (0..8u8).map(|thread| std::thread::spawn(move || {
+        for i in 0..16u8 {
+            fleetspeak::send(fleetspeak::Message {
+                service: String::from("foo"),
+                data: vec![thread, i],
+                ..Default::default()
+            });
+        }
+    })).collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // Messages of different threads can interleave but messages of a single
+    // thread have to be written in order.
+    let mut next = [0u8; 8];
+    for _ in 0..8 * 16 {
+        let message = fake.recv_timeout(TIMEOUT).unwrap();
+        let (thread, i) = (message.data[0] as usize, message.data[1]);
+
+        assert_eq!(next[thread], i);
+        next[thread] += 1;
+    }
+}
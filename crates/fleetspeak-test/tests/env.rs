@@ -0,0 +1,44 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+// The environment is set up once per process, so this binary has to contain
+// exactly one test.
+
+mod common;
+
+use fleetspeak::env::{Env, BUFFER_CAPACITY_VAR, COMMS_IN_VAR, COMMS_OUT_VAR, MAX_MESSAGE_SIZE_VAR};
+
+#[test]
+fn env_from_fake() {
+    let error = Env::from_env().unwrap_err();
+    assert!(error.is_not_specified());
+    assert_eq!(error.var(), COMMS_IN_VAR);
+
+    let _fake = common::install();
+    std::env::set_var("FLEETSPEAK_FOO", "bar");
+    std::env::set_var(MAX_MESSAGE_SIZE_VAR, "1024");
+    std::env::set_var(BUFFER_CAPACITY_VAR, "4096");
+
+    let env = Env::from_env().unwrap();
+    #[cfg(target_family = "unix")]
+    let (comms_in, comms_out) = (env.comms_in.to_string(), env.comms_out.to_string());
+    #[cfg(target_family = "windows")]
+    let (comms_in, comms_out) = ((env.comms_in as usize).to_string(), (env.comms_out as usize).to_string());
+    assert_eq!(comms_in, std::env::var(COMMS_IN_VAR).unwrap());
+    assert_eq!(comms_out, std::env::var(COMMS_OUT_VAR).unwrap());
+    assert_eq!(env.max_message_size, Some(1024));
+    assert_eq!(env.buffer_capacity, Some(4096));
+    assert_eq!(env.other, vec![("FLEETSPEAK_FOO".into(), "bar".into())]);
+
+    // Messages over the limit are refused without breaking the connection.
+    let message = |size| fleetspeak::Message {
+        service: String::from("foo"),
+        data: vec![0; size],
+        ..Default::default()
+    };
+    assert!(fleetspeak::try_send(message(2048)).is_err());
+    assert!(fleetspeak::try_send(message(16)).is_ok());
+    assert_eq!(fleetspeak::status(), fleetspeak::Status::Connected);
+}
@@ -0,0 +1,48 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+#![cfg(target_family = "unix")]
+
+mod common;
+
+use common::TIMEOUT;
+
+#[test]
+fn disown_in_forked_child() {
+    let fake = common::install();
+
+    fleetspeak::startup("1.2.3");
+
+    // SAFETY: The child only disowns the connection and exits.
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0, "fork failed: {}", std::io::Error::last_os_error());
+
+    if pid == 0 {
+        let disowned = fleetspeak::disown_after_fork().is_ok();
+        let closed = fleetspeak::status() == fleetspeak::Status::Closed;
+
+        // SAFETY: Exiting immediately is exactly what we want in the child.
+        unsafe {
+            libc::_exit(if disowned && closed { 0 } else { 1 });
+        }
+    }
+
+    let mut status = 0;
+    // SAFETY: We wait for the child we have just forked.
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 0);
+
+    // The parent still owns the connection.
+    assert_eq!(fleetspeak::status(), fleetspeak::Status::Connected);
+    fleetspeak::send(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"bar".to_vec(),
+        ..Default::default()
+    });
+
+    let message = fake.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(message.data, b"bar");
+}
@@ -0,0 +1,39 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use fleetspeak::Message;
+
+use common::TIMEOUT;
+
+#[test]
+fn global_connection() {
+    let fake = common::install();
+
+    fleetspeak::startup("1.2.3");
+
+    fake.inject(Message {
+        service: String::from("foo"),
+        kind: Some(String::from("bar")),
+        data: b"baz".to_vec(),
+        ..Default::default()
+    }).unwrap();
+
+    let message = fleetspeak::receive();
+    fleetspeak::send(Message {
+        service: message.service,
+        kind: message.kind,
+        data: message.data,
+        ..Default::default()
+    });
+
+    let message = fake.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(message.service, "foo");
+    assert_eq!(message.kind.as_deref(), Some("bar"));
+    assert_eq!(message.data, b"baz");
+
+    assert_eq!(fake.version().as_deref(), Some("1.2.3"));
+}
@@ -0,0 +1,79 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+// Conformance of the framing of the connector with the canonical wire format.
+
+use fleetspeak::Connection;
+use fleetspeak_test::golden;
+
+#[test]
+fn handshake() {
+    let mut conn = Connection::new(&golden::HANDSHAKE[..], Vec::new());
+    conn.handshake().unwrap();
+
+    let (_, output) = conn.into_inner();
+    golden::assert_bytes_eq(&output, &golden::HANDSHAKE);
+}
+
+#[test]
+fn heartbeat() {
+    let mut conn = Connection::new(std::io::empty(), Vec::new());
+    conn.heartbeat().unwrap();
+
+    let (_, output) = conn.into_inner();
+    golden::assert_bytes_eq(&output, golden::HEARTBEAT);
+}
+
+#[test]
+fn startup() {
+    let mut conn = Connection::new(std::io::empty(), Vec::new());
+    conn.startup(golden::STARTUP_VERSION).unwrap();
+    let (_, output) = conn.into_inner();
+
+    // The process identifier and the annotations depend on the environment, so
+    // we normalize them before comparing.
+    let mut proto = Connection::new(&output[..], std::io::sink())
+        .receive_raw().unwrap();
+    proto.clear_annotations();
+
+    let mut data = fleetspeak::any::unpack::<fleetspeak_proto::channel::StartupData>(proto.data())
+        .unwrap();
+    assert_eq!(data.pid, i64::from(std::process::id()));
+    data.pid = golden::STARTUP_PID;
+    *proto.mut_data() = fleetspeak::any::pack(&data).unwrap();
+
+    let mut conn = Connection::new(std::io::empty(), Vec::new());
+    conn.send_raw(proto).unwrap();
+
+    let (_, output) = conn.into_inner();
+    golden::assert_bytes_eq(&output, golden::STARTUP);
+}
+
+#[test]
+fn message_out() {
+    let mut conn = Connection::new(std::io::empty(), Vec::new());
+    conn.send(fleetspeak::Message {
+        service: String::from("example"),
+        kind: Some(String::from("greeting")),
+        data: b"Hello".to_vec(),
+        ..Default::default()
+    }).unwrap();
+
+    let (_, output) = conn.into_inner();
+    golden::assert_bytes_eq(&output, golden::MESSAGE_OUT);
+}
+
+#[test]
+fn message_in() {
+    let frames = [&golden::HANDSHAKE[..], golden::MESSAGE_IN].concat();
+
+    let mut conn = Connection::new(&frames[..], std::io::sink());
+    conn.handshake().unwrap();
+
+    let message = conn.receive().unwrap();
+    assert_eq!(message.service, "example");
+    assert_eq!(message.kind.as_deref(), Some("greeting"));
+    assert_eq!(message.data, b"Hello");
+}
@@ -0,0 +1,89 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+// The harness needs a service binary to spawn. Instead of building a separate
+// one, the test binary re-executes itself running only one of the `*_service`
+// tests which act as the service when the communication channels are present.
+
+use std::time::Duration;
+
+use fleetspeak::Message;
+use fleetspeak_test::Harness;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn echo_service() {
+    if std::env::var_os("FLEETSPEAK_COMMS_CHANNEL_INFD").is_none() {
+        return;
+    }
+
+    fleetspeak::startup("1.2.3");
+
+    let message = fleetspeak::receive();
+    fleetspeak::send(message);
+}
+
+#[test]
+fn harness_echo() {
+    let exe = std::env::current_exe().unwrap();
+    let mut harness = Harness::spawn(exe, ["--exact", "echo_service", "--test-threads=1"]).unwrap();
+
+    assert_eq!(harness.expect_startup(TIMEOUT).unwrap(), "1.2.3");
+
+    harness.send(Message {
+        service: String::from("foo"),
+        kind: Some(String::from("bar")),
+        data: b"baz".to_vec(),
+        ..Default::default()
+    }).unwrap();
+
+    let message = harness.expect(TIMEOUT).unwrap();
+    assert_eq!(message.service, "foo");
+    assert_eq!(message.kind.as_deref(), Some("bar"));
+    assert_eq!(message.data, b"baz");
+
+    assert!(harness.wait().unwrap().success());
+}
+
+struct Echo;
+
+impl fleetspeak::Service for Echo {
+
+    fn startup_version(&self) -> &str {
+        "4.5.6"
+    }
+
+    fn handle(&mut self, message: Message) {
+        fleetspeak::send(message);
+    }
+}
+
+#[test]
+fn run_service() {
+    if std::env::var_os("FLEETSPEAK_COMMS_CHANNEL_INFD").is_none() {
+        return;
+    }
+
+    fleetspeak::run(Echo);
+}
+
+#[test]
+fn harness_run() {
+    let exe = std::env::current_exe().unwrap();
+    let harness = Harness::spawn(exe, ["--exact", "run_service", "--test-threads=1"]).unwrap();
+
+    assert_eq!(harness.expect_startup(TIMEOUT).unwrap(), "4.5.6");
+
+    for data in [b"foo", b"bar"] {
+        harness.send(Message {
+            service: String::from("echo"),
+            data: data.to_vec(),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(harness.expect(TIMEOUT).unwrap().data, data);
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TIMEOUT;
+
+#[test]
+fn background_heartbeats() {
+    let fake = common::install();
+
+    fleetspeak::startup("1.2.3");
+    fleetspeak::start_heartbeats(Duration::from_millis(10));
+
+    let deadline = Instant::now() + TIMEOUT;
+    while fake.heartbeats() < 3 {
+        assert!(Instant::now() < deadline, "not enough heartbeats");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    fleetspeak::stop_heartbeats();
+
+    // Heartbeats are written synchronously, so the stopped thread has already
+    // written everything it ever will. Still, the fake might not have read it.
+    std::thread::sleep(Duration::from_millis(100));
+    let heartbeats = fake.heartbeats();
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(fake.heartbeats(), heartbeats);
+}
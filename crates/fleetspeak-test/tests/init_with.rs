@@ -0,0 +1,39 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use fleetspeak_test::FakeFleetspeak;
+
+use common::TIMEOUT;
+
+#[test]
+fn init_with_in_memory_transport() {
+    let (service, fake) = fleetspeak_test::pipe::duplex();
+    let (service_input, service_output) = service.into_split();
+    let (fake_input, fake_output) = fake.into_split();
+
+    let fake = FakeFleetspeak::new(fake_input, fake_output).unwrap();
+
+    fleetspeak::init_with(service_input, service_output).unwrap();
+    assert!(fleetspeak::is_initialized());
+
+    fleetspeak::send(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"foo".to_vec(),
+        ..Default::default()
+    });
+    assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().data, b"foo");
+
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"bar".to_vec(),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(fleetspeak::receive().data, b"bar");
+
+    let (input, output) = fleetspeak_test::pipe::duplex().0.into_split();
+    assert!(fleetspeak::init_with(input, output).is_err());
+}
@@ -0,0 +1,19 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+#[test]
+fn initialized_after_init() {
+    let _fake = common::install();
+    assert!(!fleetspeak::is_initialized());
+
+    fleetspeak::init().unwrap();
+    assert!(fleetspeak::is_initialized());
+
+    // Subsequent calls report the result of the first attempt.
+    fleetspeak::init().unwrap();
+    assert!(fleetspeak::is_initialized());
+}
@@ -0,0 +1,32 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use common::TIMEOUT;
+
+#[test]
+fn ship_log_records() {
+    let fake = common::install();
+
+    fleetspeak::logger::Logger::new("logs")
+        .with_max_records(2)
+        .install()
+        .unwrap();
+
+    log::info!("foo");
+    log::debug!("ignored");
+    log::warn!("bar");
+
+    let message = fake.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(message.service, "logs");
+    assert_eq!(message.kind.as_deref(), Some(fleetspeak::logger::KIND));
+
+    let data = String::from_utf8(message.data).unwrap();
+    let lines = data.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(" INFO logger: foo"));
+    assert!(lines[1].ends_with(" WARN logger: bar"));
+}
@@ -0,0 +1,30 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+#[test]
+fn skip_malformed() {
+    let fake = common::install();
+
+    fleetspeak::set_malformed(fleetspeak::Malformed::Skip);
+
+    // Data compressed with an unknown algorithm cannot be decoded.
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"bar".to_vec(),
+        annotations: vec![(String::from(fleetspeak::compression::ANNOTATION), String::from("bogus"))],
+        ..Default::default()
+    }).unwrap();
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"baz".to_vec(),
+        ..Default::default()
+    }).unwrap();
+
+    let message = fleetspeak::try_receive().unwrap();
+    assert_eq!(message.data, b"baz");
+    assert_eq!(fleetspeak::stats().decode_failures, 1);
+}
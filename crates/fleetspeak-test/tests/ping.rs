@@ -0,0 +1,35 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use common::TIMEOUT;
+
+#[test]
+fn answer_pings() {
+    let fake = common::install();
+
+    fleetspeak::startup("1.2.3");
+    fleetspeak::answer_pings("Ping", "1.2.3");
+
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        kind: Some(String::from("Ping")),
+        ..Default::default()
+    }).unwrap();
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        kind: Some(String::from("bar")),
+        ..Default::default()
+    }).unwrap();
+
+    // The ping is answered by the library and never reaches the service.
+    let message = fleetspeak::receive();
+    assert_eq!(message.kind.as_deref(), Some("bar"));
+
+    let pong = fake.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!(pong.service, "foo");
+    assert_eq!(pong.kind.as_deref(), Some(fleetspeak::PONG_KIND));
+}
@@ -0,0 +1,87 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+#![cfg(target_family = "unix")]
+
+mod common;
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use fleetspeak::{Message, QueueFullPolicy};
+use fleetspeak_test::FakeFleetspeak;
+
+use common::TIMEOUT;
+
+/// A reader that blocks until it is opened, simulating a stalled client.
+struct Gate<R> {
+    inner: R,
+    open: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl<R: std::io::Read> std::io::Read for Gate<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (open, opened) = &*self.open;
+        let mut open = open.lock().unwrap();
+        while !*open {
+            open = opened.wait(open).unwrap();
+        }
+        drop(open);
+
+        self.inner.read(buf)
+    }
+}
+
+fn message(kind: &str, data: Vec<u8>) -> Message {
+    Message {
+        service: String::from("foo"),
+        kind: Some(String::from(kind)),
+        data,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn send_queued_full() {
+    use std::os::fd::IntoRawFd as _;
+
+    let (service_input, fake_output) = std::io::pipe().unwrap();
+    let (fake_input, service_output) = std::io::pipe().unwrap();
+    std::env::set_var(fleetspeak::env::COMMS_IN_VAR, service_input.into_raw_fd().to_string());
+    std::env::set_var(fleetspeak::env::COMMS_OUT_VAR, service_output.into_raw_fd().to_string());
+
+    let open = Arc::new((Mutex::new(false), Condvar::new()));
+    let fake = FakeFleetspeak::new(Gate {
+        inner: fake_input,
+        open: open.clone(),
+    }, fake_output).unwrap();
+
+    fleetspeak::init().unwrap();
+    fleetspeak::limit_send_queue(2, QueueFullPolicy::WouldBlock);
+
+    // The message does not fit into the pipe, so the writer gets stuck on it
+    // until the client starts reading.
+    fleetspeak::send_queued(message("big", vec![0; 1024 * 1024])).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+
+    fleetspeak::send_queued(message("a", vec![])).unwrap();
+    fleetspeak::send_queued(message("b", vec![])).unwrap();
+
+    let error = fleetspeak::send_queued(message("c", vec![])).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+
+    fleetspeak::limit_send_queue(2, QueueFullPolicy::DropOldest);
+    fleetspeak::send_queued(message("d", vec![])).unwrap();
+
+    *open.0.lock().unwrap() = true;
+    open.1.notify_all();
+    assert!(fleetspeak::drain(TIMEOUT));
+
+    let kinds = (0..3)
+        .map(|_| fake.recv_timeout(TIMEOUT).unwrap().kind.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(kinds, ["big", "b", "d"]);
+}
@@ -0,0 +1,29 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use common::TIMEOUT;
+
+#[test]
+fn send_queued_in_order() {
+    let fake = common::install();
+
+    for i in 0..8 {
+        fleetspeak::send_queued(fleetspeak::Message {
+            service: String::from("foo"),
+            data: vec![i],
+            ..Default::default()
+        }).unwrap();
+    }
+    assert!(fleetspeak::drain(TIMEOUT));
+
+    for i in 0..8 {
+        assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().data, vec![i]);
+    }
+
+    assert_eq!(fleetspeak::status(), fleetspeak::Status::Connected);
+    assert!(fleetspeak::close_error().is_none());
+}
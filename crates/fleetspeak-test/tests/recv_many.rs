@@ -0,0 +1,47 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use std::time::Duration;
+
+use common::TIMEOUT;
+
+#[test]
+fn recv_many_batches() {
+    let fake = common::install();
+
+    fleetspeak::startup("1.2.3");
+
+    assert!(fleetspeak::recv_many(8, Duration::from_millis(50)).unwrap().is_empty());
+
+    for data in [b"foo", b"bar", b"baz"] {
+        fake.inject(fleetspeak::Message {
+            service: String::from("foo"),
+            data: data.to_vec(),
+            ..Default::default()
+        }).unwrap();
+    }
+
+    let messages = fleetspeak::recv_many(2, TIMEOUT).unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].data, b"foo");
+    assert_eq!(messages[1].data, b"bar");
+
+    let messages = fleetspeak::recv_many(8, TIMEOUT).unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].data, b"baz");
+
+    // A call that timed out does not take messages from later receives.
+    assert!(fleetspeak::recv_many(8, Duration::from_millis(50)).unwrap().is_empty());
+
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"qux".to_vec(),
+        ..Default::default()
+    }).unwrap();
+
+    assert_eq!(fleetspeak::receive().data, b"qux");
+}
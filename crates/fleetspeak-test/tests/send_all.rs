@@ -0,0 +1,54 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use common::TIMEOUT;
+
+/// Signer refusing to sign empty payloads.
+struct PickySigner;
+
+impl fleetspeak::signing::Signer for PickySigner {
+
+    fn algorithm(&self) -> &str {
+        "picky"
+    }
+
+    fn sign(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        if data.is_empty() {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+
+        Ok(data.to_vec())
+    }
+}
+
+#[test]
+fn send_all_rejected() {
+    let fake = common::install();
+
+    fleetspeak::signing::install_signer(PickySigner);
+
+    let message = |data: &[u8]| fleetspeak::Message {
+        service: String::from("foo"),
+        data: data.to_vec(),
+        ..Default::default()
+    };
+
+    fleetspeak::send_queued(message(b"bar")).unwrap();
+
+    let error = fleetspeak::send_all([message(b"baz"), message(b"")]).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+    fleetspeak::send_all([message(b"quux"), message(b"norf")]).unwrap();
+
+    // The batch is written after the message queued before and nothing from
+    // the rejected batch is written at all.
+    assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().data, b"bar");
+    assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().data, b"quux");
+    assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().data, b"norf");
+
+    assert_eq!(fleetspeak::status(), fleetspeak::Status::Connected);
+}
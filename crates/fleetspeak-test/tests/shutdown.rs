@@ -0,0 +1,34 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use common::TIMEOUT;
+
+#[test]
+fn shutdown_with_final_message() {
+    let fake = common::install();
+
+    fleetspeak::startup("1.2.3");
+    fleetspeak::send(fleetspeak::Message {
+        service: String::from("foo"),
+        kind: Some(String::from("first")),
+        ..Default::default()
+    });
+
+    fleetspeak::shutdown(Some(fleetspeak::Message {
+        service: String::from("foo"),
+        kind: Some(String::from("last")),
+        ..Default::default()
+    })).unwrap();
+
+    assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().kind.as_deref(), Some("first"));
+    assert_eq!(fake.recv_timeout(TIMEOUT).unwrap().kind.as_deref(), Some("last"));
+
+    let error = fake.recv_timeout(TIMEOUT).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    assert_eq!(fleetspeak::status(), fleetspeak::Status::Closed);
+}
@@ -0,0 +1,40 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+#![cfg(target_family = "unix")]
+
+mod common;
+
+use std::time::Duration;
+
+use fleetspeak::Received;
+
+const RATE: Duration = Duration::from_secs(1);
+
+#[test]
+fn receive_until_sigterm() {
+    let fake = common::install();
+
+    fleetspeak::startup("1.2.3");
+
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        kind: Some(String::from("bar")),
+        ..Default::default()
+    }).unwrap();
+
+    // The first call installs the handlers.
+    match fleetspeak::receive_until_shutdown(RATE) {
+        Received::Message(message) => assert_eq!(message.kind.as_deref(), Some("bar")),
+        Received::Shutdown => panic!("unexpected shutdown"),
+    }
+
+    // SAFETY: Raising a signal has no memory safety requirements. The handler
+    // has been installed above, so the process is not terminated.
+    assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+
+    assert!(matches!(fleetspeak::receive_until_shutdown(RATE), Received::Shutdown));
+    assert!(matches!(fleetspeak::receive_until_shutdown(RATE), Received::Shutdown));
+}
@@ -0,0 +1,41 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+mod common;
+
+use common::TIMEOUT;
+
+#[test]
+fn stats_counters() {
+    let fake = common::install();
+
+    let before = fleetspeak::stats();
+    assert_eq!(before.messages_received, 0);
+    assert!(before.last_received.is_none());
+
+    fleetspeak::send(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"foo".to_vec(),
+        ..Default::default()
+    });
+    fleetspeak::heartbeat();
+    fake.recv_timeout(TIMEOUT).unwrap();
+
+    fake.inject(fleetspeak::Message {
+        service: String::from("foo"),
+        data: b"quux".to_vec(),
+        ..Default::default()
+    }).unwrap();
+    fleetspeak::receive();
+
+    let after = fleetspeak::stats();
+    assert_eq!(after.messages_sent, before.messages_sent + 1);
+    assert_eq!(after.bytes_sent, before.bytes_sent + 3);
+    assert_eq!(after.heartbeats_sent, before.heartbeats_sent + 1);
+    assert_eq!(after.messages_received, 1);
+    assert_eq!(after.bytes_received, 4);
+    assert_eq!(after.decode_failures, 0);
+    assert!(after.last_received.is_some());
+}
@@ -20,6 +20,7 @@ fn main() {
             service: String::from("greeter"),
             kind: None,
             data: response.into_bytes(),
+            ..Default::default()
         });
     }
 }
@@ -0,0 +1,188 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Type-checked packing of Protocol Buffers messages into `Any`.
+//!
+//! Fleetspeak wraps message payloads in [`Any`], which pairs the serialized
+//! message with a URL identifying its type. The functions in this module set
+//! the URL following the `type.googleapis.com/<full name>` convention used by
+//! the Fleetspeak server and verify it when unpacking, so that a payload of an
+//! unexpected type is reported as such rather than parsed into garbage.
+//!
+//! # Examples
+//!
+//! ```
+//! use protobuf::well_known_types::timestamp::Timestamp;
+//! use protobuf::well_known_types::duration::Duration;
+//!
+//! let any = fleetspeak::any::pack(&Timestamp::now())
+//!     .expect("failed to pack the message");
+//! assert_eq!(any.type_url, "type.googleapis.com/google.protobuf.Timestamp");
+//!
+//! let error = fleetspeak::any::unpack::<Duration>(&any).unwrap_err();
+//! assert!(error.is_type_mismatch());
+//! ```
+
+use protobuf::well_known_types::any::Any;
+
+/// Prefix of type URLs of packed messages.
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/";
+
+/// Packs the message into [`Any`].
+pub fn pack<M>(message: &M) -> std::io::Result<Any>
+where
+    M: protobuf::MessageFull,
+{
+    let mut any = Any::new();
+    any.type_url = type_url(M::descriptor().full_name());
+    any.value = message.write_to_bytes()?;
+
+    Ok(any)
+}
+
+/// Unpacks a message of the expected type from [`Any`].
+///
+/// An error is returned if the type URL does not name the expected message
+/// type or if the message cannot be parsed.
+pub fn unpack<M>(any: &Any) -> Result<M, UnpackError>
+where
+    M: protobuf::MessageFull,
+{
+    verify_type_url(&any.type_url, M::descriptor().full_name())?;
+
+    M::parse_from_bytes(&any.value)
+        .map_err(|error| UnpackError {
+            repr: UnpackErrorRepr::Parse(error),
+        })
+}
+
+/// An error returned in case unpacking a message fails.
+#[derive(Debug)]
+pub struct UnpackError {
+    repr: UnpackErrorRepr,
+}
+
+#[derive(Debug)]
+enum UnpackErrorRepr {
+    /// The type URL does not name the expected message type.
+    TypeMismatch {
+        /// The type URL of the packed message.
+        type_url: String,
+        /// The fully qualified name of the expected message type.
+        expected: String,
+    },
+    /// The packed message could not be parsed.
+    Parse(protobuf::Error),
+}
+
+impl UnpackError {
+
+    /// Returns whether the packed message is of a different type than expected.
+    pub fn is_type_mismatch(&self) -> bool {
+        matches!(self.repr, UnpackErrorRepr::TypeMismatch { .. })
+    }
+}
+
+impl std::fmt::Display for UnpackError {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.repr {
+            UnpackErrorRepr::TypeMismatch { type_url, expected } => {
+                write!(fmt, "unexpected payload type '{type_url}' (expected '{expected}')")
+            }
+            UnpackErrorRepr::Parse(error) => {
+                write!(fmt, "malformed payload: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UnpackError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.repr {
+            UnpackErrorRepr::TypeMismatch { .. } => None,
+            UnpackErrorRepr::Parse(error) => Some(error),
+        }
+    }
+}
+
+impl From<UnpackError> for std::io::Error {
+
+    fn from(error: UnpackError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Returns the type URL for a message with the given fully qualified name.
+pub(crate) fn type_url(full_name: &str) -> String {
+    format!("{TYPE_URL_PREFIX}{full_name}")
+}
+
+/// Verifies that the type URL refers to the message with the given fully
+/// qualified name.
+///
+/// Only the last segment of the URL is relevant, the prefix is ignored.
+pub(crate) fn verify_type_url(type_url: &str, full_name: &str) -> Result<(), UnpackError> {
+    let name = match type_url.rsplit_once('/') {
+        Some((_, name)) => name,
+        None => type_url,
+    };
+
+    if name != full_name {
+        return Err(UnpackError {
+            repr: UnpackErrorRepr::TypeMismatch {
+                type_url: String::from(type_url),
+                expected: String::from(full_name),
+            },
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use protobuf::well_known_types::duration::Duration;
+    use protobuf::well_known_types::timestamp::Timestamp;
+
+    use super::*;
+
+    #[test]
+    fn pack_unpack() {
+        let mut timestamp = Timestamp::new();
+        timestamp.seconds = 1337;
+
+        let any = pack(&timestamp).unwrap();
+        assert_eq!(unpack::<Timestamp>(&any).unwrap(), timestamp);
+    }
+
+    #[test]
+    fn unpack_type_mismatch() {
+        let any = pack(&Timestamp::new()).unwrap();
+
+        let error = unpack::<Duration>(&any).unwrap_err();
+        assert!(error.is_type_mismatch());
+    }
+
+    #[test]
+    fn unpack_malformed() {
+        let mut any = pack(&Timestamp::new()).unwrap();
+        any.value = vec![0xff];
+
+        let error = unpack::<Timestamp>(&any).unwrap_err();
+        assert!(!error.is_type_mismatch());
+    }
+
+    #[test]
+    fn verify_type_url_prefix() {
+        assert!(verify_type_url("type.googleapis.com/foo.Bar", "foo.Bar").is_ok());
+        assert!(verify_type_url("example.com/types/foo.Bar", "foo.Bar").is_ok());
+        assert!(verify_type_url("foo.Bar", "foo.Bar").is_ok());
+        assert!(verify_type_url("type.googleapis.com/foo.Baz", "foo.Bar").is_err());
+        assert!(verify_type_url("", "foo.Bar").is_err());
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Asynchronous variants of the connector functions for Tokio services.
+//!
+//! The functions in this module never block the executor thread waiting for
+//! the Fleetspeak client. Instead, they yield to the runtime until the
+//! communication channel is ready.
+//!
+//! On Unix, the channel descriptors are registered with the Tokio reactor, so
+//! the runtime used to drive the futures has to have the I/O driver enabled.
+//! Anonymous pipes on Windows (and descriptors that cannot be registered with
+//! the reactor on Unix, like regular files) are checked periodically instead.
+//! The checks back off exponentially up to [`MAX_INTERVAL`] so that an idle
+//! service does not burn CPU cycles, which also bounds the latency added by
+//! this approach. In this case, the runtime has to have the time driver
+//! enabled.
+//!
+//! Note that the connection itself is established (including the handshake)
+//! synchronously on first use, just like with the blocking functions. Since
+//! the Fleetspeak client answers the handshake immediately, this is usually not
+//! a concern. Moreover, on Windows anonymous pipes do not allow to check how
+//! much data can be written without blocking, so sending might block if the
+//! Fleetspeak client does not drain the channel.
+
+use std::io::Read as _;
+use std::time::Duration;
+
+use crate::Message;
+
+/// Shortest interval between periodic readiness checks of the channel.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Longest interval between periodic readiness checks of the channel.
+pub const MAX_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Asynchronously sends a heartbeat signal to the Fleetspeak client.
+///
+/// See documentation for the [`heartbeat`] function for more details.
+///
+/// [`heartbeat`]: crate::heartbeat
+pub async fn heartbeat() {
+    writable().await;
+
+    if let Err(error) = crate::deliver_heartbeat() {
+        crate::fail(error);
+    }
+}
+
+/// Asynchronously sends a system message with startup information to the
+/// Fleetspeak client.
+///
+/// See documentation for the [`startup`] function for more details.
+///
+/// [`startup`]: crate::startup
+pub async fn startup(version: &str) {
+    writable().await;
+
+    crate::startup(version)
+}
+
+/// Asynchronously sends the message to the Fleetspeak server.
+///
+/// See documentation for the [`send`] function for more details.
+///
+/// [`send`]: crate::send
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example() {
+/// use fleetspeak::Message;
+///
+/// fleetspeak::asynchronous::send(Message {
+///     service: String::from("example"),
+///     kind: None,
+///     data: String::from("Hello, world!").into_bytes(),
+///     ..Default::default()
+/// }).await;
+/// # }
+/// ```
+pub async fn send(message: Message) {
+    writable().await;
+
+    if let Err(error) = crate::deliver(message) {
+        crate::fail(error);
+    }
+}
+
+/// Asynchronously receives a message from the Fleetspeak server.
+///
+/// Messages are received in full before they are returned, so dropping the
+/// future does not lose any partially read message: it is received by the next
+/// call. However, this function should not be used concurrently with blocking
+/// [`receive`] calls as those are not aware of partially read messages.
+///
+/// Frames are parsed the same way as by the blocking functions, so the same
+/// message size limit (see [`env::MAX_MESSAGE_SIZE_VAR`]) and [resynchronization]
+/// rules apply.
+///
+/// See documentation for the [`receive`] function for more details.
+///
+/// [`receive`]: crate::receive
+/// [`env::MAX_MESSAGE_SIZE_VAR`]: crate::env::MAX_MESSAGE_SIZE_VAR
+/// [resynchronization]: crate::set_resync_limit
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example() {
+/// let message = fleetspeak::asynchronous::receive().await;
+/// println!("received {}", message.preview());
+/// # }
+/// ```
+pub async fn receive() -> Message {
+    loop {
+        let mut partial = PARTIAL.lock().await;
+
+        let mut eof = false;
+        let proto = loop {
+            let missing = match parse_frame(&mut partial, eof) {
+                Ok(proto) => break proto,
+                Err(Incomplete::Missing(missing)) => missing,
+                Err(Incomplete::Failed(error)) => crate::fail(error),
+            };
+
+            match read_more(&mut partial, missing).await {
+                Ok(0) => eof = true,
+                Ok(_) => (),
+                Err(error) => crate::fail(error),
+            }
+        };
+        drop(partial);
+
+        if let Some(message) = crate::accept(proto) {
+            return message;
+        }
+    }
+}
+
+/// Waits until the output channel is ready for writing.
+async fn writable() {
+    let mut backoff = Backoff::new();
+    loop {
+        // The guard has to go out of scope before the future yields, as it
+        // cannot be sent between threads.
+        let (ready, handle) = {
+            let output = crate::CONNECTION.output.lock()
+                .expect("poisoned connection mutex");
+            let ready = output.get_ref().wait(Duration::ZERO);
+            let handle = Handle::of(output.get_ref());
+
+            // A heartbeat might have been requested while we held the channel.
+            if let Err(error) = crate::release(output) {
+                crate::fail(error);
+            }
+
+            (ready, handle)
+        };
+
+        match ready {
+            Ok(true) => return,
+            Ok(false) => handle.ready(tokio::io::Interest::WRITABLE, &mut backoff).await,
+            Err(error) => crate::fail(error),
+        }
+    }
+}
+
+/// Reason why a frame could not be parsed from the bytes read so far.
+enum Incomplete {
+    /// At least the given number of bytes has to be read to continue.
+    Missing(usize),
+    /// The frame is invalid.
+    Failed(std::io::Error),
+}
+
+/// Parses the first frame out of the bytes read from the input channel so far.
+///
+/// The frame is parsed by the same code as frames read by the blocking
+/// functions. If it turns out that the parser needs more bytes than there are
+/// in `buf`, the buffer is left intact and the number of missing bytes is
+/// returned (unless the input ended, as indicated by `eof`). Otherwise the
+/// bytes consumed by the parser are removed from the buffer.
+fn parse_frame(buf: &mut Vec<u8>, eof: bool) -> Result<fleetspeak_proto::common::Message, Incomplete> {
+    let mut input = Available {
+        buf: &buf[..],
+        missing: 0,
+    };
+
+    let result = crate::io::read_proto(&mut input);
+    if result.is_err() && input.missing > 0 && !eof {
+        return Err(Incomplete::Missing(input.missing));
+    }
+
+    let consumed = buf.len() - input.buf.len();
+    buf.drain(..consumed);
+
+    result.map_err(Incomplete::Failed)
+}
+
+/// Reads at most `len` bytes from the input channel and appends them to `buf`.
+///
+/// Bytes that have been already read are kept in the buffer, so that reading
+/// can continue in case the future is dropped. Returns the number of bytes
+/// read (zero if the input ended).
+async fn read_more(buf: &mut Vec<u8>, len: usize) -> std::io::Result<usize> {
+    let mut backoff = Backoff::new();
+    loop {
+        let handle = {
+            let mut input = crate::CONNECTION.input.lock()
+                .expect("poisoned connection mutex");
+
+            let mut chunk = vec![0; len];
+            if let Some(count) = try_read(&mut input, &mut chunk[..])? {
+                buf.extend_from_slice(&chunk[..count]);
+                return Ok(count);
+            }
+
+            Handle::of(input.get_ref())
+        };
+
+        handle.ready(tokio::io::Interest::READABLE, &mut backoff).await;
+    }
+}
+
+/// Reads from the input channel without blocking.
+///
+/// Returns `None` if there is nothing to read at the moment.
+fn try_read(input: &mut crate::io::FrameReader<crate::io::CommsInRaw>, buf: &mut [u8]) -> std::io::Result<Option<usize>> {
+    // Data buffered by the reader has been already taken from the channel, so
+    // we have to use it first.
+    if !input.buffer().is_empty() {
+        return input.read(buf).map(Some);
+    }
+
+    // Switching the mode is idempotent and blocking reads are not affected by
+    // it, so there is no harm in doing it every time.
+    #[cfg(target_family = "unix")]
+    input.get_ref().set_nonblocking(true)?;
+
+    match input.get_mut().read_timeout(buf, Duration::ZERO) {
+        Ok(count) => {
+            // Reading from the channel directly bypasses the capture of the
+            // buffered reader.
+            crate::capture::inbound(&buf[..count]);
+            Ok(Some(count))
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// A communication channel that can be waited on to become ready.
+///
+/// On Unix, this is the descriptor of the channel. Communication descriptors
+/// are never closed while the connection is in use, so it is fine to refer to
+/// them by their number.
+struct Handle {
+    #[cfg(target_family = "unix")]
+    fd: std::os::fd::RawFd,
+}
+
+impl Handle {
+
+    #[cfg(target_family = "unix")]
+    fn of<C: std::os::fd::AsRawFd>(channel: &C) -> Handle {
+        Handle {
+            fd: channel.as_raw_fd(),
+        }
+    }
+
+    #[cfg(target_family = "windows")]
+    fn of<C>(_: &C) -> Handle {
+        Handle {}
+    }
+
+    /// Waits until the channel is ready for the given `interest`.
+    ///
+    /// The descriptor is registered with the Tokio reactor for the duration of
+    /// the wait. If this is not possible (e.g. it is a regular file or another
+    /// task is waiting for it already), this falls back to waiting for the next
+    /// interval of the `backoff`, which is also the only option for anonymous
+    /// pipes on Windows. Spurious wakeups are possible, so the caller has to
+    /// check the readiness afterwards.
+    #[cfg(target_family = "unix")]
+    async fn ready(self, interest: tokio::io::Interest, backoff: &mut Backoff) {
+        let fd = match tokio::io::unix::AsyncFd::with_interest(self, interest) {
+            Ok(fd) => fd,
+            Err(_) => return backoff.wait().await,
+        };
+
+        if fd.ready(interest).await.is_err() {
+            backoff.wait().await;
+        }
+    }
+
+    #[cfg(target_family = "windows")]
+    async fn ready(self, _: tokio::io::Interest, backoff: &mut Backoff) {
+        backoff.wait().await;
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl std::os::fd::AsRawFd for Handle {
+
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.fd
+    }
+}
+
+/// A reader over the bytes read from the input channel so far.
+struct Available<'a> {
+    buf: &'a [u8],
+    /// Number of bytes requested but not available in the last read.
+    missing: usize,
+}
+
+impl std::io::Read for Available<'_> {
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = std::io::Read::read(&mut self.buf, buf)?;
+        self.missing = buf.len() - count;
+
+        Ok(count)
+    }
+}
+
+/// Exponentially growing intervals between readiness checks.
+struct Backoff {
+    interval: Duration,
+}
+
+impl Backoff {
+
+    fn new() -> Backoff {
+        Backoff {
+            interval: MIN_INTERVAL,
+        }
+    }
+
+    /// Returns the next interval to wait for.
+    fn next(&mut self) -> Duration {
+        let interval = self.interval;
+        self.interval = std::cmp::min(self.interval * 2, MAX_INTERVAL);
+
+        interval
+    }
+
+    /// Yields to the runtime for the next interval.
+    async fn wait(&mut self) {
+        tokio::time::sleep(self.next()).await;
+    }
+}
+
+/// Frame that has been partially read by a dropped [`receive`] call.
+static PARTIAL: tokio::sync::Mutex<Vec<u8>> = tokio::sync::Mutex::const_new(Vec::new());
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn backoff_bounded() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next(), MIN_INTERVAL);
+        assert_eq!(backoff.next(), MIN_INTERVAL * 2);
+
+        for _ in 0..32 {
+            assert!(backoff.next() <= MAX_INTERVAL);
+        }
+        assert_eq!(backoff.next(), MAX_INTERVAL);
+    }
+
+    #[test]
+    fn parse_frame_incomplete() {
+        let mut frame = Vec::new();
+        crate::io::write_proto(&mut frame, fleetspeak_proto::common::Message::new()).unwrap();
+        crate::io::write_proto(&mut frame, fleetspeak_proto::common::Message::new()).unwrap();
+
+        let (first, second) = frame.split_at(frame.len() / 2);
+
+        let mut buf = Vec::new();
+        assert!(matches!(parse_frame(&mut buf, false), Err(Incomplete::Missing(4))));
+
+        buf.extend_from_slice(&first[..2]);
+        assert!(matches!(parse_frame(&mut buf, false), Err(Incomplete::Missing(2))));
+
+        buf.extend_from_slice(&first[2..]);
+        assert!(parse_frame(&mut buf, false).is_ok());
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(&second[..second.len() - 1]);
+        assert!(matches!(parse_frame(&mut buf, false), Err(Incomplete::Missing(1))));
+        assert!(matches!(parse_frame(&mut buf, true), Err(Incomplete::Failed(_))));
+    }
+
+    #[test]
+    fn futures_send() {
+        fn assert_send<F: Send>(_: F) {
+        }
+
+        // Futures do nothing until polled, so this does not touch the
+        // connection.
+        assert_send(receive());
+        assert_send(send(Message {
+            service: String::from("foo"),
+            kind: None,
+            data: Vec::new(),
+            ..Default::default()
+        }));
+    }
+}
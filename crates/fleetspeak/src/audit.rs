@@ -0,0 +1,316 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! A local audit log of exchanged messages.
+//!
+//! When enabled, every message sent to or received from the Fleetspeak client
+//! is recorded in an append-only file on the local machine. Only metadata of
+//! messages is recorded (timestamp, direction, service, kind, size and SHA-256
+//! digest of the data), never the data itself.
+//!
+//! Each record occupies a single line with tab-separated fields. Backslashes,
+//! tabs and line breaks in the service name and message kind are escaped (as
+//! `\\`, `\t`, `\n` and `\r`), so that they cannot break the record apart.
+//!
+//! Once the log file grows beyond the configured size, it is rotated: the
+//! current file is renamed with a `.1` suffix (older files are shifted to `.2`,
+//! `.3` and so on) and a new file is started.
+
+use std::fs::File;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::Message;
+
+/// Configuration of the local audit log.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Path to the file to which records are appended.
+    pub path: PathBuf,
+    /// Maximum size (in bytes) of a single log file before it gets rotated.
+    pub max_size: u64,
+    /// Maximum number of rotated log files to keep around.
+    pub max_files: usize,
+}
+
+/// Enables the audit log with the given configuration.
+///
+/// If the audit log is already enabled, the old configuration is replaced and
+/// subsequent records are written according to the new one.
+///
+/// An error is returned if the log file cannot be opened.
+pub fn enable(config: Config) -> std::io::Result<()> {
+    let log = Log::open(config)?;
+    *LOG.lock().expect("poisoned audit log mutex") = Some(log);
+
+    Ok(())
+}
+
+/// Disables the audit log.
+///
+/// Messages exchanged after this call are not recorded anymore.
+pub fn disable() {
+    *LOG.lock().expect("poisoned audit log mutex") = None;
+}
+
+/// Direction in which a message has been exchanged.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Direction {
+    Sent,
+    Received,
+}
+
+/// A single record of the audit log.
+pub(crate) struct Entry {
+    direction: Direction,
+    timestamp: std::time::SystemTime,
+    service: String,
+    kind: Option<String>,
+    size: usize,
+    digest: String,
+}
+
+impl Entry {
+
+    /// Creates a new record for the given message.
+    ///
+    /// Computing the record is not free (the data needs to be hashed), so this
+    /// returns `None` if the audit log is not enabled.
+    pub(crate) fn new(direction: Direction, message: &Message) -> Option<Entry> {
+        if LOG.lock().expect("poisoned audit log mutex").is_none() {
+            return None;
+        }
+
+        Some(Entry::new_unchecked(direction, message))
+    }
+
+    fn new_unchecked(direction: Direction, message: &Message) -> Entry {
+        use sha2::Digest as _;
+
+        let digest = crate::hex::encode(&sha2::Sha256::digest(&message.data));
+
+        Entry {
+            direction,
+            timestamp: std::time::SystemTime::now(),
+            service: message.service.clone(),
+            kind: message.kind.clone(),
+            size: message.data.len(),
+            digest,
+        }
+    }
+
+    /// Appends the record to the audit log (if it is enabled).
+    ///
+    /// Failures to write the record are logged but are not considered fatal, as
+    /// the audit log should not take the service down.
+    pub(crate) fn log(self) {
+        let mut log = LOG.lock().expect("poisoned audit log mutex");
+        if let Some(log) = log.as_mut() {
+            if let Err(error) = log.append(&self) {
+                log::error!("failed to write audit log record: {error}");
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Entry {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timestamp = self.timestamp
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let direction = match self.direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        };
+
+        write!(fmt, "{}.{:03}\t{}\t{}\t{}\t{}\t{}",
+            timestamp.as_secs(), timestamp.subsec_millis(),
+            direction,
+            Escaped(&self.service),
+            Escaped(self.kind.as_deref().unwrap_or("-")),
+            self.size,
+            self.digest,
+        )
+    }
+}
+
+/// A string field of a record with the field and record separators escaped.
+struct Escaped<'a>(&'a str);
+
+impl std::fmt::Display for Escaped<'_> {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use std::fmt::Write as _;
+
+        for char in self.0.chars() {
+            match char {
+                '\\' => fmt.write_str("\\\\")?,
+                '\t' => fmt.write_str("\\t")?,
+                '\n' => fmt.write_str("\\n")?,
+                '\r' => fmt.write_str("\\r")?,
+                _ => fmt.write_char(char)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An open audit log file.
+struct Log {
+    config: Config,
+    file: File,
+    size: u64,
+}
+
+impl Log {
+
+    /// Opens the log file specified in the configuration for appending.
+    fn open(config: Config) -> std::io::Result<Log> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Log { config, file, size })
+    }
+
+    /// Appends the given record to the log, rotating the file if needed.
+    fn append(&mut self, entry: &Entry) -> std::io::Result<()> {
+        let line = format!("{entry}\n");
+        let len = line.len() as u64;
+
+        if self.size > 0 && self.size + len > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.size += len;
+
+        Ok(())
+    }
+
+    /// Rotates the log files and starts a new one.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated_path = |index: usize| {
+            let mut path = self.config.path.clone().into_os_string();
+            path.push(format!(".{index}"));
+            PathBuf::from(path)
+        };
+
+        if self.config.max_files == 0 {
+            std::fs::remove_file(&self.config.path)?;
+        } else {
+            // We shift the files from the oldest to the newest, so that none of
+            // them is overwritten before being moved. The oldest one simply
+            // gets replaced.
+            for index in (1..self.config.max_files).rev() {
+                let path = rotated_path(index);
+                if path.exists() {
+                    std::fs::rename(&path, rotated_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.config.path, rotated_path(1))?;
+        }
+
+        *self = Log::open(self.config.clone())?;
+
+        Ok(())
+    }
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("fleetspeak-audit-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn entry(data: &[u8]) -> Entry {
+        Entry::new_unchecked(Direction::Received, &Message {
+            service: String::from("foo"),
+            kind: Some(String::from("bar")),
+            data: data.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn entry_format() {
+        let line = entry(b"").to_string();
+        let fields = line.split('\t').collect::<Vec<_>>();
+
+        assert_eq!(fields[1..], [
+            "received",
+            "foo",
+            "bar",
+            "0",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        ]);
+    }
+
+    #[test]
+    fn entry_format_escaped() {
+        let entry = Entry::new_unchecked(Direction::Sent, &Message {
+            service: String::from("foo\\"),
+            kind: Some(String::from("bar\tsent\nforged\r")),
+            data: Vec::new(),
+            ..Default::default()
+        });
+
+        let line = entry.to_string();
+        assert_eq!(line.lines().count(), 1);
+
+        let fields = line.split('\t').collect::<Vec<_>>();
+        assert_eq!(fields[1..4], [
+            "sent",
+            "foo\\\\",
+            "bar\\tsent\\nforged\\r",
+        ]);
+    }
+
+    #[test]
+    fn log_rotation() {
+        let dir = tempdir("rotation");
+        let path = dir.join("audit.log");
+
+        let mut log = Log::open(Config {
+            path: path.clone(),
+            max_size: 1,
+            max_files: 2,
+        }).unwrap();
+
+        log.append(&entry(b"1")).unwrap();
+        log.append(&entry(b"2")).unwrap();
+        log.append(&entry(b"3")).unwrap();
+        log.append(&entry(b"4")).unwrap();
+
+        let read = |suffix: &str| {
+            let mut path = path.clone().into_os_string();
+            path.push(suffix);
+            std::fs::read_to_string(path).unwrap()
+        };
+
+        assert_eq!(read("").lines().count(), 1);
+        assert_eq!(read(".1").lines().count(), 1);
+        assert_eq!(read(".2").lines().count(), 1);
+        assert!(!dir.join("audit.log.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::{Message, ReadError};
+
+/// A token for cancelling blocked [`receive_cancellable`] calls.
+///
+/// Tokens are cheap to clone and all the clones refer to the same token, so
+/// one clone can be handed to the thread receiving messages and another one
+/// kept (e.g. by a signal handler or a supervising thread) to cancel it. Once
+/// cancelled, the token stays cancelled: all subsequent calls using it return
+/// immediately.
+///
+/// On Unix, the token is backed by a self-pipe and [`cancel`] only performs
+/// async-signal-safe operations, so it can be called from a signal handler. On
+/// Windows, the waiting thread checks the token in short intervals.
+///
+/// [`cancel`]: CancelToken::cancel
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Whether the token has been cancelled.
+    cancelled: AtomicBool,
+    /// Read end of the self-pipe, readable once the token is cancelled.
+    #[cfg(target_family = "unix")]
+    reader: std::io::PipeReader,
+    /// Write end of the self-pipe.
+    #[cfg(target_family = "unix")]
+    writer: std::io::PipeWriter,
+}
+
+impl CancelToken {
+
+    /// Creates a new token that is not cancelled.
+    pub fn new() -> std::io::Result<CancelToken> {
+        #[cfg(target_family = "unix")]
+        let (reader, writer) = std::io::pipe()?;
+
+        Ok(CancelToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                #[cfg(target_family = "unix")]
+                reader,
+                #[cfg(target_family = "unix")]
+                writer,
+            }),
+        })
+    }
+
+    /// Cancels the token, interrupting the [`receive_cancellable`] call that
+    /// waits for a message using it (if any).
+    pub fn cancel(&self) {
+        let cancelled = self.inner.cancelled.swap(true, Ordering::SeqCst);
+
+        #[cfg(target_family = "unix")]
+        if !cancelled {
+            self.wake();
+        }
+
+        #[cfg(target_family = "windows")]
+        let _ = cancelled;
+    }
+
+    /// Returns whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Makes the read end of the self-pipe readable.
+    #[cfg(target_family = "unix")]
+    fn wake(&self) {
+        use std::os::fd::AsRawFd as _;
+
+        // We cannot use `std::io::Write` here as it is not guaranteed to be
+        // async-signal-safe. The byte is written only once (guarded by the
+        // flag), so the pipe never fills up and the write never blocks.
+        //
+        // SAFETY: We pass a valid descriptor of the pipe we own and a valid
+        // buffer of one byte as described in the docs [1]. There is nothing we
+        // can do about a failure (the read end is owned by us as well, so it
+        // should not happen anyway), so we ignore the result.
+        //
+        // [1]: https://man7.org/linux/man-pages/man2/write.2.html
+        unsafe {
+            libc::write(self.inner.writer.as_raw_fd(), [0u8].as_ptr().cast(), 1);
+        }
+    }
+}
+
+/// Receives a message from the Fleetspeak server unless the given `token` is
+/// cancelled first.
+///
+/// This is a variant of [`try_receive`] that allows interrupting the wait for a
+/// message, e.g. in order to shut the service down gracefully. Only the wait
+/// for the beginning of a message can be cancelled: once the message starts
+/// arriving it is read in full, so that the connection can still be used after
+/// the call is cancelled.
+///
+/// The input channel is not held while waiting, so other threads can keep
+/// using the connection in the meantime.
+///
+/// If the token is cancelled, [`ReadError::Cancelled`] is returned. Other
+/// errors are the same as for [`try_receive`].
+///
+/// [`try_receive`]: crate::try_receive
+///
+/// # Examples
+///
+/// ```no_run
+/// let token = fleetspeak::CancelToken::new()
+///     .expect("failed to create a cancel token");
+///
+/// std::thread::spawn({
+///     let token = token.clone();
+///     move || {
+///         std::thread::sleep(std::time::Duration::from_secs(60));
+///         token.cancel();
+///     }
+/// });
+///
+/// match fleetspeak::receive_cancellable(&token) {
+///     Ok(message) => println!("received {}", message.preview()),
+///     Err(error) if error.is_cancelled() => println!("no message within a minute"),
+///     Err(error) => panic!("{error}"),
+/// }
+/// ```
+pub fn receive_cancellable(token: &CancelToken) -> Result<Message, ReadError> {
+    /// Maximum time for which the reader thread waits for data before the token
+    /// is checked again on platforms where the wait cannot be interrupted.
+    #[cfg(target_family = "windows")]
+    const INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+    loop {
+        // Cancellation takes precedence over data that is ready to be read.
+        if token.is_cancelled() {
+            return Err(ReadError::Cancelled);
+        }
+
+        // On Unix, the reader thread only picks up data that is already there
+        // and the wait for more happens below, without holding the input.
+        #[cfg(target_family = "unix")]
+        let deadline = Instant::now();
+        #[cfg(target_family = "windows")]
+        let deadline = Instant::now() + INTERVAL;
+
+        match crate::reader::recv_started_by(deadline) {
+            Some(Ok(proto)) => match crate::try_accept(proto) {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => continue,
+                Err(error) => return Err(ReadError::Malformed(error)),
+            },
+            Some(Err(error)) => {
+                crate::close(&error);
+                return Err(ReadError::Input(error));
+            }
+            None => (),
+        }
+
+        #[cfg(target_family = "unix")]
+        if let Err(error) = wait(token) {
+            crate::close(&error);
+            return Err(ReadError::Input(error));
+        }
+    }
+}
+
+/// Waits until there is data to read from the input channel or the `token` is
+/// cancelled.
+#[cfg(target_family = "unix")]
+fn wait(token: &CancelToken) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd as _;
+
+    let mut pollfds = [
+        libc::pollfd {
+            fd: token.inner.reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: crate::CONNECTION.input_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+
+    loop {
+        // SAFETY: We pass a valid pointer to an array of `pollfd` structures
+        // and its length as described in the docs [1]. Invalid descriptors are
+        // reported in the `revents` field. We verify the result afterwards.
+        //
+        // [1]: https://man7.org/linux/man-pages/man2/poll.2.html
+        let count = unsafe {
+            libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1)
+        };
+
+        if count < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(error);
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn cancel_clone() {
+        let token = CancelToken::new().unwrap();
+        assert!(!token.is_cancelled());
+
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+
+        // Cancelling again is a no-op.
+        token.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn cancel_wakes() {
+        use std::io::Read as _;
+
+        let token = CancelToken::new().unwrap();
+        token.cancel();
+
+        let mut buf = [0; 1];
+        assert_eq!((&token.inner.reader).read(&mut buf).unwrap(), 1);
+    }
+}
@@ -0,0 +1,475 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Recording of the traffic exchanged with the Fleetspeak client.
+//!
+//! If the [`CAPTURE_DIR_VAR`] environment variable is set, every frame read
+//! from or written to the communication channels of the global connection is
+//! also appended to a capture file in the given directory, so that protocol issues can be diagnosed after
+//! the fact. The file is named after the time at which the recording started
+//! and the process identifier, e.g. `fleetspeak-1700000000-1234.capture`.
+//!
+//! The capture file starts with the 8-byte [`HEADER`], followed by a sequence
+//! of records. Each record consists of:
+//!
+//!   * a single byte with the direction (`0` for inbound, `1` for outbound),
+//!   * the time of the record in microseconds since Unix epoch (as a 64-bit
+//!     little-endian integer),
+//!   * the length of the frame in bytes (as a 32-bit little-endian integer),
+//!   * the frame exactly as it appeared on the wire.
+//!
+//! The handshake magic numbers are recorded as 4-byte frames. Every other frame
+//! consists of the length prefix, the serialized message and the magic number
+//! suffix. Captures can be read back with [`Reader`] and the traffic received
+//! by the service can be fed back into it with [`Replay`].
+//!
+//! Traffic of connections created explicitly (e.g. with [`Connection::new`])
+//! is not recorded.
+//!
+//! Note that captures contain complete messages, so they should be handled
+//! with the same care as the data the service exchanges.
+//!
+//! [`CAPTURE_DIR_VAR`]: crate::env::CAPTURE_DIR_VAR
+//! [`Connection::new`]: crate::Connection::new
+
+use std::io::Write as _;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Header at the beginning of every capture file.
+pub const HEADER: [u8; 8] = *b"FSCAPv1\n";
+
+/// Frames larger than this (when no message size limit is advertised) are
+/// considered corrupted and stop the recording.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Direction of a recorded frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The frame has been read from the Fleetspeak client.
+    Inbound,
+    /// The frame has been written to the Fleetspeak client.
+    Outbound,
+}
+
+/// A frame recorded in a capture file.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// Direction of the frame.
+    pub direction: Direction,
+    /// Time at which the frame has been recorded.
+    pub time: SystemTime,
+    /// The frame exactly as it appeared on the wire.
+    pub frame: Vec<u8>,
+}
+
+/// Reader of capture files.
+///
+/// # Examples
+///
+/// ```no_run
+/// let file = std::fs::File::open("fleetspeak-1700000000-1234.capture")
+///     .expect("failed to open capture");
+///
+/// let reader = fleetspeak::capture::Reader::new(std::io::BufReader::new(file))
+///     .expect("invalid capture");
+///
+/// for record in reader {
+///     let record = record.expect("failed to read record");
+///     println!("{:?}: {} bytes", record.direction, record.frame.len());
+/// }
+/// ```
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl<R: std::io::Read> Reader<R> {
+
+    /// Creates a reader of the capture, verifying its header.
+    pub fn new(mut inner: R) -> std::io::Result<Reader<R>> {
+        let mut header = [0; HEADER.len()];
+        inner.read_exact(&mut header)?;
+        if header != HEADER {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+                "invalid capture header"
+            }));
+        }
+
+        Ok(Reader { inner })
+    }
+
+    /// Reads the next record (if there is any left).
+    fn read_record(&mut self) -> std::io::Result<Option<Record>> {
+        let mut direction = [0; 1];
+        if self.inner.read(&mut direction)? == 0 {
+            return Ok(None);
+        }
+        let direction = match direction[0] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            byte => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+                format!("invalid record direction: {byte}")
+            })),
+        };
+
+        let mut time = [0; 8];
+        self.inner.read_exact(&mut time)?;
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(time));
+
+        let mut len = [0; 4];
+        self.inner.read_exact(&mut len)?;
+        let mut frame = vec![0; u32::from_le_bytes(len) as usize];
+        self.inner.read_exact(&mut frame)?;
+
+        Ok(Some(Record {
+            direction,
+            time,
+            frame,
+        }))
+    }
+}
+
+impl<R: std::io::Read> Iterator for Reader<R> {
+
+    type Item = std::io::Result<Record>;
+
+    fn next(&mut self) -> Option<std::io::Result<Record>> {
+        self.read_record().transpose()
+    }
+}
+
+/// Replay of the inbound traffic of a capture.
+///
+/// This is a stream yielding all inbound frames of the capture (including the
+/// handshake) back-to-back, exactly as the service read them. It can be used as
+/// the input of a [`Connection`] to reproduce issues observed in the field
+/// deterministically. Outbound frames are skipped.
+///
+/// [`Connection`]: crate::Connection
+///
+/// # Examples
+///
+/// ```no_run
+/// let file = std::fs::File::open("fleetspeak-1700000000-1234.capture")
+///     .expect("failed to open capture");
+/// let reader = fleetspeak::capture::Reader::new(std::io::BufReader::new(file))
+///     .expect("invalid capture");
+///
+/// let replay = fleetspeak::capture::Replay::new(reader);
+/// let mut conn = fleetspeak::Connection::new(replay, std::io::sink());
+/// conn.handshake().expect("handshake failure");
+///
+/// while let Ok(message) = conn.receive() {
+///     println!("replayed {}", message.preview());
+/// }
+/// ```
+pub struct Replay<R> {
+    reader: Reader<R>,
+    /// The frame currently being replayed.
+    frame: Vec<u8>,
+    /// Number of bytes of the current frame that have been replayed already.
+    pos: usize,
+}
+
+impl<R: std::io::Read> Replay<R> {
+
+    /// Creates a replay of the inbound traffic of the capture.
+    pub fn new(reader: Reader<R>) -> Replay<R> {
+        Replay {
+            reader,
+            frame: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for Replay<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.frame.len() {
+            match self.reader.read_record()? {
+                Some(record) if record.direction == Direction::Inbound => {
+                    self.frame = record.frame;
+                    self.pos = 0;
+                }
+                Some(_) => continue,
+                None => return Ok(0),
+            }
+        }
+
+        let len = std::cmp::min(buf.len(), self.frame.len() - self.pos);
+        buf[..len].copy_from_slice(&self.frame[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+/// Records bytes read from the input channel of the global connection.
+pub(crate) fn inbound(bytes: &[u8]) {
+    if let Some(capture) = &*CAPTURE {
+        capture.lock().expect("poisoned capture mutex").record(Direction::Inbound, bytes);
+    }
+}
+
+/// Records bytes written to the output channel of the global connection.
+pub(crate) fn outbound(bytes: &[u8]) {
+    if let Some(capture) = &*CAPTURE {
+        capture.lock().expect("poisoned capture mutex").record(Direction::Outbound, bytes);
+    }
+}
+
+/// An open capture file along with the frames being reassembled.
+struct Capture {
+    file: std::fs::File,
+    inbound: Frames,
+    outbound: Frames,
+}
+
+impl Capture {
+
+    /// Opens a new capture file in the given directory.
+    fn open(dir: &std::path::Path) -> std::io::Result<Capture> {
+        let start = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let path = dir.join(format! {
+            "fleetspeak-{}-{}.capture",
+            start.as_secs(),
+            std::process::id(),
+        });
+
+        let mut options = std::fs::OpenOptions::new();
+        options.append(true).create_new(true);
+
+        // The traffic might contain sensitive data, so the capture should be
+        // readable only by the user running the service.
+        #[cfg(target_family = "unix")]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&path)?;
+        file.write_all(&HEADER)?;
+
+        log::info!("recording traffic to '{}'", path.display());
+
+        // A service that re-executed itself inherits the connection in which
+        // the handshake has been already done.
+        #[cfg(target_family = "unix")]
+        let handshake = !crate::env::established();
+        #[cfg(not(target_family = "unix"))]
+        let handshake = true;
+
+        Ok(Capture {
+            file,
+            inbound: Frames::new(handshake),
+            outbound: Frames::new(handshake),
+        })
+    }
+
+    /// Feeds the bytes to the reassembly and records all completed frames.
+    fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        let frames = match direction {
+            Direction::Inbound => &mut self.inbound,
+            Direction::Outbound => &mut self.outbound,
+        };
+
+        for frame in frames.push(bytes) {
+            if let Err(error) = write_record(&mut self.file, direction, &frame) {
+                log::error!("failed to record {direction:?} frame: {error}");
+            }
+        }
+    }
+}
+
+/// Writes a single record to the capture file.
+fn write_record<W>(output: &mut W, direction: Direction, frame: &[u8]) -> std::io::Result<()>
+where
+    W: std::io::Write,
+{
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let len = u32::try_from(frame.len())
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+    let mut record = Vec::with_capacity(1 + 8 + 4 + frame.len());
+    record.push(match direction {
+        Direction::Inbound => 0,
+        Direction::Outbound => 1,
+    });
+    record.extend_from_slice(&(time.as_micros() as u64).to_le_bytes());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(frame);
+
+    // The record is written at once, so that captures of crashed processes
+    // contain only complete records.
+    output.write_all(&record)
+}
+
+/// Reassembly of frames from bytes transferred in arbitrary chunks.
+struct Frames {
+    /// Bytes of the frame that has not been completed yet.
+    pending: Vec<u8>,
+    /// Whether the handshake magic is still expected.
+    handshake: bool,
+    /// Whether the stream is considered corrupted and no longer recorded.
+    corrupted: bool,
+}
+
+impl Frames {
+
+    /// Creates a new reassembly, expecting the handshake first if requested.
+    fn new(handshake: bool) -> Frames {
+        Frames {
+            pending: Vec::new(),
+            handshake,
+            corrupted: false,
+        }
+    }
+
+    /// Feeds the bytes and returns all the frames they complete.
+    fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        if self.corrupted {
+            return Vec::new();
+        }
+        self.pending.extend_from_slice(bytes);
+
+        let mut frames = Vec::new();
+        loop {
+            let len = match self.frame_len() {
+                Some(len) if len <= self.pending.len() => len,
+                _ => break,
+            };
+
+            frames.push(self.pending.drain(..len).collect());
+            self.handshake = false;
+        }
+
+        frames
+    }
+
+    /// Returns the length of the pending frame (if it is already known).
+    fn frame_len(&mut self) -> Option<usize> {
+        if self.handshake {
+            return Some(4);
+        }
+
+        let len = u32::from_le_bytes(self.pending.get(..4)?.try_into().ok()?) as usize;
+
+        let max_size = crate::env::max_message_size().unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+        if len > max_size {
+            log::warn!("corrupted frame of {len} bytes, stopping the recording");
+            self.corrupted = true;
+            self.pending = Vec::new();
+            return None;
+        }
+
+        Some(4 + len + 4)
+    }
+}
+
+static CAPTURE: LazyLock<Option<Mutex<Capture>>> = LazyLock::new(|| {
+    let dir = crate::env::capture_dir()?;
+
+    match Capture::open(&dir) {
+        Ok(capture) => Some(Mutex::new(capture)),
+        Err(error) => {
+            log::error!("failed to open capture file: {error}");
+            None
+        }
+    }
+});
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn frames_reassembly() {
+        let mut frames = Frames::new(true);
+
+        assert!(frames.push(&[0x01, 0x10]).is_empty());
+        assert_eq!(frames.push(&[0xee, 0xf1, 0x03, 0x00]), vec![vec![0x01, 0x10, 0xee, 0xf1]]);
+        assert!(frames.push(&[0x00, 0x00, b'f', b'o']).is_empty());
+        assert_eq! {
+            frames.push(&[b'o', 0x01, 0x10, 0xee, 0xf1]),
+            vec![vec![0x03, 0x00, 0x00, 0x00, b'f', b'o', b'o', 0x01, 0x10, 0xee, 0xf1]],
+        };
+    }
+
+    #[test]
+    fn write_and_read_records() {
+        let mut capture = HEADER.to_vec();
+        write_record(&mut capture, Direction::Inbound, b"foo").unwrap();
+        write_record(&mut capture, Direction::Outbound, b"quux").unwrap();
+
+        let records = Reader::new(&capture[..]).unwrap()
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Inbound);
+        assert_eq!(records[0].frame, b"foo");
+        assert_eq!(records[1].direction, Direction::Outbound);
+        assert_eq!(records[1].frame, b"quux");
+    }
+
+    #[test]
+    fn replay_inbound() {
+        let mut frame = Vec::new();
+        crate::io::write_proto(&mut frame, {
+            let mut proto = fleetspeak_proto::common::Message::new();
+            proto.mut_source().set_service_name(String::from("foo"));
+            proto.set_message_type(String::from("bar"));
+            proto
+        }).unwrap();
+
+        let mut magic = Vec::new();
+        crate::io::write_magic(&mut magic).unwrap();
+
+        let mut capture = HEADER.to_vec();
+        write_record(&mut capture, Direction::Outbound, &magic).unwrap();
+        write_record(&mut capture, Direction::Inbound, &magic).unwrap();
+        write_record(&mut capture, Direction::Outbound, b"quux").unwrap();
+        write_record(&mut capture, Direction::Inbound, &frame).unwrap();
+
+        let replay = Replay::new(Reader::new(&capture[..]).unwrap());
+        let mut conn = crate::Connection::new(replay, std::io::sink());
+        conn.handshake().unwrap();
+
+        let message = conn.receive().unwrap();
+        assert_eq!(message.service, "foo");
+        assert_eq!(message.kind.as_deref(), Some("bar"));
+
+        let error = conn.receive().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn open_private() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = std::env::temp_dir()
+            .join(format!("fleetspeak-capture-{}-private", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        drop(Capture::open(&dir).unwrap());
+
+        let entry = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        let mode = entry.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reader_invalid_header() {
+        assert!(Reader::new(&b"FOOBAR42"[..]).is_err());
+    }
+}
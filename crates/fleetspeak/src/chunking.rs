@@ -0,0 +1,503 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Transfer of payloads exceeding the Fleetspeak message size limit.
+//!
+//! Fleetspeak refuses messages bigger than 2 MiB (or whatever limit the client
+//! advertises, see [`MAX_MESSAGE_SIZE_VAR`]). Services that need to transfer
+//! more data at once can [`split`] the message into chunks and send them as
+//! separate messages. The receiving side puts them back together with a
+//! [`Reassembler`].
+//!
+//! Every chunk is a copy of the original message (with the same service, kind,
+//! annotations and so on) carrying a consecutive part of its data. Chunks are
+//! marked with three annotations:
+//!
+//!   * [`ID_ANNOTATION`] with an identifier unique to the transfer,
+//!   * [`SEQUENCE_ANNOTATION`] with the (zero-based) index of the chunk,
+//!   * [`TOTAL_ANNOTATION`] with the number of chunks of the transfer.
+//!
+//! Both numbers are written in decimal form, so that server-side services can
+//! implement the reassembly without depending on this library.
+//!
+//! Chunking is opt-in: messages are never split implicitly.
+//!
+//! [`MAX_MESSAGE_SIZE_VAR`]: crate::env::MAX_MESSAGE_SIZE_VAR
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+
+use crate::Message;
+
+/// Key of the annotation with the identifier of the transfer.
+pub const ID_ANNOTATION: &str = "fleetspeak-rs/chunk-id";
+
+/// Key of the annotation with the index of the chunk within the transfer.
+pub const SEQUENCE_ANNOTATION: &str = "fleetspeak-rs/chunk-sequence";
+
+/// Key of the annotation with the number of chunks of the transfer.
+pub const TOTAL_ANNOTATION: &str = "fleetspeak-rs/chunk-total";
+
+/// Default size (in bytes) of the data of a single chunk.
+///
+/// It leaves plenty of room below the default Fleetspeak limit of 2 MiB for
+/// the rest of the message.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Default maximum number of chunks of a single transfer accepted by the
+/// [`Reassembler`].
+///
+/// With the [default chunk size](DEFAULT_CHUNK_SIZE), this allows transfers of
+/// up to 1 GiB.
+pub const DEFAULT_MAX_CHUNKS: usize = 1024;
+
+/// Default maximum number of incomplete transfers kept by the [`Reassembler`].
+pub const DEFAULT_MAX_PENDING: usize = 16;
+
+/// Splits the message into chunks with at most `chunk_size` bytes of data each.
+///
+/// A message with no data yields a single (empty) chunk. See the [module-level
+/// documentation](self) for the format of the chunks.
+///
+/// # Panics
+///
+/// Panics if `chunk_size` is zero.
+///
+/// # Examples
+///
+/// ```no_run
+/// use fleetspeak::chunking::{split, DEFAULT_CHUNK_SIZE};
+///
+/// let message = fleetspeak::Message {
+///     service: String::from("example"),
+///     kind: Some(String::from("upload")),
+///     data: vec![0; 16 * 1024 * 1024],
+///     ..Default::default()
+/// };
+///
+/// fleetspeak::send_all(split(message, DEFAULT_CHUNK_SIZE))
+///     .expect("chunks rejected");
+/// ```
+pub fn split(message: Message, chunk_size: usize) -> Vec<Message> {
+    assert!(chunk_size > 0, "chunk size must be positive");
+
+    let id = transfer_id();
+
+    let mut chunks = message.data.chunks(chunk_size)
+        .map(<[u8]>::to_vec)
+        .collect::<Vec<_>>();
+    if chunks.is_empty() {
+        chunks.push(Vec::new());
+    }
+
+    let total = chunks.len();
+    chunks.into_iter().enumerate()
+        .map(|(sequence, data)| {
+            let mut annotations = message.annotations.clone();
+            annotations.push((String::from(ID_ANNOTATION), id.clone()));
+            annotations.push((String::from(SEQUENCE_ANNOTATION), sequence.to_string()));
+            annotations.push((String::from(TOTAL_ANNOTATION), total.to_string()));
+
+            Message {
+                service: message.service.clone(),
+                kind: message.kind.clone(),
+                data,
+                priority: message.priority,
+                annotations,
+                background: message.background,
+                data_type_url: message.data_type_url.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Puts chunks produced by [`split`] back together.
+///
+/// Chunks can arrive in any order and chunks of different transfers can be
+/// interleaved. Chunks delivered more than once are ignored.
+///
+/// Note that chunks of transfers that never complete are kept in memory. Use
+/// [`pending`](Reassembler::pending) to keep an eye on them and [`clear`] to
+/// drop them.
+///
+/// Since the number of chunks is advertised by the sender, the reassembler
+/// refuses transfers of more than [`DEFAULT_MAX_CHUNKS`] chunks and keeps at
+/// most [`DEFAULT_MAX_PENDING`] incomplete transfers at a time. Both limits
+/// can be changed with [`with_max_chunks`] and [`with_max_pending`].
+///
+/// [`clear`]: Reassembler::clear
+/// [`with_max_chunks`]: Reassembler::with_max_chunks
+/// [`with_max_pending`]: Reassembler::with_max_pending
+///
+/// # Examples
+///
+/// ```no_run
+/// let mut reassembler = fleetspeak::chunking::Reassembler::new();
+///
+/// loop {
+///     let message = match reassembler.push(fleetspeak::receive()) {
+///         Ok(Some(message)) => message,
+///         Ok(None) => continue,
+///         Err(error) => {
+///             eprintln!("invalid chunk: {error}");
+///             continue;
+///         }
+///     };
+///
+///     println!("received {} bytes", message.data.len());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Reassembler {
+    /// Incomplete transfers, keyed by the service and the transfer identifier.
+    transfers: HashMap<(String, String), Transfer>,
+    /// Maximum number of chunks of a single transfer.
+    max_chunks: usize,
+    /// Maximum number of incomplete transfers.
+    max_pending: usize,
+}
+
+impl Default for Reassembler {
+
+    fn default() -> Reassembler {
+        Reassembler {
+            transfers: HashMap::new(),
+            max_chunks: DEFAULT_MAX_CHUNKS,
+            max_pending: DEFAULT_MAX_PENDING,
+        }
+    }
+}
+
+impl Reassembler {
+
+    /// Creates a reassembler with no pending transfers.
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Sets the maximum number of chunks of a single transfer.
+    ///
+    /// Chunks of transfers advertising more chunks are rejected as invalid.
+    pub fn with_max_chunks(mut self, max_chunks: usize) -> Reassembler {
+        self.max_chunks = max_chunks;
+        self
+    }
+
+    /// Sets the maximum number of incomplete transfers.
+    ///
+    /// Chunks starting a new transfer while there are this many incomplete
+    /// ones are rejected as invalid.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Reassembler {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Feeds the received message to the reassembler.
+    ///
+    /// Returns the reassembled message if the given one completes a transfer
+    /// and `None` if more chunks are needed. Messages that are not chunks are
+    /// returned as they are.
+    ///
+    /// An error is returned if the chunk annotations of the message are not
+    /// valid (e.g. the sequence number is out of range or the number of chunks
+    /// does not match the one of other chunks of the transfer) or if the chunk
+    /// exceeds the limits of the reassembler.
+    pub fn push(&mut self, mut message: Message) -> std::io::Result<Option<Message>> {
+        use std::io::ErrorKind::InvalidData;
+
+        let chunk = match Chunk::take(&mut message)? {
+            Some(chunk) => chunk,
+            None => return Ok(Some(message)),
+        };
+
+        // The number of chunks comes from the sender and determines how much
+        // memory is allocated for the transfer, so it has to be bounded.
+        if chunk.total > self.max_chunks {
+            return Err(std::io::Error::new(InvalidData, format! {
+                "too many chunks of transfer {:?}: {} (limit {})",
+                chunk.id, chunk.total, self.max_chunks,
+            }));
+        }
+
+        let key = (message.service.clone(), chunk.id);
+        if !self.transfers.contains_key(&key) && self.transfers.len() >= self.max_pending {
+            return Err(std::io::Error::new(InvalidData, format! {
+                "too many pending transfers to start transfer {:?} (limit {})",
+                key.1, self.max_pending,
+            }));
+        }
+
+        let transfer = self.transfers.entry(key.clone())
+            .or_insert_with(|| Transfer::new(chunk.total));
+
+        if transfer.chunks.len() != chunk.total {
+            return Err(std::io::Error::new(InvalidData, format! {
+                "inconsistent number of chunks of transfer {:?}: {} (expected {})",
+                key.1, chunk.total, transfer.chunks.len(),
+            }));
+        }
+
+        let slot = &mut transfer.chunks[chunk.sequence];
+        if slot.is_some() {
+            log::warn!("duplicate chunk {} of transfer {:?}", chunk.sequence, key.1);
+            return Ok(None);
+        }
+
+        let data = std::mem::take(&mut message.data);
+        transfer.received += data.len();
+        *slot = Some(data);
+
+        if chunk.sequence == 0 {
+            transfer.head = Some(message);
+        }
+
+        if transfer.chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let transfer = self.transfers.remove(&key)
+            .expect("no completed transfer");
+
+        let mut message = transfer.head
+            .expect("no first chunk of completed transfer");
+        message.data = Vec::with_capacity(transfer.received);
+        for data in transfer.chunks.into_iter().flatten() {
+            message.data.extend_from_slice(&data);
+        }
+
+        Ok(Some(message))
+    }
+
+    /// Returns the number of transfers that are not complete yet.
+    pub fn pending(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Drops chunks of all the incomplete transfers.
+    pub fn clear(&mut self) {
+        self.transfers.clear();
+    }
+}
+
+/// State of a transfer that is not complete yet.
+#[derive(Debug)]
+struct Transfer {
+    /// Data of the chunks received so far, indexed by sequence number.
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Total size of the data received so far.
+    received: usize,
+    /// The first chunk (stripped of its data), used as the reassembled message.
+    head: Option<Message>,
+}
+
+impl Transfer {
+
+    fn new(total: usize) -> Transfer {
+        Transfer {
+            chunks: std::iter::repeat_with(|| None).take(total).collect(),
+            received: 0,
+            head: None,
+        }
+    }
+}
+
+/// Chunk annotations of a message.
+struct Chunk {
+    /// Identifier of the transfer.
+    id: String,
+    /// Index of the chunk within the transfer.
+    sequence: usize,
+    /// Number of chunks of the transfer.
+    total: usize,
+}
+
+impl Chunk {
+
+    /// Removes the chunk annotations from the message and parses them.
+    ///
+    /// Returns `None` if the message has no chunk annotations at all.
+    fn take(message: &mut Message) -> std::io::Result<Option<Chunk>> {
+        use std::io::ErrorKind::InvalidData;
+
+        let mut take = |key: &str| {
+            let index = message.annotations.iter().position(|(k, _)| k == key)?;
+            Some(message.annotations.remove(index).1)
+        };
+
+        let (id, sequence, total) = match (
+            take(ID_ANNOTATION),
+            take(SEQUENCE_ANNOTATION),
+            take(TOTAL_ANNOTATION),
+        ) {
+            (None, None, None) => return Ok(None),
+            (Some(id), Some(sequence), Some(total)) => (id, sequence, total),
+            _ => return Err(std::io::Error::new(InvalidData, {
+                "incomplete chunk annotations"
+            })),
+        };
+
+        let parse = |key: &str, value: &str| {
+            value.parse::<usize>().map_err(|_| std::io::Error::new(InvalidData, {
+                format!("invalid chunk annotation {key:?}: {value:?}")
+            }))
+        };
+
+        let sequence = parse(SEQUENCE_ANNOTATION, &sequence)?;
+        let total = parse(TOTAL_ANNOTATION, &total)?;
+
+        if sequence >= total {
+            return Err(std::io::Error::new(InvalidData, {
+                format!("chunk {sequence} out of range of {total} chunks")
+            }));
+        }
+
+        Ok(Some(Chunk {
+            id,
+            sequence,
+            total,
+        }))
+    }
+}
+
+/// Returns a new identifier of a transfer.
+///
+/// Identifiers are unique within the process and, thanks to the time-based
+/// prefix, very unlikely to repeat across restarts of the service.
+pub(crate) fn transfer_id() -> String {
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{counter:x}", *EPOCH, std::process::id())
+}
+
+static EPOCH: LazyLock<u128> = LazyLock::new(|| {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0)
+});
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn message(data: &[u8]) -> Message {
+        Message {
+            service: String::from("foo"),
+            kind: Some(String::from("bar")),
+            data: data.to_vec(),
+            annotations: vec![(String::from("baz"), String::from("quux"))],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let chunks = split(message(b"0123456789"), 4);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2].data, b"89");
+
+        let mut reassembler = Reassembler::new();
+        let mut chunks = chunks.into_iter().rev();
+        assert!(reassembler.push(chunks.next().unwrap()).unwrap().is_none());
+        assert!(reassembler.push(chunks.next().unwrap()).unwrap().is_none());
+        assert_eq!(reassembler.pending(), 1);
+
+        let message = reassembler.push(chunks.next().unwrap()).unwrap().unwrap();
+        assert_eq!(message.service, "foo");
+        assert_eq!(message.kind.as_deref(), Some("bar"));
+        assert_eq!(message.data, b"0123456789");
+        assert_eq!(message.annotations, vec![(String::from("baz"), String::from("quux"))]);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn split_empty() {
+        let mut chunks = split(message(b""), 4);
+        assert_eq!(chunks.len(), 1);
+
+        let message = Reassembler::new().push(chunks.remove(0)).unwrap().unwrap();
+        assert!(message.data.is_empty());
+    }
+
+    #[test]
+    fn reassemble_interleaved_and_duplicated() {
+        let foo = split(message(b"foofoo"), 3);
+        let bar = split(message(b"barbar"), 3);
+
+        let mut reassembler = Reassembler::new();
+        let mut foo = foo.into_iter();
+        let mut bar = bar.into_iter();
+
+        let foo_first = foo.next().unwrap();
+        let foo_first_again = Message {
+            annotations: foo_first.annotations.clone(),
+            ..message(b"foo")
+        };
+        assert!(reassembler.push(foo_first).unwrap().is_none());
+        assert!(reassembler.push(bar.next().unwrap()).unwrap().is_none());
+        assert!(reassembler.push(foo_first_again).unwrap().is_none());
+
+        let message = reassembler.push(bar.next().unwrap()).unwrap().unwrap();
+        assert_eq!(message.data, b"barbar");
+
+        let message = reassembler.push(foo.next().unwrap()).unwrap().unwrap();
+        assert_eq!(message.data, b"foofoo");
+    }
+
+    #[test]
+    fn reassemble_not_chunked() {
+        let message = Reassembler::new().push(message(b"foo")).unwrap().unwrap();
+        assert_eq!(message.data, b"foo");
+    }
+
+    #[test]
+    fn reassemble_invalid() {
+        let mut chunk = split(message(b"foo"), 4).remove(0);
+        for (key, value) in &mut chunk.annotations {
+            if key == SEQUENCE_ANNOTATION {
+                *value = String::from("1");
+            }
+        }
+        assert!(Reassembler::new().push(chunk).is_err());
+
+        let mut chunk = split(message(b"foo"), 4).remove(0);
+        chunk.annotations.retain(|(key, _)| key != TOTAL_ANNOTATION);
+        assert!(Reassembler::new().push(chunk).is_err());
+    }
+
+    #[test]
+    fn reassemble_too_many_chunks() {
+        let mut chunk = split(message(b"foo"), 4).remove(0);
+        for (key, value) in &mut chunk.annotations {
+            if key == TOTAL_ANNOTATION {
+                *value = usize::MAX.to_string();
+            }
+        }
+
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.push(chunk).is_err());
+        assert_eq!(reassembler.pending(), 0);
+
+        let chunk = split(message(b"foobar"), 2).remove(0);
+        assert!(Reassembler::new().with_max_chunks(2).push(chunk).is_err());
+    }
+
+    #[test]
+    fn reassemble_too_many_pending() {
+        let mut reassembler = Reassembler::new().with_max_pending(1);
+
+        let mut foo = split(message(b"foofoo"), 3).into_iter();
+        let bar = split(message(b"barbar"), 3).remove(0);
+
+        assert!(reassembler.push(foo.next().unwrap()).unwrap().is_none());
+        assert!(reassembler.push(bar).is_err());
+
+        let message = reassembler.push(foo.next().unwrap()).unwrap().unwrap();
+        assert_eq!(message.data, b"foofoo");
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Framing of Fleetspeak messages for [`tokio_util::codec`].
+//!
+//! On the wire, every message is prefixed with its length and followed by the
+//! Fleetspeak magic number. [`Codec`] implements this framing as an encoder
+//! and decoder pair, so any asynchronous transport can be wrapped with
+//! [`Framed`] to exchange messages compatible with Fleetspeak.
+//!
+//! Note that the codec operates on raw Protocol Buffers messages: it does not
+//! apply any payload transformations (like [compression]) nor performs the
+//! initial handshake (which consists of exchanging the bare magic numbers).
+//!
+//! [`Framed`]: tokio_util::codec::Framed
+//! [compression]: crate::compression
+
+use bytes::{Buf as _, BufMut as _, BytesMut};
+
+/// Size of the length prefix and of the magic number suffix of every frame.
+const FIELD_LEN: usize = std::mem::size_of::<u32>();
+
+/// Encoder and decoder of Fleetspeak message frames.
+///
+/// The length prefix of incoming frames is not trusted: frames declaring a
+/// message longer than the [maximum frame length] are refused with an error of
+/// the [`InvalidData`] kind before any buffer space is reserved for them.
+///
+/// [maximum frame length]: Codec::with_max_frame_length
+/// [`InvalidData`]: std::io::ErrorKind::InvalidData
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn example<T>(transport: T) {
+/// use tokio_util::codec::Framed;
+///
+/// let framed = Framed::new(transport, fleetspeak::codec::Codec::new());
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Codec {
+    /// Maximum length of a message in a decoded frame.
+    max_frame_length: usize,
+}
+
+impl Codec {
+
+    /// Creates a new codec.
+    ///
+    /// The maximum frame length is the message size limit of incoming messages
+    /// of the global connection (see [`env::MAX_MESSAGE_SIZE_VAR`]).
+    ///
+    /// [`env::MAX_MESSAGE_SIZE_VAR`]: crate::env::MAX_MESSAGE_SIZE_VAR
+    pub fn new() -> Codec {
+        Codec {
+            max_frame_length: crate::env::max_incoming_size(),
+        }
+    }
+
+    /// Sets the maximum length (in bytes) of a message in a decoded frame.
+    ///
+    /// The length does not include the length prefix nor the magic number.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Codec {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+}
+
+impl Default for Codec {
+
+    fn default() -> Codec {
+        Codec::new()
+    }
+}
+
+impl tokio_util::codec::Decoder for Codec {
+
+    type Item = fleetspeak_proto::common::Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
+        if src.len() < FIELD_LEN {
+            return Ok(None);
+        }
+
+        let len = (&src[..FIELD_LEN]).get_u32_le() as usize;
+        if len > self.max_frame_length {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+                format!("frame of {len} bytes exceeds the limit of {} bytes", self.max_frame_length)
+            }));
+        }
+
+        let frame_len = FIELD_LEN + len + FIELD_LEN;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(frame_len);
+        crate::io::read_proto_with_limit(&mut &frame[..], self.max_frame_length).map(Some)
+    }
+}
+
+impl tokio_util::codec::Encoder<fleetspeak_proto::common::Message> for Codec {
+
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: fleetspeak_proto::common::Message, dst: &mut BytesMut) -> std::io::Result<()> {
+        crate::io::write_proto(&mut dst.writer(), item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+
+    use super::*;
+
+    #[test]
+    fn decode_partial() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.set_message_type(String::from("foo"));
+
+        let mut buf = BytesMut::new();
+        Codec::new().encode(proto.clone(), &mut buf).unwrap();
+        Codec::new().encode(proto.clone(), &mut buf).unwrap();
+
+        let mut src = BytesMut::new();
+        let mut protos = Vec::new();
+        for byte in buf {
+            src.put_u8(byte);
+            protos.extend(Codec::new().decode(&mut src).unwrap());
+        }
+
+        assert_eq!(protos, vec![proto.clone(), proto]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_too_long() {
+        let mut src = BytesMut::new();
+        src.put_u32_le(1025);
+
+        let mut codec = Codec::new().with_max_frame_length(1024);
+        let error = codec.decode(&mut src).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(src.capacity() < 1024);
+
+        let mut src = BytesMut::new();
+        src.put_u32_le(u32::MAX);
+
+        let error = Codec::new().decode(&mut src).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_invalid_magic() {
+        let mut src = BytesMut::new();
+        src.put_u32_le(0);
+        src.put_u32_le(0xdeadbeef);
+
+        assert!(Codec::new().decode(&mut src).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Compression of message payloads.
+//!
+//! Once a [`Compressor`] is installed, data of every outgoing message is
+//! compressed before being sent. Compressed messages are marked with the
+//! [`ANNOTATION`] annotation, the value of which is the name of the algorithm
+//! used. Received messages marked with this annotation are decompressed using
+//! the compressor registered for that algorithm.
+//!
+//! The algorithm is pluggable: applications can provide their own compressor
+//! implementations. The library also provides built-in compressors for common
+//! algorithms behind feature flags:
+//!
+//!   * [`Gzip`] and [`Deflate`] (with the `gzip` feature), compatible with the
+//!     standard `gzip` and `zlib` modules of Python server-side consumers.
+//!   * [`Zstd`] (with the `zstd` feature) for better efficiency.
+//!
+//! Built-in compressors are always available for decompression of incoming
+//! messages, no registration is required.
+//!
+//! Compressing small payloads is rarely worth it (and can even make them bigger
+//! because of the format overhead). Use [`set_threshold`] to compress only the
+//! data of messages that are big enough, e.g. log or artifact uploads.
+//!
+//! A small compressed payload can expand to a huge amount of data, so the size
+//! of decompressed data is limited (see [`set_decompressed_limit`]). Incoming
+//! messages exceeding the limit are rejected as malformed.
+//!
+//! Note that compression happens before [encryption] of the payload (as
+//! encrypted data does not compress well).
+//!
+//! [encryption]: crate::crypto
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+
+/// Key of the annotation that marks compressed messages.
+pub const ANNOTATION: &str = "fleetspeak-rs/compression";
+
+/// A compression algorithm used for message payloads.
+pub trait Compressor: Send + Sync {
+
+    /// Returns the name of the compression algorithm.
+    ///
+    /// The name is attached to every compressed message, so that the receiving
+    /// side knows how to decompress it.
+    fn algorithm(&self) -> &str;
+
+    /// Compresses the given data.
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Decompresses the given data.
+    ///
+    /// Implementations should stop decompressing once the output exceeds the
+    /// [limit] (e.g. by reading it with [`read_limited`]). The limit is also
+    /// enforced on the result, but only after it has been fully decompressed.
+    ///
+    /// [limit]: set_decompressed_limit
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>>;
+}
+
+/// Installs the compressor to use for outgoing messages.
+///
+/// Messages sent after this call are compressed with the given compressor. The
+/// compressor is also registered for decompression of incoming messages (see
+/// [`register`]).
+///
+/// # Examples
+///
+/// ```no_run
+/// # #[cfg(feature = "gzip")]
+/// fleetspeak::compression::install(fleetspeak::compression::Gzip);
+/// ```
+pub fn install<C>(compressor: C)
+where
+    C: Compressor + 'static,
+{
+    let compressor = Arc::new(compressor);
+
+    let mut state = STATE.write().expect("poisoned compression lock");
+    state.registered.insert(String::from(compressor.algorithm()), compressor.clone());
+    state.outgoing = Some(compressor);
+}
+
+/// Uninstalls the compressor used for outgoing messages (if any).
+///
+/// Messages sent after this call are not compressed anymore. Incoming messages
+/// are still decompressed with the registered compressors.
+pub fn uninstall() {
+    STATE.write().expect("poisoned compression lock").outgoing = None;
+}
+
+/// Sets the minimum size (in bytes) of data of outgoing messages to compress.
+///
+/// Messages with less data than `threshold` are sent uncompressed (and without
+/// the [`ANNOTATION`]), even if a compressor is [installed]. By default, data of
+/// every message is compressed.
+///
+/// [installed]: install
+///
+/// # Examples
+///
+/// ```no_run
+/// # #[cfg(feature = "zstd")]
+/// fleetspeak::compression::install(fleetspeak::compression::Zstd::default());
+/// fleetspeak::compression::set_threshold(4 * 1024);
+/// ```
+pub fn set_threshold(threshold: usize) {
+    STATE.write().expect("poisoned compression lock").threshold = threshold;
+}
+
+/// Sets the maximum size (in bytes) of decompressed data of incoming messages.
+///
+/// Messages with data that decompresses to more than `limit` bytes are
+/// rejected with an error of the [`InvalidData`] kind. By default, the limit
+/// is the size limit of incoming messages (see [`env::MAX_MESSAGE_SIZE_VAR`]).
+///
+/// [`InvalidData`]: std::io::ErrorKind::InvalidData
+/// [`env::MAX_MESSAGE_SIZE_VAR`]: crate::env::MAX_MESSAGE_SIZE_VAR
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::compression::set_decompressed_limit(16 * 1024 * 1024);
+/// ```
+pub fn set_decompressed_limit(limit: usize) {
+    STATE.write().expect("poisoned compression lock").limit = Some(limit);
+}
+
+/// Reads the decompressed data from `decoder` up to the [limit].
+///
+/// This is a helper for [`Compressor`] implementations: reading stops as soon
+/// as the limit is exceeded and an error of the [`InvalidData`] kind is
+/// returned in such case.
+///
+/// [limit]: set_decompressed_limit
+/// [`InvalidData`]: std::io::ErrorKind::InvalidData
+pub fn read_limited<R>(decoder: R) -> std::io::Result<Vec<u8>>
+where
+    R: std::io::Read,
+{
+    use std::io::Read as _;
+
+    let limit = limit();
+
+    // We read one byte more than the limit to tell whether it was exceeded.
+    let mut buf = Vec::new();
+    decoder.take(limit as u64 + 1).read_to_end(&mut buf)?;
+    check_limit(&buf, limit)?;
+
+    Ok(buf)
+}
+
+/// Registers the compressor for decompression of incoming messages.
+///
+/// Unlike [`install`], this does not affect outgoing messages. Registering a
+/// compressor for an algorithm that already has one replaces it.
+pub fn register<C>(compressor: C)
+where
+    C: Compressor + 'static,
+{
+    let compressor = Arc::new(compressor);
+
+    let mut state = STATE.write().expect("poisoned compression lock");
+    state.registered.insert(String::from(compressor.algorithm()), compressor);
+}
+
+/// Compresses data of the outgoing message with the installed compressor (if
+/// any).
+pub(crate) fn compress(proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let state = STATE.read().expect("poisoned compression lock");
+    let compressor = match &state.outgoing {
+        Some(compressor) if proto.data.value.len() >= state.threshold => {
+            compressor.clone()
+        }
+        _ => return Ok(()),
+    };
+    drop(state);
+
+    compress_with(&*compressor, proto)
+}
+
+/// Decompresses data of the incoming message if it is marked as compressed.
+pub(crate) fn decompress(proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    use std::io::ErrorKind::InvalidData;
+
+    let algorithm = match crate::io::take_annotation(proto, ANNOTATION) {
+        Some(algorithm) => algorithm,
+        None => return Ok(()),
+    };
+
+    let compressor = match compressor(&algorithm) {
+        Some(compressor) => compressor,
+        None => return Err(std::io::Error::new(InvalidData, {
+            format!("unsupported compression algorithm: {algorithm:?}")
+        })),
+    };
+
+    let data = proto.mut_data();
+    data.value = compressor.decompress(&data.value)?;
+
+    // Custom compressors do not necessarily respect the limit themselves.
+    check_limit(&data.value, limit())
+}
+
+fn compress_with(compressor: &dyn Compressor, proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let data = proto.mut_data();
+    data.value = compressor.compress(&data.value)?;
+
+    crate::io::add_annotation(proto, ANNOTATION, String::from(compressor.algorithm()));
+
+    Ok(())
+}
+
+/// Returns the maximum size of decompressed data.
+fn limit() -> usize {
+    STATE.read().expect("poisoned compression lock").limit
+        .unwrap_or_else(crate::env::max_incoming_size)
+}
+
+/// Verifies that the decompressed data does not exceed the `limit`.
+fn check_limit(data: &[u8], limit: usize) -> std::io::Result<()> {
+    if data.len() > limit {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+            format!("decompressed data exceeds the limit of {limit} bytes")
+        }));
+    }
+
+    Ok(())
+}
+
+/// Returns the compressor for the given algorithm (if there is one).
+fn compressor(algorithm: &str) -> Option<Arc<dyn Compressor>> {
+    let state = STATE.read().expect("poisoned compression lock");
+    if let Some(compressor) = state.registered.get(algorithm) {
+        return Some(compressor.clone());
+    }
+    drop(state);
+
+    match algorithm {
+        #[cfg(feature = "gzip")]
+        "gzip" => Some(Arc::new(Gzip)),
+        #[cfg(feature = "gzip")]
+        "deflate" => Some(Arc::new(Deflate)),
+        #[cfg(feature = "zstd")]
+        "zstd" => Some(Arc::new(Zstd::default())),
+        _ => None,
+    }
+}
+
+/// A compressor using the gzip format.
+#[cfg(feature = "gzip")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Gzip;
+
+#[cfg(feature = "gzip")]
+impl Compressor for Gzip {
+
+    fn algorithm(&self) -> &str {
+        "gzip"
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Write as _;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        read_limited(flate2::read::GzDecoder::new(data))
+    }
+}
+
+/// A compressor using the zlib-wrapped deflate format.
+#[cfg(feature = "gzip")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deflate;
+
+#[cfg(feature = "gzip")]
+impl Compressor for Deflate {
+
+    fn algorithm(&self) -> &str {
+        "deflate"
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Write as _;
+
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        read_limited(flate2::read::ZlibDecoder::new(data))
+    }
+}
+
+/// A compressor using the Zstandard format.
+#[cfg(feature = "zstd")]
+#[derive(Clone, Copy, Debug)]
+pub struct Zstd {
+    /// Compression level to use (see the [`zstd`] crate for details).
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+
+    fn default() -> Zstd {
+        Zstd {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for Zstd {
+
+    fn algorithm(&self) -> &str {
+        "zstd"
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        zstd::encode_all(data, self.level)
+    }
+
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        read_limited(zstd::stream::read::Decoder::new(data)?)
+    }
+}
+
+/// Compressors configured by the application.
+struct State {
+    /// The compressor used for outgoing messages.
+    outgoing: Option<Arc<dyn Compressor>>,
+    /// Compressors available for incoming messages, keyed by algorithm name.
+    registered: HashMap<String, Arc<dyn Compressor>>,
+    /// Minimum size of data of outgoing messages to compress.
+    threshold: usize,
+    /// Maximum size of decompressed data (if other than the default one).
+    limit: Option<usize>,
+}
+
+static STATE: LazyLock<RwLock<State>> = LazyLock::new(|| RwLock::new(State {
+    outgoing: None,
+    registered: HashMap::new(),
+    threshold: 0,
+    limit: None,
+}));
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn roundtrip(compressor: &dyn Compressor) {
+        let data = b"foo".repeat(1024);
+
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = data.clone();
+
+        compress_with(compressor, &mut proto).unwrap();
+        assert!(proto.data.value.len() < data.len());
+
+        decompress(&mut proto).unwrap();
+        assert_eq!(proto.data.value, data);
+        assert!(proto.annotations.entries.is_empty());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_roundtrip() {
+        roundtrip(&Gzip);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn deflate_roundtrip() {
+        roundtrip(&Deflate);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_roundtrip() {
+        roundtrip(&Zstd::default());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn decompress_over_limit() {
+        // Compressed, this is just a few kilobytes.
+        let data = vec![0; crate::env::DEFAULT_MAX_INCOMING_SIZE + 1];
+
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = data;
+
+        compress_with(&Gzip, &mut proto).unwrap();
+
+        let error = decompress(&mut proto).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decompress_unsupported() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        crate::io::add_annotation(&mut proto, ANNOTATION, String::from("lzma"));
+
+        assert!(decompress(&mut proto).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::io::{Read, Write};
+
+use crate::Message;
+
+/// A connection to the Fleetspeak client over an arbitrary pair of streams.
+///
+/// The free functions of this library operate on a global connection backed by
+/// the communication channels that the Fleetspeak client passes through the
+/// environment. This type allows to speak the same protocol over any `input`
+/// and `output` streams instead, which is useful for testing services without
+/// a real Fleetspeak client or for embedding the connector in processes that
+/// receive the channels some other way.
+///
+/// Unlike the free functions, methods of this type do not panic on I/O errors
+/// but return them. They also do not contribute to the global [metrics] or the
+/// [connection status].
+///
+/// [metrics]: crate::metrics
+/// [connection status]: crate::status
+///
+/// # Examples
+///
+/// ```no_run
+/// let input = std::fs::File::open("/tmp/fleetspeak-in")
+///     .expect("failed to open the input channel");
+/// let output = std::fs::File::create("/tmp/fleetspeak-out")
+///     .expect("failed to open the output channel");
+///
+/// let mut conn = fleetspeak::Connection::new(input, output);
+/// conn.handshake().expect("handshake failure");
+/// conn.startup("0.0.1").expect("failed to send startup information");
+///
+/// let message = conn.receive().expect("failed to receive a message");
+/// println!("received {}", message.preview());
+/// ```
+pub struct Connection<R, W> {
+    input: R,
+    output: W,
+    /// Policy for messages without data (if overridden for this connection).
+    missing_data: Option<crate::MissingData>,
+    /// Buffer for bodies of frames being read, kept between messages.
+    input_scratch: Vec<u8>,
+    /// Buffer for bodies of frames being written, kept between messages.
+    output_scratch: Vec<u8>,
+}
+
+impl<R: Read, W: Write> Connection<R, W> {
+
+    /// Creates a new connection over the given streams.
+    ///
+    /// No data is exchanged until the first method call. In particular, the
+    /// [`handshake`] has to be performed explicitly.
+    ///
+    /// [`handshake`]: Connection::handshake
+    pub fn new(input: R, output: W) -> Connection<R, W> {
+        Connection {
+            input,
+            output,
+            missing_data: None,
+            input_scratch: Vec::new(),
+            output_scratch: Vec::new(),
+        }
+    }
+
+    /// Sets the policy for handling incoming messages that carry no data on
+    /// this connection.
+    ///
+    /// By default, the policy set with [`set_missing_data`] is used. See
+    /// documentation for [`MissingData`] for more details.
+    ///
+    /// [`set_missing_data`]: crate::set_missing_data
+    /// [`MissingData`]: crate::MissingData
+    pub fn with_missing_data(mut self, policy: crate::MissingData) -> Connection<R, W> {
+        self.missing_data = Some(policy);
+        self
+    }
+
+    /// Executes the handshake procedure.
+    ///
+    /// This has to be done before any messages are exchanged.
+    pub fn handshake(&mut self) -> std::io::Result<()> {
+        crate::io::handshake(&mut self.input, &mut self.output)
+    }
+
+    /// Sends a heartbeat signal to the Fleetspeak client.
+    ///
+    /// See documentation for the [`heartbeat`] function for more details.
+    ///
+    /// [`heartbeat`]: crate::heartbeat
+    pub fn heartbeat(&mut self) -> std::io::Result<()> {
+        crate::io::write_heartbeat(&mut self.output)?;
+        self.output.flush()
+    }
+
+    /// Sends a system message with startup information to the Fleetspeak
+    /// client.
+    ///
+    /// See documentation for the [`startup`] function for more details.
+    ///
+    /// [`startup`]: crate::startup
+    pub fn startup(&mut self, version: &str) -> std::io::Result<()> {
+        self.startup_with(&crate::StartupOptions {
+            version: String::from(version),
+            ..Default::default()
+        })
+    }
+
+    /// Sends a system message with extended startup information to the
+    /// Fleetspeak client.
+    ///
+    /// See documentation for the [`startup_with`] function for more details.
+    ///
+    /// [`startup_with`]: crate::startup_with
+    pub fn startup_with(&mut self, options: &crate::StartupOptions) -> std::io::Result<()> {
+        crate::io::write_startup(&mut self.output, options)?;
+        self.output.flush()
+    }
+
+    /// Sends the message to the Fleetspeak server.
+    ///
+    /// See documentation for the [`send`] function for more details.
+    ///
+    /// [`send`]: crate::send
+    pub fn send(&mut self, message: Message) -> std::io::Result<()> {
+        let proto = crate::encode(message)?;
+        crate::io::write_frame_with_scratch(&mut self.output, proto, &mut self.output_scratch)?;
+        self.output.flush()
+    }
+
+    /// Sends all the messages to the Fleetspeak server at once.
+    ///
+    /// See documentation for the [`send_all`] function for more details.
+    ///
+    /// [`send_all`]: crate::send_all
+    pub fn send_all<I>(&mut self, messages: I) -> std::io::Result<()>
+    where
+        I: IntoIterator<Item = Message>,
+    {
+        for message in messages {
+            let proto = crate::encode(message)?;
+            crate::io::write_frame_with_scratch(&mut self.output, proto, &mut self.output_scratch)?;
+        }
+        self.output.flush()
+    }
+
+    /// Receives a message from the Fleetspeak server.
+    ///
+    /// See documentation for the [`receive`] function for more details.
+    ///
+    /// [`receive`]: crate::receive
+    pub fn receive(&mut self) -> std::io::Result<Message> {
+        loop {
+            let proto = crate::io::read_proto_with_scratch(&mut self.input, &mut self.input_scratch)?;
+            if let Some(message) = crate::decode(proto, self.missing_data())? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Receives a message from the Fleetspeak server together with its
+    /// metadata.
+    ///
+    /// See documentation for the [`receive_with_metadata`] function for more
+    /// details.
+    ///
+    /// [`receive_with_metadata`]: crate::receive_with_metadata
+    pub fn receive_with_metadata(&mut self) -> std::io::Result<(Message, crate::Metadata)> {
+        loop {
+            let proto = crate::io::read_proto_with_scratch(&mut self.input, &mut self.input_scratch)?;
+            if let Some(result) = crate::decode_with_metadata(proto, self.missing_data())? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Sends a raw Protocol Buffers message to the Fleetspeak server.
+    ///
+    /// See documentation for the [`send_raw`] function for more details.
+    ///
+    /// [`send_raw`]: crate::send_raw
+    pub fn send_raw(&mut self, proto: fleetspeak_proto::common::Message) -> std::io::Result<()> {
+        crate::io::write_frame_with_scratch(&mut self.output, proto, &mut self.output_scratch)?;
+        self.output.flush()
+    }
+
+    /// Receives a raw Protocol Buffers message from the Fleetspeak server.
+    ///
+    /// See documentation for the [`receive_raw`] function for more details.
+    ///
+    /// [`receive_raw`]: crate::receive_raw
+    pub fn receive_raw(&mut self) -> std::io::Result<fleetspeak_proto::common::Message> {
+        crate::io::read_proto_with_scratch(&mut self.input, &mut self.input_scratch)
+    }
+
+    /// Returns the underlying input and output streams.
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+
+    /// Returns the policy for messages without data used by this connection.
+    fn missing_data(&self) -> crate::MissingData {
+        self.missing_data.unwrap_or_else(crate::missing_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+
+    use super::*;
+
+    const MAGIC: [u8; 4] = 0xf1ee1001u32.to_le_bytes();
+
+    #[test]
+    fn handshake() {
+        let mut conn = Connection::new(Cursor::new(MAGIC), Vec::new());
+        conn.handshake().unwrap();
+
+        let (_, output) = conn.into_inner();
+        assert_eq!(output, MAGIC);
+    }
+
+    #[test]
+    fn send() {
+        let mut conn = Connection::new(std::io::empty(), Vec::new());
+        conn.send(Message {
+            service: String::from("foo"),
+            kind: Some(String::from("bar")),
+            data: b"baz".to_vec(),
+            ..Default::default()
+        }).unwrap();
+
+        let (_, output) = conn.into_inner();
+
+        let proto = crate::io::read_proto(&mut Cursor::new(output)).unwrap();
+        assert_eq!(proto.destination().service_name(), "foo");
+        assert_eq!(proto.message_type(), "bar");
+        assert_eq!(proto.data().value, b"baz");
+    }
+
+    #[test]
+    fn send_all_single_flush() {
+        struct Output {
+            buf: Vec<u8>,
+            flushes: usize,
+        }
+
+        impl Write for Output {
+
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.buf.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        let mut conn = Connection::new(std::io::empty(), Output {
+            buf: Vec::new(),
+            flushes: 0,
+        });
+        conn.send_all((0..3).map(|i| Message {
+            service: String::from("foo"),
+            data: vec![i],
+            ..Default::default()
+        })).unwrap();
+
+        let (_, output) = conn.into_inner();
+        assert_eq!(output.flushes, 1);
+
+        let mut input = Cursor::new(output.buf);
+        for i in 0..3 {
+            let proto = crate::io::read_proto(&mut input).unwrap();
+            assert_eq!(proto.data().value, vec![i]);
+        }
+    }
+
+    #[test]
+    fn receive() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_source().set_service_name(String::from("foo"));
+        proto.set_message_type(String::from("bar"));
+        proto.mut_data().value = b"baz".to_vec();
+
+        let mut input = Vec::new();
+        crate::io::write_proto(&mut input, proto).unwrap();
+
+        let mut conn = Connection::new(Cursor::new(input), std::io::sink());
+        let message = conn.receive().unwrap();
+        assert_eq!(message.service, "foo");
+        assert_eq!(message.kind.as_deref(), Some("bar"));
+        assert_eq!(message.data, b"baz");
+    }
+
+    #[test]
+    fn receive_skip_missing_data() {
+        let mut input = Vec::new();
+        for data in [None, Some(b"bar")] {
+            let mut proto = fleetspeak_proto::common::Message::new();
+            proto.mut_source().set_service_name(String::from("foo"));
+            if let Some(data) = data {
+                proto.mut_data().value = data.to_vec();
+            }
+
+            crate::io::write_proto(&mut input, proto).unwrap();
+        }
+
+        let mut conn = Connection::new(Cursor::new(input), std::io::sink())
+            .with_missing_data(crate::MissingData::Skip);
+        assert_eq!(conn.receive().unwrap().data, b"bar");
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use protobuf::well_known_types::struct_::{Struct, Value};
+
+/// Maximum time to wait for the crash report to be written.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Message kind of crash reports.
+pub const CRASH_REPORT_KIND: &str = "CrashReport";
+
+/// Installs a panic hook reporting crashes to the given server `service`.
+///
+/// When any thread panics, the hook sends a message of the [`CRASH_REPORT_KIND`]
+/// kind and then aborts the process, so that the fleet operator sees why the
+/// service died (and the Fleetspeak client restarts it instead of keeping it
+/// running with a dead thread). The data of the message is a serialized
+/// `google.protobuf.Struct` with the following fields:
+///
+///   * `message` with the panic message (if it is a string),
+///   * `location` with the source location of the panic (if known),
+///   * `thread` with the name of the panicking thread (if it has one),
+///   * `backtrace` with the backtrace of the panicking thread,
+///   * `library_version` with the [version] of this library.
+///
+/// Reporting is best-effort: if the report cannot be written within a short
+/// time (e.g. because the panicking thread holds the connection), the process
+/// is aborted without it. The previously installed hook (e.g. the default one
+/// printing the panic to the standard error) is called before aborting.
+///
+/// Note that this makes the process abort even on panics that would otherwise
+/// be caught with [`catch_unwind`](std::panic::catch_unwind).
+///
+/// [version]: crate::VERSION
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::install_panic_hook("monitoring");
+/// ```
+pub fn install_panic_hook(service: &str) {
+    let service = String::from(service);
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        // Only the first panic is reported: others might be caused by the
+        // report itself or be consequences of the first one.
+        if !REPORTING.swap(true, Ordering::SeqCst) {
+            let backtrace = std::backtrace::Backtrace::force_capture();
+            let report = report(
+                info.payload_as_str(),
+                info.location(),
+                std::thread::current().name(),
+                &backtrace,
+            );
+
+            let result = protobuf::Message::write_to_bytes(&report)
+                .map_err(std::io::Error::from)
+                .and_then(|data| {
+                    crate::send_timeout(crate::Message {
+                        service: service.clone(),
+                        kind: Some(String::from(CRASH_REPORT_KIND)),
+                        data,
+                        ..Default::default()
+                    }, REPORT_TIMEOUT)
+                    .map_err(|error| std::io::Error::new(std::io::ErrorKind::TimedOut, error))
+                });
+
+            if let Err(error) = result {
+                log::error!("failed to report crash: {error}");
+            }
+        }
+
+        previous(info);
+
+        std::process::abort();
+    }));
+}
+
+/// Builds the structured crash report.
+fn report(
+    message: Option<&str>,
+    location: Option<&std::panic::Location<'_>>,
+    thread: Option<&str>,
+    backtrace: &dyn std::fmt::Display,
+) -> Struct {
+    let mut report = Struct::new();
+    let mut insert = |key: &str, string: String| {
+        let mut value = Value::new();
+        value.set_string_value(string);
+        report.fields.insert(String::from(key), value);
+    };
+
+    if let Some(message) = message {
+        insert("message", String::from(message));
+    }
+    if let Some(location) = location {
+        insert("location", location.to_string());
+    }
+    if let Some(thread) = thread {
+        insert("thread", String::from(thread));
+    }
+    insert("backtrace", backtrace.to_string());
+    insert("library_version", String::from(crate::VERSION));
+
+    report
+}
+
+/// Whether a crash is being reported already.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn report_fields() {
+        let location = std::panic::Location::caller();
+        let report = report(Some("foo"), Some(location), Some("bar"), &"baz");
+
+        assert_eq!(report.fields["message"].string_value(), "foo");
+        assert_eq!(report.fields["location"].string_value(), location.to_string());
+        assert_eq!(report.fields["thread"].string_value(), "bar");
+        assert_eq!(report.fields["backtrace"].string_value(), "baz");
+        assert_eq!(report.fields["library_version"].string_value(), crate::VERSION);
+    }
+
+    #[test]
+    fn report_unknown() {
+        let report = report(None, None, None, &"");
+
+        assert!(!report.fields.contains_key("message"));
+        assert!(!report.fields.contains_key("location"));
+        assert!(!report.fields.contains_key("thread"));
+    }
+}
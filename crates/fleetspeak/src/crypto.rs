@@ -0,0 +1,165 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Application-layer encryption of message payloads.
+//!
+//! Fleetspeak secures the transport between the client and the server but some
+//! deployments require payloads to be encrypted end-to-end by the services
+//! themselves. This module provides hook points for that: once a [`Cipher`] is
+//! installed, data of every outgoing message is encrypted before being sent and
+//! data of every incoming message marked as encrypted is decrypted after being
+//! received.
+//!
+//! The library does not implement any encryption algorithm itself: both the
+//! algorithm and the key material are supplied by the application through the
+//! [`Cipher`] implementation.
+//!
+//! Encrypted messages are marked with the [`ANNOTATION`] annotation, the value
+//! of which is the name of the algorithm used (as reported by the cipher). The
+//! server-side service is expected to use it to pick the right decryption
+//! method.
+
+use std::sync::{Arc, RwLock};
+
+/// Key of the annotation that marks encrypted messages.
+pub const ANNOTATION: &str = "fleetspeak-rs/encryption";
+
+/// A symmetric cipher used to encrypt and decrypt message payloads.
+pub trait Cipher: Send + Sync {
+
+    /// Returns the name of the encryption algorithm.
+    ///
+    /// The name is attached to every encrypted message, so that the receiving
+    /// side knows how to decrypt it.
+    fn algorithm(&self) -> &str;
+
+    /// Encrypts the given plaintext.
+    fn encrypt(&self, plaintext: &[u8]) -> std::io::Result<Vec<u8>>;
+
+    /// Decrypts the given ciphertext.
+    fn decrypt(&self, ciphertext: &[u8]) -> std::io::Result<Vec<u8>>;
+}
+
+/// Installs the cipher to use for encryption of message payloads.
+///
+/// Messages sent after this call are encrypted with the given cipher. Received
+/// messages encrypted with the same algorithm are decrypted.
+pub fn install<C>(cipher: C)
+where
+    C: Cipher + 'static,
+{
+    *CIPHER.write().expect("poisoned cipher lock") = Some(Arc::new(cipher));
+}
+
+/// Uninstalls the currently installed cipher (if any).
+///
+/// Messages sent after this call are not encrypted anymore.
+pub fn uninstall() {
+    *CIPHER.write().expect("poisoned cipher lock") = None;
+}
+
+/// Encrypts data of the outgoing message with the installed cipher (if any).
+pub(crate) fn seal(proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    match cipher() {
+        Some(cipher) => seal_with(&*cipher, proto),
+        None => Ok(()),
+    }
+}
+
+/// Decrypts data of the incoming message if it is marked as encrypted.
+pub(crate) fn open(proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    open_with(cipher().as_deref(), proto)
+}
+
+fn seal_with(cipher: &dyn Cipher, proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let data = proto.mut_data();
+    data.value = cipher.encrypt(&data.value)?;
+
+    crate::io::add_annotation(proto, ANNOTATION, String::from(cipher.algorithm()));
+
+    Ok(())
+}
+
+fn open_with(cipher: Option<&dyn Cipher>, proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    use std::io::ErrorKind::InvalidData;
+
+    let algorithm = match crate::io::take_annotation(proto, ANNOTATION) {
+        Some(algorithm) => algorithm,
+        None => return Ok(()),
+    };
+
+    let cipher = match cipher {
+        Some(cipher) if cipher.algorithm() == algorithm => cipher,
+        _ => return Err(std::io::Error::new(InvalidData, {
+            format!("unsupported encryption algorithm: {algorithm:?}")
+        })),
+    };
+
+    let data = proto.mut_data();
+    data.value = cipher.decrypt(&data.value)?;
+
+    Ok(())
+}
+
+/// Returns the currently installed cipher (if any).
+fn cipher() -> Option<Arc<dyn Cipher>> {
+    CIPHER.read().expect("poisoned cipher lock").clone()
+}
+
+static CIPHER: RwLock<Option<Arc<dyn Cipher>>> = RwLock::new(None);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Xor(u8);
+
+    impl Cipher for Xor {
+
+        fn algorithm(&self) -> &str {
+            "xor"
+        }
+
+        fn encrypt(&self, plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(plaintext.iter().map(|byte| byte ^ self.0).collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+            self.encrypt(ciphertext)
+        }
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = b"foo".to_vec();
+
+        seal_with(&Xor(0x42), &mut proto).unwrap();
+        assert_ne!(proto.data.value, b"foo");
+        assert_eq!(proto.annotations.entries[0].value, "xor");
+
+        open_with(Some(&Xor(0x42)), &mut proto).unwrap();
+        assert_eq!(proto.data.value, b"foo");
+        assert!(proto.annotations.entries.is_empty());
+    }
+
+    #[test]
+    fn open_not_encrypted() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = b"foo".to_vec();
+
+        open_with(Some(&Xor(0x42)), &mut proto).unwrap();
+        assert_eq!(proto.data.value, b"foo");
+    }
+
+    #[test]
+    fn open_without_cipher() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        seal_with(&Xor(0x42), &mut proto).unwrap();
+
+        assert!(open_with(None, &mut proto).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Support for services that fork or re-execute themselves.
+
+use std::io::Write as _;
+
+/// Restores the connection in a child process after `fork`.
+///
+/// Only the thread that called `fork` survives in the child process. This
+/// function re-validates the communication descriptors and re-registers the
+/// background threads of the library (the writer used by [`send_timeout`], the
+/// [keepalive probe] and [background heartbeats]), so that the child can keep
+/// using the connection (and heartbeating) as if nothing happened.
+///
+/// The connection should be used by only one of the processes after the fork,
+/// typically the parent exits right after forking. If the parent keeps using
+/// the connection (e.g. it forks worker processes), children should call
+/// [`disown_after_fork`] instead. Moreover, the fork must not
+/// happen while other threads are in the middle of using the connection (e.g.
+/// blocked in [`receive`]): the state they held cannot be recovered in the
+/// child and an error is returned in such case.
+///
+/// [`send_timeout`]: crate::send_timeout
+/// [keepalive probe]: crate::start_keepalive
+/// [background heartbeats]: crate::start_heartbeats
+/// [`receive`]: crate::receive
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::startup("0.0.1");
+///
+/// // SAFETY: The service is single-threaded at this point.
+/// match unsafe { libc::fork() } {
+///     -1 => panic!("fork failed"),
+///     0 => fleetspeak::after_fork().expect("failed to restore the connection"),
+///     _ => std::process::exit(0),
+/// }
+///
+/// fleetspeak::heartbeat();
+/// ```
+pub fn after_fork() -> std::io::Result<()> {
+    let input = match crate::CONNECTION.input.try_lock() {
+        Ok(input) => input,
+        Err(_) => return Err(busy("input channel in use during fork")),
+    };
+    input.get_ref().validate()?;
+    drop(input);
+
+    let output = match crate::CONNECTION.output.try_lock() {
+        Ok(output) => output,
+        Err(_) => return Err(busy("output channel in use during fork")),
+    };
+    output.get_ref().validate()?;
+    drop(output);
+
+    crate::reader::after_fork()?;
+    crate::writer::after_fork()?;
+    crate::keepalive::after_fork(true)?;
+    crate::heartbeats::after_fork(true)?;
+
+    log::info!("connection restored after fork");
+
+    Ok(())
+}
+
+/// Gives up the connection in a child process after `fork`.
+///
+/// Services that fork worker processes keep using the connection in the parent
+/// process. Children inherit the communication descriptors though, and if both
+/// processes wrote to them, their frames would interleave and corrupt the
+/// stream. This function closes the copies of the descriptors in the child
+/// (which does not affect the parent) and discards everything the child
+/// inherited from the parent: buffered data, queued messages as well as the
+/// [keepalive probe] and [background heartbeats].
+///
+/// Afterwards, the connection is [closed] in the child and any attempt to use
+/// it fails. The same restrictions as for [`after_fork`] apply: the fork must
+/// not happen while other threads are in the middle of using the connection.
+///
+/// [keepalive probe]: crate::start_keepalive
+/// [background heartbeats]: crate::start_heartbeats
+/// [closed]: crate::Status::Closed
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::startup("0.0.1");
+///
+/// // SAFETY: The service is single-threaded at this point.
+/// match unsafe { libc::fork() } {
+///     -1 => panic!("fork failed"),
+///     0 => {
+///         fleetspeak::disown_after_fork()
+///             .expect("failed to disown the connection");
+///
+///         // Do the work of the child without talking to Fleetspeak.
+///         std::process::exit(0);
+///     }
+///     _ => (),
+/// }
+///
+/// fleetspeak::heartbeat();
+/// ```
+pub fn disown_after_fork() -> std::io::Result<()> {
+    use std::io::BufRead as _;
+
+    let mut input = match crate::CONNECTION.input.try_lock() {
+        Ok(input) => input,
+        Err(_) => return Err(busy("input channel in use during fork")),
+    };
+    // Buffered data has been read by the parent, it is not ours to consume.
+    let buffered = input.buffer().len();
+    input.consume(buffered);
+    input.get_mut().close()?;
+    drop(input);
+
+    // Data buffered in the output is not discarded, but once the channel is
+    // closed it is flushed to `/dev/null` instead of the parent's connection.
+    let mut output = match crate::CONNECTION.output.try_lock() {
+        Ok(output) => output,
+        Err(_) => return Err(busy("output channel in use during fork")),
+    };
+    output.get_mut().close()?;
+    drop(output);
+
+    crate::reader::after_fork()?;
+    crate::writer::after_fork()?;
+    crate::keepalive::after_fork(false)?;
+    crate::heartbeats::after_fork(false)?;
+
+    crate::status::close(&std::io::Error::other("connection disowned after fork"));
+
+    log::info!("connection disowned after fork");
+
+    Ok(())
+}
+
+/// Prepares the connection to be handed over to a new process image.
+///
+/// The given `command` is expected to replace the current process image (e.g.
+/// using [`CommandExt::exec`]). This function makes the communication
+/// descriptors survive the `exec` call and marks the connection as already
+/// established, so that the new image does not repeat the handshake (which the
+/// Fleetspeak client would see as a protocol violation).
+///
+/// The environment variables specifying the communication descriptors have to
+/// be passed to the new image, so the environment of the command must not be
+/// cleared. No other thread may use the connection while this function runs
+/// and an error is returned if some received data has been buffered but not
+/// consumed yet (as it would be lost).
+///
+/// [`CommandExt::exec`]: std::os::unix::process::CommandExt::exec
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::os::unix::process::CommandExt as _;
+///
+/// let mut command = std::process::Command::new("/proc/self/exe");
+/// fleetspeak::prepare_exec(&mut command)
+///     .expect("failed to prepare the connection for exec");
+///
+/// let error = command.exec();
+/// panic!("failed to re-execute: {error}");
+/// ```
+pub fn prepare_exec(command: &mut std::process::Command) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd as _;
+
+    let input = match crate::CONNECTION.input.try_lock() {
+        Ok(input) => input,
+        Err(_) => return Err(busy("input channel in use")),
+    };
+    if !input.buffer().is_empty() || crate::reader::has_unclaimed() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+            "unconsumed input data buffered"
+        }));
+    }
+    input.get_ref().inherit()?;
+    command.env(crate::env::COMMS_IN_VAR, input.get_ref().as_raw_fd().to_string());
+    drop(input);
+
+    let mut output = match crate::CONNECTION.output.try_lock() {
+        Ok(output) => output,
+        Err(_) => return Err(busy("output channel in use")),
+    };
+    output.flush()?;
+    output.get_ref().inherit()?;
+    command.env(crate::env::COMMS_OUT_VAR, output.get_ref().as_raw_fd().to_string());
+    drop(output);
+
+    command.env(crate::env::ESTABLISHED_VAR, "1");
+
+    Ok(())
+}
+
+/// Creates an error indicating that the connection is in use.
+fn busy(message: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::WouldBlock, message)
+}
@@ -0,0 +1,156 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::Message;
+
+/// A handler of incoming messages.
+type Handler<'h> = Box<dyn FnMut(Message) + 'h>;
+
+/// Routes incoming messages to handlers registered for their kinds.
+///
+/// Most services consist of a loop that receives messages, heartbeats while
+/// waiting for them and matches on their kinds. The dispatcher implements this
+/// loop: handlers are registered with [`on`] and [`run`] takes care of the
+/// rest. Messages of kinds without a registered handler are passed to the
+/// [`fallback`] handler (by default they are logged and dropped).
+///
+/// [`on`]: Dispatcher::on
+/// [`run`]: Dispatcher::run
+/// [`fallback`]: Dispatcher::fallback
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fleetspeak::startup("0.0.1");
+///
+/// fleetspeak::Dispatcher::new(Duration::from_secs(30))
+///     .on("ListFiles", |message| {
+///         println!("listing files: {}", message.preview());
+///     })
+///     .on("Ping", |message| {
+///         fleetspeak::send(fleetspeak::Message {
+///             kind: Some(String::from("Pong")),
+///             ..message
+///         });
+///     })
+///     .run();
+/// ```
+pub struct Dispatcher<'h> {
+    /// Handlers of messages by their kind.
+    handlers: HashMap<String, Handler<'h>>,
+    /// Handler of messages of kinds without a registered handler.
+    fallback: Handler<'h>,
+    /// Frequency of heartbeats sent while waiting for messages.
+    heartbeat_rate: Duration,
+}
+
+impl<'h> Dispatcher<'h> {
+
+    /// Creates a dispatcher that heartbeats with the given `rate` while waiting
+    /// for messages.
+    pub fn new(heartbeat_rate: Duration) -> Dispatcher<'h> {
+        Dispatcher {
+            handlers: HashMap::new(),
+            fallback: Box::new(|message| {
+                log::warn!("no handler for message: {}", message.preview());
+            }),
+            heartbeat_rate,
+        }
+    }
+
+    /// Registers a handler for messages of the given `kind`.
+    ///
+    /// Messages without a kind are routed to the handler registered for the
+    /// empty string. Registering another handler for the same kind replaces
+    /// the previous one.
+    pub fn on<F>(&mut self, kind: &str, handler: F) -> &mut Dispatcher<'h>
+    where
+        F: FnMut(Message) + 'h,
+    {
+        self.handlers.insert(String::from(kind), Box::new(handler));
+        self
+    }
+
+    /// Registers a handler for messages of kinds without a registered handler.
+    pub fn fallback<F>(&mut self, handler: F) -> &mut Dispatcher<'h>
+    where
+        F: FnMut(Message) + 'h,
+    {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Routes the message to the appropriate handler.
+    pub fn dispatch(&mut self, message: Message) {
+        let kind = message.kind.as_deref().unwrap_or("");
+        match self.handlers.get_mut(kind) {
+            Some(handler) => handler(message),
+            None => (self.fallback)(message),
+        }
+    }
+
+    /// Receives and routes messages forever.
+    ///
+    /// In case of any I/O failure, this function will panic (see [`receive`]
+    /// for more details).
+    ///
+    /// [`receive`]: crate::receive
+    pub fn run(&mut self) -> ! {
+        loop {
+            let message = crate::receive_with_heartbeat(self.heartbeat_rate);
+            self.dispatch(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn message(kind: Option<&str>) -> Message {
+        Message {
+            service: String::from("foo"),
+            kind: kind.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dispatch_by_kind() {
+        let routed = std::cell::RefCell::new(Vec::new());
+
+        let mut dispatcher = Dispatcher::new(Duration::from_secs(1));
+        dispatcher
+            .on("bar", |_| routed.borrow_mut().push("bar"))
+            .fallback(|_| routed.borrow_mut().push("fallback"));
+
+        dispatcher.dispatch(message(Some("bar")));
+        dispatcher.dispatch(message(Some("baz")));
+        dispatcher.dispatch(message(None));
+        drop(dispatcher);
+
+        assert_eq!(routed.into_inner(), vec!["bar", "fallback", "fallback"]);
+    }
+
+    #[test]
+    fn dispatch_without_kind() {
+        let mut count = 0;
+
+        let mut dispatcher = Dispatcher::new(Duration::from_secs(1));
+        dispatcher.on("", |_| count += 1);
+
+        dispatcher.dispatch(message(None));
+        dispatcher.dispatch(message(Some("")));
+        drop(dispatcher);
+
+        assert_eq!(count, 2);
+    }
+}
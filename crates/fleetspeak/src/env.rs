@@ -0,0 +1,324 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Environment set up by the Fleetspeak client.
+//!
+//! The Fleetspeak client passes the communication channels (and optionally
+//! other settings) to the service via `FLEETSPEAK_*` environment variables.
+//! This module exposes them in a parsed form (see [`Env`]), e.g. for
+//! diagnostics or for services that hand the channels over to other processes.
+//!
+//! All the variables are read through this module, so [`Env`] accounts for
+//! every one of them the library interprets.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+/// Environment variable with the descriptor (or handle) of the input channel.
+pub const COMMS_IN_VAR: &str = "FLEETSPEAK_COMMS_CHANNEL_INFD";
+
+/// Environment variable with the descriptor (or handle) of the output channel.
+pub const COMMS_OUT_VAR: &str = "FLEETSPEAK_COMMS_CHANNEL_OUTFD";
+
+/// Environment variable with the maximum size (in bytes) of a message.
+///
+/// Incoming messages are limited to 2 MiB if the client does not advertise a
+/// limit, so that a corrupted length prefix cannot make the service allocate
+/// gigabytes of memory. Outgoing messages are not limited in such case.
+pub const MAX_MESSAGE_SIZE_VAR: &str = "FLEETSPEAK_MAX_MESSAGE_SIZE";
+
+/// Environment variable with the loopback port of a development server to
+/// connect to instead of using the inherited channels.
+///
+/// This makes it easy to point a service at a mock server or a test harness.
+/// The connection is neither authenticated nor encrypted, so the variable is
+/// available only with the `dev-tcp` feature, which must not be enabled in
+/// production builds.
+#[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+pub const DEV_TCP_PORT_VAR: &str = "FLEETSPEAK_DEV_TCP_PORT";
+
+/// Environment variable with the directory to record the traffic to.
+///
+/// See the [`capture`](crate::capture) module for more details.
+pub const CAPTURE_DIR_VAR: &str = "FLEETSPEAK_CAPTURE_DIR";
+
+/// Environment variable with the capacity (in bytes) of the buffers of the
+/// communication channels.
+///
+/// See [`set_buffer_capacity`](crate::set_buffer_capacity) for more details.
+pub const BUFFER_CAPACITY_VAR: &str = "FLEETSPEAK_BUFFER_CAPACITY";
+
+/// Environment variable marking that the connection has already been
+/// established by an earlier image of the process.
+///
+/// See [`prepare_exec`](crate::prepare_exec) for more details.
+#[cfg(target_family = "unix")]
+pub(crate) const ESTABLISHED_VAR: &str = "FLEETSPEAK_RS_ESTABLISHED";
+
+/// Prefix of all the environment variables related to Fleetspeak.
+const PREFIX: &str = "FLEETSPEAK_";
+
+/// Environment variables interpreted by the library.
+const KNOWN_VARS: &[&str] = &[
+    COMMS_IN_VAR,
+    COMMS_OUT_VAR,
+    MAX_MESSAGE_SIZE_VAR,
+    #[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+    DEV_TCP_PORT_VAR,
+    CAPTURE_DIR_VAR,
+    BUFFER_CAPACITY_VAR,
+    #[cfg(target_family = "unix")]
+    ESTABLISHED_VAR,
+];
+
+/// A raw communication channel: a file descriptor on Unix.
+#[cfg(target_family = "unix")]
+pub type RawChannel = std::os::fd::RawFd;
+
+/// A raw communication channel: a file handle on Windows.
+#[cfg(target_family = "windows")]
+pub type RawChannel = std::os::windows::io::RawHandle;
+
+/// Parsed `FLEETSPEAK_*` environment variables.
+#[derive(Clone, Debug)]
+pub struct Env {
+    /// The input channel (given by [`COMMS_IN_VAR`]).
+    pub comms_in: RawChannel,
+    /// The output channel (given by [`COMMS_OUT_VAR`]).
+    pub comms_out: RawChannel,
+    /// The maximum size of a message (given by [`MAX_MESSAGE_SIZE_VAR`]), if
+    /// the client advertises one.
+    pub max_message_size: Option<usize>,
+    /// The loopback port of a development server (given by
+    /// [`DEV_TCP_PORT_VAR`]), if any.
+    #[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+    pub dev_tcp_port: Option<u16>,
+    /// The directory to record the traffic to (given by [`CAPTURE_DIR_VAR`]),
+    /// if any.
+    pub capture_dir: Option<PathBuf>,
+    /// The capacity of the channel buffers (given by [`BUFFER_CAPACITY_VAR`]),
+    /// if overridden.
+    pub buffer_capacity: Option<usize>,
+    /// All the other `FLEETSPEAK_*` variables, not interpreted in any way.
+    pub other: Vec<(OsString, OsString)>,
+}
+
+impl Env {
+
+    /// Parses the variables of the current process.
+    ///
+    /// Note that this only parses the variables: it does not verify that the
+    /// channels are actually valid.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// match fleetspeak::env::Env::from_env() {
+    ///     Ok(env) => println!("input: {:?}, output: {:?}", env.comms_in, env.comms_out),
+    ///     Err(error) if error.is_not_specified() => println!("not launched by Fleetspeak"),
+    ///     Err(error) => println!("invalid environment: {error}"),
+    /// }
+    /// ```
+    pub fn from_env() -> Result<Env, EnvError> {
+        let channel = |var| {
+            channel(var)
+                .map_err(|error| EnvError {
+                    var,
+                    repr: EnvErrorRepr::Channel(error),
+                })
+        };
+
+        let comms_in = channel(COMMS_IN_VAR)?;
+        let comms_out = channel(COMMS_OUT_VAR)?;
+        let max_message_size = parse_var(MAX_MESSAGE_SIZE_VAR)?;
+        #[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+        let dev_tcp_port = dev_tcp_port()?;
+        let buffer_capacity = parse_var(BUFFER_CAPACITY_VAR)?;
+
+        let other = std::env::vars_os()
+            .filter(|(key, _)| {
+                let key = key.to_string_lossy();
+                key.starts_with(PREFIX) && !KNOWN_VARS.contains(&&key[..])
+            })
+            .collect();
+
+        Ok(Env {
+            comms_in,
+            comms_out,
+            max_message_size,
+            #[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+            dev_tcp_port,
+            capture_dir: capture_dir(),
+            buffer_capacity,
+            other,
+        })
+    }
+}
+
+/// An error returned when the Fleetspeak environment cannot be parsed.
+#[derive(Clone, Debug)]
+pub struct EnvError {
+    /// Name of the variable that could not be parsed.
+    var: &'static str,
+    repr: EnvErrorRepr,
+}
+
+#[derive(Clone, Debug)]
+enum EnvErrorRepr {
+    /// A communication channel is invalid.
+    Channel(crate::io::CommsEnvError),
+    /// A variable other than a communication channel is invalid.
+    NotParsable(OsString),
+}
+
+impl EnvError {
+
+    /// Returns the name of the variable that could not be parsed.
+    pub fn var(&self) -> &'static str {
+        self.var
+    }
+
+    /// Returns whether the variable is not specified at all.
+    ///
+    /// This typically means that the process has not been launched by the
+    /// Fleetspeak client.
+    pub fn is_not_specified(&self) -> bool {
+        match &self.repr {
+            EnvErrorRepr::Channel(error) => error.is_not_specified(),
+            EnvErrorRepr::NotParsable(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for EnvError {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.repr {
+            EnvErrorRepr::Channel(error) => {
+                write!(fmt, "{}: {}", self.var, error)
+            }
+            EnvErrorRepr::NotParsable(value) => {
+                write!(fmt, "{}: invalid value: {:?}", self.var, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.repr {
+            EnvErrorRepr::Channel(error) => Some(error),
+            EnvErrorRepr::NotParsable(_) => None,
+        }
+    }
+}
+
+/// Maximum size of an incoming message if the client does not advertise one.
+pub(crate) const DEFAULT_MAX_INCOMING_SIZE: usize = 2 * 1024 * 1024;
+
+/// Returns the maximum size of a message advertised by the client (if any).
+///
+/// The variable is read only once. An invalid value is logged and ignored.
+pub(crate) fn max_message_size() -> Option<usize> {
+    *MAX_MESSAGE_SIZE
+}
+
+/// Returns the maximum size of an incoming message.
+///
+/// This is the limit advertised by the client or [`DEFAULT_MAX_INCOMING_SIZE`]
+/// if there is none.
+pub(crate) fn max_incoming_size() -> usize {
+    max_message_size().unwrap_or(DEFAULT_MAX_INCOMING_SIZE)
+}
+
+/// Returns the communication channel given in the environment variable `var`.
+pub(crate) fn channel(var: &str) -> Result<RawChannel, crate::io::CommsEnvError> {
+    let value = match std::env::var_os(var) {
+        Some(value) => value,
+        None => return Err(crate::io::CommsEnvError::not_specified()),
+    };
+
+    match value.to_str().and_then(parse_channel) {
+        Some(channel) => Ok(channel),
+        None => Err(crate::io::CommsEnvError::not_parsable(value)),
+    }
+}
+
+/// Parses a file descriptor.
+#[cfg(target_family = "unix")]
+fn parse_channel(string: &str) -> Option<RawChannel> {
+    string.parse().ok()
+}
+
+/// Parses a file handle (given as its numeric value).
+#[cfg(target_family = "windows")]
+fn parse_channel(string: &str) -> Option<RawChannel> {
+    string.parse::<usize>().ok().map(|handle| handle as RawChannel)
+}
+
+/// Returns the capacity of the channel buffers given in the environment (if
+/// any).
+///
+/// An invalid value is logged and ignored.
+pub(crate) fn buffer_capacity() -> Option<usize> {
+    match parse_var(BUFFER_CAPACITY_VAR) {
+        Ok(capacity) => capacity,
+        Err(error) => {
+            log::warn!("ignoring buffer capacity: {error}");
+            None
+        }
+    }
+}
+
+/// Returns the port of the development server given in the environment (if
+/// any).
+#[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+pub(crate) fn dev_tcp_port() -> Result<Option<u16>, EnvError> {
+    parse_var(DEV_TCP_PORT_VAR)
+}
+
+/// Returns the directory to record the traffic to given in the environment
+/// (if any).
+pub(crate) fn capture_dir() -> Option<PathBuf> {
+    std::env::var_os(CAPTURE_DIR_VAR).map(PathBuf::from)
+}
+
+/// Returns whether the connection has been established by an earlier image of
+/// the process.
+#[cfg(target_family = "unix")]
+pub(crate) fn established() -> bool {
+    std::env::var_os(ESTABLISHED_VAR).is_some()
+}
+
+/// Parses the value of the environment variable `var` (if it is set).
+fn parse_var<T>(var: &'static str) -> Result<Option<T>, EnvError>
+where
+    T: std::str::FromStr,
+{
+    let value = match std::env::var_os(var) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    match value.to_str().and_then(|string| string.parse().ok()) {
+        Some(parsed) => Ok(Some(parsed)),
+        None => Err(EnvError {
+            var,
+            repr: EnvErrorRepr::NotParsable(value),
+        }),
+    }
+}
+
+static MAX_MESSAGE_SIZE: LazyLock<Option<usize>> = LazyLock::new(|| {
+    match parse_var(MAX_MESSAGE_SIZE_VAR) {
+        Ok(size) => size,
+        Err(error) => {
+            log::warn!("ignoring message size limit: {error}");
+            None
+        }
+    }
+});
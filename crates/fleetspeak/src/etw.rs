@@ -0,0 +1,134 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Event Tracing for Windows (ETW) support.
+//!
+//! When enabled, the connector registers itself as an ETW provider and emits
+//! events for connection lifecycle (handshake, startup, failures) and metadata
+//! of exchanged messages. Events are emitted as plain strings and can be
+//! consumed with standard tooling (e.g. `logman` or `tracerpt`) by enabling
+//! the provider with the [`PROVIDER_ID`] identifier.
+//!
+//! Events are categorized using the [`KEYWORD_LIFECYCLE`], [`KEYWORD_MESSAGE`]
+//! and [`KEYWORD_HEARTBEAT`] keywords, so that consumers can subscribe only to
+//! the ones they are interested in.
+
+use std::sync::LazyLock;
+
+use windows_sys::Win32::System::Diagnostics::Etw as etw;
+
+/// Identifier of the ETW provider of the connector.
+pub const PROVIDER_ID: &str = "5f9c3a8e-2b1d-4e7a-9c61-0d4b8f3e2a17";
+
+/// Keyword of events related to the connection lifecycle.
+pub const KEYWORD_LIFECYCLE: u64 = 0x1;
+
+/// Keyword of events related to exchanged messages.
+pub const KEYWORD_MESSAGE: u64 = 0x2;
+
+/// Keyword of events related to heartbeat signals.
+pub const KEYWORD_HEARTBEAT: u64 = 0x4;
+
+/// Numeric representation of [`PROVIDER_ID`].
+const PROVIDER_GUID: windows_sys::core::GUID = {
+    windows_sys::core::GUID::from_u128(0x5f9c3a8e_2b1d_4e7a_9c61_0d4b8f3e2a17)
+};
+
+/// Registers the ETW provider if it has not been registered yet.
+pub(crate) fn init() {
+    LazyLock::force(&HANDLE);
+}
+
+/// Emits an informational event about the connection lifecycle.
+pub(crate) fn lifecycle(args: std::fmt::Arguments<'_>) {
+    write(etw::TRACE_LEVEL_INFORMATION, KEYWORD_LIFECYCLE, args);
+}
+
+/// Emits an error event about the connection lifecycle.
+pub(crate) fn failure(args: std::fmt::Arguments<'_>) {
+    write(etw::TRACE_LEVEL_ERROR, KEYWORD_LIFECYCLE, args);
+}
+
+/// Emits an event with metadata of the given message.
+pub(crate) fn message(direction: &str, message: &crate::Message) {
+    write(etw::TRACE_LEVEL_INFORMATION, KEYWORD_MESSAGE, format_args! {
+        "{direction} message (service: {}, kind: {}, size: {})",
+        message.service,
+        message.kind.as_deref().unwrap_or("-"),
+        message.data.len(),
+    });
+}
+
+/// Emits an event about a heartbeat signal being sent.
+pub(crate) fn heartbeat() {
+    write(etw::TRACE_LEVEL_INFORMATION, KEYWORD_HEARTBEAT, format_args!("heartbeat"));
+}
+
+/// Writes a string event with the given level and keyword.
+///
+/// The event is formatted only if there is a consumer interested in it, so
+/// emitting events is cheap when tracing is not active.
+fn write(level: u32, keyword: u64, args: std::fmt::Arguments<'_>) {
+    let handle = match *HANDLE {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    // SAFETY: `EventProviderEnabled` has no requirements on its arguments [1]:
+    // in case the handle is not a valid registration handle, it simply returns
+    // false.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/evntprov/nf-evntprov-eventproviderenabled
+    let enabled = unsafe {
+        etw::EventProviderEnabled(handle, level as u8, keyword)
+    };
+    if enabled == 0 {
+        return;
+    }
+
+    let mut string = args.to_string().encode_utf16().collect::<Vec<u16>>();
+    string.push(0);
+
+    // SAFETY: The handle is a valid registration handle (we verified that the
+    // registration succeeded) and `string` is a null-terminated UTF-16 string
+    // as required by the documentation [1] that lives until the end of the
+    // call. The status is not verified as there is nothing we can do in case
+    // the event cannot be delivered.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/evntprov/nf-evntprov-eventwritestring
+    unsafe {
+        etw::EventWriteString(handle, level as u8, keyword, string.as_ptr());
+    }
+}
+
+static HANDLE: LazyLock<Option<etw::REGHANDLE>> = LazyLock::new(|| {
+    let mut handle = std::mem::MaybeUninit::uninit();
+
+    // SAFETY: We pass a valid pointer to the provider identifier, no
+    // callback (which makes the context irrelevant) and a valid pointer to
+    // the output handle as described in the documentation [1]. We verify
+    // the status after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/evntprov/nf-evntprov-eventregister
+    let status = unsafe {
+        etw::EventRegister(
+            &PROVIDER_GUID,
+            None,
+            std::ptr::null(),
+            handle.as_mut_ptr(),
+        )
+    };
+
+    if status != windows_sys::Win32::Foundation::ERROR_SUCCESS {
+        log::warn!("failed to register ETW provider (status: {status})");
+        return None;
+    }
+
+    // SAFETY: We verified that the call to `EventRegister` succeeded and
+    // thus the handle is guaranteed to be initialized.
+    let handle = unsafe { handle.assume_init() };
+
+    Some(handle as etw::REGHANDLE)
+});
@@ -0,0 +1,263 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Starts heartbeating with the given `rate` in the background.
+///
+/// Services doing long CPU-bound work would otherwise have to scatter calls
+/// to [`heartbeat`] throughout their code not to be considered unresponsive.
+/// Instead, a single background thread owned by the library sends heartbeats
+/// until [`stop_heartbeats`] is called (or until the connection fails, in
+/// which case the failure is logged and the [status] becomes
+/// [`Status::Closed`]).
+///
+/// Note that heartbeats are meant to signal that the service is healthy. Since
+/// the background thread keeps heartbeating even if the rest of the service is
+/// stuck, it should be stopped when the service goes idle.
+///
+/// Calling this function again while the thread is running only updates the
+/// rate.
+///
+/// [`heartbeat`]: crate::heartbeat
+/// [status]: crate::status
+/// [`Status::Closed`]: crate::Status::Closed
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fleetspeak::start_heartbeats(Duration::from_secs(30));
+///
+/// // Long computation without any heartbeats.
+///
+/// fleetspeak::stop_heartbeats();
+/// ```
+pub fn start_heartbeats(rate: Duration) {
+    let mut current = RATE.lock().expect("poisoned heartbeats mutex");
+
+    let running = current.is_some();
+    *current = Some(rate);
+
+    if running {
+        // Wake the thread up so that the new rate takes effect immediately.
+        WAKE.notify_all();
+    } else {
+        *THREAD.lock().expect("poisoned heartbeats mutex") = Some(std::thread::spawn(run));
+    }
+}
+
+/// Stops heartbeating in the background (if it is running) and waits for the
+/// heartbeat thread to exit.
+///
+/// See documentation for [`start_heartbeats`] for more details.
+pub fn stop_heartbeats() {
+    let mut rate = RATE.lock().expect("poisoned heartbeats mutex");
+    *rate = None;
+    let thread = THREAD.lock().expect("poisoned heartbeats mutex").take();
+    drop(rate);
+
+    WAKE.notify_all();
+
+    if let Some(thread) = thread {
+        if thread.join().is_err() {
+            log::error!("heartbeat thread panicked");
+        }
+    }
+}
+
+/// Calls `f`, heartbeating with the given `rate` until it returns.
+///
+/// The heartbeats are sent from a thread shared by all the calls, which is
+/// spawned on first use and stays parked while not needed. Concurrent calls are
+/// served one after another: heartbeats are per process, so it is enough that
+/// one of them is served at any time.
+pub(crate) fn with_heartbeat<F, T>(rate: Duration, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let (sender, receiver) = std::sync::mpsc::channel::<Never>();
+    register(Call { rate, done: receiver });
+
+    let result = f();
+
+    // Notify the heartbeat thread that the call finished. However, instead of
+    // sending any real message we just shut the sender down and the receiver
+    // will receive a disconnection error.
+    drop(sender);
+
+    result
+}
+
+/// Stops background heartbeating and the thread shared by [`with_heartbeat`]
+/// calls and waits for them to exit.
+///
+/// If a [`with_heartbeat`] call is in progress, this waits for it to finish.
+pub(crate) fn stop() {
+    stop_heartbeats();
+
+    let waiter = WAITER.lock().expect("poisoned heartbeats mutex").take();
+    if let Some(waiter) = waiter {
+        // Dropping the sender makes the thread exit once it is done with the
+        // calls registered so far.
+        drop(waiter.sender);
+
+        if waiter.thread.join().is_err() {
+            log::error!("heartbeat thread panicked");
+        }
+    }
+}
+
+/// Restarts heartbeating (if it was running) in a child process after `fork`.
+///
+/// If `restart` is `false`, heartbeating is stopped instead.
+#[cfg(target_family = "unix")]
+pub(crate) fn after_fork(restart: bool) -> std::io::Result<()> {
+    let mut waiter = match WAITER.try_lock() {
+        Ok(waiter) => waiter,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "heartbeat waiter locked during fork"
+        })),
+    };
+
+    // The thread shared by `with_heartbeat` calls does not exist in the child
+    // process either. It is spawned again on next use.
+    std::mem::forget(waiter.take());
+    drop(waiter);
+
+    let mut rate = match RATE.try_lock() {
+        Ok(rate) => rate,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "heartbeat rate locked during fork"
+        })),
+    };
+
+    let mut thread = match THREAD.try_lock() {
+        Ok(thread) => thread,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "heartbeat thread locked during fork"
+        })),
+    };
+
+    // The heartbeat thread does not exist in the child process, so its handle
+    // must not be joined nor detached.
+    std::mem::forget(thread.take());
+
+    if !restart {
+        *rate = None;
+    }
+    if rate.is_some() {
+        *thread = Some(std::thread::spawn(run));
+    }
+
+    Ok(())
+}
+
+/// Body of the heartbeat thread.
+fn run() {
+    loop {
+        if let Err(error) = crate::request_heartbeat() {
+            crate::close(&error);
+            *RATE.lock().expect("poisoned heartbeats mutex") = None;
+
+            return;
+        }
+
+        let guard = RATE.lock().expect("poisoned heartbeats mutex");
+        let rate = match *guard {
+            Some(rate) => rate,
+            None => return,
+        };
+
+        let (guard, _) = WAKE.wait_timeout(guard, rate)
+            .expect("poisoned heartbeats mutex");
+
+        // Heartbeating might have been stopped while we were waiting.
+        if guard.is_none() {
+            return;
+        }
+    }
+}
+
+// TODO(rust-lang/rust#35121): Replace with `!` once stable.
+enum Never {
+}
+
+/// A [`with_heartbeat`] call that needs heartbeats to be sent.
+struct Call {
+    /// Frequency of the heartbeats.
+    rate: Duration,
+    /// Disconnected once the call finishes.
+    done: Receiver<Never>,
+}
+
+/// Handle to the thread shared by [`with_heartbeat`] calls.
+struct Waiter {
+    sender: Sender<Call>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+/// Registers the call with the shared thread, spawning it if necessary.
+fn register(call: Call) {
+    let mut waiter = WAITER.lock().expect("poisoned heartbeats mutex");
+
+    let call = match &*waiter {
+        Some(waiter) => match waiter.sender.send(call) {
+            Ok(()) => return,
+            // The thread is gone (e.g. it panicked because heartbeating failed),
+            // so we have to spawn a new one.
+            Err(std::sync::mpsc::SendError(call)) => call,
+        },
+        None => call,
+    };
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    sender.send(call)
+        .expect("heartbeat thread receiver dropped");
+
+    *waiter = Some(Waiter {
+        sender,
+        thread: std::thread::spawn(move || serve(receiver)),
+    });
+}
+
+/// Body of the thread shared by [`with_heartbeat`] calls.
+fn serve(calls: Receiver<Call>) {
+    // The thread is blocked here while there are no calls to serve and exits
+    // once the sender is dropped.
+    for call in calls {
+        loop {
+            use std::sync::mpsc::TryRecvError::*;
+
+            // We keep hearbeating until the sender disconnects (in which case
+            // the receiver will receive a disconnection error).
+            match call.done.try_recv() {
+                Ok(never) => match never {},
+                Err(Empty) => (),
+                Err(Disconnected) => break,
+            }
+
+            crate::heartbeat();
+
+            // Unlike sleeping, this wakes up as soon as the call finishes. Both
+            // the timeout and the disconnection are handled above.
+            if let Ok(never) = call.done.recv_timeout(call.rate) {
+                match never {}
+            }
+        }
+    }
+}
+
+static RATE: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// Notified when heartbeating is stopped or its rate changes.
+static WAKE: Condvar = Condvar::new();
+
+static THREAD: Mutex<Option<std::thread::JoinHandle<()>>> = Mutex::new(None);
+
+static WAITER: Mutex<Option<Waiter>> = Mutex::new(None);
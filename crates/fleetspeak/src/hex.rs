@@ -0,0 +1,61 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Hexadecimal form of binary data (digests, signatures and such).
+
+/// Encodes the bytes in lowercase hexadecimal form.
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    use std::fmt::Write as _;
+
+    let mut string = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        // Writing to a string is infallible.
+        let _ = write!(string, "{byte:02x}");
+    }
+
+    string
+}
+
+/// Decodes bytes from hexadecimal form.
+///
+/// Both lowercase and uppercase digits are accepted. Returns `None` if the
+/// string is not a valid hexadecimal form of any bytes.
+pub(crate) fn decode(string: &str) -> Option<Vec<u8>> {
+    if !string.len().is_multiple_of(2) {
+        return None;
+    }
+
+    string.as_bytes().chunks(2)
+        .map(|pair| Some((digit(pair[0])? << 4) | digit(pair[1])?))
+        .collect()
+}
+
+/// Returns the value of a single hexadecimal digit.
+fn digit(char: u8) -> Option<u8> {
+    char::from(char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(&[0x00, 0x7f, 0xff]), "007fff");
+
+        assert_eq!(decode("007fff"), Some(vec![0x00, 0x7f, 0xff]));
+        assert_eq!(decode("007FFF"), Some(vec![0x00, 0x7f, 0xff]));
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("xy"), None);
+        assert_eq!(decode("+f"), None);
+        assert_eq!(decode("żż"), None);
+    }
+}
@@ -0,0 +1,416 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// An error returned in case establishing the connection fails.
+#[derive(Clone, Debug)]
+pub struct InitError {
+    repr: InitErrorRepr,
+}
+
+#[derive(Clone, Debug)]
+enum InitErrorRepr {
+    /// The input communication channel is invalid.
+    Input(crate::io::CommsEnvError),
+    /// The output communication channel is invalid.
+    Output(crate::io::CommsEnvError),
+    /// The handshake with the Fleetspeak client failed.
+    Handshake(Arc<std::io::Error>),
+    /// Connecting to the socket of the Fleetspeak client failed.
+    #[cfg(target_family = "unix")]
+    Socket(Arc<std::io::Error>),
+    /// Setting up the standalone mode failed.
+    #[cfg(feature = "standalone")]
+    Standalone(Arc<std::io::Error>),
+    /// Setting up the transport given to [`init_with`] failed.
+    Transport(Arc<std::io::Error>),
+}
+
+impl InitError {
+
+    /// Returns whether the error indicates that the process has not been
+    /// launched by Fleetspeak at all (as opposed to a broken connection).
+    pub fn is_not_launched(&self) -> bool {
+        match &self.repr {
+            InitErrorRepr::Input(error) => error.is_not_specified(),
+            InitErrorRepr::Output(error) => error.is_not_specified(),
+            InitErrorRepr::Handshake(_) => false,
+            #[cfg(target_family = "unix")]
+            InitErrorRepr::Socket(_) => false,
+            #[cfg(feature = "standalone")]
+            InitErrorRepr::Standalone(_) => false,
+            InitErrorRepr::Transport(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for InitError {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.repr {
+            InitErrorRepr::Input(error) => {
+                write!(fmt, "invalid input communication channel: {error}")
+            }
+            InitErrorRepr::Output(error) => {
+                write!(fmt, "invalid output communication channel: {error}")
+            }
+            InitErrorRepr::Handshake(error) => {
+                write!(fmt, "handshake failure: {error}")
+            }
+            #[cfg(target_family = "unix")]
+            InitErrorRepr::Socket(error) => {
+                write!(fmt, "socket connection failure: {error}")
+            }
+            #[cfg(feature = "standalone")]
+            InitErrorRepr::Standalone(error) => {
+                write!(fmt, "standalone mode failure: {error}")
+            }
+            InitErrorRepr::Transport(error) => {
+                write!(fmt, "transport failure: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InitError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.repr {
+            InitErrorRepr::Input(error) => Some(error),
+            InitErrorRepr::Output(error) => Some(error),
+            InitErrorRepr::Handshake(error) => Some(&**error),
+            #[cfg(target_family = "unix")]
+            InitErrorRepr::Socket(error) => Some(&**error),
+            #[cfg(feature = "standalone")]
+            InitErrorRepr::Standalone(error) => Some(&**error),
+            InitErrorRepr::Transport(error) => Some(&**error),
+        }
+    }
+}
+
+/// Establishes the connection with the Fleetspeak client eagerly.
+///
+/// Normally, the connection is established lazily on first use and failing to
+/// do so results in a panic. This function allows to detect the problem early
+/// and react to it gracefully instead, e.g. to log diagnostics and exit when
+/// the service has been launched outside of Fleetspeak.
+///
+/// The result of the first attempt is final: subsequent calls return the same
+/// result and, in case of an error, all the other functions of this library
+/// panic.
+///
+/// # Examples
+///
+/// ```no_run
+/// if let Err(error) = fleetspeak::init() {
+///     if error.is_not_launched() {
+///         eprintln!("this program has to be run by Fleetspeak");
+///     } else {
+///         eprintln!("failed to connect to Fleetspeak: {error}");
+///     }
+///     std::process::exit(1);
+/// }
+///
+/// fleetspeak::startup("0.0.1");
+/// ```
+pub fn init() -> Result<(), InitError> {
+    match crate::try_connection() {
+        Ok(_) => Ok(()),
+        Err(error) => Err(error.clone()),
+    }
+}
+
+/// Returns whether the connection with the Fleetspeak client is established.
+///
+/// This does not attempt to establish the connection itself: it returns `false`
+/// both when no attempt has been made yet (see [`init`]) and when the attempt
+/// failed. This is useful e.g. for panic hooks and logging backends that should
+/// not try to talk to Fleetspeak unless the service is known to be connected.
+///
+/// # Examples
+///
+/// ```no_run
+/// assert!(!fleetspeak::is_initialized());
+///
+/// fleetspeak::init()
+///     .expect("failed to connect to Fleetspeak");
+///
+/// assert!(fleetspeak::is_initialized());
+/// ```
+pub fn is_initialized() -> bool {
+    matches!(crate::INIT.get(), Some(Ok(_)))
+}
+
+/// Establishes the connection with the Fleetspeak client listening on the
+/// Unix domain socket at `path`.
+///
+/// By default, the connection uses descriptors inherited from the Fleetspeak
+/// client that started the service (a daemon service). Services configured as
+/// socket services are not started by the client and instead connect to the
+/// socket it listens on, which allows them to run as standalone daemons.
+///
+/// This function has to be called before any other function of this library,
+/// otherwise an error is returned. Apart from that, it behaves like [`init`].
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::init_socket("/var/run/fleetspeak/example.sock")
+///     .expect("failed to connect to Fleetspeak");
+///
+/// fleetspeak::startup("0.0.1");
+/// ```
+#[cfg(target_family = "unix")]
+pub fn init_socket<P>(path: P) -> Result<(), InitError>
+where
+    P: AsRef<std::path::Path>,
+{
+    *SOCKET.lock().expect("poisoned socket mutex") = Some(path.as_ref().to_path_buf());
+    init()?;
+
+    // The path is taken once the connection is being established, so if it is
+    // still there the connection must have been established before.
+    if SOCKET.lock().expect("poisoned socket mutex").take().is_some() {
+        return Err(InitError {
+            repr: InitErrorRepr::Socket(Arc::new(std::io::Error::other({
+                "connection already established"
+            }))),
+        });
+    }
+
+    Ok(())
+}
+
+/// Establishes the connection over the given `input` and `output` transport.
+///
+/// Instead of discovering the communication channels through the environment,
+/// the connection uses the given reader of data sent by the Fleetspeak client
+/// and writer of data for it, performing the usual handshake over them. This
+/// allows embedding the library in applications that talk to Fleetspeak in an
+/// unusual way as well as testing services without any environment setup.
+///
+/// This function has to be called before any other function of this library,
+/// otherwise an error is returned. Apart from that, it behaves like [`init`].
+///
+/// # Examples
+///
+/// ```no_run
+/// let stream = std::net::TcpStream::connect("localhost:1337")
+///     .expect("failed to connect to the proxy");
+/// let input = stream.try_clone()
+///     .expect("failed to clone the stream");
+///
+/// fleetspeak::init_with(input, stream)
+///     .expect("failed to connect to Fleetspeak");
+/// ```
+pub fn init_with<R, W>(input: R, output: W) -> Result<(), InitError>
+where
+    R: std::io::Read + Send + 'static,
+    W: std::io::Write + Send + 'static,
+{
+    init_transport(crate::transport::Transport::new(input, output))
+}
+
+/// Establishes the connection over the given transport.
+///
+/// An error is returned if the connection has been established before.
+pub(crate) fn init_transport(transport: crate::transport::Transport) -> Result<(), InitError> {
+    *TRANSPORT.lock().expect("poisoned transport mutex") = Some(transport);
+    init()?;
+
+    // The transport is taken once the connection is being established, so if
+    // it is still there the connection must have been established before.
+    if TRANSPORT.lock().expect("poisoned transport mutex").take().is_some() {
+        return Err(InitError {
+            repr: InitErrorRepr::Transport(Arc::new(std::io::Error::other({
+                "connection already established"
+            }))),
+        });
+    }
+
+    Ok(())
+}
+
+/// Sets the capacity (in bytes) of the buffers of the communication channels.
+///
+/// Reads from the input channel and writes to the output channel are buffered,
+/// by default in buffers of 8 KiB. Services that routinely exchange messages of
+/// megabytes can make the buffers bigger to reduce the number of system calls.
+/// The capacity can also be set through the [`BUFFER_CAPACITY_VAR`] environment
+/// variable, but the value set with this function takes precedence.
+///
+/// This function has to be called before the connection is established (see
+/// [`init`]), it has no effect afterwards.
+///
+/// [`BUFFER_CAPACITY_VAR`]: crate::env::BUFFER_CAPACITY_VAR
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::set_buffer_capacity(1024 * 1024);
+/// fleetspeak::init()
+///     .expect("failed to connect to Fleetspeak");
+/// ```
+pub fn set_buffer_capacity(capacity: usize) {
+    *BUFFER_CAPACITY.lock().expect("poisoned buffer capacity mutex") = Some(capacity);
+}
+
+/// Resolves the communication channels.
+///
+/// Apart from the channels, returns whether the handshake has been already
+/// done on them.
+fn channels() -> Result<(crate::io::CommsInRaw, crate::io::CommsOutRaw, bool), InitError> {
+    if let Some(transport) = TRANSPORT.lock().expect("poisoned transport mutex").take() {
+        log::info!("connecting over the supplied transport");
+
+        let (input, output) = transport.channels()
+            .map_err(|error| InitError {
+                repr: InitErrorRepr::Transport(Arc::new(error)),
+            })?;
+
+        return Ok((input, output, false));
+    }
+
+    #[cfg(target_family = "unix")]
+    if let Some(path) = SOCKET.lock().expect("poisoned socket mutex").take() {
+        log::info!("connecting to socket at '{}'", path.display());
+
+        let (input, output) = crate::io::socket::connect(&path)
+            .map_err(|error| InitError {
+                repr: InitErrorRepr::Socket(Arc::new(error)),
+            })?;
+
+        // The connection is always a fresh one, even if the process has been
+        // re-executed.
+        return Ok((input, output, false));
+    }
+
+    #[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+    let dev_tcp_port = crate::env::dev_tcp_port()
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error))
+        .map_err(|error| InitError {
+            repr: InitErrorRepr::Socket(Arc::new(error)),
+        })?;
+
+    #[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+    if let Some(port) = dev_tcp_port {
+
+        log::warn!("connecting to development server at port {port}");
+
+        let (input, output) = crate::io::tcp::connect(port)
+            .map_err(|error| InitError {
+                repr: InitErrorRepr::Socket(Arc::new(error)),
+            })?;
+
+        return Ok((input, output, false));
+    }
+
+    #[cfg(feature = "standalone")]
+    if crate::standalone::is_standalone() {
+        let (input, output) = crate::standalone::channels()
+            .map_err(|error| InitError {
+                repr: InitErrorRepr::Standalone(Arc::new(error)),
+            })?;
+
+        return Ok((input, output, false));
+    }
+
+    let input = crate::io::CommsInRaw::from_env()
+        .map_err(|error| InitError {
+            repr: InitErrorRepr::Input(error),
+        })?;
+
+    let output = crate::io::CommsOutRaw::from_env()
+        .map_err(|error| InitError {
+            repr: InitErrorRepr::Output(error),
+        })?;
+
+    // A service that re-executed itself inherits the connection in which the
+    // handshake has been already done.
+    #[cfg(target_family = "unix")]
+    let established = crate::env::established();
+    #[cfg(not(target_family = "unix"))]
+    let established = false;
+
+    Ok((input, output, established))
+}
+
+/// Resolves the communication channels and performs the handshake.
+pub(crate) fn establish() -> Result<crate::GlobalConnection, InitError> {
+    let start = Instant::now();
+
+    let (input, output, established) = channels()?;
+
+    let capacity = BUFFER_CAPACITY.lock().expect("poisoned buffer capacity mutex")
+        .or_else(crate::env::buffer_capacity)
+        .unwrap_or(DEFAULT_BUFFER_CAPACITY);
+    let mut input = crate::io::FrameReader::with_capacity(capacity, input)
+        .with_capture();
+    let mut output = crate::io::FlushOnDrop::with_capacity(capacity, output)
+        .with_capture();
+
+    let env_resolution = start.elapsed();
+    crate::metrics::record_env_resolution(env_resolution);
+
+    log::info!("communication channels resolved in {env_resolution:?}");
+
+    if let Some(max_size) = crate::env::max_message_size() {
+        log::info!("message size limit: {max_size} bytes");
+    }
+
+    if established {
+        log::info!("connection inherited from previous process image");
+    } else {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("fleetspeak_handshake").entered();
+
+        let start = Instant::now();
+
+        crate::io::handshake(&mut input, &mut output)
+            .map_err(|error| {
+                #[cfg(feature = "tracing")]
+                tracing::error!(%error, "handshake failed");
+
+                InitError {
+                    repr: InitErrorRepr::Handshake(Arc::new(error)),
+                }
+            })?;
+
+        let handshake = start.elapsed();
+        crate::metrics::record_handshake(handshake);
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(rtt = ?handshake, "handshake successful");
+
+        #[cfg(not(feature = "tracing"))]
+        log::info!("handshake successful (round-trip time: {handshake:?})");
+
+        #[cfg(all(target_family = "windows", feature = "etw"))]
+        crate::etw::lifecycle(format_args!("handshake successful (round-trip time: {handshake:?})"));
+    }
+
+    Ok(crate::GlobalConnection {
+        #[cfg(target_family = "unix")]
+        input_fd: std::os::fd::AsRawFd::as_raw_fd(input.get_ref()),
+        input: Mutex::new(input),
+        output: Mutex::new(output),
+        established: Instant::now(),
+    })
+}
+
+/// Default capacity of the buffers of the communication channels.
+const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+
+/// Capacity of the channel buffers set with [`set_buffer_capacity`].
+static BUFFER_CAPACITY: Mutex<Option<usize>> = Mutex::new(None);
+
+/// Transport to establish the connection over set with [`init_with`].
+static TRANSPORT: Mutex<Option<crate::transport::Transport>> = Mutex::new(None);
+
+#[cfg(target_family = "unix")]
+/// Path of the socket to connect to instead of using inherited descriptors.
+static SOCKET: Mutex<Option<std::path::PathBuf>> = Mutex::new(None);
@@ -4,8 +4,9 @@
 // in the LICENSE file or at https://opensource.org/licenses/MIT.
 
 use std::io::{Read, Write};
+use std::sync::LazyLock;
 
-use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+use byteorder::{LittleEndian, WriteBytesExt as _};
 
 use crate::Message;
 
@@ -15,6 +16,12 @@ mod unix;
 #[cfg(target_family = "windows")]
 mod windows;
 
+#[cfg(target_family = "unix")]
+pub mod socket;
+
+#[cfg(all(target_family = "unix", feature = "dev-tcp"))]
+pub mod tcp;
+
 mod sys {
     #[cfg(target_family = "unix")]
     pub use crate::io::unix::*;
@@ -40,6 +47,30 @@ enum CommsEnvErrorRepr {
     NotSpecified,
     /// Communication channel specified in the environment is not valid.
     NotParsable(std::ffi::OsString),
+    /// Communication channel specified in the environment is closed.
+    Closed(std::ffi::OsString),
+}
+
+impl CommsEnvError {
+
+    /// Creates an error for a channel not specified in the environment.
+    pub(crate) fn not_specified() -> CommsEnvError {
+        CommsEnvError {
+            repr: CommsEnvErrorRepr::NotSpecified,
+        }
+    }
+
+    /// Creates an error for a channel specified with an invalid `value`.
+    pub(crate) fn not_parsable(value: std::ffi::OsString) -> CommsEnvError {
+        CommsEnvError {
+            repr: CommsEnvErrorRepr::NotParsable(value),
+        }
+    }
+
+    /// Returns whether the channel is not specified in the environment at all.
+    pub fn is_not_specified(&self) -> bool {
+        matches!(self.repr, CommsEnvErrorRepr::NotSpecified)
+    }
 }
 
 impl std::fmt::Display for CommsEnvError {
@@ -52,6 +83,9 @@ impl std::fmt::Display for CommsEnvError {
             CommsEnvErrorRepr::NotParsable(value) => {
                 write!(fmt, "invalid communication channel value: {value:?}")
             }
+            CommsEnvErrorRepr::Closed(value) => {
+                write!(fmt, "communication channel closed: {value:?}")
+            }
         }
     }
 }
@@ -59,6 +93,118 @@ impl std::fmt::Display for CommsEnvError {
 impl std::error::Error for CommsEnvError {
 }
 
+/// A buffered writer that flushes its buffer when dropped.
+///
+/// [`std::io::BufWriter`] flushes on drop as well but silently discards any
+/// error. This wrapper logs failures instead, so that messages lost this way
+/// (e.g. written just before the process exits) do not go unnoticed.
+///
+/// Like [`FrameReader`], it keeps a scratch buffer for the frames it writes,
+/// so sending typical messages with [`FlushOnDrop::write_frame`] does not
+/// allocate.
+pub struct FlushOnDrop<W: Write> {
+    inner: std::io::BufWriter<W>,
+    /// Buffer for bodies of frames being written.
+    scratch: Vec<u8>,
+    /// Whether the written data is recorded to the capture file.
+    capture: bool,
+}
+
+impl<W: Write> FlushOnDrop<W> {
+
+    /// Wraps the given writer in a buffer of the given capacity flushed on
+    /// drop.
+    pub fn with_capacity(capacity: usize, inner: W) -> FlushOnDrop<W> {
+        FlushOnDrop {
+            inner: std::io::BufWriter::with_capacity(capacity, inner),
+            scratch: Vec::new(),
+            capture: false,
+        }
+    }
+
+    /// Records the written data to the capture file (if there is one).
+    ///
+    /// Data is recorded as it is accepted into the buffer, which is also the
+    /// order in which it is written to the underlying writer.
+    pub(crate) fn with_capture(mut self) -> FlushOnDrop<W> {
+        self.capture = true;
+        self
+    }
+
+    /// Writes a raw Fleetspeak Protocol Buffers message without flushing.
+    ///
+    /// See documentation for the [`write_frame`] function for more details.
+    pub fn write_frame(&mut self, proto: fleetspeak_proto::common::Message) -> std::io::Result<()> {
+        // The scratch buffer is taken out for the time of the write, as the
+        // frame has to go through our own `Write` implementation.
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let result = write_frame_with_scratch(self, proto, &mut scratch);
+        self.scratch = scratch;
+
+        result
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    ///
+    /// Writing to the underlying writer directly bypasses the buffer, so it
+    /// should be flushed first.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut()
+    }
+}
+
+impl<W: Write> Write for FlushOnDrop<W> {
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        if self.capture {
+            crate::capture::outbound(&buf[..count]);
+        }
+
+        Ok(count)
+    }
+
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        let count = self.inner.write_vectored(bufs)?;
+        if self.capture {
+            let mut left = count;
+            for buf in bufs {
+                let len = buf.len().min(left);
+                crate::capture::outbound(&buf[..len]);
+
+                left -= len;
+                if left == 0 {
+                    break;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for FlushOnDrop<W> {
+
+    fn drop(&mut self) {
+        if self.inner.buffer().is_empty() {
+            return;
+        }
+
+        if let Err(error) = self.inner.flush() {
+            log::error!("failed to flush {} buffered bytes: {error}", self.inner.buffer().len());
+        }
+    }
+}
+
 /// Executes the handshake procedure.
 ///
 /// The handshake procedure consists of writing and reading magic numbers from
@@ -91,12 +237,34 @@ pub fn write_heartbeat<W>(output: &mut W) -> std::io::Result<()>
 where
     W: Write,
 {
+    output.write_all(&HEARTBEAT_FRAME)?;
+    output.flush()?;
+
+    #[cfg(feature = "tracing")]
+    tracing::trace!(kind = "Heartbeat", size = HEARTBEAT_FRAME.len() - 8, "frame written");
+
+    Ok(())
+}
+
+/// Serialized frame of the heartbeat record.
+///
+/// Heartbeats never change, so they are serialized only once instead of
+/// every time one is sent.
+static HEARTBEAT_FRAME: LazyLock<Vec<u8>> = LazyLock::new(|| {
     let mut proto = fleetspeak_proto::common::Message::new();
     proto.set_message_type(String::from("Heartbeat"));
     proto.mut_destination().set_service_name(String::from("system"));
 
-    write_proto(output, proto)
-}
+    let proto_buf = protobuf::Message::write_to_bytes(&proto)
+        .expect("failed to serialize heartbeat");
+
+    let mut buf = Vec::with_capacity(proto_buf.len() + 8);
+    buf.extend_from_slice(&(proto_buf.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&proto_buf);
+    buf.extend_from_slice(&MAGIC.to_le_bytes());
+
+    buf
+});
 
 /// Writes a Fleetspeak startup record to the output buffer.
 ///
@@ -104,53 +272,104 @@ where
 /// client does not receive this information quickly enough, the service
 /// will be killed.
 ///
-/// The `version` string should contain a self-reported version of the
-/// service. This data is used primarily for statistics.
-pub fn write_startup<W>(output: &mut W, version: &str) -> std::io::Result<()>
+/// The `version` of the options should contain a self-reported version of the
+/// service. This data is used primarily for statistics. The rest of the
+/// options is reported as annotations of the record.
+///
+/// The record is annotated with the version of this library and the list of
+/// its enabled features, so that operators can track the connector rollout
+/// across the fleet.
+pub fn write_startup<W>(output: &mut W, options: &crate::StartupOptions) -> std::io::Result<()>
 where
     W: Write,
 {
     let mut data = fleetspeak_proto::channel::StartupData::new();
     data.set_pid(i64::from(std::process::id()));
-    data.set_version(String::from(version));
+    data.set_version(options.version.clone());
 
     let mut proto = fleetspeak_proto::common::Message::new();
     proto.set_message_type(String::from("StartupData"));
     proto.mut_destination().set_service_name(String::from("system"));
-    *proto.mut_data() = protobuf::well_known_types::any::Any::pack(&data)?;
+    *proto.mut_data() = crate::any::pack(&data)?;
+
+    add_annotation(&mut proto, VERSION_ANNOTATION, String::from(crate::VERSION));
+    add_annotation(&mut proto, FEATURES_ANNOTATION, features().join(","));
+
+    if let Some(commit) = &options.commit {
+        add_annotation(&mut proto, COMMIT_ANNOTATION, commit.clone());
+    }
+    if let Some(build_time) = options.build_time {
+        // Build times before the epoch make no sense, so we do not bother to
+        // represent them correctly.
+        let secs = build_time.duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        add_annotation(&mut proto, BUILD_TIME_ANNOTATION, secs.to_string());
+    }
+    for (name, value) in &options.labels {
+        add_annotation(&mut proto, &format!("{LABEL_ANNOTATION_PREFIX}{name}"), value.clone());
+    }
 
     write_proto(output, proto)
 }
 
-/// Writes a Fleetspeak message to the output buffer.
+/// Returns names of the enabled optional features of this library.
+fn features() -> Vec<&'static str> {
+    let features = [
+        ("audit", cfg!(feature = "audit")),
+        ("bincode", cfg!(feature = "bincode")),
+        ("bytes", cfg!(feature = "bytes")),
+        ("cbor", cfg!(feature = "cbor")),
+        ("codec", cfg!(feature = "codec")),
+        ("dev-tcp", cfg!(feature = "dev-tcp")),
+        ("etw", cfg!(feature = "etw")),
+        ("gzip", cfg!(feature = "gzip")),
+        ("memfd", cfg!(feature = "memfd")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("prost", cfg!(feature = "prost")),
+        ("serde", cfg!(feature = "serde")),
+        ("standalone", cfg!(feature = "standalone")),
+        ("test-util", cfg!(feature = "test-util")),
+        ("tokio", cfg!(feature = "tokio")),
+        ("tracing", cfg!(feature = "tracing")),
+        ("transfer", cfg!(feature = "transfer")),
+        ("zstd", cfg!(feature = "zstd")),
+    ];
+
+    features.into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
+}
+
+/// Converts a Fleetspeak message to its Protocol Buffers representation.
 ///
-/// The message is sent to the server-side `service` and tagged with the
+/// The message is addressed to the server-side `service` and tagged with the
 /// `kind` type. Note that this message type is rather irrelevant for
 /// Fleetspeak and it is up to the service what to do with this information.
-pub fn write_message<W>(output: &mut W, message: Message) -> std::io::Result<()>
-where
-    W: Write,
-{
+pub fn encode_message(message: Message) -> fleetspeak_proto::common::Message {
     let mut proto = fleetspeak_proto::common::Message::new();
     proto.set_message_type(message.kind.unwrap_or_else(String::new));
     proto.mut_destination().set_service_name(message.service);
-    // TODO: Consider a way of providing the type URL of the data being sent.
     proto.mut_data().value = message.data;
+    if let Some(type_url) = message.data_type_url {
+        proto.mut_data().type_url = type_url;
+    }
+    proto.set_priority(message.priority.to_proto());
+    proto.set_background(message.background);
+    for (key, value) in message.annotations {
+        add_annotation(&mut proto, &key, value);
+    }
 
-    write_proto(output, proto)
+    proto
 }
 
-/// Reads a Fleetspeak message from the input buffer.
+/// Converts a Protocol Buffers representation to a Fleetspeak message.
 ///
-/// This function will block until there is a message to be read in the
-/// input. Errors are reported in case of any I/O failure or if the read
-/// message was malformed (e.g. it cannot be parsed to the expected type).
-pub fn read_message<R>(input: &mut R) -> std::io::Result<Message>
-where
-    R: Read,
-{
-    let mut proto = read_proto(input)?;
-
+/// Errors are reported if the message is malformed (e.g. it does not specify
+/// the source address). Messages without data are handled according to the
+/// given `missing_data` policy, `None` is returned if the policy is to skip
+/// them.
+pub fn decode_message(mut proto: fleetspeak_proto::common::Message, missing_data: crate::MissingData) -> std::io::Result<Option<Message>> {
     // While missing source address might not be considered a critical error
     // in most cases, for our own sanity we fail for such messages as well.
     // Allowing such behaviour might indicate a more severe problem with
@@ -168,35 +387,56 @@ where
     };
 
     // It is not clear what is the best approach here. If there is no data,
-    // should we error-out or return a default value? For the time being we
-    // stick to the default approach, but if this proves to be not working
-    // well in practice, it might be reconsidered.
+    // should we error-out or return a default value? By default we return the
+    // default value (for compatibility) but services that want to catch such
+    // issues can opt into rejecting these messages.
     let data = if proto.has_data() {
         proto.take_data()
     } else {
-        log::warn!("empty message from '{}'", service);
-        Default::default()
+        match missing_data {
+            crate::MissingData::Empty => {
+                log::warn!("empty message from '{}'", service);
+                Default::default()
+            }
+            crate::MissingData::Reject => {
+                use std::io::ErrorKind::InvalidData;
+                return Err(std::io::Error::new(InvalidData, {
+                    format!("missing data in message from '{service}'")
+                }));
+            }
+            crate::MissingData::Skip => {
+                log::warn!("skipping empty message from '{}'", service);
+                return Ok(None);
+            }
+        }
     };
 
-    Ok(Message {
+    let annotations = proto.take_annotations().entries.into_iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect();
+
+    Ok(Some(Message {
         service: service,
         kind: Some(proto.message_type),
         data: data.value,
-    })
+        data_type_url: if data.type_url.is_empty() {
+            None
+        } else {
+            Some(data.type_url)
+        },
+        priority: crate::Priority::from_proto(proto.priority),
+        annotations,
+        background: proto.background,
+    }))
 }
 
-/// Writes a raw Fleetspeak Protocol Buffers message to the output buffer.
+/// Computes the size of the frame body for the given message.
 ///
-/// This method does not perform any validation of the message being emitted
-/// and assumes that all the required fields are present.
+/// Messages over the size limit advertised by the client are refused with an
+/// error of the [`InvalidInput`] kind.
 ///
-/// Note that this call will fail only if the message cannot be written to
-/// the output or cannot be properly encoded but will succeed even if the
-/// message is not what the server expects.
-fn write_proto<W>(output: &mut W, proto: fleetspeak_proto::common::Message) -> std::io::Result<()>
-where
-    W: Write,
-{
+/// [`InvalidInput`]: std::io::ErrorKind::InvalidInput
+pub(crate) fn frame_size(proto: &fleetspeak_proto::common::Message) -> std::io::Result<u32> {
     use protobuf::Message as _;
 
     // Fleetspeak is not able to send messages bigger than 2 MiB anyway, so we
@@ -206,34 +446,503 @@ where
             std::io::Error::new(std::io::ErrorKind::InvalidData, error)
         })?;
 
-    output.write_u32::<LittleEndian>(size)?;
-    proto.write_to_writer(output)?;
-    write_magic(output)?;
+    // Messages over the limit would be rejected by the client, possibly along
+    // with the connection, so we refuse to write them at all.
+    if let Some(max_size) = crate::env::max_message_size() {
+        if size as usize > max_size {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(kind = proto.message_type(), size, max_size, "refusing to write oversized frame");
+
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, {
+                format!("message too large ({size} bytes, limit: {max_size} bytes)")
+            }));
+        }
+    }
+
+    Ok(size)
+}
+
+/// Writes a raw Fleetspeak Protocol Buffers message to the output buffer.
+///
+/// This method does not perform any validation of the message being emitted
+/// and assumes that all the required fields are present.
+///
+/// Note that this call will fail only if the message cannot be written to
+/// the output, cannot be properly encoded or exceeds the size limit advertised
+/// by the client but will succeed even if the message is not what the server
+/// expects.
+pub fn write_proto<W>(output: &mut W, proto: fleetspeak_proto::common::Message) -> std::io::Result<()>
+where
+    W: Write,
+{
+    write_frame(output, proto)?;
     output.flush()?;
 
     Ok(())
 }
 
+/// Writes a raw Fleetspeak Protocol Buffers message to the output buffer
+/// without flushing it.
+///
+/// This allows writing multiple messages with a single flush of a buffered
+/// output. Apart from that, it behaves like [`write_proto`].
+pub fn write_frame<W>(output: &mut W, proto: fleetspeak_proto::common::Message) -> std::io::Result<()>
+where
+    W: Write,
+{
+    write_frame_with_scratch(output, proto, &mut Vec::new())
+}
+
+/// Writes a raw Fleetspeak Protocol Buffers message to the output buffer
+/// without flushing it, encoding it into the given `scratch` buffer.
+///
+/// The scratch buffer is cleared before use, so it can be kept between
+/// messages to avoid allocating a new buffer for each of them.
+pub(crate) fn write_frame_with_scratch<W>(
+    output: &mut W,
+    proto: fleetspeak_proto::common::Message,
+    scratch: &mut Vec<u8>,
+) -> std::io::Result<()>
+where
+    W: Write,
+{
+    use protobuf::Message as _;
+
+    let size = frame_size(&proto)?;
+
+    let size_buf = size.to_le_bytes();
+    let magic_buf = MAGIC.to_le_bytes();
+
+    if size as usize <= MAX_VECTORED_SIZE {
+        // Sizes have been cached by computing the size above. Frames up to this
+        // size never grow the scratch buffer above the shrinking threshold.
+        scratch.clear();
+        scratch.reserve(size as usize);
+        let mut stream = protobuf::CodedOutputStream::vec(scratch);
+        proto.write_to_with_cached_sizes(&mut stream)?;
+        stream.flush()?;
+        drop(stream);
+
+        // The whole frame is written at once, so that it takes a single system
+        // call for unbuffered outputs.
+        write_all_vectored(output, &mut [
+            std::io::IoSlice::new(&size_buf),
+            std::io::IoSlice::new(scratch),
+            std::io::IoSlice::new(&magic_buf),
+        ])?;
+    } else {
+        output.write_all(&size_buf)?;
+
+        // Large messages are encoded directly into the output rather than into
+        // an intermediate buffer, so sending a message close to the size limit
+        // does not need twice as much memory.
+        let mut stream = protobuf::CodedOutputStream::new(output);
+        proto.write_to_with_cached_sizes(&mut stream)?;
+        stream.flush()?;
+        drop(stream);
+
+        output.write_all(&magic_buf)?;
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::trace!(kind = proto.message_type(), size, "frame written");
+
+    Ok(())
+}
+
+/// Size of messages up to which frames are written with a single vectored write.
+///
+/// Bigger messages are streamed into the output instead, which takes more than
+/// one write but does not need a copy of the whole serialized message.
+const MAX_VECTORED_SIZE: usize = 64 * 1024;
+
+/// Writes all the buffers to the output.
+///
+/// The output channel might accept only a part of the data at a time (e.g.
+/// when the pipe buffer is full), so this retries until everything is written,
+/// like [`Write::write_all`] does for a single buffer.
+fn write_all_vectored<W>(output: &mut W, mut bufs: &mut [std::io::IoSlice<'_>]) -> std::io::Result<()>
+where
+    W: Write,
+{
+    // Skip the leading empty buffers, so that an empty write below is not
+    // mistaken for a failure.
+    std::io::IoSlice::advance_slices(&mut bufs, 0);
+
+    while !bufs.is_empty() {
+        match output.write_vectored(bufs) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(count) => std::io::IoSlice::advance_slices(&mut bufs, count),
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Capacity above which scratch buffers are shrunk after use.
+///
+/// Typical messages fit in buffers of this size, so receiving them does not
+/// allocate. Occasional large messages, on the other hand, do not pin a lot of
+/// memory for the lifetime of the connection.
+const MAX_SCRATCH_CAPACITY: usize = 64 * 1024;
+
+/// A buffered reader that keeps a scratch buffer for the frames it reads.
+///
+/// Reading a frame with [`FrameReader::read_proto`] reuses the buffer of the
+/// previous one, so receiving typical messages does not allocate.
+pub struct FrameReader<R: Read> {
+    inner: std::io::BufReader<Tap<R>>,
+    /// Buffer for bodies of frames being read.
+    scratch: Vec<u8>,
+}
+
+impl<R: Read> FrameReader<R> {
+
+    /// Wraps the given reader in a buffer of the given capacity.
+    pub fn with_capacity(capacity: usize, inner: R) -> FrameReader<R> {
+        FrameReader {
+            inner: std::io::BufReader::with_capacity(capacity, Tap {
+                inner,
+                capture: false,
+            }),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Records the data read to the capture file (if there is one).
+    ///
+    /// Data read from the underlying reader directly (see [`get_mut`]) is not
+    /// recorded and has to be recorded by the caller.
+    ///
+    /// [`get_mut`]: FrameReader::get_mut
+    pub(crate) fn with_capture(mut self) -> FrameReader<R> {
+        self.inner.get_mut().capture = true;
+        self
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner.get_ref().inner
+    }
+
+    /// Returns a mutable reference to the underlying reader.
+    ///
+    /// Reading from the underlying reader directly bypasses the buffer, so
+    /// the data buffered already might be read out of order.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner.get_mut().inner
+    }
+
+    /// Returns the data buffered but not consumed yet.
+    pub fn buffer(&self) -> &[u8] {
+        self.inner.buffer()
+    }
+
+    /// Reads a raw Fleetspeak Protocol Buffers message.
+    ///
+    /// See documentation for the [`read_proto`] function for more details.
+    pub fn read_proto(&mut self) -> std::io::Result<fleetspeak_proto::common::Message> {
+        read_proto_with_scratch(&mut self.inner, &mut self.scratch)
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read> std::io::BufRead for FrameReader<R> {
+
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount)
+    }
+}
+
+/// A reader that records the data read from it to the capture file.
+///
+/// It sits below the buffer of [`FrameReader`], so the data is recorded in
+/// the order it has been read from the channel.
+struct Tap<R> {
+    inner: R,
+    /// Whether the data read is recorded.
+    capture: bool,
+}
+
+impl<R: Read> Read for Tap<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if self.capture {
+            crate::capture::inbound(&buf[..count]);
+        }
+
+        Ok(count)
+    }
+}
+
 /// Reads a raw Fleetspeeak Protocol Buffers message from the input buffer.
 ///
 /// This function will block until there is a message to be read from the
 /// input. It will fail in case of any I/O error or if the message cannot
 /// be parsed as a Fleetspeak message.
-fn read_proto<R>(input: &mut R) -> std::io::Result<fleetspeak_proto::common::Message>
+#[cfg(any(test, feature = "standalone", feature = "test-util", feature = "tokio"))]
+pub fn read_proto<R>(input: &mut R) -> std::io::Result<fleetspeak_proto::common::Message>
 where
     R: Read,
 {
-    let len = input.read_u32::<LittleEndian>()? as usize;
-    let mut buf = vec!(0; len);
+    read_proto_with_scratch(input, &mut Vec::new())
+}
 
-    input.read_exact(&mut buf[..])?;
-    read_magic(input)?;
+/// Reads a raw Fleetspeak Protocol Buffers message using the given scratch
+/// buffer for the body of the frame.
+///
+/// This is a variant of [`read_proto`] for readers that receive many messages
+/// and keep the buffer between them, so that it does not have to be allocated
+/// for each one.
+pub(crate) fn read_proto_with_scratch<R>(input: &mut R, scratch: &mut Vec<u8>) -> std::io::Result<fleetspeak_proto::common::Message>
+where
+    R: Read,
+{
+    let resync_limit = crate::RESYNC_LIMIT.load(std::sync::atomic::Ordering::SeqCst);
+    read_frame(input, crate::env::max_incoming_size(), resync_limit, scratch)
+}
+
+/// Reads a raw Fleetspeak Protocol Buffers message of at most `max_size` bytes
+/// from the input buffer.
+///
+/// This is a variant of [`read_proto`] for inputs with a message size limit
+/// other than the one of the global connection.
+#[cfg(feature = "codec")]
+pub(crate) fn read_proto_with_limit<R>(input: &mut R, max_size: usize) -> std::io::Result<fleetspeak_proto::common::Message>
+where
+    R: Read,
+{
+    let resync_limit = crate::RESYNC_LIMIT.load(std::sync::atomic::Ordering::SeqCst);
+    read_frame(input, max_size, resync_limit, &mut Vec::new())
+}
+
+/// Reads a frame of at most `max_size` bytes from the input, skipping at most
+/// `resync_limit` bytes to get past a corrupted one (see
+/// [`crate::set_resync_limit`]).
+///
+/// The body of the frame is read into `scratch`, which is left empty (but with
+/// its capacity kept for reuse) afterwards.
+fn read_frame<R>(
+    input: &mut R,
+    max_size: usize,
+    resync_limit: usize,
+    scratch: &mut Vec<u8>,
+) -> std::io::Result<fleetspeak_proto::common::Message>
+where
+    R: Read,
+{
+    let mut len_buf = [0; 4];
+    match read_full(input, &mut len_buf)? {
+        // The stream ended cleanly between frames.
+        0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+        4 => (),
+        consumed => return Err(FrameError {
+            declared: None,
+            consumed,
+            repr: FrameErrorRepr::Truncated,
+        }.into()),
+    }
+    let len = u32::from_le_bytes(len_buf) as usize;
 
-    Ok(protobuf::Message::parse_from_bytes(&buf[..])?)
+    // The length is not trusted to size the buffer, as a corrupted frame could
+    // make us allocate gigabytes of memory.
+    if len > max_size {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(size = len, max_size, "refusing to read oversized frame");
+
+        return Err(FrameError {
+            declared: Some(len),
+            consumed: 4,
+            repr: FrameErrorRepr::TooLarge { limit: max_size },
+        }.into());
+    }
+
+    let result = match read_body(input, len, scratch) {
+        Ok(proto) => Ok(proto),
+        Err(error) if resync_limit == 0 || !is_bad_magic(&error) => Err(error),
+        Err(error) => {
+            resync(input, std::mem::take(scratch), max_size, resync_limit, error)
+        }
+    };
+
+    scratch.clear();
+    scratch.shrink_to(MAX_SCRATCH_CAPACITY);
+
+    result
+}
+
+/// Reads the rest of a frame with a length prefix of `len` from the input.
+///
+/// All the bytes read (the message and the magic) are left in `buf`, so that
+/// they can be scanned again in case the frame turns out to be corrupted.
+fn read_body<R>(
+    input: &mut R,
+    len: usize,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<fleetspeak_proto::common::Message>
+where
+    R: Read,
+{
+    buf.clear();
+    buf.resize(len + 4, 0);
+
+    let read = read_full(input, &mut buf[..])?;
+    if read < len + 4 {
+        buf.truncate(read);
+        return Err(FrameError {
+            declared: Some(len),
+            consumed: 4 + read,
+            repr: FrameErrorRepr::Truncated,
+        }.into());
+    }
+
+    let magic = u32::from_le_bytes([buf[len], buf[len + 1], buf[len + 2], buf[len + 3]]);
+    if magic != MAGIC {
+        return Err(FrameError {
+            declared: Some(len),
+            consumed: 4 + len + 4,
+            repr: FrameErrorRepr::BadMagic { magic },
+        }.into());
+    }
+
+    let proto: fleetspeak_proto::common::Message = protobuf::Message::parse_from_bytes(&buf[..len])
+        .map_err(|error| FrameError {
+            declared: Some(len),
+            consumed: 4 + len + 4,
+            repr: FrameErrorRepr::Decode(error),
+        })?;
+
+    #[cfg(feature = "tracing")]
+    tracing::trace!(kind = proto.message_type(), size = len, "frame read");
+
+    Ok(proto)
+}
+
+/// Scans the input for the next valid frame after a magic mismatch.
+///
+/// The scan starts with `consumed` (the bytes of the corrupted frame after its
+/// length prefix), as the corruption might have been in the length prefix
+/// itself. A frame boundary is recognized by the magic that ends a frame,
+/// followed by a plausible length prefix (at most `max_len`) of the next one.
+/// At most `limit` bytes are skipped before giving up with the original
+/// `error`.
+fn resync<R>(
+    input: &mut R,
+    consumed: Vec<u8>,
+    max_len: usize,
+    limit: usize,
+    error: std::io::Error,
+) -> std::io::Result<fleetspeak_proto::common::Message>
+where
+    R: Read,
+{
+    let mut input = Rescan {
+        pending: consumed.into(),
+        input,
+    };
+
+    let mut window = [0; 8];
+    let mut skipped = 0;
+    let mut buf = Vec::new();
+
+    while skipped < limit {
+        let mut byte = [0];
+        if read_full(&mut input, &mut byte)? == 0 {
+            break;
+        }
+
+        window.copy_within(1.., 0);
+        window[7] = byte[0];
+        skipped += 1;
+
+        if skipped < window.len() || window[..4] != MAGIC.to_le_bytes() {
+            continue;
+        }
+
+        let len = u32::from_le_bytes([window[4], window[5], window[6], window[7]]) as usize;
+        if len > max_len {
+            continue;
+        }
+
+        match read_body(&mut input, len, &mut buf) {
+            Ok(proto) => {
+                log::warn!("resynchronized input channel after skipping {} bytes", skipped - 4);
+                return Ok(proto);
+            }
+            // The candidate frame is not a frame after all, so its bytes have
+            // to be scanned again.
+            Err(error) if is_bad_magic(&error) => {
+                for byte in buf.drain(..).rev() {
+                    input.pending.push_front(byte);
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    Err(error)
+}
+
+/// Returns whether the error is caused by a magic mismatch.
+fn is_bad_magic(error: &std::io::Error) -> bool {
+    error.get_ref()
+        .and_then(|error| error.downcast_ref::<FrameError>())
+        .and_then(FrameError::magic)
+        .is_some()
+}
+
+/// Input with bytes to be read again before the rest of it.
+struct Rescan<'r, R> {
+    pending: std::collections::VecDeque<u8>,
+    input: &'r mut R,
+}
+
+impl<'r, R> Read for Rescan<'r, R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            self.input.read(buf)
+        } else {
+            self.pending.read(buf)
+        }
+    }
+}
+
+/// Adds an annotation with the given key and value to the message.
+pub fn add_annotation(proto: &mut fleetspeak_proto::common::Message, key: &str, value: String) {
+    let mut entry = fleetspeak_proto::common::annotations::Entry::new();
+    entry.key = String::from(key);
+    entry.value = value;
+
+    proto.mut_annotations().entries.push(entry);
+}
+
+/// Removes the annotation with the given key from the message, returning its
+/// value (if any).
+pub fn take_annotation(proto: &mut fleetspeak_proto::common::Message, key: &str) -> Option<String> {
+    let entries = &mut proto.mut_annotations().entries;
+
+    let index = entries.iter().position(|entry| entry.key == key)?;
+    Some(entries.remove(index).value)
 }
 
 /// Writes the Fleetspeak magic to the output buffer.
-fn write_magic<W>(output: &mut W) -> std::io::Result<()>
+pub(crate) fn write_magic<W>(output: &mut W) -> std::io::Result<()>
 where
     W: Write,
 {
@@ -243,47 +952,213 @@ where
 }
 
 /// Reads the Fleetspeak magic from the input buffer.
-fn read_magic<R>(input: &mut R) -> std::io::Result<()>
+pub(crate) fn read_magic<R>(input: &mut R) -> std::io::Result<()>
 where
     R: Read,
 {
-    let magic = input.read_u32::<LittleEndian>()?;
+    let mut buf = [0; 4];
+    match read_full(input, &mut buf)? {
+        0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+        4 => (),
+        consumed => return Err(FrameError {
+            declared: None,
+            consumed,
+            repr: FrameErrorRepr::Truncated,
+        }.into()),
+    }
+
+    let magic = u32::from_le_bytes(buf);
     if magic != MAGIC {
-        return Err(InvalidMagicError { magic }.into());
+        return Err(FrameError {
+            declared: None,
+            consumed: 4,
+            repr: FrameErrorRepr::BadMagic { magic },
+        }.into());
     }
 
     Ok(())
 }
 
-/// Invalid magic number was read from the input stream.
+/// Reads from the input until the buffer is full or the input ends.
+///
+/// Unlike [`Read::read_exact`], this returns the number of bytes read even if
+/// the input ends prematurely, which is needed to report how much of a frame
+/// has been consumed.
+fn read_full<R>(input: &mut R, mut buf: &mut [u8]) -> std::io::Result<usize>
+where
+    R: Read,
+{
+    let mut total = 0;
+    while !buf.is_empty() {
+        match input.read(buf) {
+            Ok(0) => break,
+            Ok(count) => {
+                total += count;
+                buf = &mut buf[count..];
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(total)
+}
+
+/// An error returned when a frame read from the input channel violates the
+/// Fleetspeak protocol.
+///
+/// Such errors are reported as [`std::io::Error`]s of the [`InvalidData`] kind
+/// (or [`UnexpectedEof`] if the stream ends in the middle of a frame) wrapping
+/// this error, which provides context for telling a truncated stream from a
+/// corrupted one:
+///
+/// ```no_run
+/// if let Err(error) = fleetspeak::try_receive() {
+///     let error = std::io::Error::from(error);
+///     let frame_error = error.get_ref()
+///         .and_then(|error| error.downcast_ref::<fleetspeak::FrameError>());
+///
+///     if let Some(frame_error) = frame_error {
+///         eprintln!("protocol failure after {} bytes", frame_error.consumed());
+///     }
+/// }
+/// ```
+///
+/// [`InvalidData`]: std::io::ErrorKind::InvalidData
+/// [`UnexpectedEof`]: std::io::ErrorKind::UnexpectedEof
 #[derive(Debug)]
-struct InvalidMagicError {
-    /// Invalid magic that was read from the input stream.
-    magic: u32,
+pub struct FrameError {
+    /// Length of the frame as declared by its prefix (if it was read).
+    declared: Option<usize>,
+    /// Number of bytes of the frame consumed from the input.
+    consumed: usize,
+    repr: FrameErrorRepr,
 }
 
-impl std::fmt::Display for InvalidMagicError {
+#[derive(Debug)]
+enum FrameErrorRepr {
+    /// The stream ended in the middle of the frame.
+    Truncated,
+    /// The declared length exceeds the message size limit.
+    TooLarge {
+        limit: usize,
+    },
+    /// The magic number is not the expected one.
+    BadMagic {
+        magic: u32,
+    },
+    /// The frame is well-formed but the message in it cannot be decoded.
+    Decode(protobuf::Error),
+}
+
+impl FrameError {
+
+    /// Returns whether the stream ended in the middle of a frame.
+    ///
+    /// Otherwise, the frame was read but turned out to be corrupted.
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.repr, FrameErrorRepr::Truncated)
+    }
+
+    /// Returns the length of the frame as declared by its length prefix.
+    ///
+    /// This is `None` if the length prefix itself could not be read or if the
+    /// error concerns the magic number exchanged in the handshake.
+    pub fn declared_len(&self) -> Option<usize> {
+        self.declared
+    }
+
+    /// Returns the number of bytes of the frame (including the length prefix)
+    /// consumed from the input before the failure was detected.
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    /// Returns the invalid magic number that was read (if that is the cause of
+    /// the error).
+    pub fn magic(&self) -> Option<u32> {
+        match self.repr {
+            FrameErrorRepr::BadMagic { magic } => Some(magic),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FrameError {
 
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(fmt, "invalid Fleetspeak magic: 0x{:08x}", self.magic)
+        match &self.repr {
+            FrameErrorRepr::Truncated => {
+                write!(fmt, "truncated frame on input channel")?;
+            }
+            FrameErrorRepr::TooLarge { limit } => {
+                write!(fmt, "frame on input channel exceeds the limit of {limit} bytes")?;
+            }
+            FrameErrorRepr::BadMagic { magic } => {
+                write! {
+                    fmt,
+                    "invalid Fleetspeak magic on input channel: expected 0x{:08x}, read 0x{:08x}",
+                    MAGIC, magic,
+                }?;
+            }
+            FrameErrorRepr::Decode(error) => {
+                write!(fmt, "malformed message on input channel: {error}")?;
+            }
+        }
+
+        match self.declared {
+            Some(declared) => {
+                write!(fmt, " (declared length: {declared}, consumed: {} bytes)", self.consumed)
+            }
+            None => write!(fmt, " (consumed: {} bytes)", self.consumed),
+        }
     }
 }
 
-impl std::error::Error for InvalidMagicError {
+impl std::error::Error for FrameError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.repr {
+            FrameErrorRepr::Decode(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
-impl From<InvalidMagicError> for std::io::Error {
+impl From<FrameError> for std::io::Error {
 
-    fn from(error: InvalidMagicError) -> std::io::Error {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    fn from(error: FrameError) -> std::io::Error {
+        let kind = if error.is_truncated() {
+            std::io::ErrorKind::UnexpectedEof
+        } else {
+            std::io::ErrorKind::InvalidData
+        };
+
+        std::io::Error::new(kind, error)
     }
 }
 
 const MAGIC: u32 = 0xf1ee1001;
 
+/// Key of the startup annotation with the version of this library.
+const VERSION_ANNOTATION: &str = "fleetspeak-rs/version";
+
+/// Key of the startup annotation with the enabled features of this library.
+const FEATURES_ANNOTATION: &str = "fleetspeak-rs/features";
+
+/// Key of the startup annotation with the commit the service was built from.
+const COMMIT_ANNOTATION: &str = "service/commit";
+
+/// Key of the startup annotation with the build time of the service.
+const BUILD_TIME_ANNOTATION: &str = "service/build-time";
+
+/// Prefix of keys of the startup annotations with labels of the service.
+const LABEL_ANNOTATION_PREFIX: &str = "service/label/";
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
+    use byteorder::ReadBytesExt as _;
     use super::*;
 
     #[test]
@@ -314,4 +1189,290 @@ mod tests {
         let mut cur_out = Cursor::new(&mut buf_out[..]);
         assert!(handshake(&mut cur_in, &mut cur_out).is_err());
     }
+
+    #[test]
+    fn decode_message_missing_data() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_source().set_service_name(String::from("foo"));
+
+        let message = decode_message(proto.clone(), crate::MissingData::Empty).unwrap().unwrap();
+        assert!(message.data.is_empty());
+
+        assert!(decode_message(proto.clone(), crate::MissingData::Reject).is_err());
+        assert!(decode_message(proto, crate::MissingData::Skip).unwrap().is_none());
+    }
+
+    #[test]
+    fn encode_message_priority() {
+        use fleetspeak_proto::common::message::Priority as ProtoPriority;
+
+        let proto = encode_message(Message {
+            service: String::from("foo"),
+            priority: crate::Priority::Low,
+            ..Default::default()
+        });
+        assert_eq!(proto.priority(), ProtoPriority::LOW);
+
+        let proto = encode_message(Message {
+            service: String::from("foo"),
+            ..Default::default()
+        });
+        assert_eq!(proto.priority(), ProtoPriority::MEDIUM);
+    }
+
+    #[test]
+    fn encode_message_background() {
+        let proto = encode_message(Message {
+            service: String::from("foo"),
+            background: true,
+            ..Default::default()
+        });
+        assert!(proto.background);
+    }
+
+    #[test]
+    fn message_annotations() {
+        let proto = encode_message(Message {
+            service: String::from("foo"),
+            annotations: vec![(String::from("bar"), String::from("baz"))],
+            ..Default::default()
+        });
+        assert_eq!(proto.annotations.entries.len(), 1);
+
+        let mut proto = proto;
+        proto.mut_source().set_service_name(String::from("foo"));
+
+        let message = decode_message(proto, crate::MissingData::Empty).unwrap().unwrap();
+        assert_eq!(message.annotations, vec![(String::from("bar"), String::from("baz"))]);
+    }
+
+    #[test]
+    fn flush_on_drop() {
+        let mut buf = Vec::new();
+
+        let mut output = FlushOnDrop::with_capacity(1024, &mut buf);
+        output.write_all(b"foo").unwrap();
+        drop(output);
+
+        assert_eq!(buf, b"foo");
+    }
+
+    #[test]
+    fn startup_annotations() {
+        let mut buf = Vec::new();
+        write_startup(&mut buf, &crate::StartupOptions {
+            version: String::from("1.2.3"),
+            ..Default::default()
+        }).unwrap();
+
+        let mut proto = read_proto(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq! {
+            take_annotation(&mut proto, VERSION_ANNOTATION).as_deref(),
+            Some(crate::VERSION),
+        };
+        assert_eq! {
+            take_annotation(&mut proto, FEATURES_ANNOTATION),
+            Some(features().join(",")),
+        };
+    }
+
+    #[test]
+    fn startup_extended_annotations() {
+        let mut buf = Vec::new();
+        write_startup(&mut buf, &crate::StartupOptions {
+            version: String::from("1.2.3"),
+            labels: vec![(String::from("channel"), String::from("beta"))],
+            commit: Some(String::from("abc123")),
+            build_time: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1337)),
+        }).unwrap();
+
+        let mut proto = read_proto(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(take_annotation(&mut proto, COMMIT_ANNOTATION).as_deref(), Some("abc123"));
+        assert_eq!(take_annotation(&mut proto, BUILD_TIME_ANNOTATION).as_deref(), Some("1337"));
+        assert_eq!(take_annotation(&mut proto, "service/label/channel").as_deref(), Some("beta"));
+    }
+
+    fn frame_error(error: std::io::Error) -> FrameError {
+        *error.into_inner().unwrap().downcast::<FrameError>().unwrap()
+    }
+
+    fn framed(data: &[u8]) -> Vec<u8> {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = data.to_vec();
+
+        let mut buf = Vec::new();
+        write_proto(&mut buf, proto).unwrap();
+        buf
+    }
+
+    #[test]
+    fn read_proto_clean_eof() {
+        let error = read_proto(&mut Cursor::new(&[][..])).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(error.get_ref().is_none());
+    }
+
+    #[test]
+    fn read_proto_truncated() {
+        let buf = framed(b"foo");
+
+        let error = read_proto(&mut Cursor::new(&buf[..buf.len() - 6])).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let error = frame_error(error);
+        assert!(error.is_truncated());
+        assert_eq!(error.declared_len(), Some(buf.len() - 8));
+        assert_eq!(error.consumed(), buf.len() - 6);
+
+        let error = frame_error(read_proto(&mut Cursor::new(&buf[..2])).unwrap_err());
+        assert!(error.is_truncated());
+        assert_eq!(error.declared_len(), None);
+        assert_eq!(error.consumed(), 2);
+    }
+
+    #[test]
+    fn read_proto_bad_magic() {
+        let mut buf = framed(b"foo");
+        let len = buf.len();
+        buf[len - 4..].copy_from_slice(&0xf1ee1337u32.to_le_bytes());
+
+        let error = read_proto(&mut Cursor::new(&buf[..])).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let error = frame_error(error);
+        assert!(!error.is_truncated());
+        assert_eq!(error.magic(), Some(0xf1ee1337));
+        assert_eq!(error.declared_len(), Some(len - 8));
+        assert_eq!(error.consumed(), len);
+        assert!(error.to_string().contains("expected 0xf1ee1001, read 0xf1ee1337"));
+    }
+
+    #[test]
+    fn read_proto_oversized() {
+        // The client in tests does not advertise any limit, so the default one
+        // applies.
+        let len = crate::env::DEFAULT_MAX_INCOMING_SIZE as u32 + 1;
+
+        let error = read_proto(&mut Cursor::new(&len.to_le_bytes()[..])).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let error = frame_error(error);
+        assert_eq!(error.declared_len(), Some(len as usize));
+        assert_eq!(error.consumed(), 4);
+    }
+
+    #[test]
+    fn read_frame_resync() {
+        let mut buf = framed(b"foo");
+        let len = buf.len();
+        // A corrupted length prefix makes the frame swallow the next one.
+        buf[0] += 2;
+        buf.extend(framed(b"bar"));
+        buf.extend(framed(b"baz"));
+
+        let mut scratch = Vec::new();
+
+        let mut cur = Cursor::new(&buf[..]);
+        assert!(is_bad_magic(&read_frame(&mut cur, 1024, 0, &mut scratch).unwrap_err()));
+
+        let mut cur = Cursor::new(&buf[..]);
+        assert_eq!(read_frame(&mut cur, 1024, 64, &mut scratch).unwrap().data.value, b"bar");
+        assert_eq!(read_frame(&mut cur, 1024, 64, &mut scratch).unwrap().data.value, b"baz");
+
+        let mut cur = Cursor::new(&buf[..]);
+        assert!(is_bad_magic(&read_frame(&mut cur, 1024, len - 8, &mut scratch).unwrap_err()));
+    }
+
+    #[test]
+    fn write_frame_short_writes() {
+        /// An output accepting at most a single byte at a time.
+        struct Trickle(Vec<u8>);
+
+        impl Write for Trickle {
+
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(&buf[..buf.len().min(1)])
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = b"foo".to_vec();
+
+        let mut output = Trickle(Vec::new());
+        write_frame(&mut output, proto).unwrap();
+
+        let proto = read_proto(&mut Cursor::new(output.0)).unwrap();
+        assert_eq!(proto.data.value, b"foo");
+    }
+
+    #[test]
+    fn write_frame_large() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = vec![0xf0; 2 * MAX_VECTORED_SIZE];
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, proto).unwrap();
+
+        let proto = read_proto(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(proto.data.value, vec![0xf0; 2 * MAX_VECTORED_SIZE]);
+    }
+
+    #[test]
+    fn write_heartbeat_frame() {
+        let mut buf = Vec::new();
+        write_heartbeat(&mut buf).unwrap();
+        write_heartbeat(&mut buf).unwrap();
+
+        let mut cur = Cursor::new(buf);
+        for _ in 0..2 {
+            let proto = read_proto(&mut cur).unwrap();
+            assert_eq!(proto.message_type(), "Heartbeat");
+            assert_eq!(proto.destination().service_name(), "system");
+        }
+    }
+
+    #[test]
+    fn frame_reader_scratch_reuse() {
+        let mut buf = framed(b"foo");
+        buf.extend(framed(b"bar"));
+        buf.extend(framed(&vec![0; 4 * MAX_SCRATCH_CAPACITY]));
+
+        let mut reader = FrameReader::with_capacity(1024, Cursor::new(buf));
+
+        assert_eq!(reader.read_proto().unwrap().data.value, b"foo");
+        assert!(reader.scratch.is_empty());
+        let ptr = reader.scratch.as_ptr();
+
+        assert_eq!(reader.read_proto().unwrap().data.value, b"bar");
+        assert!(reader.scratch.is_empty());
+        assert_eq!(reader.scratch.as_ptr(), ptr);
+
+        assert_eq!(reader.read_proto().unwrap().data.value.len(), 4 * MAX_SCRATCH_CAPACITY);
+        assert!(reader.scratch.capacity() <= MAX_SCRATCH_CAPACITY);
+    }
+
+    #[test]
+    fn flush_on_drop_scratch_reuse() {
+        let mut buf = Vec::new();
+        let mut output = FlushOnDrop::with_capacity(1024, &mut buf);
+
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = b"foo".to_vec();
+        output.write_frame(proto).unwrap();
+        let ptr = output.scratch.as_ptr();
+
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = b"bar".to_vec();
+        output.write_frame(proto).unwrap();
+        assert_eq!(output.scratch.as_ptr(), ptr);
+        drop(output);
+
+        let mut cur = Cursor::new(buf);
+        assert_eq!(read_proto(&mut cur).unwrap().data.value, b"foo");
+        assert_eq!(read_proto(&mut cur).unwrap().data.value, b"bar");
+    }
 }
@@ -0,0 +1,61 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Transport over a Unix domain socket.
+//!
+//! Services configured as socket services are not started by the Fleetspeak
+//! client. Instead, the client listens on a Unix domain socket and services run
+//! as standalone daemons that connect to it. Apart from that, the protocol
+//! (the handshake and the message framing) is the same as with the descriptors
+//! inherited by daemon services.
+
+use std::os::fd::OwnedFd;
+
+use super::{CommsInRaw, CommsOutRaw};
+
+/// Connects to the Fleetspeak client listening on the socket at `path`.
+///
+/// The socket is used for both directions: the returned channels own separate
+/// duplicates of its descriptor.
+pub fn connect<P>(path: P) -> std::io::Result<(CommsInRaw, CommsOutRaw)>
+where
+    P: AsRef<std::path::Path>,
+{
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    let clone = stream.try_clone()?;
+
+    Ok((CommsInRaw::from(OwnedFd::from(stream)), CommsOutRaw::from(OwnedFd::from(clone))))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read as _, Write as _};
+
+    use super::*;
+
+    #[test]
+    fn connect_duplex() {
+        let tempdir = std::env::temp_dir().join(format!("fleetspeak-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&tempdir).unwrap();
+        let path = tempdir.join("socket");
+        let _ = std::fs::remove_file(&path);
+
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let (mut input, mut output) = connect(&path).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        output.write_all(b"foo").unwrap();
+        let mut buf = [0; 3];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"foo");
+
+        peer.write_all(b"bar").unwrap();
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bar");
+
+        std::fs::remove_dir_all(&tempdir).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Transport over a loopback TCP connection (for development only).
+//!
+//! Pointing a service at a mock server or a test harness normally requires the
+//! harness to set up descriptors inherited by the service. With this transport
+//! the service connects to `127.0.0.1:<port>` instead and then speaks the same
+//! protocol (the handshake and the message framing) as over the descriptors.
+//!
+//! The connection is neither authenticated nor encrypted, so this transport is
+//! available only with the `dev-tcp` feature and must not be used in production.
+
+use std::os::fd::OwnedFd;
+
+use super::{CommsInRaw, CommsOutRaw};
+
+/// Connects to the development server listening on the loopback `port`.
+///
+/// The connection is used for both directions: the returned channels own
+/// separate duplicates of its descriptor.
+pub fn connect(port: u16) -> std::io::Result<(CommsInRaw, CommsOutRaw)> {
+    let stream = std::net::TcpStream::connect((std::net::Ipv4Addr::LOCALHOST, port))?;
+    // Messages are framed and flushed explicitly, so there is no point in
+    // delaying small writes (e.g. heartbeats).
+    stream.set_nodelay(true)?;
+    let clone = stream.try_clone()?;
+
+    Ok((CommsInRaw::from(OwnedFd::from(stream)), CommsOutRaw::from(OwnedFd::from(clone))))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read as _, Write as _};
+
+    use super::*;
+
+    #[test]
+    fn connect_duplex() {
+        let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (mut input, mut output) = connect(port).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        output.write_all(b"foo").unwrap();
+        let mut buf = [0; 3];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"foo");
+
+        peer.write_all(b"bar").unwrap();
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bar");
+    }
+}
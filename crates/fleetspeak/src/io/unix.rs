@@ -3,64 +3,274 @@
 // Use of this source code is governed by an MIT-style license that can be found
 // in the LICENSE file or at https://opensource.org/licenses/MIT.
 
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+
 use super::{CommsEnvError, CommsEnvErrorRepr};
 
 /// Alternative for [`std::io::Stdin`] for communicating with Fleetspeak.
 ///
 /// Reading from this communication channel is not synchronized nor buffered.
+///
+/// The descriptor is exposed through the [`AsFd`] and [`AsRawFd`] traits, so
+/// that it can be registered with `poll` or an I/O reactor. Channels can also
+/// be created from descriptors obtained by other means with [`From<OwnedFd>`].
+///
+/// [`AsFd`]: std::os::fd::AsFd
+/// [`AsRawFd`]: std::os::fd::AsRawFd
+/// [`From<OwnedFd>`]: std::os::fd::OwnedFd
 pub struct CommsInRaw {
-    /// File descriptor of the input channel passeed by the Fleetspeak process.
-    fd: libc::c_int,
+    /// Descriptor of the input channel.
+    ///
+    /// It is never closed (see [`CommsInRaw::close`]).
+    fd: OwnedFd,
 }
 
 /// Alternative for [`std::io::Stdout`] for communicating with Fleetspeak.
 ///
 /// Writing to this communication channel is not synchronized nor buffered.
+///
+/// Like [`CommsInRaw`], it implements [`AsFd`] and [`AsRawFd`] and can be
+/// created from an [`OwnedFd`].
+///
+/// [`AsFd`]: std::os::fd::AsFd
+/// [`AsRawFd`]: std::os::fd::AsRawFd
+/// [`OwnedFd`]: std::os::fd::OwnedFd
 pub struct CommsOutRaw {
-    /// File descriptor of the output channel passeed by the Fleetspeak process.
-    fd: libc::c_int,
+    /// Descriptor of the output channel.
+    ///
+    /// Like the input one, it is never closed (see [`CommsOutRaw::close`]).
+    fd: OwnedFd,
 }
 
 impl CommsInRaw {
 
-    /// Returns a [`CommsIn`] instance given by the parent Fleetspeak process.
+    /// Returns the input channel given by the parent Fleetspeak process.
     pub fn from_env() -> Result<CommsInRaw, CommsEnvError> {
-        Ok(CommsInRaw {
-            fd: env_var_fd("FLEETSPEAK_COMMS_CHANNEL_INFD")?,
-        })
+        Ok(CommsInRaw::from(owned_env_channel(crate::env::COMMS_IN_VAR)?))
+    }
+
+    /// Waits until there is data to read or the `timeout` elapses.
+    ///
+    /// Returns `true` if reading from the channel will not block (which also
+    /// includes the case when the channel has been closed by the other end).
+    pub fn wait(&self, timeout: std::time::Duration) -> std::io::Result<bool> {
+        poll_fd(self.as_raw_fd(), libc::POLLIN, timeout)
+    }
+
+    /// Switches the descriptor to or from the non-blocking mode.
+    ///
+    /// In the non-blocking mode, reads with [`read_timeout`] give up once the
+    /// timeout elapses. Reads through the [`std::io::Read`] implementation are
+    /// unaffected: they wait (using `poll`) until there is something to read.
+    ///
+    /// Note that the mode is a property of the open file description, so it is
+    /// shared with duplicates of the descriptor (e.g. ones inherited through
+    /// `exec`).
+    ///
+    /// [`read_timeout`]: CommsInRaw::read_timeout
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        set_nonblocking(self.as_raw_fd(), nonblocking)
+    }
+
+    /// Reads data into `buf`, waiting at most `timeout` for it to arrive.
+    ///
+    /// If no data arrives in time, an error of the [`WouldBlock`] kind is
+    /// returned. A zero `timeout` makes this a non-blocking read attempt. The
+    /// timeout is respected only if the descriptor is in the [non-blocking
+    /// mode], otherwise this behaves like a regular read.
+    ///
+    /// [`WouldBlock`]: std::io::ErrorKind::WouldBlock
+    /// [non-blocking mode]: CommsInRaw::set_nonblocking
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout: std::time::Duration) -> std::io::Result<usize> {
+        let deadline = std::time::Instant::now() + timeout;
+
+        loop {
+            match read_fd(self.as_raw_fd(), buf) {
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => (),
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() || !poll_fd(self.as_raw_fd(), libc::POLLIN, remaining)? {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+        }
+    }
+
+    /// Verifies that the descriptor is still open for reading.
+    pub fn validate(&self) -> std::io::Result<()> {
+        validate_fd(self.as_raw_fd(), libc::O_RDONLY)
+    }
+
+    /// Makes the descriptor survive `exec` calls.
+    pub fn inherit(&self) -> std::io::Result<()> {
+        clear_cloexec(self.as_raw_fd())
+    }
+
+    /// Closes the channel.
+    ///
+    /// The descriptor number stays allocated but refers to `/dev/null`
+    /// afterwards, so that descriptors borrowed from the channel (e.g. through
+    /// [`poll_handle`]) never dangle nor get reused for unrelated files. Further
+    /// reads see the end of file. Closing an already closed channel does
+    /// nothing.
+    ///
+    /// [`poll_handle`]: crate::poll_handle
+    pub fn close(&mut self) -> std::io::Result<()> {
+        close_fd(&self.fd, std::fs::File::open("/dev/null")?)
     }
 }
 
 impl CommsOutRaw {
 
-    /// Returns a [`CommsOut`] instance given by the parent Fleetspeak process.
+    /// Returns the output channel given by the parent Fleetspeak process.
     pub fn from_env() -> Result<CommsOutRaw, CommsEnvError> {
-        Ok(CommsOutRaw {
-            fd: env_var_fd("FLEETSPEAK_COMMS_CHANNEL_OUTFD")?,
-        })
+        Ok(CommsOutRaw::from(owned_env_channel(crate::env::COMMS_OUT_VAR)?))
+    }
+
+    /// Waits until some data can be written or the `timeout` elapses.
+    ///
+    /// Returns `true` if writing to the channel will not block (as long as not
+    /// more than `PIPE_BUF` bytes are written).
+    #[cfg(feature = "tokio")]
+    pub fn wait(&self, timeout: std::time::Duration) -> std::io::Result<bool> {
+        poll_fd(self.as_raw_fd(), libc::POLLOUT, timeout)
+    }
+
+    /// Verifies that the descriptor is still open for writing.
+    pub fn validate(&self) -> std::io::Result<()> {
+        validate_fd(self.as_raw_fd(), libc::O_WRONLY)
+    }
+
+    /// Makes the descriptor survive `exec` calls.
+    pub fn inherit(&self) -> std::io::Result<()> {
+        clear_cloexec(self.as_raw_fd())
+    }
+
+    /// Closes the channel.
+    ///
+    /// Like with [`CommsInRaw::close`], the descriptor number stays allocated
+    /// but refers to `/dev/null` afterwards, so that borrowed descriptors never
+    /// dangle. Further writes are discarded. Closing an already closed channel
+    /// does nothing.
+    pub fn close(&mut self) -> std::io::Result<()> {
+        let null = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")?;
+
+        close_fd(&self.fd, null)
+    }
+}
+
+impl From<OwnedFd> for CommsInRaw {
+
+    fn from(fd: OwnedFd) -> CommsInRaw {
+        CommsInRaw { fd }
+    }
+}
+
+impl From<OwnedFd> for CommsOutRaw {
+
+    fn from(fd: OwnedFd) -> CommsOutRaw {
+        CommsOutRaw { fd }
+    }
+}
+
+impl From<std::io::PipeReader> for CommsInRaw {
+
+    fn from(pipe: std::io::PipeReader) -> CommsInRaw {
+        CommsInRaw::from(OwnedFd::from(pipe))
+    }
+}
+
+impl From<std::io::PipeWriter> for CommsOutRaw {
+
+    fn from(pipe: std::io::PipeWriter) -> CommsOutRaw {
+        CommsOutRaw::from(OwnedFd::from(pipe))
+    }
+}
+
+impl std::os::fd::FromRawFd for CommsInRaw {
+
+    unsafe fn from_raw_fd(fd: std::os::fd::RawFd) -> CommsInRaw {
+        // SAFETY: The caller guarantees that the descriptor is open and owned.
+        CommsInRaw::from(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+impl std::os::fd::FromRawFd for CommsOutRaw {
+
+    unsafe fn from_raw_fd(fd: std::os::fd::RawFd) -> CommsOutRaw {
+        // SAFETY: The caller guarantees that the descriptor is open and owned.
+        CommsOutRaw::from(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+impl std::os::fd::AsRawFd for CommsInRaw {
+
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl std::os::fd::AsRawFd for CommsOutRaw {
+
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl std::os::fd::AsFd for CommsInRaw {
+
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl std::os::fd::AsFd for CommsOutRaw {
+
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.fd.as_fd()
     }
 }
 
 impl std::io::Read for CommsInRaw {
 
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // SAFETY: We do not have any assumptions on `self.fd`. We usually want
-        // it to be a valid file descriptor but since it is passed to us from
-        // the parent process, we cannot guarantee that it actually is.
+        loop {
+            match read_fd(self.as_raw_fd(), buf) {
+                // The descriptor is in the non-blocking mode, but this is a
+                // blocking read, so we wait until there is something to read.
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    poll_fd(self.as_raw_fd(), libc::POLLIN, std::time::Duration::MAX)?;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl std::io::Write for CommsOutRaw {
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // SAFETY: We do not have any assumptions on the descriptor. We usually
+        // want it to be a valid file descriptor but since it is passed to us
+        // from the parent process, we cannot guarantee that it actually is.
         //
         // However, there is no unsafety here: in case we are not allowed to do
-        // a read operation on this supposed descriptor, it will simply fail
+        // a write operation on this supposed descriptor, it will simply fail
         // (e.g. with `EBADF` if this is not actually a descriptor).
         //
         // The rest is just a function call as described in the docs [1, 2]: we
-        // pass a valid buffer and the number of bytes that we want to read
+        // pass a valid buffer and the number of bytes that we want to write
         // (which is equal to the length of the buffer). We verify the result
         // afterwards.
         //
-        // [1]: https://man7.org/linux/man-pages/man2/read.2.html
-        // [2]: https://pubs.opengroup.org/onlinepubs/009604599/functions/read.html
+        // [1]: https://man7.org/linux/man-pages/man2/write.2.html
+        // [2]: https://pubs.opengroup.org/onlinepubs/9699919799/functions/write.html
         let count = unsafe {
-            libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len())
+            libc::write(self.as_raw_fd(), buf.as_ptr().cast(), buf.len())
         };
 
         if count < 0 {
@@ -69,28 +279,24 @@ impl std::io::Read for CommsInRaw {
 
         Ok(count as usize)
     }
-}
 
-impl std::io::Write for CommsOutRaw {
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        // The number of buffers accepted at once is limited (`IOV_MAX`), and
+        // 1024 is the limit on the platforms we care about. Writing fewer than
+        // the requested buffers is just a partial write.
+        let bufs = &bufs[..bufs.len().min(1024)];
 
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        // SAFETY: We do not have any assumptions on `self.fd`. We usually want
-        // it to be a valid file descriptor but since it is passed to us from
-        // the parent process, we cannot guarantee that it actually is.
-        //
-        // However, there is no unsafety here: in case we are not allowed to do
-        // a write operation on this supposed descriptor, it will simply fail
-        // (e.g. with `EBADF` if this is not actually a descriptor).
-        //
-        // The rest is just a function call as described in the docs [1, 2]: we
-        // pass a valid buffer and the number of bytes that we want to write
-        // (which is equal to the length of the buffer). We verify the result
+        // SAFETY: See the comment in the `write` method for the assumptions on
+        // the descriptor. `IoSlice` is guaranteed to be ABI-compatible with
+        // `iovec` [1], so we pass a valid array of buffers along with its
+        // length as described in the docs [2, 3]. We verify the result
         // afterwards.
         //
-        // [1]: https://man7.org/linux/man-pages/man2/write.2.html
-        // [2]: https://pubs.opengroup.org/onlinepubs/9699919799/functions/write.html
+        // [1]: https://doc.rust-lang.org/std/io/struct.IoSlice.html
+        // [2]: https://man7.org/linux/man-pages/man2/writev.2.html
+        // [3]: https://pubs.opengroup.org/onlinepubs/9699919799/functions/writev.html
         let count = unsafe {
-            libc::write(self.fd, buf.as_ptr().cast(), buf.len())
+            libc::writev(self.as_raw_fd(), bufs.as_ptr().cast(), bufs.len() as libc::c_int)
         };
 
         if count < 0 {
@@ -107,23 +313,315 @@ impl std::io::Write for CommsOutRaw {
     }
 }
 
-/// Retrieves a file descriptor specified in the given environment variable.
-fn env_var_fd<K>(key: K) -> Result<libc::c_int, CommsEnvError>
-where
-    K: AsRef<std::ffi::OsStr>,
-{
-    match std::env::var(key) {
-        Ok(fd) => match fd.parse::<libc::c_int>() {
-            Ok(fd) => Ok(fd),
-            Err(_) => Err(CommsEnvError {
-                repr: CommsEnvErrorRepr::NotParsable(fd.into()),
-            }),
+/// Takes ownership of the descriptor specified in the given environment
+/// variable.
+fn owned_env_channel(key: &str) -> Result<OwnedFd, CommsEnvError> {
+    let fd = crate::env::channel(key)?;
+
+    // SAFETY: `F_GETFD` does not have any requirements on the descriptor [1]:
+    // in case it is not valid, the call fails with `EBADF`.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/fcntl.2.html
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(CommsEnvError {
+            repr: CommsEnvErrorRepr::Closed(fd.to_string().into()),
+        });
+    }
+
+    // SAFETY: We verified above that the descriptor is open. It is passed to
+    // us by the parent Fleetspeak process exclusively for the communication
+    // channel, so nothing else in the process owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Waits until any of the `events` occurs on the descriptor or the `timeout`
+/// elapses.
+fn poll_fd(fd: libc::c_int, events: libc::c_short, timeout: std::time::Duration) -> std::io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+
+    // We round up so that waiting for a sub-millisecond duration does not turn
+    // into a non-blocking check.
+    let timeout = libc::c_int::try_from(timeout.as_nanos().div_ceil(1_000_000))
+        .unwrap_or(libc::c_int::MAX);
+
+    loop {
+        // SAFETY: We pass a valid pointer to a single `pollfd` structure as
+        // described in the docs [1]. Invalid descriptors are reported in the
+        // `revents` field, so there are no assumptions on `fd`. We verify the
+        // result afterwards.
+        //
+        // [1]: https://man7.org/linux/man-pages/man2/poll.2.html
+        let count = unsafe {
+            libc::poll(&mut pollfd, 1, timeout)
+        };
+
+        if count < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(error);
         }
-        Err(std::env::VarError::NotPresent) => Err(CommsEnvError {
-            repr: CommsEnvErrorRepr::NotSpecified,
-        }),
-        Err(std::env::VarError::NotUnicode(value)) => Err(CommsEnvError {
-            repr: CommsEnvErrorRepr::NotParsable(value),
-        }),
+
+        return Ok(count > 0);
+    }
+}
+
+/// Reads data from the descriptor into `buf`.
+fn read_fd(fd: libc::c_int, buf: &mut [u8]) -> std::io::Result<usize> {
+    // SAFETY: We do not have any assumptions on `fd`. We usually want it to be
+    // a valid file descriptor but since it is passed to us from the parent
+    // process, we cannot guarantee that it actually is.
+    //
+    // However, there is no unsafety here: in case we are not allowed to do a
+    // read operation on this supposed descriptor, it will simply fail (e.g.
+    // with `EBADF` if this is not actually a descriptor).
+    //
+    // The rest is just a function call as described in the docs [1, 2]: we
+    // pass a valid buffer and the number of bytes that we want to read (which
+    // is equal to the length of the buffer). We verify the result afterwards.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/read.2.html
+    // [2]: https://pubs.opengroup.org/onlinepubs/009604599/functions/read.html
+    let count = unsafe {
+        libc::read(fd, buf.as_mut_ptr().cast(), buf.len())
+    };
+
+    if count < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(count as usize)
+}
+
+/// Switches the descriptor to or from the non-blocking mode.
+fn set_nonblocking(fd: libc::c_int, nonblocking: bool) -> std::io::Result<()> {
+    // SAFETY: `F_GETFL` and `F_SETFL` do not have any requirements on the
+    // descriptor [1]: in case it is not valid, the call fails with `EBADF`. We
+    // verify the results afterwards.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/fcntl.2.html
+    let flags = unsafe {
+        libc::fcntl(fd, libc::F_GETFL)
+    };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let flags = if nonblocking {
+        flags | libc::O_NONBLOCK
+    } else {
+        flags & !libc::O_NONBLOCK
+    };
+
+    // SAFETY: See the comment above.
+    let status = unsafe {
+        libc::fcntl(fd, libc::F_SETFL, flags)
+    };
+    if status < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Verifies that the descriptor is open with the given access mode.
+///
+/// Descriptors opened for both reading and writing are accepted for any mode.
+fn validate_fd(fd: libc::c_int, mode: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: `F_GETFL` does not have any requirements on the descriptor [1]:
+    // in case it is not valid, the call fails with `EBADF`. We verify the
+    // result afterwards.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/fcntl.2.html
+    let flags = unsafe {
+        libc::fcntl(fd, libc::F_GETFL)
+    };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let access = flags & libc::O_ACCMODE;
+    if access != mode && access != libc::O_RDWR {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, {
+            format!("invalid access mode of descriptor {fd}")
+        }));
+    }
+
+    Ok(())
+}
+
+/// Closes the file of the descriptor, replacing it with the `null` one.
+///
+/// The descriptor number itself stays open, so it cannot be reused for an
+/// unrelated file while something still refers to it.
+fn close_fd(fd: &OwnedFd, null: std::fs::File) -> std::io::Result<()> {
+    // SAFETY: Both descriptors are owned and open. `dup2` atomically closes
+    // the original file of the target descriptor and makes it refer to the
+    // file of the source one [1]. We verify the result afterwards.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/dup.2.html
+    let status = unsafe {
+        libc::dup2(null.as_raw_fd(), fd.as_raw_fd())
+    };
+    if status < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Clears the close-on-exec flag of the descriptor.
+fn clear_cloexec(fd: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: See the comment in `validate_fd`, the same applies to `F_GETFD`
+    // and `F_SETFD` [1]. We verify the results afterwards.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/fcntl.2.html
+    let flags = unsafe {
+        libc::fcntl(fd, libc::F_GETFD)
+    };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: See the comment above.
+    let status = unsafe {
+        libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC)
+    };
+    if status < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read as _, Write as _};
+    use std::os::fd::{AsFd as _, AsRawFd as _};
+
+    use super::*;
+
+    #[test]
+    fn read_timeout_nonblocking() {
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let mut input = CommsInRaw::from(OwnedFd::from(reader));
+        input.set_nonblocking(true).unwrap();
+
+        let mut buf = [0; 3];
+        let error = input.read_timeout(&mut buf, std::time::Duration::ZERO).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+
+        writer.write_all(b"foo").unwrap();
+        assert_eq!(input.read_timeout(&mut buf, std::time::Duration::ZERO).unwrap(), 3);
+        assert_eq!(&buf, b"foo");
+    }
+
+    #[test]
+    fn read_nonblocking_waits() {
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let mut input = CommsInRaw::from(OwnedFd::from(reader));
+        input.set_nonblocking(true).unwrap();
+
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            writer.write_all(b"bar").unwrap();
+        });
+
+        let mut buf = [0; 3];
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bar");
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn write_vectored() {
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let mut output = CommsOutRaw::from(OwnedFd::from(writer));
+
+        let bufs = [
+            std::io::IoSlice::new(b"foo"),
+            std::io::IoSlice::new(b""),
+            std::io::IoSlice::new(b"bar"),
+        ];
+        assert_eq!(output.write_vectored(&bufs).unwrap(), 6);
+        drop(output);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"foobar");
+    }
+
+    #[test]
+    fn from_owned_fd() {
+        let (reader, writer) = std::io::pipe().unwrap();
+        let mut input = CommsInRaw::from(OwnedFd::from(reader));
+        let mut output = CommsOutRaw::from(OwnedFd::from(writer));
+        assert_eq!(input.as_fd().as_raw_fd(), input.as_raw_fd());
+        assert_eq!(output.as_fd().as_raw_fd(), output.as_raw_fd());
+
+        output.write_all(b"foo").unwrap();
+        output.close().unwrap();
+
+        let mut buf = Vec::new();
+        input.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"foo");
+    }
+
+    #[test]
+    fn input_close_keeps_descriptor() {
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let mut input = CommsInRaw::from(reader);
+        let fd = input.as_raw_fd();
+
+        writer.write_all(b"foo").unwrap();
+        input.close().unwrap();
+        input.close().unwrap();
+
+        // The descriptor stays valid but no longer refers to the pipe.
+        assert_eq!(input.as_raw_fd(), fd);
+        assert!(input.validate().is_ok());
+
+        let mut buf = Vec::new();
+        input.read_to_end(&mut buf).unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn output_close_keeps_descriptor() {
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let mut output = CommsOutRaw::from(writer);
+        let fd = output.as_raw_fd();
+
+        output.write_all(b"foo").unwrap();
+        output.close().unwrap();
+        output.close().unwrap();
+
+        // The descriptor stays valid but no longer refers to the pipe.
+        assert_eq!(output.as_raw_fd(), fd);
+        assert_eq!(output.as_fd().as_raw_fd(), fd);
+        assert!(output.validate().is_ok());
+        output.write_all(b"bar").unwrap();
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"foo");
+    }
+
+    #[test]
+    fn owned_env_channel_closed() {
+        const VAR: &str = "FLEETSPEAK_RS_TEST_CLOSED_FD";
+
+        std::env::set_var(VAR, "1048575");
+
+        let error = owned_env_channel(VAR).unwrap_err();
+        assert!(matches!(error.repr, CommsEnvErrorRepr::Closed(_)));
     }
 }
@@ -3,73 +3,259 @@
 // Use of this source code is governed by an MIT-style license that can be found
 // in the LICENSE file or at https://opensource.org/licenses/MIT.
 
+use std::os::windows::io::{AsRawHandle as _, FromRawHandle as _, IntoRawHandle as _, OwnedHandle};
+
 use super::{CommsEnvError, CommsEnvErrorRepr};
 
 /// Alternative for [`std::io::Stdin`] for communicating with Fleetspeak.
 ///
 /// Reading from this communication channel is not synchronized nor buffered.
+///
+/// The handle is exposed through the [`AsHandle`] and [`AsRawHandle`] traits,
+/// so that it can be registered with an I/O reactor. Channels can also be
+/// created from handles obtained by other means with [`From<OwnedHandle>`].
+///
+/// [`AsHandle`]: std::os::windows::io::AsHandle
+/// [`AsRawHandle`]: std::os::windows::io::AsRawHandle
+/// [`From<OwnedHandle>`]: std::os::windows::io::OwnedHandle
 pub struct CommsInRaw {
-    /// File handle of the input channel passed by the Fleetspeak process.
-    handle: windows_sys::Win32::Foundation::HANDLE,
+    /// Handle of the input channel (`None` once the channel is closed).
+    handle: Option<OwnedHandle>,
+    /// Event used for overlapped reads (if the handle supports them).
+    event: Option<Event>,
 }
 
 /// Alternative for [`std::io::Stdout`] for communicating with Fleetspeak.
 ///
 /// Writing to this communication channel is not synchronized nor buffered.
+///
+/// Like [`CommsInRaw`], it implements [`AsHandle`] and [`AsRawHandle`] and can
+/// be created from an [`OwnedHandle`].
+///
+/// [`AsHandle`]: std::os::windows::io::AsHandle
+/// [`AsRawHandle`]: std::os::windows::io::AsRawHandle
+/// [`OwnedHandle`]: std::os::windows::io::OwnedHandle
 pub struct CommsOutRaw {
-    /// File handle of the output channel passed by the Fleetspeak process.
-    handle: windows_sys::Win32::Foundation::HANDLE,
-}
-
-// SAFETY: It is safe to send `CommsInRaw` between threads as there is no
-// dependence on thread-locality or other interior mutability shenanigans to
-// ensure its safety.
-//
-// This marker trait is not automatically implemented for this type because
-// handle is a raw pointer (which implements `!Send`) so we explicitly opt into
-// that.
-unsafe impl Send for CommsInRaw {
-}
-
-// SAFETY: It is safe to send `CommsOutRaw` between threads as there is no
-// dependence on thread-locality or other interior mutability shenanigans to
-// ensure its safety.
-//
-// This marker trait is not automatically implemented for this type because
-// handle is a raw pointer (which implements `!Send`) so we explicitly opt into
-// that.
-unsafe impl Send for CommsOutRaw {
+    /// Handle of the output channel (`None` once the channel is closed).
+    handle: Option<OwnedHandle>,
+    /// Event used for overlapped writes (if the handle supports them).
+    event: Option<Event>,
 }
 
 impl CommsInRaw {
 
-    /// Returns a [`CommsIn`] instance given by the parent Fleetspeak process.
+    /// Returns the input channel given by the parent Fleetspeak process.
     pub fn from_env() -> Result<CommsInRaw, CommsEnvError> {
-        Ok(CommsInRaw {
-            handle: env_var_handle("FLEETSPEAK_COMMS_CHANNEL_INFD")?,
-        })
+        Ok(CommsInRaw::from(owned_env_channel(crate::env::COMMS_IN_VAR)?))
+    }
+
+    /// Reads data into `buf`, waiting at most `timeout` for it to arrive.
+    ///
+    /// If no data arrives in time, an error of the [`WouldBlock`] kind is
+    /// returned. A zero `timeout` makes this a non-blocking read attempt.
+    ///
+    /// If the handle supports overlapped I/O, the read is issued right away and
+    /// cancelled once the timeout elapses. Otherwise, the channel is [polled]
+    /// for available data first.
+    ///
+    /// [`WouldBlock`]: std::io::ErrorKind::WouldBlock
+    /// [polled]: CommsInRaw::wait
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout: std::time::Duration) -> std::io::Result<usize> {
+        use std::io::Read as _;
+
+        match &self.event {
+            Some(event) => read_overlapped(self.as_raw_handle(), event, buf, Some(timeout)),
+            None if self.wait(timeout)? => self.read(buf),
+            None => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// Waits until there is data to read or the `timeout` elapses.
+    ///
+    /// Returns `true` if reading from the channel will not block.
+    pub fn wait(&self, timeout: std::time::Duration) -> std::io::Result<bool> {
+        // Anonymous pipes do not support overlapped I/O, so there is no way to
+        // wait on them. Instead, we poll the pipe with a short interval.
+        const INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+        let deadline = std::time::Instant::now() + timeout;
+
+        loop {
+            let mut available = std::mem::MaybeUninit::uninit();
+
+            // SAFETY: We do not have any assumptions on the handle (see the
+            // comment in the `read` method for more details). We do not ask
+            // for any data to be copied and pass a valid pointer for the total
+            // number of available bytes as described in the docs [1]. We
+            // verify the status after the call.
+            //
+            // [1]: https://learn.microsoft.com/en-us/windows/win32/api/namedpipeapi/nf-namedpipeapi-peeknamedpipe
+            let status = unsafe {
+                windows_sys::Win32::System::Pipes::PeekNamedPipe(
+                    self.as_raw_handle(),
+                    std::ptr::null_mut(),
+                    0,
+                    std::ptr::null_mut(),
+                    available.as_mut_ptr(),
+                    std::ptr::null_mut(),
+                )
+            };
+
+            if status == windows_sys::Win32::Foundation::FALSE {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            // SAFETY: We verified that the call to `PeekNamedPipe` succeeded
+            // and thus `available` is guaranteed to be initialized.
+            if unsafe { available.assume_init() } > 0 {
+                return Ok(true);
+            }
+
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+
+            std::thread::sleep(std::cmp::min(remaining, INTERVAL));
+        }
+    }
+
+    /// Closes the handle.
+    ///
+    /// Further operations on the channel fail with `ERROR_INVALID_HANDLE`.
+    /// Closing an already closed channel does nothing.
+    pub fn close(&mut self) -> std::io::Result<()> {
+        self.event = None;
+        close_handle(self.handle.take())
     }
 }
 
 impl CommsOutRaw {
 
-    /// Returns a [`CommsOut`] instance given by the parent Fleetspeak process.
+    /// Returns the output channel given by the parent Fleetspeak process.
     pub fn from_env() -> Result<CommsOutRaw, CommsEnvError> {
-        Ok(CommsOutRaw {
-            handle: env_var_handle("FLEETSPEAK_COMMS_CHANNEL_OUTFD")?,
-        })
+        Ok(CommsOutRaw::from(owned_env_channel(crate::env::COMMS_OUT_VAR)?))
+    }
+
+    /// Waits until some data can be written or the `timeout` elapses.
+    ///
+    /// Anonymous pipes provide no way of checking how much data can be written
+    /// without blocking, so this always returns `true` immediately.
+    #[cfg(feature = "tokio")]
+    pub fn wait(&self, timeout: std::time::Duration) -> std::io::Result<bool> {
+        let _ = timeout;
+        Ok(true)
+    }
+
+    /// Closes the handle.
+    ///
+    /// Further operations on the channel fail with `ERROR_INVALID_HANDLE`.
+    /// Closing an already closed channel does nothing.
+    pub fn close(&mut self) -> std::io::Result<()> {
+        self.event = None;
+        close_handle(self.handle.take())
+    }
+}
+
+impl From<OwnedHandle> for CommsInRaw {
+
+    fn from(handle: OwnedHandle) -> CommsInRaw {
+        CommsInRaw {
+            event: overlapped_event(handle.as_raw_handle()),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl From<OwnedHandle> for CommsOutRaw {
+
+    fn from(handle: OwnedHandle) -> CommsOutRaw {
+        CommsOutRaw {
+            event: overlapped_event(handle.as_raw_handle()),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl From<std::io::PipeReader> for CommsInRaw {
+
+    fn from(pipe: std::io::PipeReader) -> CommsInRaw {
+        CommsInRaw::from(OwnedHandle::from(pipe))
+    }
+}
+
+impl From<std::io::PipeWriter> for CommsOutRaw {
+
+    fn from(pipe: std::io::PipeWriter) -> CommsOutRaw {
+        CommsOutRaw::from(OwnedHandle::from(pipe))
+    }
+}
+
+impl std::os::windows::io::FromRawHandle for CommsInRaw {
+
+    unsafe fn from_raw_handle(handle: std::os::windows::io::RawHandle) -> CommsInRaw {
+        // SAFETY: The caller guarantees that the handle is open and owned.
+        CommsInRaw::from(unsafe { OwnedHandle::from_raw_handle(handle) })
+    }
+}
+
+impl std::os::windows::io::FromRawHandle for CommsOutRaw {
+
+    unsafe fn from_raw_handle(handle: std::os::windows::io::RawHandle) -> CommsOutRaw {
+        // SAFETY: The caller guarantees that the handle is open and owned.
+        CommsOutRaw::from(unsafe { OwnedHandle::from_raw_handle(handle) })
+    }
+}
+
+/// Returns a null handle if the channel has been closed.
+impl std::os::windows::io::AsRawHandle for CommsInRaw {
+
+    fn as_raw_handle(&self) -> std::os::windows::io::RawHandle {
+        raw_handle(&self.handle)
+    }
+}
+
+/// Returns a null handle if the channel has been closed.
+impl std::os::windows::io::AsRawHandle for CommsOutRaw {
+
+    fn as_raw_handle(&self) -> std::os::windows::io::RawHandle {
+        raw_handle(&self.handle)
+    }
+}
+
+/// # Panics
+///
+/// Panics if the channel has been [closed](CommsInRaw::close).
+impl std::os::windows::io::AsHandle for CommsInRaw {
+
+    fn as_handle(&self) -> std::os::windows::io::BorrowedHandle<'_> {
+        self.handle.as_ref().expect("channel closed").as_handle()
+    }
+}
+
+/// # Panics
+///
+/// Panics if the channel has been [closed](CommsOutRaw::close).
+impl std::os::windows::io::AsHandle for CommsOutRaw {
+
+    fn as_handle(&self) -> std::os::windows::io::BorrowedHandle<'_> {
+        self.handle.as_ref().expect("channel closed").as_handle()
     }
 }
 
 impl std::io::Read for CommsInRaw {
 
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(event) = &self.event {
+            return read_overlapped(self.as_raw_handle(), event, buf, None);
+        }
+
         let buf_len = u32::try_from(buf.len())
             .map_err(|_| std::io::ErrorKind::InvalidInput)?;
 
         let mut count = std::mem::MaybeUninit::uninit();
 
-        // SAFETY: We do not have any assumptons on `self.handle`. We usually
+        // SAFETY: We do not have any assumptons on the handle. We usually
         // want it to be a valid file handle but since it is passed to us from
         // the parent process, we cannot guarantee that it actually is.
         //
@@ -91,7 +277,7 @@ impl std::io::Read for CommsInRaw {
         // [3]: https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-flushfilebuffers
         let status = unsafe {
             windows_sys::Win32::Storage::FileSystem::ReadFile(
-                self.handle,
+                self.as_raw_handle(),
                 buf.as_mut_ptr(),
                 buf_len,
                 count.as_mut_ptr(),
@@ -115,12 +301,16 @@ impl std::io::Read for CommsInRaw {
 impl std::io::Write for CommsOutRaw {
 
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(event) = &self.event {
+            return write_overlapped(self.as_raw_handle(), event, buf);
+        }
+
         let buf_len = u32::try_from(buf.len())
             .map_err(|_| std::io::ErrorKind::InvalidInput)?;
 
         let mut count = std::mem::MaybeUninit::uninit();
 
-        // SAFETY: We do not have any assumptons on `self.handle`. We usually
+        // SAFETY: We do not have any assumptons on the handle. We usually
         // want it to be a valid file handle but since it is passed to us from
         // the parent process, we cannot guarantee that it actually is.
         //
@@ -142,7 +332,7 @@ impl std::io::Write for CommsOutRaw {
         // [3]: https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-flushfilebuffers
         let status = unsafe {
             windows_sys::Win32::Storage::FileSystem::WriteFile(
-                self.handle,
+                self.as_raw_handle(),
                 buf.as_ptr(),
                 buf_len,
                 count.as_mut_ptr(),
@@ -162,8 +352,26 @@ impl std::io::Write for CommsOutRaw {
         Ok(count as usize)
     }
 
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        // `WriteFileGather` works only with page-aligned buffers on handles
+        // opened without buffering, so we gather the buffers ourselves to still
+        // write them with a single call.
+        let mut nonempty = bufs.iter().filter(|buf| !buf.is_empty());
+        match (nonempty.next(), nonempty.next()) {
+            (None, _) => Ok(0),
+            (Some(buf), None) => self.write(buf),
+            (Some(_), Some(_)) => {
+                let buf = bufs.iter()
+                    .flat_map(|buf| buf.iter().copied())
+                    .collect::<Vec<u8>>();
+
+                self.write(&buf)
+            }
+        }
+    }
+
     fn flush(&mut self) -> std::io::Result<()> {
-        // SAFETY: We do not have any assumptons on `self.handle`. We usually
+        // SAFETY: We do not have any assumptons on the handle. We usually
         // want it to be a valid file handle but since it is passed to use from
         // the parent process, we cannot guarantee that it actually is.
         //
@@ -174,7 +382,7 @@ impl std::io::Write for CommsOutRaw {
         // [1]: https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-flushfilebuffers
         let status = unsafe {
             windows_sys::Win32::Storage::FileSystem::FlushFileBuffers(
-                self.handle,
+                self.as_raw_handle(),
             )
         };
 
@@ -186,23 +394,323 @@ impl std::io::Write for CommsOutRaw {
     }
 }
 
-/// Retrieves a file handle specified in the given environment variable.
-fn env_var_handle<K>(key: K) -> Result<windows_sys::Win32::Foundation::HANDLE, CommsEnvError>
-where
-    K: AsRef<std::ffi::OsStr>,
-{
-    match std::env::var(key) {
-        Ok(string) => match string.parse::<usize>() {
-            Ok(handle) => Ok(handle as windows_sys::Win32::Foundation::HANDLE),
-            Err(_) => Err(CommsEnvError {
-                repr: CommsEnvErrorRepr::NotParsable(string.into()),
-            }),
+/// Takes ownership of the handle specified in the given environment variable.
+fn owned_env_channel(key: &str) -> Result<OwnedHandle, CommsEnvError> {
+    let handle = crate::env::channel(key)?;
+
+    let mut flags = 0;
+
+    // SAFETY: Invalid handles make the call fail [1], there are no other
+    // assumptions on the handle. We pass a valid pointer for the flags.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/handleapi/nf-handleapi-gethandleinformation
+    let status = unsafe {
+        windows_sys::Win32::Foundation::GetHandleInformation(handle, &mut flags)
+    };
+    if handle.is_null() || status == windows_sys::Win32::Foundation::FALSE {
+        return Err(CommsEnvError {
+            repr: CommsEnvErrorRepr::Closed((handle as usize).to_string().into()),
+        });
+    }
+
+    // SAFETY: We verified above that the handle is open. It is passed to us by
+    // the parent Fleetspeak process exclusively for the communication channel,
+    // so nothing else in the process owns it.
+    Ok(unsafe { OwnedHandle::from_raw_handle(handle) })
+}
+
+/// Returns the raw handle of a channel (null if it has been closed).
+///
+/// Operations on closed channels are not rejected upfront but simply fail with
+/// `ERROR_INVALID_HANDLE`.
+fn raw_handle(handle: &Option<OwnedHandle>) -> windows_sys::Win32::Foundation::HANDLE {
+    handle.as_ref().map_or(std::ptr::null_mut(), |handle| handle.as_raw_handle())
+}
+
+/// Closes the handle (if any).
+///
+/// Unlike dropping the handle, this reports errors of the close call.
+fn close_handle(handle: Option<OwnedHandle>) -> std::io::Result<()> {
+    let handle = match handle {
+        Some(handle) => handle.into_raw_handle(),
+        None => return Ok(()),
+    };
+
+    // SAFETY: The handle was owned by the channel and ownership has been
+    // released above, so it is closed exactly once. Invalid handles make the
+    // call fail [1]. We verify the result afterwards.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/handleapi/nf-handleapi-closehandle
+    let status = unsafe {
+        windows_sys::Win32::Foundation::CloseHandle(handle)
+    };
+    if status == windows_sys::Win32::Foundation::FALSE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// A manual-reset event signaled on completion of overlapped operations.
+struct Event {
+    handle: OwnedHandle,
+}
+
+impl Event {
+
+    /// Creates a new event that is not signaled.
+    fn new() -> std::io::Result<Event> {
+        use windows_sys::Win32::Foundation::{FALSE, TRUE};
+
+        // SAFETY: We create an anonymous manual-reset event with the default
+        // security attributes as described in the docs [1]. We verify the
+        // result afterwards.
+        //
+        // [1]: https://learn.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-createeventw
+        let handle = unsafe {
+            windows_sys::Win32::System::Threading::CreateEventW(
+                std::ptr::null(),
+                TRUE,
+                FALSE,
+                std::ptr::null(),
+            )
+        };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // SAFETY: The handle has been just created by us and is not owned by
+        // anything else.
+        Ok(Event {
+            handle: unsafe { OwnedHandle::from_raw_handle(handle) },
+        })
+    }
+}
+
+/// Returns an event for overlapped operations if the handle supports them.
+///
+/// Anonymous pipes (which the Fleetspeak client uses) are opened for
+/// synchronous I/O only, in which case `None` is returned. Overlapped I/O is
+/// used only with handles opened with `FILE_FLAG_OVERLAPPED` (e.g. named pipes
+/// created by a custom launcher).
+fn overlapped_event(handle: windows_sys::Win32::Foundation::HANDLE) -> Option<Event> {
+    if !is_overlapped(handle) {
+        return None;
+    }
+
+    match Event::new() {
+        Ok(event) => Some(event),
+        Err(error) => {
+            log::warn!("failed to create overlapped I/O event: {error}");
+            None
         }
-        Err(std::env::VarError::NotPresent) => Err(CommsEnvError {
-            repr: CommsEnvErrorRepr::NotSpecified,
-        }),
-        Err(std::env::VarError::NotUnicode(string)) => Err(CommsEnvError {
-            repr: CommsEnvErrorRepr::NotParsable(string),
-        }),
     }
 }
+
+/// Determines whether the handle has been opened for overlapped I/O.
+fn is_overlapped(handle: windows_sys::Win32::Foundation::HANDLE) -> bool {
+    use windows_sys::Wdk::Storage::FileSystem::*;
+
+    let mut status = std::mem::MaybeUninit::uninit();
+    let mut info = FILE_MODE_INFORMATION {
+        Mode: 0,
+    };
+
+    // SAFETY: We do not have any assumptions on `handle` (see the comment in
+    // the `read` method for more details), invalid handles are reported with
+    // an error status. We pass valid pointers to the status block and to the
+    // information structure together with its size as described in the docs
+    // [1]. We verify the result afterwards.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows-hardware/drivers/ddi/ntifs/nf-ntifs-ntqueryinformationfile
+    let status = unsafe {
+        NtQueryInformationFile(
+            handle,
+            status.as_mut_ptr(),
+            (&mut info as *mut FILE_MODE_INFORMATION).cast(),
+            std::mem::size_of::<FILE_MODE_INFORMATION>() as u32,
+            FileModeInformation,
+        )
+    };
+
+    // Negative statuses indicate errors. In such case we stay on the safe side
+    // and use synchronous I/O.
+    if status < 0 {
+        return false;
+    }
+
+    info.Mode & (FILE_SYNCHRONOUS_IO_ALERT | FILE_SYNCHRONOUS_IO_NONALERT) == 0
+}
+
+/// Reads data into `buf` using overlapped I/O.
+///
+/// If `timeout` is given and elapses before the read completes, the read is
+/// cancelled and an error of the [`WouldBlock`] kind is returned.
+///
+/// [`WouldBlock`]: std::io::ErrorKind::WouldBlock
+fn read_overlapped(
+    handle: windows_sys::Win32::Foundation::HANDLE,
+    event: &Event,
+    buf: &mut [u8],
+    timeout: Option<std::time::Duration>,
+) -> std::io::Result<usize> {
+    let buf_len = u32::try_from(buf.len())
+        .map_err(|_| std::io::ErrorKind::InvalidInput)?;
+
+    // SAFETY: All-zero is a valid value of the structure.
+    let mut overlapped: windows_sys::Win32::System::IO::OVERLAPPED = unsafe {
+        std::mem::zeroed()
+    };
+    overlapped.hEvent = event.handle.as_raw_handle();
+
+    // SAFETY: See the comment in the `read` method for the assumptions on the
+    // handle. We pass a valid buffer with its length and a valid overlapped
+    // structure with an event as described in the docs [1]. Both stay valid
+    // until the operation completes, as we always wait for the result below.
+    // We verify the status after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-readfile
+    let status = unsafe {
+        windows_sys::Win32::Storage::FileSystem::ReadFile(
+            handle,
+            buf.as_mut_ptr(),
+            buf_len,
+            std::ptr::null_mut(),
+            &mut overlapped,
+        )
+    };
+
+    complete_overlapped(handle, status, &overlapped, timeout)
+}
+
+/// Writes data from `buf` using overlapped I/O.
+fn write_overlapped(
+    handle: windows_sys::Win32::Foundation::HANDLE,
+    event: &Event,
+    buf: &[u8],
+) -> std::io::Result<usize> {
+    let buf_len = u32::try_from(buf.len())
+        .map_err(|_| std::io::ErrorKind::InvalidInput)?;
+
+    // SAFETY: All-zero is a valid value of the structure.
+    let mut overlapped: windows_sys::Win32::System::IO::OVERLAPPED = unsafe {
+        std::mem::zeroed()
+    };
+    overlapped.hEvent = event.handle.as_raw_handle();
+
+    // SAFETY: See the comment in the `write` method for the assumptions on the
+    // handle. We pass a valid buffer with its length and a valid overlapped
+    // structure with an event as described in the docs [1]. Both stay valid
+    // until the operation completes, as we always wait for the result below.
+    // We verify the status after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-writefile
+    let status = unsafe {
+        windows_sys::Win32::Storage::FileSystem::WriteFile(
+            handle,
+            buf.as_ptr(),
+            buf_len,
+            std::ptr::null_mut(),
+            &mut overlapped,
+        )
+    };
+
+    complete_overlapped(handle, status, &overlapped, None)
+}
+
+/// Waits for the overlapped operation started with the given `status` to
+/// complete and returns the number of transferred bytes.
+///
+/// If `timeout` is given and elapses before the operation completes, it is
+/// cancelled and an error of the [`WouldBlock`] kind is returned.
+///
+/// [`WouldBlock`]: std::io::ErrorKind::WouldBlock
+fn complete_overlapped(
+    handle: windows_sys::Win32::Foundation::HANDLE,
+    status: windows_sys::Win32::Foundation::BOOL,
+    overlapped: &windows_sys::Win32::System::IO::OVERLAPPED,
+    timeout: Option<std::time::Duration>,
+) -> std::io::Result<usize> {
+    use windows_sys::Win32::Foundation::*;
+    use windows_sys::Win32::System::Threading::{WaitForSingleObject, INFINITE};
+
+    if status == FALSE {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+            return Err(error);
+        }
+    }
+
+    let millis = match timeout {
+        // We round up so that waiting for a sub-millisecond duration does not
+        // turn into a non-blocking check and make sure not to wait forever.
+        Some(timeout) => u32::try_from(timeout.as_nanos().div_ceil(1_000_000))
+            .unwrap_or(INFINITE - 1)
+            .min(INFINITE - 1),
+        None => INFINITE,
+    };
+
+    // SAFETY: The event is valid as long as `overlapped` refers to it. We
+    // verify the result afterwards.
+    let wait = unsafe {
+        WaitForSingleObject(overlapped.hEvent, millis)
+    };
+
+    let mut error = None;
+    if wait != WAIT_OBJECT_0 {
+        if wait == WAIT_FAILED {
+            error = Some(std::io::Error::last_os_error());
+        }
+
+        // SAFETY: We cancel the operation we have started with the given
+        // overlapped structure as described in the docs [1]. If it has already
+        // completed, the call fails, which is fine as the result is retrieved
+        // below anyway.
+        //
+        // [1]: https://learn.microsoft.com/en-us/windows/win32/fileio/cancelioex-func
+        unsafe {
+            windows_sys::Win32::System::IO::CancelIoEx(handle, overlapped);
+        }
+    }
+
+    let mut count = std::mem::MaybeUninit::uninit();
+
+    // SAFETY: We pass the overlapped structure of the operation we started
+    // and a valid pointer for the number of transferred bytes as described in
+    // the docs [1]. Because we ask to wait for the completion, the buffer and
+    // the overlapped structure are no longer used by the system once this
+    // returns (even if the operation has been cancelled). We verify the status
+    // after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/ioapiset/nf-ioapiset-getoverlappedresult
+    let status = unsafe {
+        windows_sys::Win32::System::IO::GetOverlappedResult(
+            handle,
+            overlapped,
+            count.as_mut_ptr(),
+            TRUE,
+        )
+    };
+
+    if let Some(error) = error {
+        return Err(error);
+    }
+
+    if status == FALSE {
+        let error = std::io::Error::last_os_error();
+
+        // Operations cancelled because of the timeout are reported as such. We
+        // leave cancellations from elsewhere (e.g. by a cancel token) intact.
+        if wait == WAIT_TIMEOUT && error.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        return Err(error);
+    }
+
+    // SAFETY: We verified that the call to `GetOverlappedResult` succeeded
+    // and thus `count` is guaranteed to be initialized.
+    let count = unsafe { count.assume_init() };
+
+    Ok(count as usize)
+}
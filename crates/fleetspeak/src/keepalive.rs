@@ -0,0 +1,184 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::Status;
+
+/// Starts a background probe detecting whether the connection is still alive.
+///
+/// Normally, a service learns that the Fleetspeak client stopped reading from
+/// the communication channel only when a subsequent send blocks or fails. This
+/// might be long after the problem started. The probe makes the detection
+/// proactive: every `interval` it submits a heartbeat signal to the background
+/// writer and waits at most `deadline` for it to be written. If the heartbeat
+/// is not written in time, the connection is considered stalled and its
+/// [status] becomes [`Status::Degraded`] until a subsequent write (e.g. of the
+/// next heartbeat) succeeds. If writing the heartbeat fails, the connection is
+/// declared dead and its status becomes [`Status::Closed`].
+///
+/// Calling this function again while the probe is running only updates its
+/// parameters. The probe stops once the connection is declared dead.
+///
+/// [status]: crate::status
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fleetspeak::start_keepalive(Duration::from_secs(30), Duration::from_secs(10));
+/// ```
+pub fn start_keepalive(interval: Duration, deadline: Duration) {
+    let mut probe = PROBE.lock().expect("poisoned keepalive mutex");
+
+    match &mut *probe {
+        Some(probe) => {
+            probe.interval = interval;
+            probe.deadline = deadline;
+        }
+        None => {
+            let generation = GENERATION.fetch_add(1, Ordering::SeqCst);
+            *probe = Some(Probe { interval, deadline, generation });
+
+            let thread = std::thread::spawn(move || run(generation));
+            *THREAD.lock().expect("poisoned keepalive mutex") = Some(thread);
+        }
+    }
+}
+
+/// Stops the keepalive probe (if it is running) and waits for it to exit.
+pub(crate) fn stop() {
+    let mut probe = PROBE.lock().expect("poisoned keepalive mutex");
+    *probe = None;
+    let thread = THREAD.lock().expect("poisoned keepalive mutex").take();
+    drop(probe);
+
+    WAKE.notify_all();
+
+    if let Some(thread) = thread {
+        if thread.join().is_err() {
+            log::error!("keepalive thread panicked");
+        }
+    }
+}
+
+/// Restarts the keepalive probe (if it was running) in a child process after
+/// `fork`.
+///
+/// If `restart` is `false`, the probe is stopped instead.
+#[cfg(target_family = "unix")]
+pub(crate) fn after_fork(restart: bool) -> std::io::Result<()> {
+    let mut probe = match PROBE.try_lock() {
+        Ok(probe) => probe,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "keepalive probe locked during fork"
+        })),
+    };
+
+    let mut thread = match THREAD.try_lock() {
+        Ok(thread) => thread,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "keepalive thread locked during fork"
+        })),
+    };
+
+    // The probe thread does not exist in the child process, so its handle must
+    // not be joined nor detached.
+    std::mem::forget(thread.take());
+
+    if !restart {
+        *probe = None;
+    }
+    if let Some(probe) = *probe {
+        *thread = Some(std::thread::spawn(move || run(probe.generation)));
+    }
+
+    Ok(())
+}
+
+/// Parameters of the keepalive probe.
+#[derive(Clone, Copy)]
+struct Probe {
+    interval: Duration,
+    deadline: Duration,
+    /// Generation of the thread running the probe.
+    ///
+    /// A thread of a probe that has been stopped might still be running (e.g.
+    /// while it is being joined) when a new one is started, so threads check
+    /// it to only ever act on their own probe.
+    generation: u64,
+}
+
+/// Body of the keepalive probe thread of the given `generation`.
+fn run(generation: u64) {
+    let current = |probe: &Option<Probe>| {
+        probe.filter(|probe| probe.generation == generation)
+    };
+
+    loop {
+        let guard = PROBE.lock().expect("poisoned keepalive mutex");
+        let interval = match current(&guard) {
+            Some(probe) => probe.interval,
+            None => return,
+        };
+
+        let (guard, _) = WAKE.wait_timeout_while(guard, interval, |probe| current(probe).is_some())
+            .expect("poisoned keepalive mutex");
+
+        // The probe might have been stopped (and possibly replaced by a new
+        // one) while we were waiting.
+        let probe = match current(&guard) {
+            Some(probe) => probe,
+            None => return,
+        };
+        drop(guard);
+
+        match crate::writer::heartbeat(probe.deadline) {
+            Some(Ok(())) => {
+                crate::status::set(Status::Connected);
+                continue;
+            }
+            Some(Err(error)) => {
+                log::error!("keepalive heartbeat failed: {error}");
+            }
+            None => {
+                // The client might just be slow, so this is not fatal: the
+                // next successful write marks the connection as recovered.
+                log::warn!("keepalive heartbeat not written within {:?}", probe.deadline);
+                crate::status::set(Status::Degraded);
+                continue;
+            }
+        }
+
+        crate::status::set(Status::Closed);
+
+        let mut probe = PROBE.lock().expect("poisoned keepalive mutex");
+        // If the probe has been stopped in the meantime, both the probe and the
+        // thread handle might belong to a new one already, so we leave them be.
+        if current(&probe).is_some() {
+            *probe = None;
+            // Nobody is going to join the thread anymore, so we just detach it.
+            // The probe mutex is held so that this does not race with a new
+            // probe being started.
+            drop(THREAD.lock().expect("poisoned keepalive mutex").take());
+        }
+        drop(probe);
+
+        return;
+    }
+}
+
+static PROBE: Mutex<Option<Probe>> = Mutex::new(None);
+
+/// Generation of the next probe thread to be started.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Notified when the probe is stopped.
+static WAKE: Condvar = Condvar::new();
+
+static THREAD: Mutex<Option<std::thread::JoinHandle<()>>> = Mutex::new(None);
@@ -9,7 +9,8 @@
 //! services. Each of these functions operates on a global connection object
 //! that is lazily established. If this global connection cannot be established,
 //! the library will panic (because without this connection Fleetspeak will shut
-//! the service down anyway).
+//! the service down anyway). Services that want to handle this gracefully can
+//! establish the connection eagerly with [`init`](fn@crate::init).
 //!
 //! Note that each service should send startup information upon its inception
 //! and continue to heartbeat from time to time to notify the Fleetspeak client
@@ -17,12 +18,121 @@
 //!
 //! [Fleetspeak]: https://github.com/google/fleetspeak
 
+mod cancel;
+mod connection;
+mod crash;
+mod dispatcher;
+mod heartbeats;
+mod hex;
+mod init;
 mod io;
+mod keepalive;
+mod metadata;
 
-use std::sync::Mutex;
+#[cfg(target_family = "unix")]
+mod daemon;
+
+pub mod any;
+pub mod capture;
+pub mod chunking;
+pub mod compression;
+pub mod crypto;
+pub mod env;
+pub mod logger;
+pub mod metrics;
+pub mod signing;
+mod ping;
+mod poll;
+mod privileges;
+mod reader;
+mod runner;
+mod scope;
+mod shutdown;
+mod signal;
+mod startup;
+mod status;
+mod system;
+mod transport;
+mod typed;
+mod writer;
+
+#[cfg(feature = "audit")]
+pub mod audit;
+
+#[cfg(feature = "serde")]
+pub mod payload;
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+
+#[cfg(feature = "codec")]
+pub mod codec;
+
+#[cfg(feature = "standalone")]
+mod standalone;
+
+#[cfg(feature = "test-util")]
+pub mod test;
+
+#[cfg(feature = "transfer")]
+pub mod transfer;
+
+#[cfg(all(target_os = "linux", feature = "memfd"))]
+pub mod memfd;
+
+#[cfg(all(target_family = "windows", feature = "etw"))]
+pub mod etw;
+
+#[cfg(target_family = "windows")]
+pub mod service;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex, MutexGuard, OnceLock, TryLockError};
 use std::time::{Duration, Instant};
 
-use lazy_static::lazy_static;
+#[cfg(target_family = "unix")]
+pub use self::daemon::{after_fork, disown_after_fork, prepare_exec};
+pub use self::cancel::{receive_cancellable, CancelToken};
+pub use self::connection::Connection;
+pub use self::crash::{install_panic_hook, CRASH_REPORT_KIND};
+pub use self::dispatcher::Dispatcher;
+pub use self::heartbeats::{start_heartbeats, stop_heartbeats};
+pub use self::init::{init, init_with, is_initialized, set_buffer_capacity, InitError};
+#[cfg(target_family = "unix")]
+pub use self::init::init_socket;
+pub use self::io::{CommsEnvError, CommsInRaw, CommsOutRaw, FrameError};
+pub use self::keepalive::start_keepalive;
+pub use self::metadata::{Metadata, Priority};
+pub use self::metrics::{stats, ConnectorStats};
+pub use self::ping::{answer_pings, PONG_KIND};
+pub use self::poll::poll_handle;
+#[cfg(target_family = "unix")]
+pub use self::privileges::drop_privileges_to;
+#[cfg(target_family = "windows")]
+pub use self::privileges::drop_token_privileges;
+pub use self::runner::{run, Service};
+pub use self::scope::{scope, Scope};
+pub use self::shutdown::{report_shutdown, request_restart, shutdown, ShutdownReason, RESTART_EXIT_CODE, SHUTDOWN_REPORT_KIND};
+pub use self::signal::{receive_until_shutdown, Received};
+pub use self::startup::{startup_with, StartupOptions};
+pub use self::status::{close_error, status, Status};
+pub use self::system::{on_system_request, SystemRequest};
+#[cfg(feature = "tokio")]
+pub use self::status::status_watch;
+pub use self::typed::{receive_msg, send_msg, Packet};
+#[cfg(feature = "prost")]
+pub use self::typed::{receive_proto, send_proto};
+pub use self::writer::{limit_send_queue, on_send_expired, QueueFullPolicy, SendClass, SendOptions, SendTimeoutError};
+
+/// Version of this library.
+///
+/// The version is reported to the Fleetspeak client along with the startup
+/// information (see [`startup`]) and included in [shutdown reports], so that
+/// operators can tell which connector versions are deployed across the fleet.
+/// Services can include it in their own diagnostics as well.
+///
+/// [shutdown reports]: crate::report_shutdown
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// A Fleetspeak client communication message.
 ///
@@ -30,6 +140,10 @@ use lazy_static::lazy_static;
 /// Fleetspeak. This is a simplified version of the underlying Protocol Buffers
 /// message that exposes too much irrelevant fields and makes the protocol easy
 /// to misuse.
+///
+/// New fields might be added in the future, so it is advised to construct
+/// messages with `..Default::default()` for the fields that are not relevant.
+#[derive(Debug, Default)]
 pub struct Message {
     /// A name of the server-side service that sent or should receive the data.
     pub service: String,
@@ -37,6 +151,203 @@ pub struct Message {
     pub kind: Option<String>,
     /// The data to sent to the specified service.
     pub data: Vec<u8>,
+    /// Priority with which Fleetspeak should deliver the message.
+    ///
+    /// Fleetspeak sends messages of higher priority first, so marking large
+    /// messages (e.g. file uploads) as [low priority] prevents them from
+    /// delaying urgent ones.
+    ///
+    /// [low priority]: Priority::Low
+    pub priority: Priority,
+    /// Key-value annotations attached to the message.
+    ///
+    /// Annotations are not interpreted by Fleetspeak itself, but they can be
+    /// used by the server-side pipeline, e.g. to correlate messages of a flow.
+    pub annotations: Vec<(String, String)>,
+    /// Whether the message is background traffic.
+    ///
+    /// Background messages (e.g. bulk telemetry) are deprioritized by the
+    /// Fleetspeak client and sent only when there is no other traffic to
+    /// deliver.
+    pub background: bool,
+    /// Type URL of the data (if it is a serialized Protocol Buffers message).
+    ///
+    /// This allows services to dispatch on the type of the payload rather than
+    /// on its `kind`. See also [`send_msg`] and [`receive_msg`] which fill it
+    /// in and verify it automatically.
+    pub data_type_url: Option<String>,
+}
+
+impl Message {
+
+    /// Maximum number of data bytes included in the [`preview`].
+    ///
+    /// [`preview`]: Message::preview
+    pub const PREVIEW_LEN: usize = 64;
+
+    /// Returns a short, human-readable summary of the message.
+    ///
+    /// The summary contains the service, the kind, the size of the data and a
+    /// prefix of at most [`PREVIEW_LEN`] bytes of the data itself. If the prefix
+    /// is valid UTF-8, it is included as an escaped string literal. Otherwise,
+    /// it is included in hexadecimal form. The summary is always a single line,
+    /// so it is safe to include it in logs and error messages.
+    ///
+    /// [`PREVIEW_LEN`]: Message::PREVIEW_LEN
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let message = fleetspeak::Message {
+    ///     service: String::from("example"),
+    ///     kind: Some(String::from("greeting")),
+    ///     data: String::from("Hello, world!").into_bytes(),
+    ///     ..Default::default()
+    /// };
+    ///
+    /// assert_eq! {
+    ///     message.preview(),
+    ///     "service: example, kind: greeting, size: 13, data: \"Hello, world!\"",
+    /// };
+    /// ```
+    pub fn preview(&self) -> String {
+        use std::fmt::Write as _;
+
+        let mut preview = format! {
+            "service: {}, kind: {}, size: {}, data: ",
+            self.service.escape_debug(),
+            self.kind.as_deref().unwrap_or("-").escape_debug(),
+            self.data.len(),
+        };
+
+        let len = std::cmp::min(self.data.len(), Self::PREVIEW_LEN);
+        let prefix = &self.data[..len];
+
+        // A truncated prefix may end in the middle of a multi-byte character.
+        // In such case we cut it at the last valid character boundary instead
+        // of falling back to the hexadecimal form.
+        let string = match std::str::from_utf8(prefix) {
+            Ok(string) => Some(string),
+            Err(error) if error.error_len().is_none() && len < self.data.len() => {
+                std::str::from_utf8(&prefix[..error.valid_up_to()]).ok()
+            }
+            Err(_) => None,
+        };
+
+        match string {
+            Some(string) => {
+                write!(preview, "{string:?}").expect("failed to format message preview");
+            }
+            None => preview.push_str(&crate::hex::encode(prefix)),
+        }
+
+        if len < self.data.len() {
+            preview.push_str("...");
+        }
+
+        preview
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Message {
+
+    /// Takes the data out of the message as [`Bytes`].
+    ///
+    /// The data is not copied, so the returned buffer can be sliced and handed
+    /// over to other subsystems cheaply. The data of the message is left empty.
+    ///
+    /// [`Bytes`]: bytes::Bytes
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// let mut message = fleetspeak::receive();
+    ///
+    /// let data = message.take_bytes();
+    /// let (header, body) = (data.slice(..4), data.slice(4..));
+    /// ```
+    pub fn take_bytes(&mut self) -> bytes::Bytes {
+        bytes::Bytes::from(std::mem::take(&mut self.data))
+    }
+
+    /// Sets the data of the message from [`Bytes`].
+    ///
+    /// The buffer is reused without copying if it is not shared with other
+    /// handles and it was created from a vector (e.g. from one returned by
+    /// [`take_bytes`]). Otherwise, the data is copied.
+    ///
+    /// [`Bytes`]: bytes::Bytes
+    /// [`take_bytes`]: Message::take_bytes
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let data = bytes::Bytes::from(b"Hello, world!".to_vec());
+    ///
+    /// let message = fleetspeak::Message {
+    ///     service: String::from("example"),
+    ///     ..Default::default()
+    /// }.with_bytes(data);
+    ///
+    /// assert_eq!(message.data, b"Hello, world!");
+    /// ```
+    pub fn with_bytes(mut self, data: bytes::Bytes) -> Message {
+        self.data = Vec::from(data);
+        self
+    }
+}
+
+/// Eagerly establishes the connection for a service that is about to sandbox
+/// itself.
+///
+/// Normally, the connection to the Fleetspeak client is established lazily on
+/// first use. This is not desirable for services that restrict their own
+/// capabilities (e.g. with seccomp, `pledge` or AppContainer), as establishing
+/// the connection requires reading environment variables and (potentially)
+/// opening additional resources. This function performs all of that upfront:
+/// it parses the environment, executes the handshake, allocates the I/O
+/// buffers and registers optional diagnostic facilities.
+///
+/// After this function returns, the library guarantees to use only a limited
+/// set of system calls:
+///
+///   * On Unix, `read` and `write` on the descriptors given by the Fleetspeak
+///     client, `futex` for synchronization, `getpid` (only in [`startup`]) and
+///     whatever the memory allocator needs (e.g. `brk`, `mmap` and `munmap`).
+///   * On Windows, `ReadFile`, `WriteFile` and `FlushFileBuffers` on the handles
+///     given by the Fleetspeak client and, if the `etw` feature is enabled,
+///     ETW event writes.
+///
+/// The background reader and writer threads are started here as well, so that
+/// receiving and sending messages does not spawn them later.
+///
+/// The only exception is [`receive_with_heartbeat`] which spawns a thread (on
+/// its first use) and thus requires the thread creation family of system calls
+/// (e.g. `clone` and `mprotect` on Linux). Similarly, rotating the [audit log]
+/// (if enabled) requires renaming and opening files. Sandboxed services should
+/// either allow these or avoid the features that use them.
+///
+/// [`startup`]: crate::startup
+/// [`receive_with_heartbeat`]: crate::receive_with_heartbeat
+/// [audit log]: crate::audit
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::init_for_sandbox();
+///
+/// // Apply sandboxing restrictions here.
+///
+/// fleetspeak::startup("0.0.1");
+/// ```
+pub fn init_for_sandbox() {
+    LazyLock::force(&CONNECTION);
+    crate::reader::start();
+    crate::writer::start();
+
+    #[cfg(all(target_family = "windows", feature = "etw"))]
+    crate::etw::init();
 }
 
 /// Sends a heartbeat signal to the Fleetspeak client.
@@ -46,8 +357,38 @@ pub struct Message {
 ///
 /// The exact frequency of the required heartbeat is defined in the service
 /// configuration file.
+///
+/// This function does not wait for other threads to finish writing messages
+/// (which can take a while for big ones). If the output channel is in use, the
+/// heartbeat is written by the thread using it as soon as it is done with its
+/// message. In such case an I/O failure is reported by that thread instead.
 pub fn heartbeat() {
-    execute(&CONNECTION.output, |buf| self::io::write_heartbeat(buf))
+    if let Err(error) = try_heartbeat() {
+        panic!("{error}");
+    }
+}
+
+/// Sends a heartbeat signal to the Fleetspeak client, returning an error on
+/// failure.
+///
+/// This is a variant of [`heartbeat`] that does not panic if the heartbeat
+/// cannot be written. The connection should be considered broken after an
+/// error: its [status] becomes [`Status::Closed`].
+///
+/// [status]: crate::status
+///
+/// # Examples
+///
+/// ```no_run
+/// if let Err(error) = fleetspeak::try_heartbeat() {
+///     eprintln!("failed to heartbeat: {error}");
+/// }
+/// ```
+pub fn try_heartbeat() -> Result<(), WriteError> {
+    request_heartbeat().map_err(|error| {
+        close(&error);
+        WriteError::Output(error)
+    })
 }
 
 /// Sends a heartbeat signal to the Fleetspeak client but no more frequently
@@ -61,9 +402,7 @@ pub fn heartbeat() {
 ///
 /// [`heartbeat`]: crate::heartbeat
 pub fn heartbeat_with_throttle(rate: Duration) {
-    lazy_static! {
-        static ref LAST_HEARTBEAT: Mutex<Option<Instant>> = Mutex::new(None);
-    }
+    static LAST_HEARTBEAT: Mutex<Option<Instant>> = Mutex::new(None);
 
     let mut last_heartbeat = LAST_HEARTBEAT.lock()
         .expect("poisoned heartbeat mutex");
@@ -88,9 +427,15 @@ pub fn heartbeat_with_throttle(rate: Duration) {
 /// killed.
 ///
 /// The `version` string should contain a self-reported version of the service.
-/// This data is used primarily for statistics.
+/// This data is used primarily for statistics. The version of this library and
+/// its enabled features are reported alongside as message annotations.
+///
+/// See [`startup_with`] for reporting more information about the service.
 pub fn startup(version: &str) {
-    execute(&CONNECTION.output, |buf| self::io::write_startup(buf, version))
+    startup_with(StartupOptions {
+        version: String::from(version),
+        ..Default::default()
+    });
 }
 
 /// Sends the message to the Fleetspeak server.
@@ -101,7 +446,8 @@ pub fn startup(version: &str) {
 /// is delivered to.
 ///
 /// In case of any I/O failure or malformed message (e.g. due to encoding
-/// problems), an error is reported.
+/// problems), this function will panic. It is a thin wrapper around
+/// [`try_send`] for services that cannot do anything about such failures.
 ///
 /// # Examples
 ///
@@ -112,10 +458,291 @@ pub fn startup(version: &str) {
 ///     service: String::from("example"),
 ///     kind: None,
 ///     data: String::from("Hello, world!").into_bytes(),
+///     ..Default::default()
 /// });
 /// ```
 pub fn send(message: Message) {
-    execute(&CONNECTION.output, |buf| self::io::write_message(buf, message))
+    if let Err(error) = try_send(message) {
+        panic!("{error}");
+    }
+}
+
+/// Sends the message to the Fleetspeak server, returning an error on failure.
+///
+/// This is a variant of [`send`] that does not panic if the message cannot be
+/// written. This allows long-running services to react to the failure (e.g. by
+/// flushing their state) before exiting. Note that the connection should still
+/// be considered broken after an error: its [status] becomes [`Status::Closed`].
+/// The only exception are messages refused before anything is written (see
+/// [`WriteError::Rejected`]), e.g. because they exceed the size limit
+/// advertised by the client.
+///
+/// The message is written by the background writer thread (in the order in
+/// which it was sent, after messages [queued] earlier), this function waits
+/// until it is done. This way, concurrent senders never contend on the output
+/// channel itself.
+///
+/// [status]: crate::status
+/// [queued]: crate::send_queued
+///
+/// # Examples
+///
+/// ```no_run
+/// use fleetspeak::Message;
+///
+/// let result = fleetspeak::try_send(Message {
+///     service: String::from("example"),
+///     kind: None,
+///     data: String::from("Hello, world!").into_bytes(),
+///     ..Default::default()
+/// });
+///
+/// if let Err(error) = result {
+///     eprintln!("failed to send the message: {error}");
+///     std::process::exit(1);
+/// }
+/// ```
+pub fn try_send(message: Message) -> Result<(), WriteError> {
+    crate::writer::send_blocking(message)
+}
+
+/// Sends all the messages to the Fleetspeak server at once.
+///
+/// This is a variant of [`send`] for services that emit bursts of messages
+/// (e.g. many small results). All the messages are encoded first and then
+/// written to the channel in one go, flushing it only once at the end. This
+/// saves a system call (and a wake-up of the Fleetspeak client) per message.
+///
+/// The messages are handed over to the background writer as a single batch:
+/// like a message sent with [`send`], the batch is written after the regular
+/// messages queued before it. Within the batch, messages are written in the
+/// order of iteration and no other message can be written in between them.
+///
+/// If one of the messages is refused (e.g. because it exceeds the size limit
+/// advertised by the client), none of them is written and the error is
+/// returned. In case of any I/O failure, this function will panic. Note that
+/// some of the messages might have been written by then.
+///
+/// [`send`]: crate::send
+///
+/// # Examples
+///
+/// ```no_run
+/// use fleetspeak::Message;
+///
+/// fleetspeak::send_all((0..16).map(|i| Message {
+///     service: String::from("example"),
+///     kind: Some(String::from("result")),
+///     data: format!("result #{i}").into_bytes(),
+///     ..Default::default()
+/// })).expect("messages rejected");
+/// ```
+pub fn send_all<I>(messages: I) -> std::io::Result<()>
+where
+    I: IntoIterator<Item = Message>,
+{
+    match crate::writer::send_all(messages.into_iter().collect()) {
+        Ok(()) => Ok(()),
+        Err(WriteError::Rejected(error)) => Err(error),
+        Err(error @ WriteError::Output(_)) => panic!("{error}"),
+    }
+}
+
+/// Sends a raw Protocol Buffers message to the Fleetspeak server.
+///
+/// This is an escape hatch for services that need fields that [`Message`] does
+/// not model (e.g. custom destinations). The message is written as is: none of
+/// the payload transformations (like [compression]) are applied and it is not
+/// validated in any way, so it is up to the caller to fill it in the way the
+/// Fleetspeak client expects.
+///
+/// The message also bypasses the bookkeeping of regular messages: it is not
+/// recorded in the [audit log] nor reported as an ETW event. It is only counted
+/// in the [traffic metrics] (under its message type).
+///
+/// In case of any I/O failure, this function will panic.
+///
+/// [compression]: crate::compression
+/// [audit log]: crate::audit
+/// [traffic metrics]: crate::metrics
+///
+/// # Examples
+///
+/// ```no_run
+/// let mut proto = fleetspeak_proto::common::Message::new();
+/// proto.mut_destination().set_service_name(String::from("example"));
+/// proto.mut_destination().set_client_id(b"custom".to_vec());
+/// proto.set_message_type(String::from("greeting"));
+///
+/// fleetspeak::send_raw(proto);
+/// ```
+pub fn send_raw(proto: fleetspeak_proto::common::Message) {
+    if let Err(error) = deliver_raw(proto) {
+        fail(error);
+    }
+}
+
+/// Sends the message to the Fleetspeak server, giving up after `timeout`.
+///
+/// If the Fleetspeak client stops draining the communication channel, [`send`]
+/// blocks until it does so again (possibly forever). This function waits for
+/// the message to be written at most for the specified `timeout` and returns an
+/// error afterwards, so the service can degrade gracefully instead.
+///
+/// The message is handed over to a background writer, so giving up on waiting
+/// never leaves the channel with a partially written message. If the writer
+/// has not started writing the message yet, it is withdrawn and can be taken
+/// back from the returned error. Otherwise, the message will be delivered in
+/// full once the Fleetspeak client resumes reading.
+///
+/// If the message is refused before anything is written (e.g. because it
+/// exceeds the size limit advertised by the client), an error is returned as
+/// well (see [`SendTimeoutError::is_rejected`]) and the connection can still be
+/// used. In case of any I/O failure, this function will panic.
+///
+/// [`send`]: crate::send
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use fleetspeak::Message;
+///
+/// let message = Message {
+///     service: String::from("example"),
+///     kind: None,
+///     data: String::from("Hello, world!").into_bytes(),
+///     ..Default::default()
+/// };
+///
+/// if let Err(error) = fleetspeak::send_timeout(message, Duration::from_secs(5)) {
+///     eprintln!("failed to send the message: {error}");
+/// }
+/// ```
+pub fn send_timeout(message: Message, timeout: Duration) -> Result<(), SendTimeoutError> {
+    send_timeout_with(message, SendOptions::default(), timeout)
+}
+
+/// Sends the message to the Fleetspeak server with the specified options,
+/// giving up after `timeout`.
+///
+/// This is a variant of [`send_timeout`] that allows to customize how the
+/// message is handled by the background writer. In particular, small control
+/// messages can be given [`SendClass::Control`] class, so that they are written
+/// ahead of large messages queued earlier.
+///
+/// See documentation for the [`send_timeout`] function for more details.
+///
+/// [`send_timeout`]: crate::send_timeout
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use fleetspeak::{Message, SendClass, SendOptions};
+///
+/// let message = Message {
+///     service: String::from("example"),
+///     kind: Some(String::from("ack")),
+///     data: vec![],
+///     ..Default::default()
+/// };
+///
+/// let options = SendOptions {
+///     class: SendClass::Control,
+///     ..Default::default()
+/// };
+///
+/// fleetspeak::send_timeout_with(message, options, Duration::from_secs(5))
+///     .expect("failed to send the acknowledgement");
+/// ```
+pub fn send_timeout_with(message: Message, options: SendOptions, timeout: Duration) -> Result<(), SendTimeoutError> {
+    match crate::writer::send(message, options, timeout) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(error)) => fail(error),
+        Err(error) if error.is_rejected() => Err(error),
+        Err(error) => {
+            crate::status::set(Status::Degraded);
+            Err(error)
+        }
+    }
+}
+
+/// Hands the message over to the background writer without waiting for it to
+/// be sent.
+///
+/// Unlike [`send`], this function never blocks on the output channel: the
+/// message is queued and written by the background writer thread, in the
+/// order in which it was queued. This is useful for services that must not
+/// stall (e.g. because they collect data in real time) while the Fleetspeak
+/// client is slow to drain the channel.
+///
+/// By default, the queue is unbounded. If it has been limited with
+/// [`limit_send_queue`], sending to a full queue behaves according to the
+/// configured [`QueueFullPolicy`]: in particular, an error of the
+/// [`WouldBlock`] kind is returned for [`QueueFullPolicy::WouldBlock`].
+///
+/// Because the caller does not wait, write errors cannot be reported to it.
+/// Instead, a write error closes the connection: it is then reflected by
+/// [`status`] and the error itself is available through [`close_error`]. Call
+/// [`drain`] before exiting so that no queued message is lost.
+///
+/// [`send`]: crate::send
+/// [`limit_send_queue`]: crate::limit_send_queue
+/// [`WouldBlock`]: std::io::ErrorKind::WouldBlock
+/// [`status`]: crate::status
+/// [`close_error`]: crate::close_error
+/// [`drain`]: crate::drain
+///
+/// # Examples
+///
+/// ```no_run
+/// for i in 0..10 {
+///     let result = fleetspeak::send_queued(fleetspeak::Message {
+///         service: String::from("example"),
+///         kind: Some(String::from("sample")),
+///         data: format!("sample #{i}").into_bytes(),
+///         ..Default::default()
+///     });
+///
+///     if let Err(error) = result {
+///         eprintln!("sample #{i} not queued: {error}");
+///     }
+/// }
+///
+/// if fleetspeak::status() == fleetspeak::Status::Closed {
+///     eprintln!("failed to send samples: {:?}", fleetspeak::close_error());
+/// }
+/// ```
+pub fn send_queued(message: Message) -> std::io::Result<()> {
+    crate::writer::send_queued(message)
+}
+
+/// Waits until all the messages handed over to the background writer are
+/// written, giving up after `timeout`.
+///
+/// This is the graceful shutdown path for services that send messages using
+/// [`send_queued`], [`send_timeout`] or [`send_timeout_with`]: calling it
+/// before exiting ensures that no queued message is lost. Returns `false` if
+/// some messages are still pending when the timeout elapses.
+///
+/// [`send_queued`]: crate::send_queued
+/// [`send_timeout`]: crate::send_timeout
+/// [`send_timeout_with`]: crate::send_timeout_with
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// if !fleetspeak::drain(Duration::from_secs(10)) {
+///     eprintln!("not all messages were sent before shutdown");
+/// }
+/// ```
+pub fn drain(timeout: Duration) -> bool {
+    crate::writer::drain(Instant::now() + timeout)
 }
 
 /// Receives a message from the Fleetspeak server.
@@ -126,7 +753,9 @@ pub fn send(message: Message) {
 /// use [`receive_with_heartbeat`] instead.
 ///
 /// In case of any I/O failure or malformed message (e.g. due to parsing issues
-/// or when some fields are not being present), an error is reported.
+/// or when some fields are not being present), this function will panic. It is
+/// a thin wrapper around [`try_receive`] for services that cannot do anything
+/// about such failures.
 ///
 /// [`receive_with_heartbeat`]: crate::receive_with_heartbeat
 ///
@@ -141,7 +770,465 @@ pub fn send(message: Message) {
 /// println!("Hello, {name}!");
 /// ```
 pub fn receive() -> Message {
-    execute(&CONNECTION.input, |buf| self::io::read_message(buf))
+    match try_receive() {
+        Ok(message) => message,
+        Err(error) => panic!("{error}"),
+    }
+}
+
+/// Receives a message from the Fleetspeak server together with its metadata.
+///
+/// This is a variant of [`receive`] for services that need more information
+/// about the message than what [`Message`] carries (e.g. its identifier or the
+/// validation tags attached by the server).
+///
+/// # Examples
+///
+/// ```no_run
+/// let (message, metadata) = fleetspeak::receive_with_metadata();
+///
+/// println!("received {} (priority: {:?})", message.preview(), metadata.priority);
+/// for (key, value) in &metadata.annotations {
+///     println!("  {key}: {value}");
+/// }
+/// ```
+pub fn receive_with_metadata() -> (Message, Metadata) {
+    match try_receive_with_metadata() {
+        Ok(result) => result,
+        Err(error) => panic!("{error}"),
+    }
+}
+
+/// Receives a message from the Fleetspeak server, returning an error on
+/// failure.
+///
+/// This is a variant of [`receive`] that does not panic if the message cannot
+/// be read. If the message was read but turned out to be malformed, the error
+/// says so (see [`ReadError::is_malformed`]) and the connection can still be
+/// used. Otherwise, the connection should be considered broken: its [status]
+/// becomes [`Status::Closed`].
+///
+/// [status]: crate::status
+///
+/// # Examples
+///
+/// ```no_run
+/// loop {
+///     match fleetspeak::try_receive() {
+///         Ok(message) => println!("received {}", message.preview()),
+///         Err(error) if error.is_malformed() => eprintln!("{error}"),
+///         Err(error) => {
+///             eprintln!("connection lost: {error}");
+///             break;
+///         }
+///     }
+/// }
+/// ```
+pub fn try_receive() -> Result<Message, ReadError> {
+    try_receive_with_metadata().map(|(message, _)| message)
+}
+
+/// Receives a message from the Fleetspeak server together with its metadata,
+/// returning an error on failure.
+///
+/// This is a variant of [`receive_with_metadata`] that does not panic. See
+/// [`try_receive`] for more details on the errors.
+pub fn try_receive_with_metadata() -> Result<(Message, Metadata), ReadError> {
+    loop {
+        let proto = match crate::reader::recv() {
+            Ok(proto) => proto,
+            Err(error) => {
+                close(&error);
+                return Err(ReadError::Input(error));
+            }
+        };
+
+        match try_accept_with_metadata(proto) {
+            Ok(Some(result)) => return Ok(result),
+            Ok(None) => continue,
+            Err(error) => return Err(ReadError::Malformed(error)),
+        }
+    }
+}
+
+/// Receives a raw Protocol Buffers message from the Fleetspeak server.
+///
+/// This is an escape hatch for services that need fields that [`Message`] does
+/// not model. The message is returned as is: none of the payload
+/// transformations (like [decompression]) are reverted and it is not validated
+/// in any way (e.g. it might lack the source address).
+///
+/// The message also bypasses the bookkeeping of regular messages: it is not
+/// recorded in the [audit log] nor reported as an ETW event. It is only counted
+/// in the [traffic metrics] (under its message type).
+///
+/// In case of any I/O failure, this function will panic.
+///
+/// [decompression]: crate::compression
+/// [audit log]: crate::audit
+/// [traffic metrics]: crate::metrics
+///
+/// # Examples
+///
+/// ```no_run
+/// let proto = fleetspeak::receive_raw();
+/// println!("received a message with {} validation tags", proto.validation_info.tags.len());
+/// ```
+pub fn receive_raw() -> fleetspeak_proto::common::Message {
+    let proto = match crate::reader::recv() {
+        Ok(proto) => proto,
+        Err(error) => fail(error),
+    };
+
+    crate::metrics::record_received(Some(proto.message_type()), proto.data().value.len());
+    crate::metrics::record_first_message(CONNECTION.established.elapsed());
+
+    *LAST_CONTACT.lock().expect("poisoned last contact mutex") = Some(Instant::now());
+
+    proto
+}
+
+/// Receives all the already available messages from the Fleetspeak server (up
+/// to `max` of them).
+///
+/// This function waits at most `timeout` for the first message to arrive. Once
+/// it does, all the subsequent messages that can be read without waiting are
+/// received as well, until there are `max` of them. An empty vector is returned
+/// if no message arrives within the timeout.
+///
+/// This is useful for batch-oriented services (e.g. ones that write incoming
+/// data to a local database) that can amortize their per-batch overhead when
+/// the server sends messages in bursts.
+///
+/// Like other receives, the messages are read by the background reader thread,
+/// so concurrent calls are served in the order in which they are made. While
+/// waiting for the first message, the reader thread holds the input channel:
+/// the descriptor returned by [`poll_handle`] can still be polled on Unix, but
+/// on Windows its event is not updated until the wait is over. A message that
+/// has only partially arrived by the timeout is not lost: it is received by the
+/// next call.
+///
+/// In case of an I/O failure, an error is returned and the connection should be
+/// considered broken: its [status] becomes [`Status::Closed`]. If one of the
+/// messages turns out to be malformed (and such messages are not [skipped]),
+/// the error is returned as well and the rest of the batch is dropped, but the
+/// connection can still be used.
+///
+/// [`poll_handle`]: crate::poll_handle
+/// [status]: crate::status
+/// [skipped]: crate::Malformed::Skip
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// loop {
+///     let messages = fleetspeak::recv_many(128, Duration::from_secs(1))
+///         .expect("failed to receive messages");
+///     if messages.is_empty() {
+///         fleetspeak::heartbeat();
+///         continue;
+///     }
+///
+///     println!("received a batch of {} messages", messages.len());
+/// }
+/// ```
+pub fn recv_many(max: usize, timeout: Duration) -> std::io::Result<Vec<Message>> {
+    let mut deadline = Instant::now() + timeout;
+
+    let mut messages = Vec::new();
+    while messages.len() < max {
+        let proto = match crate::reader::recv_until(deadline) {
+            Some(proto) => proto.inspect_err(close)?,
+            None => break,
+        };
+        messages.extend(try_accept(proto)?);
+
+        // Only the first message is waited for, the rest is received only if
+        // it is already there.
+        deadline = Instant::now();
+    }
+
+    Ok(messages)
+}
+
+/// An error returned when sending a message with [`try_send`] fails.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WriteError {
+    /// Writing to the output channel failed.
+    ///
+    /// The connection is broken: its [status] becomes [`Status::Closed`].
+    ///
+    /// [status]: crate::status
+    Output(std::io::Error),
+    /// The message was refused before anything was written.
+    ///
+    /// This happens if the message exceeds the size limit advertised by the
+    /// client (see [`env::MAX_MESSAGE_SIZE_VAR`]) or if one of the payload
+    /// transformations (like [compression]) fails. The connection can still be
+    /// used.
+    ///
+    /// [compression]: crate::compression
+    Rejected(std::io::Error),
+}
+
+impl WriteError {
+
+    /// Returns whether the message was refused before anything was written.
+    ///
+    /// Unlike other errors, this does not indicate a connection failure.
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, WriteError::Rejected(_))
+    }
+}
+
+impl std::fmt::Display for WriteError {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::Output(error) => {
+                write!(fmt, "failed to write message: {error}")
+            }
+            WriteError::Rejected(error) => {
+                write!(fmt, "message rejected: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WriteError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriteError::Output(error) => Some(error),
+            WriteError::Rejected(error) => Some(error),
+        }
+    }
+}
+
+impl From<WriteError> for std::io::Error {
+
+    fn from(error: WriteError) -> std::io::Error {
+        match error {
+            WriteError::Output(error) => error,
+            WriteError::Rejected(error) => error,
+        }
+    }
+}
+
+/// An error returned when receiving a message with [`try_receive`] (or
+/// [`receive_cancellable`]) fails.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReadError {
+    /// Reading from the input channel failed.
+    ///
+    /// The connection is broken: its [status] becomes [`Status::Closed`].
+    ///
+    /// [status]: crate::status
+    Input(std::io::Error),
+    /// The message was read but could not be decoded.
+    ///
+    /// The connection can still be used.
+    Malformed(std::io::Error),
+    /// The wait for the message has been cancelled (see [`CancelToken`]).
+    ///
+    /// The connection can still be used.
+    Cancelled,
+}
+
+impl ReadError {
+
+    /// Returns whether the message was read but turned out to be malformed.
+    ///
+    /// Unlike other errors, this does not indicate a connection failure.
+    pub fn is_malformed(&self) -> bool {
+        matches!(self, ReadError::Malformed(_))
+    }
+
+    /// Returns whether the wait for the message has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, ReadError::Cancelled)
+    }
+}
+
+impl std::fmt::Display for ReadError {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Input(error) => {
+                write!(fmt, "failed to read message: {error}")
+            }
+            ReadError::Malformed(error) => {
+                write!(fmt, "malformed message: {error}")
+            }
+            ReadError::Cancelled => {
+                write!(fmt, "receive cancelled")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Input(error) => Some(error),
+            ReadError::Malformed(error) => Some(error),
+            ReadError::Cancelled => None,
+        }
+    }
+}
+
+impl From<ReadError> for std::io::Error {
+
+    fn from(error: ReadError) -> std::io::Error {
+        match error {
+            ReadError::Input(error) => error,
+            ReadError::Malformed(error) => error,
+            ReadError::Cancelled => {
+                std::io::Error::new(std::io::ErrorKind::Interrupted, "receive cancelled")
+            }
+        }
+    }
+}
+
+/// Policy for handling incoming messages that carry no data at all.
+///
+/// On the wire, a message without data is different from a message with empty
+/// data. The former usually indicates a bug on the server side, but because
+/// [`Message::data`] is a plain vector, the two are indistinguishable once the
+/// message is received. The policy (set with [`set_missing_data`]) determines
+/// whether such messages are surfaced.
+///
+/// [`set_missing_data`]: crate::set_missing_data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingData {
+    /// Missing data is replaced with an empty vector (and a warning is logged).
+    #[default]
+    Empty,
+    /// Missing data is treated as a malformed message and reported as an error.
+    Reject,
+    /// Messages with missing data are dropped (and a warning is logged), the
+    /// receiving functions wait for the next message instead.
+    Skip,
+}
+
+/// Sets the policy for handling incoming messages that carry no data.
+///
+/// The policy applies to the global connection and to every [`Connection`]
+/// that does not override it (see [`Connection::with_missing_data`]). See
+/// documentation for [`MissingData`] for more details.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::set_missing_data(fleetspeak::MissingData::Reject);
+///
+/// // Fails if the server sends a message without data.
+/// let message = fleetspeak::receive();
+/// ```
+pub fn set_missing_data(policy: MissingData) {
+    *MISSING_DATA.lock().expect("poisoned missing data mutex") = policy;
+}
+
+/// Returns the currently set policy for handling messages without data.
+fn missing_data() -> MissingData {
+    *MISSING_DATA.lock().expect("poisoned missing data mutex")
+}
+
+/// Policy for handling incoming messages that cannot be decoded.
+///
+/// A message is malformed if it lacks the source address, if its payload
+/// cannot be decoded (e.g. because it is compressed with an unknown algorithm
+/// or its signature is invalid) or if it is rejected by the [`MissingData`]
+/// policy. The policy (set with [`set_malformed`]) determines whether such
+/// messages are surfaced.
+///
+/// [`set_malformed`]: crate::set_malformed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Malformed {
+    /// Malformed messages are reported as errors by the receiving functions.
+    #[default]
+    Fail,
+    /// Malformed messages are logged and skipped, the receiving functions wait
+    /// for the next message instead.
+    ///
+    /// This is meant for services that prioritize availability over seeing
+    /// every message. Skipped messages are still counted as [decode failures].
+    ///
+    /// [decode failures]: crate::ConnectorStats::decode_failures
+    Skip,
+}
+
+/// Sets the policy for handling incoming messages that cannot be decoded.
+///
+/// See documentation for [`Malformed`] for more details.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::set_malformed(fleetspeak::Malformed::Skip);
+///
+/// // Never fails because of a malformed message.
+/// let message = fleetspeak::receive();
+/// ```
+pub fn set_malformed(policy: Malformed) {
+    *MALFORMED.lock().expect("poisoned malformed mutex") = policy;
+}
+
+/// Enables resynchronization of the input channel after a corrupted frame.
+///
+/// By default, a frame that does not end with the Fleetspeak magic number is
+/// reported as an error (see [`FrameError`]) and, as the stream position is
+/// unknown from then on, no further message can be received. Once a non-zero
+/// `limit` is set, the input is instead scanned forward for the next frame
+/// boundary (the magic followed by a plausible length prefix) and reading
+/// continues from there. At most `limit` bytes are skipped before giving up
+/// and reporting the original error. Setting `limit` to zero turns the
+/// resynchronization off again.
+///
+/// This allows long-lived services to survive a single corrupted frame at the
+/// cost of losing the message in it (and possibly messages adjacent to it).
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::set_resync_limit(1024 * 1024);
+///
+/// loop {
+///     let message = fleetspeak::receive();
+///     println!("received a message from '{}'", message.service);
+/// }
+/// ```
+pub fn set_resync_limit(limit: usize) {
+    RESYNC_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+/// Returns the time at which the last message from the server was received.
+///
+/// This is `None` if no message has been received yet. Services that switch to
+/// an autonomous mode when the server is unreachable can use this to decide
+/// when to do so.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let unreachable = match fleetspeak::last_contact() {
+///     Some(instant) => instant.elapsed() > Duration::from_secs(60 * 60),
+///     None => true,
+/// };
+///
+/// if unreachable {
+///     println!("no contact with the server, switching to autonomous mode");
+/// }
+/// ```
+pub fn last_contact() -> Option<Instant> {
+    *LAST_CONTACT.lock().expect("poisoned last contact mutex")
 }
 
 /// Receive a message from the Fleetspeak server, heartbeating in background.
@@ -172,95 +1259,446 @@ pub fn receive() -> Message {
 /// println!("Hello, {name}!");
 /// ```
 pub fn receive_with_heartbeat(rate: Duration) -> Message {
-    // TODO(rust-lang/rust#35121): Replace with `!` once stable.
-    enum Never {
+    crate::heartbeats::with_heartbeat(rate, receive)
+}
+
+/// A connection to the Fleetspeak client.
+///
+/// The connection is realized through two files (specified by descriptors given
+/// by the Fleetspeak client as environment variables): input and output. Each
+/// of these files is guarded by a separate mutex to allow writing (e.g. for
+/// sending heartbeat signals) when another thread might be busy with reading
+/// messages.
+struct GlobalConnection {
+    input: Mutex<crate::io::FrameReader<crate::io::CommsInRaw>>,
+    output: Mutex<crate::io::FlushOnDrop<crate::io::CommsOutRaw>>,
+    /// Descriptor of the input channel.
+    ///
+    /// Closing the input channel does not release the descriptor (it is
+    /// redirected to `/dev/null` instead), so it can be polled without holding
+    /// the input for the rest of the process lifetime.
+    #[cfg(target_family = "unix")]
+    input_fd: std::os::fd::RawFd,
+    /// Time at which the connection was established.
+    established: Instant,
+}
+
+/// Whether a heartbeat has been requested while the output channel was in use.
+static PENDING_HEARTBEAT: AtomicBool = AtomicBool::new(false);
+
+/// Maximum number of bytes to skip while resynchronizing the input channel.
+static RESYNC_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+static LAST_CONTACT: Mutex<Option<Instant>> = Mutex::new(None);
+
+static MISSING_DATA: Mutex<MissingData> = Mutex::new(MissingData::Empty);
+
+static MALFORMED: Mutex<Malformed> = Mutex::new(Malformed::Fail);
+
+/// Outcome of the (only) attempt to establish the global connection.
+static INIT: OnceLock<Result<GlobalConnection, InitError>> = OnceLock::new();
+
+static CONNECTION: LazyLock<&'static GlobalConnection> = LazyLock::new(|| {
+    match try_connection() {
+        Ok(connection) => connection,
+        Err(error) => panic!("{error}"),
     }
+});
+
+/// Returns the global connection, establishing it first if needed.
+///
+/// Unlike [`CONNECTION`], this does not panic if the connection cannot be
+/// established but returns the error instead.
+fn try_connection() -> Result<&'static GlobalConnection, &'static InitError> {
+    INIT.get_or_init(crate::init::establish).as_ref()
+}
 
-    let (sender, receiver) = std::sync::mpsc::channel::<Never>();
+/// Writes the message to the output channel of the connection.
+///
+/// This is the common path of all the functions sending messages to the
+/// Fleetspeak server, no matter whether they are written directly or by the
+/// background writer.
+fn deliver(message: Message) -> std::io::Result<()> {
+    try_deliver(message).map_err(std::io::Error::from)
+}
 
-    std::thread::spawn(move || {
-        loop {
-            use std::sync::mpsc::TryRecvError::*;
+/// Writes the message to the output channel of the connection, telling apart
+/// messages refused before anything was written from connection failures.
+fn try_deliver(message: Message) -> Result<(), WriteError> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span! {
+        "fleetspeak_send",
+        service = %message.service,
+        kind = message.kind.as_deref(),
+        size = message.data.len(),
+    }.entered();
 
-            // We keep hearbeating until the sender disconnects (in which case
-            // the receiver will receive a disconnection error).
-            match receiver.try_recv() {
-                Ok(never) => match never {},
-                Err(Empty) => (),
-                Err(Disconnected) => return,
-            }
+    #[cfg(feature = "audit")]
+    let entry = crate::audit::Entry::new(crate::audit::Direction::Sent, &message);
 
-            heartbeat();
-            std::thread::sleep(rate);
+    #[cfg(all(target_family = "windows", feature = "etw"))]
+    crate::etw::message("outgoing", &message);
+
+    let start = Instant::now();
+    let kind = message.kind.clone();
+    let bytes = message.data.len();
+
+    let proto = encode(message).map_err(WriteError::Rejected)?;
+    write(proto).map_err(|error| {
+        // Oversized messages are refused before anything is written.
+        if error.kind() == std::io::ErrorKind::InvalidInput {
+            WriteError::Rejected(error)
+        } else {
+            WriteError::Output(error)
         }
-    });
+    })?;
 
-    let message = receive();
+    crate::metrics::record_sent(kind.as_deref(), bytes, start.elapsed());
 
-    // Notify the heartbeat thread to shut down. However, instead of sending any
-    // real message we just shut the sender down and the receiver will receive
-    // a disconnection error.
-    drop(sender);
+    #[cfg(feature = "audit")]
+    if let Some(entry) = entry {
+        entry.log();
+    }
 
-    message
+    Ok(())
 }
 
-/// A connection to the Fleetspeak client.
+/// Encodes the messages and writes them to the output channel of the
+/// connection with a single flush.
 ///
-/// The connection is realized through two files (specified by descriptors given
-/// by the Fleetspeak client as environment variables): input and output. Each
-/// of these files is guarded by a separate mutex to allow writing (e.g. for
-/// sending heartbeat signals) when another thread might be busy with reading
-/// messages.
-struct Connection {
-    input: Mutex<std::io::BufReader<crate::io::CommsInRaw>>,
-    output: Mutex<std::io::BufWriter<crate::io::CommsOutRaw>>,
+/// Nothing is written if one of the messages is refused.
+fn try_deliver_all(messages: Vec<Message>) -> Result<(), WriteError> {
+    let start = Instant::now();
+
+    let mut protos = Vec::with_capacity(messages.len());
+    let mut sent = Vec::with_capacity(messages.len());
+    #[cfg(feature = "audit")]
+    let mut entries = Vec::with_capacity(messages.len());
+
+    for message in messages {
+        #[cfg(feature = "audit")]
+        entries.push(crate::audit::Entry::new(crate::audit::Direction::Sent, &message));
+
+        #[cfg(all(target_family = "windows", feature = "etw"))]
+        crate::etw::message("outgoing", &message);
+
+        sent.push((message.kind.clone(), message.data.len()));
+
+        let proto = encode(message).map_err(WriteError::Rejected)?;
+        self::io::frame_size(&proto).map_err(WriteError::Rejected)?;
+        protos.push(proto);
+    }
+
+    let mut output = CONNECTION.output.lock()
+        .expect("poisoned connection mutex");
+    for proto in protos {
+        output.write_frame(proto).map_err(WriteError::Output)?;
+    }
+    std::io::Write::flush(&mut *output).map_err(WriteError::Output)?;
+    release(output).map_err(WriteError::Output)?;
+
+    crate::status::set(Status::Connected);
+
+    let elapsed = start.elapsed();
+    for (kind, bytes) in sent {
+        crate::metrics::record_sent(kind.as_deref(), bytes, elapsed);
+    }
+
+    #[cfg(feature = "audit")]
+    for entry in entries.into_iter().flatten() {
+        entry.log();
+    }
+
+    Ok(())
 }
 
-lazy_static! {
-    static ref CONNECTION: Connection = {
-        let mut input = match crate::io::CommsInRaw::from_env() {
-            Ok(input) => std::io::BufReader::new(input),
-            Err(error) => {
-                panic!("invalid input communication channel: {error}");
-            }
-        };
+/// Writes the raw message to the output channel of the connection.
+fn deliver_raw(proto: fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let start = Instant::now();
+    let kind = String::from(proto.message_type());
+    let bytes = proto.data().value.len();
 
-        let mut output = match crate::io::CommsOutRaw::from_env() {
-            Ok(output) => std::io::BufWriter::new(output),
-            Err(error) => {
-                panic!("invalid output commmunication channel: {error}");
-            }
-        };
+    write(proto)?;
+
+    crate::metrics::record_sent(Some(&kind), bytes, start.elapsed());
+
+    Ok(())
+}
+
+/// Writes the message in its wire representation to the output channel of the
+/// connection.
+fn write(proto: fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let mut output = CONNECTION.output.lock()
+        .expect("poisoned connection mutex");
+    output.write_frame(proto)?;
+    std::io::Write::flush(&mut *output)?;
+    release(output)?;
+
+    crate::status::set(Status::Connected);
+
+    Ok(())
+}
+
+/// Converts an outgoing message to its wire representation.
+///
+/// Apart from the conversion itself, this applies all the configured payload
+/// transformations (e.g. compression, encryption or signing).
+fn encode(message: Message) -> std::io::Result<fleetspeak_proto::common::Message> {
+    let mut proto = self::io::encode_message(message);
+    crate::compression::compress(&mut proto)?;
+    crate::crypto::seal(&mut proto)?;
+    crate::signing::sign(&mut proto)?;
+
+    Ok(proto)
+}
+
+/// Converts an incoming message from its wire representation.
+///
+/// Apart from the conversion itself, this reverts all the payload
+/// transformations (e.g. signing, encryption or compression) the message is
+/// marked with. `None` is returned if the message is to be skipped according
+/// to the `missing_data` policy.
+fn decode(
+    proto: fleetspeak_proto::common::Message,
+    missing_data: MissingData,
+) -> std::io::Result<Option<Message>> {
+    decode_with_metadata(proto, missing_data)
+        .map(|result| result.map(|(message, _)| message))
+}
+
+/// Converts an incoming message from its wire representation, keeping the
+/// metadata that [`Message`] does not carry.
+fn decode_with_metadata(
+    mut proto: fleetspeak_proto::common::Message,
+    missing_data: MissingData,
+) -> std::io::Result<Option<(Message, Metadata)>> {
+    crate::signing::verify(&mut proto)?;
+    crate::crypto::open(&mut proto)?;
+    crate::compression::decompress(&mut proto)?;
+
+    // Payload transformations remove their annotations, so only the ones set
+    // by the sender remain.
+    let metadata = Metadata::from_proto(&proto);
+
+    let message = self::io::decode_message(proto, missing_data)?;
+
+    Ok(message.map(|message| (message, metadata)))
+}
+
+/// Processes a message read from the input channel of the connection.
+///
+/// This is the common path of all the functions receiving messages from the
+/// Fleetspeak server. `None` is returned if the message has been consumed by
+/// the library itself (e.g. an [answered ping]) and should not be passed to
+/// the service.
+///
+/// [answered ping]: crate::answer_pings
+#[cfg(feature = "tokio")]
+fn accept(proto: fleetspeak_proto::common::Message) -> Option<Message> {
+    match try_accept(proto) {
+        Ok(message) => message,
+        Err(error) => fail(error),
+    }
+}
+
+/// Processes a message read from the input channel of the connection,
+/// returning an error if it is malformed.
+fn try_accept(proto: fleetspeak_proto::common::Message) -> std::io::Result<Option<Message>> {
+    try_accept_with_metadata(proto).map(|result| result.map(|(message, _)| message))
+}
 
-        crate::io::handshake(&mut input, &mut output)
-            .expect("handshake failure");
+/// Processes a message read from the input channel of the connection, keeping
+/// its metadata.
+fn try_accept_with_metadata(proto: fleetspeak_proto::common::Message) -> std::io::Result<Option<(Message, Metadata)>> {
+    let kind = proto.message_type.clone();
 
-        log::info!("handshake successful");
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span! {
+        "fleetspeak_decode",
+        service = %proto.source.service_name,
+        kind = %kind,
+        size = proto.data.value.len(),
+    }.entered();
+
+    let (message, metadata) = match decode_with_metadata(proto, missing_data()) {
+        Ok(Some(result)) => result,
+        Ok(None) => return Ok(None),
+        Err(error) => {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(%error, "failed to decode message");
+
+            crate::metrics::record_decode_failure(Some(&kind));
+
+            if *MALFORMED.lock().expect("poisoned malformed mutex") == Malformed::Skip {
+                log::warn!("skipping malformed message of kind {kind:?}: {error}");
+                return Ok(None);
+            }
 
-        Connection {
-            input: Mutex::new(input),
-            output: Mutex::new(output),
+            return Err(error);
         }
     };
+
+    crate::metrics::record_received(message.kind.as_deref(), message.data.len());
+    crate::metrics::record_first_message(CONNECTION.established.elapsed());
+
+    *LAST_CONTACT.lock().expect("poisoned last contact mutex") = Some(Instant::now());
+
+    #[cfg(all(target_family = "windows", feature = "etw"))]
+    crate::etw::message("incoming", &message);
+
+    #[cfg(feature = "audit")]
+    if let Some(entry) = crate::audit::Entry::new(crate::audit::Direction::Received, &message) {
+        entry.log();
+    }
+
+    crate::system::handle(&message);
+
+    if crate::ping::answer(&message) {
+        return Ok(None);
+    }
+
+    Ok(Some((message, metadata)))
 }
 
-/// Executes the given function with a file extracted from the mutex.
+/// Writes a heartbeat signal to the output channel of the connection.
 ///
-/// It might happen that the mutex becomes poisoned and this call will panic in
-/// result. This should not be a problem in practice, because mutex poisoning
-/// is a result of one of the threads being aborted. In case of a such scenario,
-/// it is likely the service needs to be restarted anyway.
+/// Unlike [`request_heartbeat`], this waits for the heartbeat to be written.
+fn deliver_heartbeat() -> std::io::Result<()> {
+    #[cfg(all(target_family = "windows", feature = "etw"))]
+    crate::etw::heartbeat();
+
+    let mut output = CONNECTION.output.lock()
+        .expect("poisoned connection mutex");
+    self::io::write_heartbeat(&mut *output)?;
+    crate::metrics::record_heartbeat();
+    release(output)
+}
+
+/// Writes a heartbeat signal to the output channel of the connection unless it
+/// is in use, in which case the heartbeat is left pending.
 ///
-/// Any I/O error returned by the executed function indicates a fatal connection
-/// failure and ends with a panic.
-fn execute<C, F, T>(mutex: &Mutex<C>, f: F) -> T
-where
-    F: FnOnce(&mut C) -> std::io::Result<T>,
-{
-    let mut file = mutex.lock().expect("poisoned connection mutex");
-    match f(&mut file) {
-        Ok(value) => value,
-        Err(error) => panic!("connection failure: {}", error),
+/// Frames cannot be interleaved, so the heartbeat has to wait for the message
+/// being written anyway. But instead of blocking the caller for that time, the
+/// heartbeat is written by the thread holding the channel when it releases it
+/// (see [`release`]). This way, the latency of heartbeats is bounded by the time
+/// it takes to write a single message.
+fn request_heartbeat() -> std::io::Result<()> {
+    #[cfg(all(target_family = "windows", feature = "etw"))]
+    crate::etw::heartbeat();
+
+    PENDING_HEARTBEAT.store(true, Ordering::SeqCst);
+
+    match CONNECTION.output.try_lock() {
+        Ok(output) => release(output),
+        Err(TryLockError::WouldBlock) => Ok(()),
+        Err(TryLockError::Poisoned(_)) => panic!("poisoned connection mutex"),
+    }
+}
+
+/// Releases the output channel of the connection, writing pending heartbeats
+/// first.
+///
+/// All the functions writing to the output channel should release it this way.
+/// Otherwise, heartbeats requested while the channel was in use are delayed
+/// until the next write.
+fn release(mut output: MutexGuard<'_, crate::io::FlushOnDrop<crate::io::CommsOutRaw>>) -> std::io::Result<()> {
+    loop {
+        if PENDING_HEARTBEAT.swap(false, Ordering::SeqCst) {
+            self::io::write_heartbeat(&mut *output)?;
+            crate::metrics::record_heartbeat();
+        }
+        drop(output);
+
+        // A heartbeat might have been requested after we checked but before we
+        // released the channel. In such case the requesting thread failed to
+        // lock it, so it is up to us to write the heartbeat (unless some other
+        // thread holds the channel now, which makes it responsible instead).
+        if !PENDING_HEARTBEAT.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        output = match CONNECTION.output.try_lock() {
+            Ok(output) => output,
+            Err(TryLockError::WouldBlock) => return Ok(()),
+            Err(TryLockError::Poisoned(_)) => panic!("poisoned connection mutex"),
+        };
+    }
+}
+
+/// Reports a fatal connection failure.
+fn fail(error: std::io::Error) -> ! {
+    close(&error);
+
+    panic!("connection failure: {}", error)
+}
+
+/// Marks the connection as closed because of the given error.
+fn close(error: &std::io::Error) {
+    log::error!("connection failure: {error}");
+    crate::status::close(error);
+
+    #[cfg(all(target_family = "windows", feature = "etw"))]
+    crate::etw::failure(format_args!("connection failure: {error}"));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn message(data: &[u8]) -> Message {
+        Message {
+            service: String::from("foo"),
+            kind: None,
+            data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn write_error_into_io_error() {
+        let error = WriteError::Rejected(std::io::ErrorKind::InvalidInput.into());
+        assert!(error.is_rejected());
+        assert_eq!(std::io::Error::from(error).kind(), std::io::ErrorKind::InvalidInput);
+
+        let error = WriteError::Output(std::io::ErrorKind::BrokenPipe.into());
+        assert!(!error.is_rejected());
+        assert_eq!(std::io::Error::from(error).kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn read_error_into_io_error() {
+        let error = ReadError::Malformed(std::io::ErrorKind::InvalidData.into());
+        assert!(error.is_malformed());
+        assert_eq!(std::io::Error::from(error).kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn preview_binary() {
+        assert_eq! {
+            message(b"\xff\x00\x42").preview(),
+            "service: foo, kind: -, size: 3, data: ff0042",
+        };
+    }
+
+    #[test]
+    fn preview_truncated() {
+        let data = "ą".repeat(Message::PREVIEW_LEN);
+
+        let preview = message(data.as_bytes()).preview();
+        let expected = format! {
+            "service: foo, kind: -, size: {}, data: {:?}...",
+            data.len(),
+            "ą".repeat(Message::PREVIEW_LEN / 2),
+        };
+        assert_eq!(preview, expected);
+    }
+
+    #[test]
+    fn preview_escaped() {
+        assert_eq! {
+            message(b"foo\nbar").preview(),
+            "service: foo, kind: -, size: 7, data: \"foo\\nbar\"",
+        };
     }
 }
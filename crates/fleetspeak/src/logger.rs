@@ -0,0 +1,247 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Centralized logging through Fleetspeak.
+//!
+//! Services running as daemons on endpoints often have nowhere good to log to.
+//! The [`Logger`] is a [`log`] backend that ships the records to a server-side
+//! service instead. Records are batched and sent as messages of the [`KIND`]
+//! kind, the data of which contains one record per line:
+//!
+//! ```text
+//! 1700000000.123456 INFO my_service::worker: job 42 finished
+//! ```
+//!
+//! The line starts with the time of the record (in seconds since the Unix epoch
+//! with microsecond precision), followed by the level, the target and the
+//! message itself.
+//!
+//! Records of this library itself are never shipped, as they might be emitted
+//! while the batch is being sent (e.g. because the connection failed).
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Message kind of batches of log records.
+pub const KIND: &str = "Log";
+
+/// Default maximum number of records in a single batch.
+pub const DEFAULT_MAX_RECORDS: usize = 64;
+
+/// Default maximum time a record waits in a batch before it is sent.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Size (in bytes) of a batch at which it is sent regardless of the number of
+/// records, to stay well below the Fleetspeak message size limit.
+const MAX_BATCH_BYTES: usize = 512 * 1024;
+
+/// A [`log`] backend sending log records to a server-side service.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::logger::Logger::new("logs")
+///     .with_level(log::LevelFilter::Debug)
+///     .install()
+///     .unwrap();
+///
+/// log::info!("service started");
+/// ```
+#[derive(Debug)]
+pub struct Logger {
+    /// Name of the server-side service to send the records to.
+    service: String,
+    /// Maximum level of records to send.
+    level: log::LevelFilter,
+    /// Maximum number of records in a single batch.
+    max_records: usize,
+    /// Maximum time a record waits in a batch before it is sent.
+    interval: Duration,
+    /// Records waiting to be sent.
+    batch: Mutex<Batch>,
+}
+
+impl Logger {
+
+    /// Creates a logger sending records to the given `service`.
+    ///
+    /// By default, records up to the [`Info`] level are sent in batches of at
+    /// most [`DEFAULT_MAX_RECORDS`] records, at least every [`DEFAULT_INTERVAL`].
+    ///
+    /// [`Info`]: log::LevelFilter::Info
+    pub fn new(service: &str) -> Logger {
+        Logger {
+            service: String::from(service),
+            level: log::LevelFilter::Info,
+            max_records: DEFAULT_MAX_RECORDS,
+            interval: DEFAULT_INTERVAL,
+            batch: Mutex::new(Batch::default()),
+        }
+    }
+
+    /// Sets the maximum level of records to send.
+    pub fn with_level(mut self, level: log::LevelFilter) -> Logger {
+        self.level = level;
+        self
+    }
+
+    /// Sets the maximum number of records in a single batch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_records` is zero.
+    pub fn with_max_records(mut self, max_records: usize) -> Logger {
+        assert!(max_records > 0, "batch size must be positive");
+
+        self.max_records = max_records;
+        self
+    }
+
+    /// Sets the maximum time a record waits in a batch before it is sent.
+    pub fn with_interval(mut self, interval: Duration) -> Logger {
+        self.interval = interval;
+        self
+    }
+
+    /// Installs the logger as the global [`log`] backend.
+    ///
+    /// This also spawns a background thread sending batches that have waited
+    /// for the configured interval. An error is returned if another backend
+    /// has already been installed.
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        let level = self.level;
+        let logger: &'static Logger = Box::leak(Box::new(self));
+
+        log::set_logger(logger)?;
+        log::set_max_level(level);
+
+        // If the thread cannot be spawned, records are still sent once batches
+        // fill up, so there is no reason to fail the installation.
+        let _ = std::thread::Builder::new()
+            .name(String::from("fleetspeak-logger"))
+            .spawn(move || loop {
+                std::thread::sleep(logger.interval);
+                log::Log::flush(logger);
+            });
+
+        Ok(())
+    }
+
+    /// Sends the data of a batch (best-effort).
+    fn send(&self, data: Vec<u8>) {
+        // There is nowhere to log the failure to, so it is simply dropped (the
+        // connection status reflects it anyway).
+        let _ = crate::send_queued(crate::Message {
+            service: self.service.clone(),
+            kind: Some(String::from(KIND)),
+            data,
+            background: true,
+            ..Default::default()
+        });
+    }
+}
+
+impl log::Log for Logger {
+
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.level && !is_own(metadata.target())
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format_record(SystemTime::now(), record);
+
+        let mut batch = self.batch.lock().expect("poisoned logger mutex");
+        batch.data.extend_from_slice(line.as_bytes());
+        batch.records += 1;
+
+        if batch.records < self.max_records && batch.data.len() < MAX_BATCH_BYTES {
+            return;
+        }
+
+        let data = batch.take();
+        drop(batch);
+
+        self.send(data);
+    }
+
+    fn flush(&self) {
+        let data = self.batch.lock().expect("poisoned logger mutex").take();
+        if !data.is_empty() {
+            self.send(data);
+        }
+    }
+}
+
+/// Records waiting to be sent.
+#[derive(Debug, Default)]
+struct Batch {
+    /// Formatted records, one per line.
+    data: Vec<u8>,
+    /// Number of records in the batch.
+    records: usize,
+}
+
+impl Batch {
+
+    /// Empties the batch, returning its data.
+    fn take(&mut self) -> Vec<u8> {
+        self.records = 0;
+        std::mem::take(&mut self.data)
+    }
+}
+
+/// Returns whether the target belongs to this library.
+fn is_own(target: &str) -> bool {
+    target == "fleetspeak" || target.starts_with("fleetspeak::")
+}
+
+/// Formats the record as a single line.
+fn format_record(time: SystemTime, record: &log::Record<'_>) -> String {
+    let time = time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    // Messages spanning multiple lines would break the format, so line breaks
+    // are escaped.
+    let message = record.args().to_string().replace('\n', "\\n");
+
+    format! {
+        "{}.{:06} {} {}: {}\n",
+        time.as_secs(),
+        time.subsec_micros(),
+        record.level(),
+        record.target(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn format_record_line() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let line = format_record(time, &log::Record::builder()
+            .level(log::Level::Warn)
+            .target("foo::bar")
+            .args(format_args!("baz\nquux"))
+            .build());
+
+        assert_eq!(line, "1700000000.123456 WARN foo::bar: baz\\nquux\n");
+    }
+
+    #[test]
+    fn is_own_targets() {
+        assert!(is_own("fleetspeak"));
+        assert!(is_own("fleetspeak::writer"));
+        assert!(!is_own("fleetspeak_foo"));
+        assert!(!is_own("foo"));
+    }
+}
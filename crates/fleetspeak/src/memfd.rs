@@ -0,0 +1,237 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Handoff of large payloads between co-located processes on Linux.
+//!
+//! Fleetspeak limits the size of messages, which makes it unsuitable for
+//! passing very large payloads even if the consumer runs on the same host
+//! (e.g. a sibling process spawned by the same service). This module allows to
+//! place such payloads in a sealed memory file ([`memfd_create`]) and to pass
+//! only a small [`Reference`] to it through Fleetspeak.
+//!
+//! The payload is sealed against any modifications before the reference is
+//! handed out, so the consumer can trust that the contents do not change under
+//! its hands. The consumer opens the payload through the `/proc` file system,
+//! so it needs to run as the same user as the producer (or otherwise have the
+//! permission to inspect it). The producer must keep the [`Payload`] object
+//! alive until the consumer has read it.
+//!
+//! [`memfd_create`]: https://man7.org/linux/man-pages/man2/memfd_create.2.html
+
+use std::io::{Read as _, Write as _};
+use std::os::fd::{AsRawFd as _, FromRawFd as _};
+
+use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+
+use crate::Message;
+
+/// Message kind of messages carrying payload references.
+pub const REFERENCE_KIND: &str = "MemfdReference";
+
+/// Seals that guarantee the contents of the payload cannot change.
+const SEALS: libc::c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+
+/// A large payload placed in a sealed memory file.
+#[derive(Debug)]
+pub struct Payload {
+    /// Memory file holding the payload.
+    file: std::fs::File,
+    /// Size of the payload (in bytes).
+    len: u64,
+}
+
+impl Payload {
+
+    /// Places the given `data` in a new sealed memory file.
+    pub fn new(data: &[u8]) -> std::io::Result<Payload> {
+        // SAFETY: We pass a valid null-terminated name and flags as described
+        // in the docs [1]. We verify the result afterwards.
+        //
+        // [1]: https://man7.org/linux/man-pages/man2/memfd_create.2.html
+        let fd = unsafe {
+            libc::memfd_create(c"fleetspeak-payload".as_ptr(), {
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING
+            })
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // SAFETY: We verified that the call to `memfd_create` succeeded, so the
+        // descriptor is valid and owned exclusively by us.
+        let mut file = unsafe {
+            std::fs::File::from_raw_fd(fd)
+        };
+        file.write_all(data)?;
+
+        // SAFETY: The descriptor is valid and there are no writable mappings
+        // of the file that would make the sealing fail [1]. We verify the
+        // result afterwards.
+        //
+        // [1]: https://man7.org/linux/man-pages/man2/fcntl.2.html
+        let status = unsafe {
+            libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, SEALS | libc::F_SEAL_SEAL)
+        };
+        if status < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Payload {
+            file,
+            len: data.len() as u64,
+        })
+    }
+
+    /// Returns the reference under which other processes can open the payload.
+    pub fn reference(&self) -> Reference {
+        Reference {
+            pid: std::process::id(),
+            fd: self.file.as_raw_fd(),
+            len: self.len,
+        }
+    }
+}
+
+/// A reference to a [`Payload`] in another process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reference {
+    /// Identifier of the process holding the payload.
+    pub pid: u32,
+    /// Descriptor of the payload within the holding process.
+    pub fd: libc::c_int,
+    /// Size of the payload (in bytes).
+    pub len: u64,
+}
+
+impl Reference {
+
+    /// Creates a message carrying the reference to the given server `service`.
+    pub fn to_message(&self, service: &str) -> Message {
+        let mut data = Vec::with_capacity(16);
+        data.write_u32::<LittleEndian>(self.pid)
+            .expect("failed to write to a vector");
+        data.write_i32::<LittleEndian>(self.fd)
+            .expect("failed to write to a vector");
+        data.write_u64::<LittleEndian>(self.len)
+            .expect("failed to write to a vector");
+
+        Message {
+            service: String::from(service),
+            kind: Some(String::from(REFERENCE_KIND)),
+            data,
+            ..Default::default()
+        }
+    }
+
+    /// Extracts the reference from the given `message`.
+    ///
+    /// An error is returned if the message is not of the [`REFERENCE_KIND`]
+    /// kind or is malformed.
+    pub fn from_message(message: &Message) -> std::io::Result<Reference> {
+        if message.kind.as_deref() != Some(REFERENCE_KIND) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+                "not a payload reference message"
+            }));
+        }
+
+        let mut data = &message.data[..];
+        let reference = Reference {
+            pid: data.read_u32::<LittleEndian>()?,
+            fd: data.read_i32::<LittleEndian>()?,
+            len: data.read_u64::<LittleEndian>()?,
+        };
+
+        if !data.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+                "trailing bytes in payload reference"
+            }));
+        }
+
+        Ok(reference)
+    }
+
+    /// Reads the referenced payload.
+    ///
+    /// An error is returned if the payload cannot be opened (e.g. because the
+    /// holding process has exited) or it is not sealed against modifications.
+    pub fn read(&self) -> std::io::Result<Vec<u8>> {
+        let path = format!("/proc/{}/fd/{}", self.pid, self.fd);
+        let mut file = std::fs::File::open(path)?;
+
+        // SAFETY: The descriptor is valid as we have just opened it. We verify
+        // the result afterwards.
+        let seals = unsafe {
+            libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS)
+        };
+        if seals < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if seals & SEALS != SEALS {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+                "payload not sealed"
+            }));
+        }
+
+        let len = file.metadata()?.len();
+        if len != self.len {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, {
+                format!("payload size mismatch (expected {}, got {len})", self.len)
+            }));
+        }
+
+        let mut data = Vec::with_capacity(len as usize);
+        file.read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+/// Places the given `data` in a sealed memory file and sends a reference to it
+/// to the given server `service`.
+///
+/// The returned payload has to be kept alive until the consumer has read it.
+///
+/// # Examples
+///
+/// ```no_run
+/// let data = vec![0; 512 * 1024 * 1024];
+///
+/// let payload = fleetspeak::memfd::send("analysis", &data)
+///     .expect("failed to hand off the payload");
+///
+/// // Wait for the consumer to acknowledge the payload.
+/// let _ = fleetspeak::receive();
+/// drop(payload);
+/// ```
+pub fn send(service: &str, data: &[u8]) -> std::io::Result<Payload> {
+    let payload = Payload::new(data)?;
+    crate::send(payload.reference().to_message(service));
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn payload_read() {
+        let payload = Payload::new(b"foobar").unwrap();
+        assert_eq!(payload.reference().read().unwrap(), b"foobar");
+    }
+
+    #[test]
+    fn reference_message() {
+        let reference = Reference {
+            pid: 1337,
+            fd: 42,
+            len: 1 << 40,
+        };
+
+        let message = reference.to_message("foo");
+        assert_eq!(Reference::from_message(&message).unwrap(), reference);
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use fleetspeak_proto::common::message::Priority as ProtoPriority;
+use protobuf::EnumOrUnknown;
+
+/// Metadata of a message received from the Fleetspeak server.
+///
+/// [`Message`] exposes only the fields that most services need. This structure
+/// carries the remaining information attached to the underlying Protocol
+/// Buffers message, which is useful for services that route or audit messages
+/// based on it.
+///
+/// Note that annotations used internally by this library (e.g. to mark
+/// [compressed] messages) are not included. Client labels are not part of the
+/// message and thus are not available here either.
+///
+/// [`Message`]: crate::Message
+/// [compressed]: crate::compression
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Identifier of the message assigned by Fleetspeak.
+    pub message_id: Vec<u8>,
+    /// Identifier of the message assigned by its source (if any).
+    pub source_message_id: Vec<u8>,
+    /// Time at which the message was created (if known).
+    pub creation_time: Option<SystemTime>,
+    /// Priority with which the message has been delivered.
+    pub priority: Priority,
+    /// Whether the message has been sent as a background message.
+    pub background: bool,
+    /// Key-value annotations attached to the message, in order.
+    pub annotations: Vec<(String, String)>,
+    /// Tags attached to the message by the server during validation.
+    pub validation_info: HashMap<String, String>,
+}
+
+/// Priority of a Fleetspeak message.
+///
+/// Note that the priority affects only the order in which Fleetspeak delivers
+/// messages, it has nothing to do with the [order of writing] them to the
+/// Fleetspeak client.
+///
+/// [order of writing]: crate::SendClass
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Low priority.
+    Low,
+    /// Medium (default) priority.
+    #[default]
+    Medium,
+    /// High priority.
+    High,
+}
+
+impl Metadata {
+
+    /// Extracts the metadata from a Protocol Buffers representation of a
+    /// message.
+    pub(crate) fn from_proto(proto: &fleetspeak_proto::common::Message) -> Metadata {
+        let annotations = proto.annotations.entries.iter()
+            .map(|entry| (entry.key.clone(), entry.value.clone()))
+            .collect();
+
+        let validation_info = proto.validation_info.tags.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        Metadata {
+            message_id: proto.message_id.clone(),
+            source_message_id: proto.source_message_id.clone(),
+            creation_time: proto.creation_time.as_ref().and_then(system_time),
+            priority: Priority::from_proto(proto.priority),
+            background: proto.background,
+            annotations,
+            validation_info,
+        }
+    }
+}
+
+impl Priority {
+
+    /// Converts the priority from its Protocol Buffers representation.
+    pub(crate) fn from_proto(priority: EnumOrUnknown<ProtoPriority>) -> Priority {
+        match priority.enum_value() {
+            Ok(ProtoPriority::LOW) => Priority::Low,
+            Ok(ProtoPriority::MEDIUM) => Priority::Medium,
+            Ok(ProtoPriority::HIGH) => Priority::High,
+            // Unknown values are newer than this library, so we treat them as
+            // the default.
+            Err(_) => Priority::Medium,
+        }
+    }
+
+    /// Converts the priority to its Protocol Buffers representation.
+    pub(crate) fn to_proto(self) -> ProtoPriority {
+        match self {
+            Priority::Low => ProtoPriority::LOW,
+            Priority::Medium => ProtoPriority::MEDIUM,
+            Priority::High => ProtoPriority::HIGH,
+        }
+    }
+}
+
+/// Converts a Protocol Buffers timestamp to a system time.
+///
+/// Returns `None` for invalid timestamps or ones that are not representable.
+fn system_time(timestamp: &protobuf::well_known_types::timestamp::Timestamp) -> Option<SystemTime> {
+    let nanos = Duration::from_nanos(u64::try_from(timestamp.nanos).ok()?);
+    let secs = Duration::from_secs(timestamp.seconds.unsigned_abs());
+
+    let time = if timestamp.seconds >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(secs)?
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(secs)?
+    };
+
+    time.checked_add(nanos)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn from_proto() {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.message_id = b"foo".to_vec();
+        proto.set_priority(ProtoPriority::HIGH);
+        proto.mut_creation_time().seconds = 1337;
+        proto.mut_validation_info().tags.insert(String::from("bar"), String::from("baz"));
+        crate::io::add_annotation(&mut proto, "quux", String::from("norf"));
+
+        let metadata = Metadata::from_proto(&proto);
+        assert_eq!(metadata.message_id, b"foo");
+        assert_eq!(metadata.priority, Priority::High);
+        assert_eq!(metadata.creation_time, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1337)));
+        assert_eq!(metadata.validation_info.get("bar").map(String::as_str), Some("baz"));
+        assert_eq!(metadata.annotations, vec![(String::from("quux"), String::from("norf"))]);
+    }
+
+    #[test]
+    fn from_proto_empty() {
+        let proto = fleetspeak_proto::common::Message::new();
+        assert_eq!(Metadata::from_proto(&proto), Metadata::default());
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Metrics of the traffic exchanged with the Fleetspeak client.
+//!
+//! Metrics are broken down by message kind, so that operators can see which
+//! message types dominate the bandwidth or fail to decode. To keep memory
+//! usage bounded even if kinds are generated dynamically, only the first
+//! [`MAX_KINDS`] distinct kinds are tracked individually. Messages of all the
+//! other kinds are accounted under the [`OTHER_KIND`] entry.
+//!
+//! Apart from the traffic, timings of the connection initialization are also
+//! recorded (see [`init`]). Overall counters of the connection, which are not
+//! affected by the kind limit, are available through [`stats`].
+//!
+//! With the `metrics` feature, everything is also emitted through the facade of
+//! the [`metrics`](::metrics) crate, so services with a metrics recorder
+//! installed get the connector metrics without any extra code:
+//!
+//!   * `fleetspeak_messages_sent_total` and `fleetspeak_bytes_sent_total`
+//!     counters (labelled with `kind`),
+//!   * `fleetspeak_messages_received_total` and
+//!     `fleetspeak_bytes_received_total` counters (labelled with `kind`),
+//!   * `fleetspeak_decode_failures_total` counter (labelled with `kind`),
+//!   * `fleetspeak_heartbeats_sent_total` counter,
+//!   * `fleetspeak_send_latency_seconds` histogram (labelled with `kind`),
+//!   * `fleetspeak_handshake_seconds` histogram.
+//!
+//! The `kind` label is subject to the same [`MAX_KINDS`] limit as the metrics
+//! kept by the library itself.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Maximum number of distinct message kinds tracked individually.
+pub const MAX_KINDS: usize = 64;
+
+/// Name of the entry that accounts for kinds over the [`MAX_KINDS`] limit.
+pub const OTHER_KIND: &str = "(other)";
+
+/// Name of the entry that accounts for messages without a kind.
+pub const NO_KIND: &str = "-";
+
+/// Metrics of messages of a particular kind.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KindMetrics {
+    /// Number of sent messages.
+    pub sent_count: u64,
+    /// Total size of data of sent messages (in bytes).
+    pub sent_bytes: u64,
+    /// Total time spent writing sent messages.
+    pub send_latency_total: Duration,
+    /// Longest time spent writing a single sent message.
+    pub send_latency_max: Duration,
+    /// Number of received messages.
+    pub received_count: u64,
+    /// Total size of data of received messages (in bytes).
+    pub received_bytes: u64,
+    /// Number of received messages that could not be decoded.
+    pub decode_failures: u64,
+}
+
+impl KindMetrics {
+
+    /// Returns the average time spent writing a single sent message.
+    pub fn send_latency_avg(&self) -> Option<Duration> {
+        let count = u32::try_from(self.sent_count).ok()?;
+        self.send_latency_total.checked_div(count)
+    }
+}
+
+/// Overall statistics of the connection.
+///
+/// Unlike [`KindMetrics`], these cover all the messages no matter their kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectorStats {
+    /// Number of sent messages.
+    pub messages_sent: u64,
+    /// Total size of data of sent messages (in bytes).
+    pub bytes_sent: u64,
+    /// Number of received messages.
+    pub messages_received: u64,
+    /// Total size of data of received messages (in bytes).
+    pub bytes_received: u64,
+    /// Number of heartbeats written to the connection.
+    pub heartbeats_sent: u64,
+    /// Number of received messages that could not be decoded.
+    pub decode_failures: u64,
+    /// Time at which the last message was received (if any).
+    pub last_received: Option<SystemTime>,
+}
+
+/// Timings of the connection initialization.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InitMetrics {
+    /// Time it took to resolve the communication channels from the environment.
+    pub env_resolution: Option<Duration>,
+    /// Round-trip time of the handshake with the Fleetspeak client.
+    ///
+    /// This is `None` if the connection has not been established yet or if it
+    /// was inherited from a previous process image (and so no handshake took
+    /// place).
+    pub handshake: Option<Duration>,
+    /// Time between establishing the connection and receiving the first
+    /// message from the server.
+    pub first_message: Option<Duration>,
+}
+
+/// Returns timings of the connection initialization.
+///
+/// Slow handshakes are often an early indicator of an overloaded endpoint, so
+/// it might be worth reporting these along other service metrics.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::startup("0.0.1");
+///
+/// if let Some(handshake) = fleetspeak::metrics::init().handshake {
+///     println!("handshake took {handshake:?}");
+/// }
+/// ```
+pub fn init() -> InitMetrics {
+    *INIT.lock().expect("poisoned metrics mutex")
+}
+
+/// Records the time it took to resolve the communication channels.
+pub(crate) fn record_env_resolution(duration: Duration) {
+    INIT.lock().expect("poisoned metrics mutex").env_resolution = Some(duration);
+}
+
+/// Records the round-trip time of the handshake.
+pub(crate) fn record_handshake(duration: Duration) {
+    INIT.lock().expect("poisoned metrics mutex").handshake = Some(duration);
+
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!("fleetspeak_handshake_seconds").record(duration);
+}
+
+/// Records the time until the first message was received (unless a message
+/// has already been received before).
+pub(crate) fn record_first_message(duration: Duration) {
+    let mut init = INIT.lock().expect("poisoned metrics mutex");
+    if init.first_message.is_none() {
+        log::info!("first message received after {duration:?}");
+        init.first_message = Some(duration);
+    }
+}
+
+/// Returns a snapshot of the overall statistics of the connection.
+///
+/// This is meant to be included in the telemetry of the service to report the
+/// health of the connector.
+///
+/// # Examples
+///
+/// ```no_run
+/// let stats = fleetspeak::stats();
+/// println!("sent {} messages ({} bytes)", stats.messages_sent, stats.bytes_sent);
+/// ```
+pub fn stats() -> ConnectorStats {
+    REGISTRY.lock().expect("poisoned metrics mutex").stats
+}
+
+/// Returns a snapshot of the metrics broken down by message kind.
+///
+/// # Examples
+///
+/// ```no_run
+/// for (kind, metrics) in fleetspeak::metrics::kinds() {
+///     println!("{kind}: {} bytes sent", metrics.sent_bytes);
+/// }
+/// ```
+pub fn kinds() -> BTreeMap<String, KindMetrics> {
+    REGISTRY.lock().expect("poisoned metrics mutex")
+        .kinds.iter()
+        .map(|(kind, metrics)| (kind.clone(), metrics.clone()))
+        .collect()
+}
+
+/// Records a message sent successfully.
+pub(crate) fn record_sent(kind: Option<&str>, bytes: usize, latency: Duration) {
+    let mut registry = REGISTRY.lock().expect("poisoned metrics mutex");
+
+    registry.stats.messages_sent += 1;
+    registry.stats.bytes_sent += bytes as u64;
+
+    #[cfg(feature = "metrics")]
+    {
+        let kind = String::from(registry.kind(kind));
+        ::metrics::counter!("fleetspeak_messages_sent_total", "kind" => kind.clone()).increment(1);
+        ::metrics::counter!("fleetspeak_bytes_sent_total", "kind" => kind.clone()).increment(bytes as u64);
+        ::metrics::histogram!("fleetspeak_send_latency_seconds", "kind" => kind).record(latency);
+    }
+
+    let metrics = registry.entry(kind);
+    metrics.sent_count += 1;
+    metrics.sent_bytes += bytes as u64;
+    metrics.send_latency_total += latency;
+    metrics.send_latency_max = std::cmp::max(metrics.send_latency_max, latency);
+}
+
+/// Records a message received successfully.
+pub(crate) fn record_received(kind: Option<&str>, bytes: usize) {
+    let mut registry = REGISTRY.lock().expect("poisoned metrics mutex");
+
+    registry.stats.messages_received += 1;
+    registry.stats.bytes_received += bytes as u64;
+    registry.stats.last_received = Some(SystemTime::now());
+
+    #[cfg(feature = "metrics")]
+    {
+        let kind = String::from(registry.kind(kind));
+        ::metrics::counter!("fleetspeak_messages_received_total", "kind" => kind.clone()).increment(1);
+        ::metrics::counter!("fleetspeak_bytes_received_total", "kind" => kind).increment(bytes as u64);
+    }
+
+    let metrics = registry.entry(kind);
+    metrics.received_count += 1;
+    metrics.received_bytes += bytes as u64;
+}
+
+/// Records a received message that could not be decoded.
+pub(crate) fn record_decode_failure(kind: Option<&str>) {
+    let mut registry = REGISTRY.lock().expect("poisoned metrics mutex");
+    registry.stats.decode_failures += 1;
+
+    #[cfg(feature = "metrics")]
+    {
+        let kind = String::from(registry.kind(kind));
+        ::metrics::counter!("fleetspeak_decode_failures_total", "kind" => kind).increment(1);
+    }
+
+    registry.entry(kind).decode_failures += 1;
+}
+
+/// Records a heartbeat written to the connection.
+pub(crate) fn record_heartbeat() {
+    REGISTRY.lock().expect("poisoned metrics mutex").stats.heartbeats_sent += 1;
+
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("fleetspeak_heartbeats_sent_total").increment(1);
+}
+
+/// Metrics of all the tracked message kinds.
+#[derive(Default)]
+struct Registry {
+    kinds: HashMap<String, KindMetrics>,
+    /// Overall statistics of the connection.
+    stats: ConnectorStats,
+}
+
+impl Registry {
+
+    /// Returns metrics of the given kind, creating the entry if needed.
+    fn entry(&mut self, kind: Option<&str>) -> &mut KindMetrics {
+        let kind = self.kind(kind);
+        self.kinds.entry(String::from(kind)).or_default()
+    }
+
+    /// Returns the name of the entry that accounts for the given kind.
+    fn kind<'a>(&self, kind: Option<&'a str>) -> &'a str {
+        let kind = match kind {
+            Some("") | None => NO_KIND,
+            Some(kind) => kind,
+        };
+
+        // The entry for other kinds is not counted towards the limit, so that
+        // it is always possible to create it.
+        let tracked = self.kinds.len() - usize::from(self.kinds.contains_key(OTHER_KIND));
+
+        if self.kinds.contains_key(kind) || tracked < MAX_KINDS {
+            kind
+        } else {
+            OTHER_KIND
+        }
+    }
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(|| {
+    Mutex::new(Registry::default())
+});
+
+static INIT: LazyLock<Mutex<InitMetrics>> = LazyLock::new(|| {
+    Mutex::new(InitMetrics::default())
+});
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn entry_no_kind() {
+        let mut registry = Registry::default();
+        registry.entry(None).received_count += 1;
+        registry.entry(Some("")).received_count += 1;
+
+        assert_eq!(registry.kinds[NO_KIND].received_count, 2);
+    }
+
+    #[test]
+    fn entry_bounded() {
+        let mut registry = Registry::default();
+        for i in 0..MAX_KINDS * 2 {
+            registry.entry(Some(&format!("kind-{i}"))).sent_count += 1;
+        }
+
+        assert_eq!(registry.kinds.len(), MAX_KINDS + 1);
+        assert_eq!(registry.kinds["kind-0"].sent_count, 1);
+        assert_eq!(registry.kinds[OTHER_KIND].sent_count, MAX_KINDS as u64);
+    }
+}
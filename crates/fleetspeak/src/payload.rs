@@ -0,0 +1,146 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Helpers for serializing message payloads with [serde].
+//!
+//! JSON is always available with the `serde` feature. It is easy to inspect on
+//! the server side but is rather verbose, which matters for high-rate payloads
+//! given the limit on message size. Compact binary formats are available with
+//! additional features:
+//!
+//!   * [bincode] with the `bincode` feature,
+//!   * [CBOR] with the `cbor` feature.
+//!
+//! Note that bincode is not self-describing, so both sides have to agree on the
+//! exact shape of the data. CBOR does not have this limitation.
+//!
+//! [serde]: https://serde.rs
+//! [bincode]: https://github.com/bincode-org/bincode
+//! [CBOR]: https://cbor.io
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let data = fleetspeak::payload::to_json(&vec!["foo", "bar"])
+//!     .expect("failed to serialize the payload");
+//!
+//! fleetspeak::send(fleetspeak::Message {
+//!     service: String::from("greeter"),
+//!     kind: Some(String::from("names")),
+//!     data,
+//!     ..Default::default()
+//! });
+//! ```
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Serializes the given value as JSON.
+pub fn to_json<T>(value: &T) -> std::io::Result<Vec<u8>>
+where
+    T: Serialize + ?Sized,
+{
+    Ok(serde_json::to_vec(value)?)
+}
+
+/// Deserializes a value from the given JSON data.
+pub fn from_json<T>(data: &[u8]) -> std::io::Result<T>
+where
+    T: DeserializeOwned,
+{
+    Ok(serde_json::from_slice(data)?)
+}
+
+/// Serializes the given value with bincode.
+#[cfg(feature = "bincode")]
+pub fn to_bincode<T>(value: &T) -> std::io::Result<Vec<u8>>
+where
+    T: Serialize + ?Sized,
+{
+    bincode::serialize(value).map_err(invalid_data)
+}
+
+/// Deserializes a value from the given bincode data.
+#[cfg(feature = "bincode")]
+pub fn from_bincode<T>(data: &[u8]) -> std::io::Result<T>
+where
+    T: DeserializeOwned,
+{
+    bincode::deserialize(data).map_err(invalid_data)
+}
+
+/// Serializes the given value as CBOR.
+#[cfg(feature = "cbor")]
+pub fn to_cbor<T>(value: &T) -> std::io::Result<Vec<u8>>
+where
+    T: Serialize + ?Sized,
+{
+    let mut buf = Vec::new();
+    ciborium::into_writer(value, &mut buf).map_err(invalid_data)?;
+
+    Ok(buf)
+}
+
+/// Deserializes a value from the given CBOR data.
+#[cfg(feature = "cbor")]
+pub fn from_cbor<T>(data: &[u8]) -> std::io::Result<T>
+where
+    T: DeserializeOwned,
+{
+    ciborium::from_reader(data).map_err(invalid_data)
+}
+
+/// Converts a format-specific error into an I/O error.
+#[cfg(any(feature = "bincode", feature = "cbor"))]
+fn invalid_data<E>(error: E) -> std::io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Sample {
+        name: String,
+        values: Vec<u64>,
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            name: String::from("foo"),
+            values: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let data = to_json(&sample()).unwrap();
+        assert_eq!(from_json::<Sample>(&data).unwrap(), sample());
+    }
+
+    #[test]
+    fn json_invalid() {
+        assert!(from_json::<Sample>(b"{").is_err());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_roundtrip() {
+        let data = to_bincode(&sample()).unwrap();
+        assert_eq!(from_bincode::<Sample>(&data).unwrap(), sample());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_roundtrip() {
+        let data = to_cbor(&sample()).unwrap();
+        assert_eq!(from_cbor::<Sample>(&data).unwrap(), sample());
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use protobuf::well_known_types::struct_::{Struct, Value};
+
+use crate::Message;
+
+/// Message kind of replies to pings.
+pub const PONG_KIND: &str = "Pong";
+
+/// Makes the library answer pings from the Fleetspeak server on its own.
+///
+/// Once enabled, every received message of the given `kind` is answered with a
+/// message of the [`PONG_KIND`] kind sent back to the server service it came
+/// from, and it is not returned to the service at all. This allows fleet-wide
+/// liveness probes without any support in the receive loop of the service.
+///
+/// The data of the reply is a serialized `google.protobuf.Struct` with the
+/// following fields:
+///
+///   * `version` with the given `version` of the service,
+///   * `library_version` with the version of this library,
+///   * `uptime_secs` with the time since the connection was established.
+///
+/// Pings are only answered while the service receives messages. Calling this
+/// function again replaces the previous configuration.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::answer_pings("Ping", env!("CARGO_PKG_VERSION"));
+/// ```
+pub fn answer_pings(kind: &str, version: &str) {
+    *CONFIG.write().expect("poisoned ping config lock") = Some(Config {
+        kind: String::from(kind),
+        version: String::from(version),
+    });
+}
+
+/// Answers the received message if it is a ping.
+///
+/// Returns `true` if the message has been answered (and thus should not be
+/// passed to the service).
+pub(crate) fn answer(message: &Message) -> bool {
+    let config = CONFIG.read().expect("poisoned ping config lock");
+    let version = match &*config {
+        Some(config) if message.kind.as_deref() == Some(&config.kind[..]) => {
+            config.version.clone()
+        }
+        _ => return false,
+    };
+    drop(config);
+
+    let pong = pong(&version, crate::CONNECTION.established.elapsed());
+
+    let result = protobuf::Message::write_to_bytes(&pong)
+        .map_err(std::io::Error::from)
+        .and_then(|data| crate::deliver(Message {
+            service: message.service.clone(),
+            kind: Some(String::from(PONG_KIND)),
+            data,
+            ..Default::default()
+        }));
+
+    // The ping is consumed anyway: there is nobody to report the failure to
+    // other than the connection status.
+    if let Err(error) = result {
+        crate::close(&error);
+    }
+
+    true
+}
+
+/// Builds the payload of the reply to a ping.
+fn pong(version: &str, uptime: Duration) -> Struct {
+    let mut pong = Struct::new();
+    let mut insert = |key: &str, value: Value| {
+        pong.fields.insert(String::from(key), value);
+    };
+
+    let mut value = Value::new();
+    value.set_string_value(String::from(version));
+    insert("version", value);
+
+    let mut value = Value::new();
+    value.set_string_value(String::from(crate::VERSION));
+    insert("library_version", value);
+
+    let mut value = Value::new();
+    value.set_number_value(uptime.as_secs_f64());
+    insert("uptime_secs", value);
+
+    pong
+}
+
+/// Configuration of answering pings.
+struct Config {
+    /// Message kind of pings.
+    kind: String,
+    /// Version of the service reported in replies.
+    version: String,
+}
+
+static CONFIG: RwLock<Option<Config>> = RwLock::new(None);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn pong_fields() {
+        let pong = pong("1.2.3", Duration::from_secs(42));
+
+        assert_eq!(pong.fields["version"].string_value(), "1.2.3");
+        assert_eq!(pong.fields["library_version"].string_value(), crate::VERSION);
+        assert_eq!(pong.fields["uptime_secs"].number_value(), 42.0);
+    }
+}
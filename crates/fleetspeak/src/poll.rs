@@ -0,0 +1,183 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Integration of the input channel with external event loops.
+
+#[cfg(target_family = "windows")]
+use std::sync::{LazyLock, Mutex};
+
+/// Returns a descriptor that becomes readable when a message is available.
+///
+/// This allows services built around an event loop (e.g. `calloop`, `glib` or
+/// a custom reactor built on `poll`) to wait for incoming messages without
+/// dedicating a thread to a blocking [`receive`] call.
+///
+/// The readiness of the descriptor is level-triggered. Because some data might
+/// have been already buffered by the library, once the descriptor is ready all
+/// the available messages should be consumed with [`recv_many`] using a zero
+/// timeout. Otherwise the event loop might not be woken up for messages that
+/// have been buffered but not yet consumed.
+///
+/// On Unix, this is the descriptor of the input channel itself.
+///
+/// [`receive`]: crate::receive
+/// [`recv_many`]: crate::recv_many
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::os::fd::AsRawFd as _;
+/// use std::time::Duration;
+///
+/// let mut pollfd = libc::pollfd {
+///     fd: fleetspeak::poll_handle().as_raw_fd(),
+///     events: libc::POLLIN,
+///     revents: 0,
+/// };
+///
+/// loop {
+///     // SAFETY: We pass a single valid `pollfd` structure.
+///     unsafe { libc::poll(&mut pollfd, 1, -1) };
+///
+///     let messages = fleetspeak::recv_many(usize::MAX, Duration::ZERO)
+///         .expect("failed to receive messages");
+///     for message in messages {
+///         println!("received {}", message.preview());
+///     }
+/// }
+/// ```
+#[cfg(target_family = "unix")]
+pub fn poll_handle() -> std::os::fd::BorrowedFd<'static> {
+    // SAFETY: The descriptor belongs to the global connection which is never
+    // dropped. Closing the input channel does not release the descriptor (it
+    // is redirected to `/dev/null` instead), so it stays open for the rest of
+    // the process lifetime.
+    unsafe {
+        std::os::fd::BorrowedFd::borrow_raw(crate::CONNECTION.input_fd)
+    }
+}
+
+/// Returns an event object that is signaled when a message is available.
+///
+/// This allows services built around an event loop (e.g. one built on
+/// `WaitForMultipleObjects`) to wait for incoming messages without dedicating
+/// a thread to a blocking [`receive`] call.
+///
+/// The event is a manual-reset event that stays signaled as long as there are
+/// messages to consume, so once it is signaled all the available messages
+/// should be consumed with [`recv_many`] using a zero timeout. Anonymous pipes
+/// cannot be waited on directly, so the event is maintained by a background
+/// thread that checks the input channel periodically. Hence, the event might
+/// lag behind the actual state of the channel by a few milliseconds.
+///
+/// [`receive`]: crate::receive
+/// [`recv_many`]: crate::recv_many
+#[cfg(target_family = "windows")]
+pub fn poll_handle() -> std::os::windows::io::BorrowedHandle<'static> {
+    let mut watcher = WATCHER.lock().expect("poisoned poll watcher mutex");
+    if watcher.is_none() {
+        *watcher = Some(std::thread::spawn(watch));
+    }
+    drop(watcher);
+
+    // SAFETY: The event is owned by a global object which is never dropped, so
+    // it stays valid for the rest of the process lifetime.
+    unsafe {
+        std::os::windows::io::BorrowedHandle::borrow_raw(EVENT.handle)
+    }
+}
+
+/// Stops the thread maintaining the readiness event (if it is running) and
+/// waits for it to exit.
+#[cfg(target_family = "windows")]
+pub(crate) fn stop() {
+    let watcher = WATCHER.lock().expect("poisoned poll watcher mutex").take();
+    if let Some(watcher) = watcher {
+        EVENT.stopping.store(true, std::sync::atomic::Ordering::SeqCst);
+        if watcher.join().is_err() {
+            log::error!("poll watcher thread panicked");
+        }
+        EVENT.stopping.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Body of the thread maintaining the readiness event.
+#[cfg(target_family = "windows")]
+fn watch() {
+    use windows_sys::Win32::System::Threading::{ResetEvent, SetEvent};
+
+    const INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+    while !EVENT.stopping.load(std::sync::atomic::Ordering::SeqCst) {
+        // If the input is locked, someone is reading the messages already and
+        // the event will be updated in one of the next rounds.
+        if let Ok(input) = crate::CONNECTION.input.try_lock() {
+            // Errors are reported as readiness, so that they are surfaced by
+            // the subsequent read.
+            let ready = !input.buffer().is_empty() || input.get_ref()
+                .wait(std::time::Duration::ZERO)
+                .unwrap_or(true);
+            drop(input);
+
+            // SAFETY: The event is owned by a global object and thus valid.
+            // The calls cannot fail for a valid event handle.
+            unsafe {
+                if ready {
+                    SetEvent(EVENT.handle);
+                } else {
+                    ResetEvent(EVENT.handle);
+                }
+            }
+        }
+
+        std::thread::sleep(INTERVAL);
+    }
+}
+
+/// A manual-reset event signaled when input is available.
+#[cfg(target_family = "windows")]
+struct Event {
+    handle: windows_sys::Win32::Foundation::HANDLE,
+    /// Whether the watcher thread should exit.
+    stopping: std::sync::atomic::AtomicBool,
+}
+
+// SAFETY: Event handles can be used from any thread.
+#[cfg(target_family = "windows")]
+unsafe impl Send for Event {
+}
+
+// SAFETY: Event handles can be used from any thread.
+#[cfg(target_family = "windows")]
+unsafe impl Sync for Event {
+}
+
+#[cfg(target_family = "windows")]
+static EVENT: LazyLock<Event> = LazyLock::new(|| {
+    // SAFETY: We create an unnamed manual-reset event that is initially
+    // not signaled as described in the docs [1]. We verify the result
+    // afterwards.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-createeventw
+    let handle = unsafe {
+        windows_sys::Win32::System::Threading::CreateEventW(
+            std::ptr::null(),
+            windows_sys::Win32::Foundation::TRUE,
+            windows_sys::Win32::Foundation::FALSE,
+            std::ptr::null(),
+        )
+    };
+    if handle.is_null() {
+        panic!("failed to create poll event: {}", std::io::Error::last_os_error());
+    }
+
+    Event {
+        handle,
+        stopping: std::sync::atomic::AtomicBool::new(false),
+    }
+});
+
+#[cfg(target_family = "windows")]
+static WATCHER: Mutex<Option<std::thread::JoinHandle<()>>> = Mutex::new(None);
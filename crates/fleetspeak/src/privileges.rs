@@ -0,0 +1,16 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+#[cfg(target_family = "unix")]
+mod unix;
+
+#[cfg(target_family = "windows")]
+mod windows;
+
+#[cfg(target_family = "unix")]
+pub use self::unix::drop_privileges_to;
+
+#[cfg(target_family = "windows")]
+pub use self::windows::drop_token_privileges;
@@ -0,0 +1,85 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+/// Drops privileges of the service process to the given user and group.
+///
+/// The connection to the Fleetspeak client is established (see [`init`])
+/// before any privileges are dropped. Because the communication channel uses
+/// descriptors that are already open, it keeps working afterwards regardless
+/// of the permissions of the new user. An error is returned if the connection
+/// cannot be established.
+///
+/// Privileges are dropped in the only safe order: supplementary groups are
+/// cleared first, then the group identifier is changed and the user identifier
+/// is changed last. Afterwards, the function verifies that the original
+/// privileges cannot be regained and returns an error if they can.
+///
+/// Note that in case of an error the process might be left with privileges
+/// partially dropped. It is advised to terminate the service in such cases.
+///
+/// [`init`]: crate::init
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::drop_privileges_to(65534, 65534)
+///     .expect("failed to drop privileges");
+///
+/// fleetspeak::startup("0.0.1");
+/// ```
+pub fn drop_privileges_to(uid: libc::uid_t, gid: libc::gid_t) -> std::io::Result<()> {
+    crate::init().map_err(std::io::Error::other)?;
+
+    // SAFETY: An empty list of groups requires no buffer [1]. We verify the
+    // status after the call.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/setgroups.2.html
+    if unsafe { libc::setgroups(0, std::ptr::null()) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: This function has no memory safety requirements [1]. We verify
+    // the status after the call.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/setgid.2.html
+    if unsafe { libc::setgid(gid) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: This function has no memory safety requirements [1]. We verify
+    // the status after the call.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/setuid.2.html
+    if unsafe { libc::setuid(uid) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: These functions have no memory safety requirements and always
+    // succeed [1, 2].
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/getuid.2.html
+    // [2]: https://man7.org/linux/man-pages/man2/getgid.2.html
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if euid != uid || egid != gid {
+        return Err(std::io::Error::other({
+            format!("unexpected identity after drop (uid: {euid}, gid: {egid})")
+        }));
+    }
+
+    // Unless we dropped to the superuser itself (which makes no sense but is
+    // not a reason to fail), regaining the superuser identity must not work.
+    if uid != 0 {
+        // SAFETY: See the comment for the `setuid` call above.
+        if unsafe { libc::setuid(0) } == 0 {
+            return Err(std::io::Error::other({
+                "privileges can be regained after drop"
+            }));
+        }
+    }
+
+    log::info!("dropped privileges (uid: {uid}, gid: {gid})");
+
+    Ok(())
+}
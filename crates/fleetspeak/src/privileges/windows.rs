@@ -0,0 +1,160 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use windows_sys::Win32::Foundation::{CloseHandle, FALSE, HANDLE};
+use windows_sys::Win32::Security::*;
+
+/// Drops all privileges of the service process token.
+///
+/// The connection to the Fleetspeak client is established (see [`init`])
+/// before any privileges are dropped. Because the communication channel uses
+/// handles that are already open, it keeps working afterwards regardless of
+/// the privileges held by the process. An error is returned if the connection
+/// cannot be established.
+///
+/// All privileges held by the process token are removed (not just disabled),
+/// so they cannot be enabled again for the remaining lifetime of the process.
+///
+/// [`init`]: crate::init
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::drop_token_privileges()
+///     .expect("failed to drop privileges");
+///
+/// fleetspeak::startup("0.0.1");
+/// ```
+pub fn drop_token_privileges() -> std::io::Result<()> {
+    crate::init().map_err(std::io::Error::other)?;
+
+    let mut token = std::mem::MaybeUninit::uninit();
+
+    // SAFETY: The process handle is a pseudo-handle that is always valid and
+    // we pass a valid pointer for the token handle [1]. We verify the status
+    // after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-openprocesstoken
+    let status = unsafe {
+        windows_sys::Win32::System::Threading::OpenProcessToken(
+            windows_sys::Win32::System::Threading::GetCurrentProcess(),
+            TOKEN_QUERY | TOKEN_ADJUST_PRIVILEGES,
+            token.as_mut_ptr(),
+        )
+    };
+    if status == FALSE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // SAFETY: We verified that the call to `OpenProcessToken` succeeded and
+    // thus the token handle is guaranteed to be initialized.
+    let token = Token(unsafe { token.assume_init() });
+
+    let mut privileges = token.privileges()?;
+
+    // The buffer is guaranteed to start with a properly initialized and aligned
+    // `TOKEN_PRIVILEGES` structure followed by the number of entries specified
+    // in its `PrivilegeCount` field.
+    let header = privileges.as_mut_ptr().cast::<TOKEN_PRIVILEGES>();
+
+    // SAFETY: See the comment above. We iterate only over entries that are
+    // declared to be in the buffer and we do not create intermediate references
+    // to the array (which is declared to have only one element).
+    unsafe {
+        let count = (*header).PrivilegeCount as usize;
+        let entries = std::ptr::addr_of_mut!((*header).Privileges)
+            .cast::<LUID_AND_ATTRIBUTES>();
+
+        for i in 0..count {
+            (*entries.add(i)).Attributes = SE_PRIVILEGE_REMOVED;
+        }
+    }
+
+    // SAFETY: The token handle is valid and opened with the adjust privileges
+    // access right, the new state is a valid `TOKEN_PRIVILEGES` structure and
+    // we do not ask for the previous state [1]. We verify the status after the
+    // call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/securitybaseapi/nf-securitybaseapi-adjusttokenprivileges
+    let status = unsafe {
+        AdjustTokenPrivileges(
+            token.0,
+            FALSE,
+            header,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if status == FALSE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // `AdjustTokenPrivileges` succeeds even if not all the privileges were
+    // removed, so we need to check the last error to be sure.
+    let error = std::io::Error::last_os_error();
+    let not_all_assigned = windows_sys::Win32::Foundation::ERROR_NOT_ALL_ASSIGNED;
+    if error.raw_os_error() == Some(not_all_assigned as i32) {
+        return Err(error);
+    }
+
+    log::info!("dropped privileges of the process token");
+
+    Ok(())
+}
+
+/// An owned token handle that is closed when dropped.
+struct Token(HANDLE);
+
+impl Token {
+
+    /// Returns a buffer with the `TOKEN_PRIVILEGES` structure of the token.
+    ///
+    /// The buffer consists of `u64` elements to guarantee proper alignment of
+    /// the structure.
+    fn privileges(&self) -> std::io::Result<Vec<u64>> {
+        let mut len = 0;
+
+        // SAFETY: We pass no buffer to query the required buffer length [1].
+        // The call is expected to fail, so we do not check its status.
+        //
+        // [1]: https://learn.microsoft.com/en-us/windows/win32/api/securitybaseapi/nf-securitybaseapi-gettokeninformation
+        unsafe {
+            GetTokenInformation(self.0, TokenPrivileges, std::ptr::null_mut(), 0, &mut len);
+        }
+
+        let mut buf = vec![0u64; (len as usize).div_ceil(std::mem::size_of::<u64>())];
+
+        // SAFETY: We pass a valid buffer of at least the length reported by the
+        // previous call [1]. We verify the status after the call.
+        //
+        // [1]: https://learn.microsoft.com/en-us/windows/win32/api/securitybaseapi/nf-securitybaseapi-gettokeninformation
+        let status = unsafe {
+            GetTokenInformation(
+                self.0,
+                TokenPrivileges,
+                buf.as_mut_ptr().cast(),
+                len,
+                &mut len,
+            )
+        };
+        if status == FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(buf)
+    }
+}
+
+impl Drop for Token {
+
+    fn drop(&mut self) {
+        // SAFETY: The handle is valid and owned by us. Failure to close it is
+        // not something we can handle in any meaningful way.
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Reading of incoming messages by a background reader thread.
+//!
+//! Blocking receivers do not read the input channel themselves: they submit a
+//! request to the reader thread and wait for it to hand over the next message.
+//! Concurrent receivers thus only contend on the request queue (which is held
+//! briefly) and are served in the order of their requests, and the input
+//! channel is only ever held by the reader thread while it reads a frame.
+//!
+//! The reader thread reads a frame only when there is a request for it. Data
+//! that nobody asked for stays in the channel, so polling the channel (see
+//! [`poll_handle`]) and handing it over to another process (see
+//! [`prepare_exec`]) keep working. The only exception are frames that started
+//! arriving for a request which gave up waiting before they arrived in full:
+//! these are kept by the reader thread and handed over to the next request.
+//!
+//! [`poll_handle`]: crate::poll_handle
+//! [`prepare_exec`]: crate::prepare_exec
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::time::Instant;
+
+use fleetspeak_proto::common::Message;
+
+/// Waits for the reader thread to read the next message.
+///
+/// I/O errors that occurred while reading the message are returned as-is and
+/// it is up to the caller to decide whether they break the connection.
+pub(crate) fn recv() -> std::io::Result<Message> {
+    match wait(&submit(None), false) {
+        Some(result) => result,
+        None => unreachable!("request without deadline given up"),
+    }
+}
+
+/// Waits for the reader thread to read the next message until the `deadline`.
+///
+/// Returns `None` if the message does not arrive in full by the deadline. A
+/// message that has only partially arrived by then is not lost: the reader
+/// thread reads the rest of it and hands it over to the next request.
+pub(crate) fn recv_until(deadline: Instant) -> Option<std::io::Result<Message>> {
+    wait(&submit(Some(deadline)), true)
+}
+
+/// Waits for the reader thread to read the next message if it starts arriving
+/// before the `deadline`.
+///
+/// Returns `None` if there is no data by the deadline. Unlike [`recv_until`],
+/// a message that has started arriving is waited for until it arrives in full.
+pub(crate) fn recv_started_by(deadline: Instant) -> Option<std::io::Result<Message>> {
+    wait(&submit(Some(deadline)), false)
+}
+
+/// Returns whether there are messages read on behalf of requests that gave up
+/// waiting for them and that have not been handed over to anyone yet.
+pub(crate) fn has_unclaimed() -> bool {
+    let queue = QUEUE.requests.lock().expect("poisoned reader queue mutex");
+    !queue.unclaimed.is_empty()
+}
+
+/// Submits a new request to the reader queue.
+fn submit(deadline: Option<Instant>) -> Arc<Request> {
+    let request = Arc::new(Request {
+        deadline,
+        state: Mutex::new(State::Pending),
+        done: Condvar::new(),
+    });
+
+    let mut queue = QUEUE.requests.lock().expect("poisoned reader queue mutex");
+    spawn(&mut queue);
+    queue.pending.push_back(request.clone());
+    drop(queue);
+
+    QUEUE.ready.notify_one();
+
+    request
+}
+
+/// Waits for the reader thread to serve the `request`.
+///
+/// Returns `None` if the request has a deadline that passes before the reader
+/// thread gets to it or before the message arrives in full (if `abandon` is
+/// set). Otherwise, the request is waited for even past its deadline, which is
+/// short as the reader thread stops waiting for data by then.
+fn wait(request: &Arc<Request>, abandon: bool) -> Option<std::io::Result<Message>> {
+    let mut state = request.state.lock().expect("poisoned reader request mutex");
+    loop {
+        if let State::Done(_) = &*state {
+            match std::mem::replace(&mut *state, State::Done(None)) {
+                State::Done(result) => return result,
+                _ => unreachable!(),
+            }
+        }
+
+        let now = Instant::now();
+        match request.deadline {
+            Some(deadline) if now < deadline => {
+                state = request.done.wait_timeout(state, deadline - now)
+                    .expect("poisoned reader request mutex").0;
+                continue;
+            }
+            Some(_) => match *state {
+                State::Pending if withdraw(request) => return None,
+                State::Reading if abandon => {
+                    *state = State::Abandoned;
+                    return None;
+                }
+                _ => (),
+            }
+            None => (),
+        }
+
+        state = request.done.wait(state).expect("poisoned reader request mutex");
+    }
+}
+
+/// Removes the `request` from the queue unless the reader thread is about to
+/// serve it (or already does).
+///
+/// Returns `true` if the request has been removed.
+fn withdraw(request: &Arc<Request>) -> bool {
+    let mut queue = QUEUE.requests.lock().expect("poisoned reader queue mutex");
+    match queue.pending.iter().position(|pending| Arc::ptr_eq(pending, request)) {
+        Some(0) if !queue.reading => false,
+        Some(index) => {
+            queue.pending.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Spawns the reader thread unless it is already running.
+pub(crate) fn start() {
+    let mut queue = QUEUE.requests.lock().expect("poisoned reader queue mutex");
+    spawn(&mut queue);
+}
+
+/// Resets the queue in a child process after `fork`.
+///
+/// The reader thread does not survive `fork`, so it has to be spawned again
+/// once there is something to read. Requests pending at the time of the fork
+/// belong to threads of the parent process and are discarded.
+#[cfg(target_family = "unix")]
+pub(crate) fn after_fork() -> std::io::Result<()> {
+    let mut queue = match QUEUE.requests.try_lock() {
+        Ok(queue) => queue,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "reader queue locked during fork"
+        })),
+    };
+
+    if queue.reading {
+        return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "reader busy during fork"
+        }));
+    }
+
+    // Messages not handed over yet have been taken from the channel already,
+    // so they are kept for the child process.
+    queue.pending.clear();
+    queue.running = false;
+    queue.stopping = false;
+    // The reader thread does not exist in the child process, so its handle
+    // must not be joined nor detached.
+    std::mem::forget(queue.thread.take());
+
+    Ok(())
+}
+
+/// Stops the reader thread.
+///
+/// Pending requests are served before the thread exits. The thread is waited
+/// for only if it is idle: a thread blocked on the input channel exits once
+/// the frame it reads arrives. The reader thread is spawned again once there
+/// is a new request.
+pub(crate) fn stop() {
+    let mut queue = QUEUE.requests.lock().expect("poisoned reader queue mutex");
+    let thread = match queue.thread.take() {
+        Some(thread) => thread,
+        None => return,
+    };
+    queue.stopping = true;
+    let busy = queue.reading || !queue.pending.is_empty();
+    drop(queue);
+
+    QUEUE.ready.notify_all();
+
+    if busy {
+        return;
+    }
+
+    if thread.join().is_err() {
+        log::error!("reader thread panicked");
+    }
+}
+
+/// Spawns the reader thread on the locked queue unless it is already running.
+fn spawn(queue: &mut Requests) {
+    if !queue.running {
+        queue.thread = Some(std::thread::spawn(run));
+        queue.running = true;
+    }
+}
+
+/// Body of the reader thread.
+fn run() {
+    loop {
+        let mut queue = QUEUE.requests.lock().expect("poisoned reader queue mutex");
+        queue.reading = false;
+        let request = loop {
+            match queue.pending.pop_front() {
+                Some(request) => break request,
+                None if queue.stopping => {
+                    queue.stopping = false;
+                    queue.running = false;
+                    return;
+                }
+                None => {
+                    queue = QUEUE.ready.wait(queue)
+                        .expect("poisoned reader queue mutex");
+                }
+            }
+        };
+        queue.reading = true;
+        let unclaimed = queue.unclaimed.pop_front();
+        drop(queue);
+
+        let result = match unclaimed {
+            Some(result) => Some(result),
+            None => read(&request),
+        };
+
+        if let Some(result) = request.finish(result) {
+            let mut queue = QUEUE.requests.lock().expect("poisoned reader queue mutex");
+            queue.unclaimed.push_back(result);
+        }
+    }
+}
+
+/// Reads a message from the input channel for the given `request`.
+///
+/// Returns `None` if there is no data by the deadline of the request.
+fn read(request: &Request) -> Option<std::io::Result<Message>> {
+    let mut input = crate::CONNECTION.input.lock()
+        .expect("poisoned connection mutex");
+
+    // Data buffered by the reader has been already taken from the channel, so
+    // we have to check it before asking the channel itself.
+    let ready = match request.deadline {
+        Some(_) if !input.buffer().is_empty() => Ok(true),
+        Some(deadline) => {
+            input.get_ref().wait(deadline.saturating_duration_since(Instant::now()))
+        }
+        None => Ok(true),
+    };
+
+    match ready {
+        Ok(true) => {
+            request.start();
+            Some(input.read_proto())
+        }
+        Ok(false) => None,
+        Err(error) => Some(Err(error)),
+    }
+}
+
+/// A single request for a message submitted to the reader queue.
+struct Request {
+    /// Time until which the reader thread waits for data (if limited).
+    deadline: Option<Instant>,
+    state: Mutex<State>,
+    /// Notified when the state changes.
+    done: Condvar,
+}
+
+/// State of a request for a message.
+enum State {
+    /// The reader thread has not started reading the message yet.
+    Pending,
+    /// The reader thread reads the message.
+    Reading,
+    /// The requester gave up waiting while the message was being read.
+    Abandoned,
+    /// The request has been served (`None` if there was no data in time).
+    Done(Option<std::io::Result<Message>>),
+}
+
+impl Request {
+
+    /// Marks the request as being read.
+    fn start(&self) {
+        *self.state.lock().expect("poisoned reader request mutex") = State::Reading;
+        self.done.notify_all();
+    }
+
+    /// Hands the `result` over to the requester.
+    ///
+    /// Returns the message back if the requester has given up waiting for it.
+    fn finish(&self, result: Option<std::io::Result<Message>>) -> Option<std::io::Result<Message>> {
+        let mut state = self.state.lock().expect("poisoned reader request mutex");
+        if let State::Abandoned = *state {
+            return result;
+        }
+
+        *state = State::Done(result);
+        drop(state);
+
+        self.done.notify_all();
+        None
+    }
+}
+
+/// The queue of requests shared between the receivers and the reader thread.
+struct Queue {
+    requests: Mutex<Requests>,
+    /// Notified when new requests are submitted.
+    ready: Condvar,
+}
+
+struct Requests {
+    /// Pending requests in the order of submission.
+    pending: VecDeque<Arc<Request>>,
+    /// Messages read for abandoned requests, handed over to the next ones.
+    unclaimed: VecDeque<std::io::Result<Message>>,
+    running: bool,
+    /// Whether the reader thread is busy with a request taken from the queue.
+    reading: bool,
+    /// Whether the reader thread should exit once the queue is empty.
+    stopping: bool,
+    /// Handle of the reader thread (unless it is being stopped).
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+static QUEUE: LazyLock<Queue> = LazyLock::new(|| Queue {
+    requests: Mutex::new(Requests {
+        pending: VecDeque::new(),
+        unclaimed: VecDeque::new(),
+        running: false,
+        reading: false,
+        stopping: false,
+        thread: None,
+    }),
+    ready: Condvar::new(),
+});
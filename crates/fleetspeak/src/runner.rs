@@ -0,0 +1,101 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::time::Duration;
+
+use crate::Message;
+
+/// Default frequency of heartbeats sent while waiting for messages.
+const DEFAULT_HEARTBEAT_RATE: Duration = Duration::from_secs(30);
+
+/// Maximum time to wait for queued messages to be written after shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Fleetspeak service driven by [`run`].
+///
+/// Implementors only provide the logic for handling messages: [`run`] takes
+/// care of the startup, heartbeating and the receive loop. Only the version and
+/// [`handle`] are mandatory, the remaining hooks have sensible defaults.
+///
+/// [`handle`]: Service::handle
+pub trait Service {
+
+    /// Returns the version of the service reported at startup.
+    ///
+    /// Typically this is the version of the service crate, i.e.
+    /// `env!("CARGO_PKG_VERSION")`.
+    fn startup_version(&self) -> &str;
+
+    /// Returns the frequency of heartbeats sent while waiting for messages.
+    fn heartbeat_rate(&self) -> Duration {
+        DEFAULT_HEARTBEAT_RATE
+    }
+
+    /// Handles a message received from the Fleetspeak server.
+    fn handle(&mut self, message: Message);
+
+    /// Cleans up once the connection with the Fleetspeak client is closed.
+    ///
+    /// Messages sent from this hook are still delivered (as long as the output
+    /// channel is not closed as well).
+    fn shutdown(&mut self) {
+    }
+}
+
+/// Runs the given service until the connection is closed.
+///
+/// This sends the startup information and then receives messages (heartbeating
+/// while waiting for them) and passes them to [`Service::handle`]. Malformed
+/// messages are logged and skipped. Once the Fleetspeak client closes the
+/// connection (e.g. because it wants the service to stop), the [shutdown hook]
+/// is called and queued messages are given a moment to be written before this
+/// function returns.
+///
+/// [shutdown hook]: Service::shutdown
+///
+/// # Examples
+///
+/// ```no_run
+/// struct Echo;
+///
+/// impl fleetspeak::Service for Echo {
+///
+///     fn startup_version(&self) -> &str {
+///         "1.0.0"
+///     }
+///
+///     fn handle(&mut self, message: fleetspeak::Message) {
+///         fleetspeak::send(message);
+///     }
+/// }
+///
+/// fleetspeak::run(Echo);
+/// ```
+pub fn run<S>(mut service: S)
+where
+    S: Service,
+{
+    crate::startup(service.startup_version());
+
+    let rate = service.heartbeat_rate();
+    loop {
+        match crate::heartbeats::with_heartbeat(rate, crate::try_receive) {
+            Ok(message) => service.handle(message),
+            Err(error) if error.is_malformed() => {
+                log::warn!("skipping malformed message: {error}");
+            }
+            Err(error) => {
+                log::info!("connection closed: {error}");
+                break;
+            }
+        }
+    }
+
+    service.shutdown();
+
+    if !crate::drain(DRAIN_TIMEOUT) {
+        log::warn!("not all messages were written before shutdown");
+    }
+}
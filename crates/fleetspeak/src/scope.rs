@@ -0,0 +1,125 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::Mutex;
+
+/// A scope in which the library threads are allowed to run.
+///
+/// See documentation for [`scope`] for more details.
+#[derive(Clone, Copy)]
+pub struct Scope<'scope, 'env: 'scope> {
+    inner: &'scope std::thread::Scope<'scope, 'env>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+
+    /// Spawns a thread within the scope.
+    ///
+    /// This behaves exactly like [`std::thread::Scope::spawn`]: the thread is
+    /// joined before [`scope`] returns (if it has not been joined manually).
+    pub fn spawn<F, T>(&self, f: F) -> std::thread::ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        self.inner.spawn(f)
+    }
+}
+
+/// Runs the given function with the background threads of the library tied to
+/// its execution.
+///
+/// Some functions of the library spawn background threads (e.g. the writer
+/// used by [`send_timeout`] or the [keepalive probe]). Normally, these threads
+/// are detached and live as long as the process. Within a scope, they are
+/// stopped and joined once the function returns (or panics) instead. Messages
+/// that are still queued for sending are written before that happens, so this
+/// might block if the Fleetspeak client does not read them.
+///
+/// Like [`std::thread::scope`], the function is given a [`Scope`] object that
+/// can be used to spawn threads borrowing from the enclosing environment. All
+/// of these are joined before the library threads are stopped.
+///
+/// The library threads are shared by the whole process: if multiple scopes
+/// run concurrently, the threads are stopped when the last of them exits. Using
+/// the library after the scope exits is allowed and spawns the threads again.
+///
+/// [`send_timeout`]: crate::send_timeout
+/// [keepalive probe]: crate::start_keepalive
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let mut count = 0;
+///
+/// fleetspeak::scope(|ctx| {
+///     fleetspeak::start_keepalive(Duration::from_secs(30), Duration::from_secs(10));
+///
+///     ctx.spawn(|| {
+///         count = fleetspeak::recv_many(16, Duration::from_secs(60))
+///             .expect("failed to receive messages")
+///             .len();
+///     });
+/// });
+///
+/// // No library threads are running at this point.
+/// println!("received {count} messages");
+/// ```
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(Scope<'scope, 'env>) -> T,
+{
+    *SCOPES.lock().expect("poisoned scope mutex") += 1;
+
+    // The guard stops the library threads even if the function panics.
+    let _guard = Guard;
+
+    std::thread::scope(|inner| f(Scope { inner }))
+}
+
+/// Stops the library threads when the last active scope exits.
+struct Guard;
+
+impl Drop for Guard {
+
+    fn drop(&mut self) {
+        let mut scopes = SCOPES.lock().expect("poisoned scope mutex");
+        *scopes -= 1;
+        if *scopes > 0 {
+            return;
+        }
+
+        crate::heartbeats::stop();
+        crate::keepalive::stop();
+        crate::reader::stop();
+        crate::writer::stop();
+        #[cfg(target_family = "windows")]
+        crate::poll::stop();
+    }
+}
+
+/// Number of scopes that are currently active.
+static SCOPES: Mutex<usize> = Mutex::new(0);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn scope_spawn() {
+        let mut values = Vec::new();
+
+        let result = scope(|ctx| {
+            ctx.spawn(|| values.push(42));
+            1337
+        });
+
+        assert_eq!(result, 1337);
+        assert_eq!(values, vec![42]);
+    }
+}
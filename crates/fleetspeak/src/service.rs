@@ -0,0 +1,301 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Integration with the Windows Service Control Manager (SCM).
+//!
+//! Fleetspeak daemon services that are also registered as Windows services have
+//! to talk to two parties: the Fleetspeak client (through the inherited
+//! communication handles) and the SCM (through the service control dispatcher).
+//! Getting the interplay right is surprisingly subtle: the SCM expects timely
+//! status reports, stop requests arrive on a separate thread and exiting
+//! without flushing queued messages loses data.
+//!
+//! [`run_service`] takes care of all of that. It connects to the SCM, eagerly
+//! establishes the Fleetspeak connection, reports the service as running and
+//! calls the service body. Once the SCM asks the service to stop (or the system
+//! shuts down), the body is notified through the [`Shutdown`] handle. After the
+//! body returns, messages queued for sending are [drained] before the service
+//! is reported as stopped.
+//!
+//! [drained]: crate::drain
+
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::time::Duration;
+
+use windows_sys::Win32::Foundation::*;
+use windows_sys::Win32::System::Services::*;
+
+/// Maximum time to wait for queued messages to be written while stopping.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A handle notified when the SCM requests the service to stop.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+
+    /// Returns whether the service has been requested to stop.
+    pub fn is_requested(&self) -> bool {
+        *self.inner.0.lock().expect("poisoned shutdown mutex")
+    }
+
+    /// Waits until the service is requested to stop or the `timeout` elapses.
+    ///
+    /// Returns `true` if the service has been requested to stop. This can be
+    /// used as an interruptible replacement for [`std::thread::sleep`] in the
+    /// main loop of the service.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (requested, cvar) = &*self.inner;
+
+        let requested = requested.lock().expect("poisoned shutdown mutex");
+        let (requested, _) = cvar.wait_timeout_while(requested, timeout, |requested| !*requested)
+            .expect("poisoned shutdown mutex");
+
+        *requested
+    }
+
+    /// Notifies all the waiters that the service should stop.
+    fn request(&self) {
+        let (requested, cvar) = &*self.inner;
+
+        *requested.lock().expect("poisoned shutdown mutex") = true;
+        cvar.notify_all();
+    }
+}
+
+/// Runs the given function as the body of a Windows service.
+///
+/// The `name` is the name under which the service is registered with the SCM.
+/// This function blocks until the service stops and must be called early in
+/// the process lifetime, as the SCM gives up on services that do not connect
+/// to it within a short period of time.
+///
+/// The service body receives a [`Shutdown`] handle that it should poll (or wait
+/// on) and return once stopping is requested. After it returns, messages queued
+/// with [`send_timeout`] are drained and the service is reported as stopped. If
+/// the body panics, the service is reported as failed.
+///
+/// An error is returned if the process is not running as a service or if the
+/// SCM rejects the connection.
+///
+/// [`send_timeout`]: crate::send_timeout
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fleetspeak::service::run_service("FleetspeakExample", |shutdown| {
+///     fleetspeak::startup("0.0.1");
+///
+///     while !shutdown.wait_timeout(Duration::from_secs(1)) {
+///         fleetspeak::heartbeat();
+///     }
+/// }).expect("failed to run the service");
+/// ```
+pub fn run_service<F>(name: &str, main: F) -> std::io::Result<()>
+where
+    F: FnOnce(Shutdown) + Send + 'static,
+{
+    let mut name = name.encode_utf16().collect::<Vec<u16>>();
+    name.push(0);
+
+    *PENDING.lock().expect("poisoned service mutex") = Some(Pending {
+        name: name.clone(),
+        main: Box::new(main),
+    });
+
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: std::ptr::null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+
+    // SAFETY: The table is a valid array of service entries terminated with a
+    // null entry as required by the documentation [1]. The service name lives
+    // until the end of the call which blocks until the service stops. We verify
+    // the status after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/winsvc/nf-winsvc-startservicectrldispatcherw
+    let status = unsafe {
+        StartServiceCtrlDispatcherW(table.as_ptr())
+    };
+
+    // The service body is consumed by `service_main`, in case it was never
+    // called we clean it up.
+    PENDING.lock().expect("poisoned service mutex").take();
+
+    if status == FALSE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Entry point of the service called by the service control dispatcher.
+extern "system" fn service_main(_: u32, _: *mut windows_sys::core::PWSTR) {
+    let pending = match PENDING.lock().expect("poisoned service mutex").take() {
+        Some(pending) => pending,
+        None => return,
+    };
+
+    // SAFETY: The name is a valid null-terminated UTF-16 string and the handler
+    // is a valid function that does not use the context [1]. We verify the
+    // returned handle after the call.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/winsvc/nf-winsvc-registerservicectrlhandlerexw
+    let handle = unsafe {
+        RegisterServiceCtrlHandlerExW(pending.name.as_ptr(), Some(handler), std::ptr::null())
+    };
+    if handle.is_null() {
+        let error = std::io::Error::last_os_error();
+        log::error!("failed to register service control handler: {error}");
+        return;
+    }
+
+    let service = Service {
+        handle: StatusHandle(handle),
+        shutdown: Shutdown {
+            inner: Arc::new((Mutex::new(false), Condvar::new())),
+        },
+    };
+    let shutdown = service.shutdown.clone();
+    *SERVICE.lock().expect("poisoned service mutex") = Some(service);
+
+    report(SERVICE_START_PENDING, NO_ERROR);
+
+    // Panics cannot unwind past this function (as it is called by the system),
+    // so we catch them to report the service as failed instead of aborting.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        // We establish the connection before reporting the service as running,
+        // so that problems with the inherited communication handles surface as
+        // failed starts rather than as a service that hangs on first use.
+        LazyLock::force(&crate::CONNECTION);
+
+        report(SERVICE_RUNNING, NO_ERROR);
+
+        (pending.main)(shutdown)
+    }));
+
+    report(SERVICE_STOP_PENDING, NO_ERROR);
+
+    if !crate::drain(DRAIN_TIMEOUT) {
+        log::warn!("not all queued messages written before service stop");
+    }
+
+    match result {
+        Ok(()) => report(SERVICE_STOPPED, NO_ERROR),
+        Err(_) => {
+            log::error!("service body panicked");
+            report(SERVICE_STOPPED, ERROR_SERVICE_SPECIFIC_ERROR);
+        }
+    }
+
+    SERVICE.lock().expect("poisoned service mutex").take();
+}
+
+/// Handler of control requests sent by the SCM.
+extern "system" fn handler(control: u32, _: u32, _: *mut core::ffi::c_void, _: *mut core::ffi::c_void) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            let shutdown = match SERVICE.lock().expect("poisoned service mutex").as_ref() {
+                Some(service) => service.shutdown.clone(),
+                None => return NO_ERROR,
+            };
+
+            log::info!("service stop requested");
+            report(SERVICE_STOP_PENDING, NO_ERROR);
+            shutdown.request();
+
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Reports the current state of the service to the SCM.
+fn report(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+    static CHECKPOINT: Mutex<u32> = Mutex::new(0);
+
+    let service = SERVICE.lock().expect("poisoned service mutex");
+    let handle = match service.as_ref() {
+        Some(service) => service.handle.0,
+        None => return,
+    };
+
+    let accepted = match state {
+        SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+        _ => 0,
+    };
+
+    // Pending states require a checkpoint that increases with every report so
+    // that the SCM knows the service is making progress.
+    let mut checkpoint = CHECKPOINT.lock().expect("poisoned checkpoint mutex");
+    let (checkpoint, wait_hint) = match state {
+        SERVICE_START_PENDING | SERVICE_STOP_PENDING => {
+            *checkpoint += 1;
+            (*checkpoint, (DRAIN_TIMEOUT * 2).as_millis() as u32)
+        }
+        _ => (0, 0),
+    };
+
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: accepted,
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: if exit_code == NO_ERROR { 0 } else { 1 },
+        dwCheckPoint: checkpoint,
+        dwWaitHint: wait_hint,
+    };
+
+    // SAFETY: The handle is a valid service status handle (it is removed once
+    // the service is stopped) and the status is a valid structure [1]. The
+    // result is not verified as there is nothing we can do about it apart from
+    // logging.
+    //
+    // [1]: https://learn.microsoft.com/en-us/windows/win32/api/winsvc/nf-winsvc-setservicestatus
+    let status = unsafe {
+        SetServiceStatus(handle, &status)
+    };
+    if status == FALSE {
+        let error = std::io::Error::last_os_error();
+        log::warn!("failed to report service status: {error}");
+    }
+}
+
+/// The service body waiting to be called by the service control dispatcher.
+struct Pending {
+    name: Vec<u16>,
+    main: Box<dyn FnOnce(Shutdown) + Send>,
+}
+
+/// State of the running service.
+struct Service {
+    handle: StatusHandle,
+    shutdown: Shutdown,
+}
+
+/// A wrapper for the service status handle so that it can be put in a static.
+struct StatusHandle(SERVICE_STATUS_HANDLE);
+
+// SAFETY: The service status handle is not tied to any thread and the status
+// can be reported from any of them [1].
+//
+// [1]: https://learn.microsoft.com/en-us/windows/win32/services/service-status
+unsafe impl Send for StatusHandle {
+}
+
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+static SERVICE: Mutex<Option<Service>> = Mutex::new(None);
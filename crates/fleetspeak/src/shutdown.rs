@@ -0,0 +1,297 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::time::Duration;
+
+use protobuf::well_known_types::struct_::{Struct, Value};
+
+/// Maximum time to wait for queued messages to be written before the report.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum time to wait for the report itself to be written.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Message kind of shutdown reports.
+pub const SHUTDOWN_REPORT_KIND: &str = "ShutdownReport";
+
+/// Exit code used by [`request_restart`].
+///
+/// This is `EX_TEMPFAIL` from `sysexits.h`, signalling a temporary failure
+/// after which the service should be started again.
+pub const RESTART_EXIT_CODE: i32 = 75;
+
+/// Reason for which a service is shutting down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The service finished its work and exits cleanly.
+    Clean,
+    /// The service restarts because of a configuration change.
+    Restart,
+    /// The service exits because of an unrecoverable error.
+    Crash,
+}
+
+impl ShutdownReason {
+
+    /// Returns the name of the reason as reported to the server.
+    fn name(self) -> &'static str {
+        match self {
+            ShutdownReason::Clean => "CLEAN",
+            ShutdownReason::Restart => "RESTART",
+            ShutdownReason::Crash => "CRASH",
+        }
+    }
+}
+
+/// Sends a final report about the service shutting down to the given server
+/// `service`.
+///
+/// This allows the fleet console to distinguish clean shutdowns from restarts
+/// and crashes. The report is a message of the [`SHUTDOWN_REPORT_KIND`] kind
+/// with a serialized `google.protobuf.Struct` as its data. The struct has the
+/// following fields:
+///
+///   * `reason` with the name of the reason (e.g. `"CLEAN"`),
+///   * `library_version` with the [version] of this library,
+///   * `uptime_secs` with the time since the connection was established,
+///   * `sent_count`, `sent_bytes`, `received_count`, `received_bytes` and
+///     `decode_failures` with the [traffic metrics] summed over all kinds,
+///   * `details` with the explanation given to [`request_restart`] (if any).
+///
+/// Messages queued for sending are given a moment to be written before the
+/// report is sent. Because the report is commonly sent from a panic hook (which
+/// may run while the panicking thread holds the connection), the report itself
+/// is given only a few seconds to be written as well and an error is returned
+/// if the channel stays in use.
+///
+/// [version]: crate::VERSION
+/// [traffic metrics]: crate::metrics
+///
+/// # Examples
+///
+/// ```no_run
+/// use fleetspeak::ShutdownReason;
+///
+/// std::panic::set_hook(Box::new(|_| {
+///     let _ = fleetspeak::report_shutdown("monitoring", ShutdownReason::Crash);
+/// }));
+///
+/// // Run the service.
+///
+/// fleetspeak::report_shutdown("monitoring", ShutdownReason::Clean)
+///     .expect("failed to report shutdown");
+/// ```
+pub fn report_shutdown(service: &str, reason: ShutdownReason) -> std::io::Result<()> {
+    send_report(service, reason, None)
+}
+
+/// Requests the service to be restarted and exits the process.
+///
+/// Services that detect an unrecoverable internal state should call this
+/// function instead of waiting for the Fleetspeak client to notice missing
+/// heartbeats. It sends a [shutdown report] with the [`Restart`] reason and
+/// the given `details` to the server `service`, lets queued messages be written
+/// and exits with [`RESTART_EXIT_CODE`]. The Fleetspeak client then starts the
+/// service again.
+///
+/// Failing to send the report does not prevent the process from exiting: it
+/// is just logged.
+///
+/// [shutdown report]: report_shutdown
+/// [`Restart`]: ShutdownReason::Restart
+///
+/// # Examples
+///
+/// ```no_run
+/// let state_consistent = false;
+///
+/// if !state_consistent {
+///     fleetspeak::request_restart("monitoring", "inconsistent internal state");
+/// }
+/// ```
+pub fn request_restart(service: &str, details: &str) -> ! {
+    log::warn!("requesting restart: {details}");
+
+    if let Err(error) = send_report(service, ShutdownReason::Restart, Some(details)) {
+        log::error!("failed to report restart: {error}");
+    }
+
+    std::process::exit(RESTART_EXIT_CODE)
+}
+
+/// Shuts the connection with the Fleetspeak client down gracefully.
+///
+/// Queued messages are given a moment to be written and the background threads
+/// of the library are stopped. Then the optional final `message` is sent, the
+/// buffered output is flushed and the channels are closed, so that the
+/// Fleetspeak client observes a clean end of the stream instead of data cut off
+/// by the process exiting.
+///
+/// The connection cannot be used afterwards: sending or receiving messages
+/// fails (and so the functions that panic on failures will panic). If another
+/// thread is blocked receiving a message, the input channel is left open.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::shutdown(Some(fleetspeak::Message {
+///     service: String::from("example"),
+///     kind: Some(String::from("goodbye")),
+///     ..Default::default()
+/// })).expect("failed to shut down");
+/// ```
+pub fn shutdown(message: Option<crate::Message>) -> std::io::Result<()> {
+    if !crate::drain(DRAIN_TIMEOUT) {
+        log::warn!("not all queued messages written before shutdown");
+    }
+
+    crate::heartbeats::stop_heartbeats();
+    crate::keepalive::stop();
+    crate::reader::stop();
+    crate::writer::stop();
+    #[cfg(target_family = "windows")]
+    crate::poll::stop();
+
+    if let Some(message) = message {
+        crate::deliver(message)?;
+    }
+
+    let mut output = crate::CONNECTION.output.lock()
+        .expect("poisoned connection mutex");
+    std::io::Write::flush(&mut *output)?;
+    output.get_mut().close()?;
+    drop(output);
+
+    match crate::CONNECTION.input.try_lock() {
+        Ok(mut input) => input.get_mut().close()?,
+        Err(std::sync::TryLockError::WouldBlock) => {
+            log::warn!("input channel in use, leaving it open");
+        }
+        Err(std::sync::TryLockError::Poisoned(_)) => panic!("poisoned connection mutex"),
+    }
+
+    log::info!("connection shut down");
+    crate::status::set(crate::Status::Closed);
+
+    Ok(())
+}
+
+/// Sends the shutdown report with optional `details` to the server `service`.
+fn send_report(service: &str, reason: ShutdownReason, details: Option<&str>) -> std::io::Result<()> {
+    if !crate::drain(DRAIN_TIMEOUT) {
+        log::warn!("not all queued messages written before shutdown report");
+    }
+
+    let uptime = crate::CONNECTION.established.elapsed();
+    let report = report(reason, details, uptime, crate::metrics::kinds().values());
+
+    let message = crate::Message {
+        service: String::from(service),
+        kind: Some(String::from(SHUTDOWN_REPORT_KIND)),
+        data: protobuf::Message::write_to_bytes(&report)?,
+        ..Default::default()
+    };
+
+    let options = crate::SendOptions {
+        class: crate::SendClass::Control,
+        ..Default::default()
+    };
+
+    match crate::writer::send(message, options, REPORT_TIMEOUT) {
+        Ok(result) => result?,
+        Err(error) if error.is_rejected() => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, error));
+        }
+        Err(error) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, error)),
+    }
+
+    log::info!("reported shutdown ({reason:?}) to '{service}'");
+
+    Ok(())
+}
+
+/// Builds the structured shutdown report.
+fn report<'a, I>(reason: ShutdownReason, details: Option<&str>, uptime: Duration, kinds: I) -> Struct
+where
+    I: IntoIterator<Item = &'a crate::metrics::KindMetrics>,
+{
+    let mut total = crate::metrics::KindMetrics::default();
+    for metrics in kinds {
+        total.sent_count += metrics.sent_count;
+        total.sent_bytes += metrics.sent_bytes;
+        total.received_count += metrics.received_count;
+        total.received_bytes += metrics.received_bytes;
+        total.decode_failures += metrics.decode_failures;
+    }
+
+    let mut report = Struct::new();
+    let mut insert = |key: &str, value: Value| {
+        report.fields.insert(String::from(key), value);
+    };
+
+    insert("reason", string_value(reason.name()));
+    insert("library_version", string_value(crate::VERSION));
+    insert("uptime_secs", number_value(uptime.as_secs_f64()));
+    insert("sent_count", number_value(total.sent_count as f64));
+    insert("sent_bytes", number_value(total.sent_bytes as f64));
+    insert("received_count", number_value(total.received_count as f64));
+    insert("received_bytes", number_value(total.received_bytes as f64));
+    insert("decode_failures", number_value(total.decode_failures as f64));
+    if let Some(details) = details {
+        insert("details", string_value(details));
+    }
+
+    report
+}
+
+fn string_value(string: &str) -> Value {
+    let mut value = Value::new();
+    value.set_string_value(String::from(string));
+    value
+}
+
+fn number_value(number: f64) -> Value {
+    let mut value = Value::new();
+    value.set_number_value(number);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn report_totals() {
+        let kinds = [
+            crate::metrics::KindMetrics {
+                sent_count: 1,
+                sent_bytes: 10,
+                ..Default::default()
+            },
+            crate::metrics::KindMetrics {
+                sent_count: 2,
+                sent_bytes: 20,
+                received_count: 3,
+                ..Default::default()
+            },
+        ];
+
+        let report = report(ShutdownReason::Restart, None, Duration::from_secs(42), &kinds);
+        assert_eq!(report.fields["reason"].string_value(), "RESTART");
+        assert_eq!(report.fields["library_version"].string_value(), crate::VERSION);
+        assert_eq!(report.fields["uptime_secs"].number_value(), 42.0);
+        assert_eq!(report.fields["sent_count"].number_value(), 3.0);
+        assert_eq!(report.fields["sent_bytes"].number_value(), 30.0);
+        assert_eq!(report.fields["received_count"].number_value(), 3.0);
+        assert!(!report.fields.contains_key("details"));
+    }
+
+    #[test]
+    fn report_details() {
+        let report = report(ShutdownReason::Restart, Some("foo"), Duration::ZERO, []);
+        assert_eq!(report.fields["details"].string_value(), "foo");
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::{CancelToken, Message};
+
+/// An outcome of [`receive_until_shutdown`].
+#[derive(Debug)]
+pub enum Received {
+    /// A message has been received from the Fleetspeak server.
+    Message(Message),
+    /// The process has been asked to terminate.
+    Shutdown,
+}
+
+/// Receives a message from the Fleetspeak server unless the process is asked
+/// to terminate first.
+///
+/// On first use, this function installs handlers for termination requests:
+/// `SIGTERM` and `SIGINT` on Unix and console control events (e.g. `CTRL+C`) on
+/// Windows. While waiting for a message, heartbeats are sent with the given
+/// `rate` (as with [`receive_with_heartbeat`]). Once a termination request
+/// arrives, the wait is interrupted and [`Received::Shutdown`] is returned, so
+/// that the service can save its state and exit cleanly. All subsequent calls
+/// return [`Received::Shutdown`] immediately.
+///
+/// The handlers replace any handlers installed for these signals before. On
+/// Unix, the default disposition is restored once a signal is handled, so that
+/// a second signal terminates the process as usual.
+///
+/// In case of any I/O failure or malformed message, this function will panic
+/// (see [`receive`] for more details).
+///
+/// [`receive_with_heartbeat`]: crate::receive_with_heartbeat
+/// [`receive`]: crate::receive
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fleetspeak::startup("0.0.1");
+///
+/// loop {
+///     match fleetspeak::receive_until_shutdown(Duration::from_secs(30)) {
+///         fleetspeak::Received::Message(message) => {
+///             println!("received {}", message.preview());
+///         }
+///         fleetspeak::Received::Shutdown => break,
+///     }
+/// }
+///
+/// fleetspeak::shutdown(None).expect("failed to shut down");
+/// ```
+pub fn receive_until_shutdown(rate: Duration) -> Received {
+    let token: &CancelToken = &TOKEN;
+
+    let result = crate::heartbeats::with_heartbeat(rate, || {
+        crate::receive_cancellable(token)
+    });
+
+    match result {
+        Ok(message) => Received::Message(message),
+        Err(error) if error.is_cancelled() => {
+            log::info!("termination requested");
+            Received::Shutdown
+        }
+        Err(error) => panic!("{error}"),
+    }
+}
+
+/// Creates the token cancelled on termination requests and installs handlers
+/// cancelling it.
+fn install() -> std::io::Result<&'static CancelToken> {
+    // The token is leaked, so that the handler can use it without any
+    // synchronization beyond an atomic load.
+    let token: &'static CancelToken = Box::leak(Box::new(CancelToken::new()?));
+    HANDLER_TOKEN.store(token as *const CancelToken as *mut CancelToken, Ordering::SeqCst);
+
+    #[cfg(target_family = "unix")]
+    {
+        install_signal(libc::SIGTERM)?;
+        install_signal(libc::SIGINT)?;
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        // SAFETY: We pass a valid handler routine and ask for it to be added
+        // to the list of handlers as described in the docs [1]. We verify the
+        // result afterwards.
+        //
+        // [1]: https://learn.microsoft.com/en-us/windows/console/setconsolectrlhandler
+        let status = unsafe {
+            windows_sys::Win32::System::Console::SetConsoleCtrlHandler(
+                Some(handle_ctrl),
+                windows_sys::Win32::Foundation::TRUE,
+            )
+        };
+        if status == windows_sys::Win32::Foundation::FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(token)
+}
+
+/// Cancels the token (if it has been installed already).
+///
+/// This is called from the signal handler, so it must only perform
+/// async-signal-safe operations (which [`CancelToken::cancel`] does on Unix).
+fn cancel() {
+    let token = HANDLER_TOKEN.load(Ordering::SeqCst);
+
+    // SAFETY: The pointer is either null or points to the leaked token which
+    // is never deallocated.
+    if let Some(token) = unsafe { token.as_ref() } {
+        token.cancel();
+    }
+}
+
+/// Installs the handler for the given signal.
+#[cfg(target_family = "unix")]
+fn install_signal(signal: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: All-zero bytes is a valid value of `sigaction` (empty signal mask
+    // and no flags).
+    let mut action = unsafe {
+        std::mem::zeroed::<libc::sigaction>()
+    };
+    action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // Interrupted system calls are restarted, as the rest of the code does not
+    // expect them to fail. The default disposition is restored once the signal
+    // is handled.
+    action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+
+    // SAFETY: We pass a valid signal number and a valid action structure with
+    // a handler that only performs async-signal-safe operations, as described
+    // in the docs [1]. We are not interested in the previous action. We verify
+    // the result afterwards.
+    //
+    // [1]: https://man7.org/linux/man-pages/man2/sigaction.2.html
+    let status = unsafe {
+        libc::sigaction(signal, &action, std::ptr::null_mut())
+    };
+    if status < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Handler of termination signals.
+#[cfg(target_family = "unix")]
+extern "C" fn handle_signal(_: libc::c_int) {
+    cancel();
+}
+
+/// Handler of console control events.
+///
+/// Unlike signal handlers, the handler runs in a separate thread, so there are
+/// no restrictions on what it can do.
+#[cfg(target_family = "windows")]
+unsafe extern "system" fn handle_ctrl(_: u32) -> windows_sys::Win32::Foundation::BOOL {
+    cancel();
+
+    // The event is handled, so that the default handler (which terminates the
+    // process right away) is not called.
+    windows_sys::Win32::Foundation::TRUE
+}
+
+/// Token cancelled by the installed handlers.
+static HANDLER_TOKEN: AtomicPtr<CancelToken> = AtomicPtr::new(std::ptr::null_mut());
+
+static TOKEN: LazyLock<&'static CancelToken> = LazyLock::new(|| {
+    match install() {
+        Ok(token) => token,
+        Err(error) => panic!("failed to install termination handlers: {error}"),
+    }
+});
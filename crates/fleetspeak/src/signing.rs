@@ -0,0 +1,226 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Signing and verification of message payloads.
+//!
+//! Fleetspeak secures the transport between the client and the server but some
+//! deployments require end-to-end integrity of payloads. This module provides
+//! hook points for that: once a [`Signer`] is installed, data of every outgoing
+//! message is signed and once a [`Verifier`] is installed, data of every
+//! incoming message is verified against it.
+//!
+//! The library does not implement any signature algorithm itself: both the
+//! algorithm and the keys are supplied by the application through the [`Signer`]
+//! and [`Verifier`] implementations.
+//!
+//! The signature is detached: the data itself is left intact and the signature
+//! (in hexadecimal form) is attached to the message as the [`ANNOTATION`]
+//! annotation, along with the name of the algorithm as the
+//! [`ALGORITHM_ANNOTATION`] annotation.
+//!
+//! Signatures cover the data exactly as it is sent, i.e. after [compression]
+//! and [encryption]. Incoming messages are thus verified before anything else
+//! is done with them.
+//!
+//! [compression]: crate::compression
+//! [encryption]: crate::crypto
+
+use std::sync::{Arc, RwLock};
+
+/// Key of the annotation with the signature of the message data.
+pub const ANNOTATION: &str = "fleetspeak-rs/signature";
+
+/// Key of the annotation with the name of the signature algorithm.
+pub const ALGORITHM_ANNOTATION: &str = "fleetspeak-rs/signature-algorithm";
+
+/// A private key used to sign outgoing message payloads.
+pub trait Signer: Send + Sync {
+
+    /// Returns the name of the signature algorithm.
+    fn algorithm(&self) -> &str;
+
+    /// Signs the given data, returning the signature.
+    fn sign(&self, data: &[u8]) -> std::io::Result<Vec<u8>>;
+}
+
+/// A public key used to verify incoming message payloads.
+pub trait Verifier: Send + Sync {
+
+    /// Returns the name of the signature algorithm.
+    fn algorithm(&self) -> &str;
+
+    /// Verifies the signature of the given data.
+    ///
+    /// An error should be returned if the signature is not valid.
+    fn verify(&self, data: &[u8], signature: &[u8]) -> std::io::Result<()>;
+}
+
+/// Installs the signer to use for outgoing messages.
+///
+/// Messages sent after this call are signed with the given signer.
+pub fn install_signer<S>(signer: S)
+where
+    S: Signer + 'static,
+{
+    *SIGNER.write().expect("poisoned signer lock") = Some(Arc::new(signer));
+}
+
+/// Uninstalls the currently installed signer (if any).
+///
+/// Messages sent after this call are not signed anymore.
+pub fn uninstall_signer() {
+    *SIGNER.write().expect("poisoned signer lock") = None;
+}
+
+/// Installs the verifier to use for incoming messages.
+///
+/// Messages received after this call must be signed with the algorithm of the
+/// verifier and the signature must be valid. Messages that are not signed are
+/// rejected as malformed.
+pub fn install_verifier<V>(verifier: V)
+where
+    V: Verifier + 'static,
+{
+    *VERIFIER.write().expect("poisoned verifier lock") = Some(Arc::new(verifier));
+}
+
+/// Uninstalls the currently installed verifier (if any).
+///
+/// Messages received after this call are not verified anymore (but signature
+/// annotations are still removed from them).
+pub fn uninstall_verifier() {
+    *VERIFIER.write().expect("poisoned verifier lock") = None;
+}
+
+/// Signs data of the outgoing message with the installed signer (if any).
+pub(crate) fn sign(proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let signer = SIGNER.read().expect("poisoned signer lock").clone();
+
+    match signer {
+        Some(signer) => sign_with(&*signer, proto),
+        None => Ok(()),
+    }
+}
+
+/// Verifies data of the incoming message with the installed verifier (if any).
+pub(crate) fn verify(proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let verifier = VERIFIER.read().expect("poisoned verifier lock").clone();
+
+    verify_with(verifier.as_deref(), proto)
+}
+
+fn sign_with(signer: &dyn Signer, proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    let signature = signer.sign(&proto.data.value)?;
+
+    crate::io::add_annotation(proto, ANNOTATION, crate::hex::encode(&signature));
+    crate::io::add_annotation(proto, ALGORITHM_ANNOTATION, String::from(signer.algorithm()));
+
+    Ok(())
+}
+
+fn verify_with(verifier: Option<&dyn Verifier>, proto: &mut fleetspeak_proto::common::Message) -> std::io::Result<()> {
+    use std::io::ErrorKind::InvalidData;
+
+    let signature = crate::io::take_annotation(proto, ANNOTATION);
+    let algorithm = crate::io::take_annotation(proto, ALGORITHM_ANNOTATION);
+
+    let verifier = match verifier {
+        Some(verifier) => verifier,
+        None => return Ok(()),
+    };
+
+    let (signature, algorithm) = match (signature, algorithm) {
+        (Some(signature), Some(algorithm)) => (signature, algorithm),
+        _ => return Err(std::io::Error::new(InvalidData, "unsigned message")),
+    };
+
+    if algorithm != verifier.algorithm() {
+        return Err(std::io::Error::new(InvalidData, {
+            format!("unsupported signature algorithm: {algorithm:?}")
+        }));
+    }
+
+    let signature = crate::hex::decode(&signature)
+        .ok_or_else(|| std::io::Error::new(InvalidData, "malformed signature"))?;
+
+    verifier.verify(&proto.data.value, &signature)
+}
+
+static SIGNER: RwLock<Option<Arc<dyn Signer>>> = RwLock::new(None);
+
+static VERIFIER: RwLock<Option<Arc<dyn Verifier>>> = RwLock::new(None);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// A toy "signature" being the sum of the data bytes XOR-ed with a key.
+    struct Sum(u8);
+
+    impl Sum {
+
+        fn signature(&self, data: &[u8]) -> Vec<u8> {
+            vec![data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) ^ self.0]
+        }
+    }
+
+    impl Signer for Sum {
+
+        fn algorithm(&self) -> &str {
+            "sum"
+        }
+
+        fn sign(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(self.signature(data))
+        }
+    }
+
+    impl Verifier for Sum {
+
+        fn algorithm(&self) -> &str {
+            "sum"
+        }
+
+        fn verify(&self, data: &[u8], signature: &[u8]) -> std::io::Result<()> {
+            if self.signature(data) == signature {
+                Ok(())
+            } else {
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad signature"))
+            }
+        }
+    }
+
+    fn proto(data: &[u8]) -> fleetspeak_proto::common::Message {
+        let mut proto = fleetspeak_proto::common::Message::new();
+        proto.mut_data().value = data.to_vec();
+        proto
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let mut proto = proto(b"foo");
+        sign_with(&Sum(0x42), &mut proto).unwrap();
+        assert_eq!(proto.data.value, b"foo");
+
+        verify_with(Some(&Sum(0x42)), &mut proto).unwrap();
+        assert!(proto.annotations.entries.is_empty());
+    }
+
+    #[test]
+    fn verify_tampered() {
+        let mut proto = proto(b"foo");
+        sign_with(&Sum(0x42), &mut proto).unwrap();
+        proto.mut_data().value = b"bar".to_vec();
+
+        assert!(verify_with(Some(&Sum(0x42)), &mut proto).is_err());
+    }
+
+    #[test]
+    fn verify_unsigned() {
+        assert!(verify_with(Some(&Sum(0x42)), &mut proto(b"foo")).is_err());
+        assert!(verify_with(None, &mut proto(b"foo")).is_ok());
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Standalone developer mode.
+//!
+//! When the service is not launched by the Fleetspeak client, there are no
+//! channels to communicate over. In standalone mode, the library then plays the
+//! role of the client on its own: messages for the service are read from the
+//! standard input and messages sent by the service are written to the standard
+//! output, one JSON object per line, e.g.:
+//!
+//! ```json
+//! {"service": "example", "kind": "greeting", "data": "Hello, world!"}
+//! ```
+//!
+//! The `kind` field is optional. Data that is not valid UTF-8 is given by the
+//! `data_hex` field (with the hexadecimal form of the bytes) instead of `data`.
+//! System messages (e.g. heartbeats) are not written to the output but logged.
+//!
+//! Internally, the library still speaks the regular protocol over a pair of
+//! pipes, the other ends of which are served by background threads translating
+//! it to and from the JSON form.
+
+use std::io::{BufRead as _, Write as _};
+
+use crate::io::{CommsInRaw, CommsOutRaw};
+
+/// Returns whether standalone mode should be used.
+///
+/// This is the case if the process has not been given any of the channels.
+pub(crate) fn is_standalone() -> bool {
+    let not_specified = |var| match crate::env::channel(var) {
+        Ok(_) => false,
+        Err(error) => error.is_not_specified(),
+    };
+
+    not_specified(crate::env::COMMS_IN_VAR) && not_specified(crate::env::COMMS_OUT_VAR)
+}
+
+/// Creates the channels served by background threads speaking JSON on stdio.
+pub(crate) fn channels() -> std::io::Result<(CommsInRaw, CommsOutRaw)> {
+    let (input, mut input_peer) = std::io::pipe()?;
+    let (output_peer, output) = std::io::pipe()?;
+
+    std::thread::Builder::new()
+        .name(String::from("fleetspeak-standalone-in"))
+        .spawn(move || {
+            if let Err(error) = serve_input(&mut input_peer) {
+                log::error!("standalone input failure: {error}");
+            }
+        })?;
+
+    std::thread::Builder::new()
+        .name(String::from("fleetspeak-standalone-out"))
+        .spawn(move || {
+            if let Err(error) = serve_output(&mut std::io::BufReader::new(output_peer)) {
+                log::error!("standalone output failure: {error}");
+            }
+        })?;
+
+    log::warn!("not launched by Fleetspeak, using standalone mode on stdio");
+
+    Ok((CommsInRaw::from(input), CommsOutRaw::from(output)))
+}
+
+/// Replies to the handshake and forwards messages from the standard input.
+fn serve_input(peer: &mut std::io::PipeWriter) -> std::io::Result<()> {
+    crate::io::write_magic(peer)?;
+    peer.flush()?;
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let proto = match parse_message(&line) {
+            Ok(proto) => proto,
+            Err(error) => {
+                log::error!("invalid standalone message: {error}");
+                continue;
+            }
+        };
+
+        crate::io::write_proto(peer, proto)?;
+    }
+
+    log::info!("standalone input closed");
+
+    Ok(())
+}
+
+/// Accepts the handshake and forwards messages to the standard output.
+fn serve_output<R>(peer: &mut R) -> std::io::Result<()>
+where
+    R: std::io::Read,
+{
+    crate::io::read_magic(peer)?;
+
+    loop {
+        let proto = match crate::io::read_proto(peer) {
+            Ok(proto) => proto,
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(error) => return Err(error),
+        };
+
+        let service = proto.destination.service_name.as_str();
+        if service == "system" {
+            log::info!("standalone system message: {}", proto.message_type);
+            continue;
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer(&mut stdout, &format_message(&proto))?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+    }
+}
+
+/// Parses a JSON line into a message addressed to the service.
+fn parse_message(line: &str) -> std::io::Result<fleetspeak_proto::common::Message> {
+    let invalid = |message: &str| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, String::from(message))
+    };
+
+    let json = serde_json::from_str::<serde_json::Value>(line)?;
+    let json = json.as_object()
+        .ok_or_else(|| invalid("not a JSON object"))?;
+
+    let string = |key: &str| match json.get(key) {
+        Some(serde_json::Value::String(string)) => Ok(Some(string.as_str())),
+        Some(_) => Err(invalid(&format!("'{key}' is not a string"))),
+        None => Ok(None),
+    };
+
+    let mut proto = fleetspeak_proto::common::Message::new();
+    proto.mut_source().set_service_name(String::from({
+        string("service")?.ok_or_else(|| invalid("missing 'service'"))?
+    }));
+    proto.set_message_type(String::from(string("kind")?.unwrap_or("")));
+    proto.mut_data().value = match (string("data")?, string("data_hex")?) {
+        (Some(data), None) => data.as_bytes().to_vec(),
+        (None, Some(data)) => crate::hex::decode(data)
+            .ok_or_else(|| invalid("'data_hex' is not valid hexadecimal"))?,
+        (None, None) => Vec::new(),
+        (Some(_), Some(_)) => return Err(invalid("both 'data' and 'data_hex' given")),
+    };
+
+    Ok(proto)
+}
+
+/// Formats a message sent by the service as a JSON object.
+fn format_message(proto: &fleetspeak_proto::common::Message) -> serde_json::Value {
+    let mut json = serde_json::Map::new();
+    json.insert(String::from("service"), proto.destination.service_name.clone().into());
+    if !proto.message_type.is_empty() {
+        json.insert(String::from("kind"), proto.message_type.clone().into());
+    }
+    match std::str::from_utf8(&proto.data.value) {
+        Ok(data) => json.insert(String::from("data"), data.into()),
+        Err(_) => json.insert(String::from("data_hex"), crate::hex::encode(&proto.data.value).into()),
+    };
+
+    json.into()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_and_format_message() {
+        let mut proto = parse_message(r#"{"service": "foo", "kind": "bar", "data": "baz"}"#).unwrap();
+        assert_eq!(proto.source.service_name, "foo");
+        assert_eq!(proto.message_type, "bar");
+        assert_eq!(proto.data.value, b"baz");
+
+        proto.mut_destination().set_service_name(String::from("foo"));
+        proto.mut_data().value = vec![0xff, 0x00];
+
+        let json = format_message(&proto);
+        assert_eq!(json["service"], "foo");
+        assert_eq!(json["kind"], "bar");
+        assert_eq!(json["data_hex"], "ff00");
+    }
+
+    #[test]
+    fn parse_message_invalid() {
+        assert!(parse_message(r#"{"kind": "bar"}"#).is_err());
+        assert!(parse_message(r#"{"service": "foo", "data_hex": "xyz"}"#).is_err());
+        assert!(parse_message(r#"["foo"]"#).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::time::SystemTime;
+
+/// Startup information of the service reported by [`startup_with`].
+///
+/// Only the version is part of the startup data understood by Fleetspeak. The
+/// remaining information is attached to the startup message as annotations, so
+/// that fleet statistics can show more than a bare version string:
+///
+///   * `service/commit` with the commit the service was built from,
+///   * `service/build-time` with the build time in seconds since Unix epoch,
+///   * `service/label/<name>` with the value of each label.
+///
+/// # Examples
+///
+/// ```
+/// let options = fleetspeak::StartupOptions {
+///     version: String::from("1.2.3"),
+///     labels: vec![(String::from("channel"), String::from("beta"))],
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct StartupOptions {
+    /// A self-reported version of the service.
+    pub version: String,
+    /// Arbitrary labels of the service (e.g. its release channel).
+    pub labels: Vec<(String, String)>,
+    /// Identifier of the commit the service was built from.
+    pub commit: Option<String>,
+    /// Time at which the service was built.
+    pub build_time: Option<SystemTime>,
+}
+
+/// Sends a system message with extended startup information to the Fleetspeak
+/// client.
+///
+/// This is a variant of [`startup`] that reports more information about the
+/// service than just its version. See documentation for [`StartupOptions`] for
+/// the details.
+///
+/// [`startup`]: crate::startup
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::startup_with(fleetspeak::StartupOptions {
+///     version: String::from(env!("CARGO_PKG_VERSION")),
+///     commit: option_env!("GIT_COMMIT").map(String::from),
+///     build_time: Some(std::time::SystemTime::UNIX_EPOCH),
+///     ..Default::default()
+/// });
+/// ```
+pub fn startup_with(options: StartupOptions) {
+    #[cfg(all(target_family = "windows", feature = "etw"))]
+    crate::etw::lifecycle(format_args!("startup (version: {})", options.version));
+
+    let mut output = crate::CONNECTION.output.lock()
+        .expect("poisoned connection mutex");
+
+    let result = crate::io::write_startup(&mut *output, &options)
+        .and_then(|()| crate::release(output));
+    if let Err(error) = result {
+        crate::fail(error);
+    }
+}
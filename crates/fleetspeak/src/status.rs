@@ -0,0 +1,135 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// State of the connection with the Fleetspeak client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The connection is established and messages are being exchanged.
+    Connected,
+    /// The connection is established but the Fleetspeak client does not drain
+    /// outgoing messages in a timely manner.
+    Degraded,
+    /// The connection failed and no more messages can be exchanged.
+    Closed,
+}
+
+/// Returns the current state of the connection with the Fleetspeak client.
+///
+/// Note that the connection is established lazily, so calling this function
+/// establishes it if that has not happened yet.
+///
+/// # Examples
+///
+/// ```no_run
+/// if fleetspeak::status() == fleetspeak::Status::Degraded {
+///     println!("Fleetspeak is not keeping up, pausing collection");
+/// }
+/// ```
+pub fn status() -> Status {
+    LazyLock::force(&crate::CONNECTION);
+
+    *STATUS.lock().expect("poisoned status mutex")
+}
+
+/// Returns the error that caused the connection to be closed (if any).
+///
+/// This is useful for services that do not wait for their messages to be
+/// written (see [`send_queued`]) and thus cannot learn about write failures
+/// otherwise. Note that `None` is returned if the connection has been closed
+/// deliberately (e.g. by [`shutdown`]).
+///
+/// [`send_queued`]: crate::send_queued
+/// [`shutdown`]: crate::shutdown
+///
+/// # Examples
+///
+/// ```no_run
+/// if let Some(error) = fleetspeak::close_error() {
+///     eprintln!("Fleetspeak connection failed: {error}");
+/// }
+/// ```
+pub fn close_error() -> Option<Arc<std::io::Error>> {
+    ERROR.lock().expect("poisoned status error mutex").clone()
+}
+
+/// Returns a channel that is notified about changes of the connection state.
+///
+/// This allows asynchronous tasks to react to the connection becoming degraded
+/// or closed (e.g. by pausing data collection or flushing caches) without
+/// polling [`status`].
+///
+/// Note that the connection is established lazily, so calling this function
+/// establishes it if that has not happened yet.
+///
+/// [`status`]: crate::status
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example() {
+/// let mut status = fleetspeak::status_watch();
+///
+/// while status.changed().await.is_ok() {
+///     if *status.borrow() == fleetspeak::Status::Closed {
+///         println!("Fleetspeak connection lost");
+///         break;
+///     }
+/// }
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+pub fn status_watch() -> tokio::sync::watch::Receiver<Status> {
+    LazyLock::force(&crate::CONNECTION);
+
+    WATCH.subscribe()
+}
+
+/// Updates the state of the connection.
+///
+/// Closed connections cannot become open again, so once the state is set to
+/// [`Status::Closed`] further updates are ignored.
+pub(crate) fn set(status: Status) {
+    let mut current = STATUS.lock().expect("poisoned status mutex");
+    if *current == status || *current == Status::Closed {
+        return;
+    }
+
+    match status {
+        Status::Connected => log::info!("connection recovered"),
+        Status::Degraded => log::warn!("connection degraded"),
+        Status::Closed => log::error!("connection closed"),
+    }
+
+    *current = status;
+
+    #[cfg(feature = "tokio")]
+    WATCH.send_replace(status);
+}
+
+/// Marks the connection as closed because of the given error.
+///
+/// Only the first error is recorded, as the subsequent ones are typically just
+/// consequences of it.
+pub(crate) fn close(error: &std::io::Error) {
+    let mut current = ERROR.lock().expect("poisoned status error mutex");
+    if current.is_none() && *STATUS.lock().expect("poisoned status mutex") != Status::Closed {
+        // I/O errors are not clonable, so we record their kind and description.
+        *current = Some(Arc::new(std::io::Error::new(error.kind(), error.to_string())));
+    }
+    drop(current);
+
+    set(Status::Closed);
+}
+
+static STATUS: Mutex<Status> = Mutex::new(Status::Connected);
+
+static ERROR: Mutex<Option<Arc<std::io::Error>>> = Mutex::new(None);
+
+#[cfg(feature = "tokio")]
+static WATCH: LazyLock<tokio::sync::watch::Sender<Status>> = LazyLock::new(|| {
+    tokio::sync::watch::Sender::new(Status::Connected)
+});
@@ -0,0 +1,115 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+use std::sync::RwLock;
+
+use crate::Message;
+
+/// Name of the service that system messages come from.
+const SYSTEM_SERVICE: &str = "system";
+
+/// A request of the Fleetspeak system addressed to the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemRequest {
+    /// The service should exit (a `Die` message).
+    Die,
+    /// The service should restart (a `RestartService` message).
+    Restart,
+}
+
+impl SystemRequest {
+
+    /// Recognizes the request given by the kind of a system message.
+    fn from_kind(kind: &str) -> Option<SystemRequest> {
+        match kind {
+            "Die" => Some(SystemRequest::Die),
+            "RestartService" => Some(SystemRequest::Restart),
+            _ => None,
+        }
+    }
+}
+
+/// Registers a handler called for every system request received from the
+/// Fleetspeak server.
+///
+/// Without a handler, the service [shuts down] cleanly once a request arrives
+/// and exits: with code 0 for [`SystemRequest::Die`] and with
+/// [`RESTART_EXIT_CODE`] for [`SystemRequest::Restart`]. If the registered
+/// handler returns, the request is then received by the service like any other
+/// message (with `"system"` as its service).
+///
+/// Only one handler can be registered at a time, registering a new one replaces
+/// the previous one. The handler is called on the thread receiving the message,
+/// before the message is returned.
+///
+/// [shuts down]: crate::shutdown
+/// [`RESTART_EXIT_CODE`]: crate::RESTART_EXIT_CODE
+///
+/// # Examples
+///
+/// ```no_run
+/// use fleetspeak::SystemRequest;
+///
+/// fleetspeak::on_system_request(|request| {
+///     if request == SystemRequest::Restart {
+///         fleetspeak::request_restart("example", "restart requested by system");
+///     }
+/// });
+/// ```
+pub fn on_system_request<F>(handler: F)
+where
+    F: Fn(SystemRequest) + Send + Sync + 'static,
+{
+    *HANDLER.write().expect("poisoned system handler lock") = Some(Box::new(handler));
+}
+
+/// Handles the received message if it is a system request.
+pub(crate) fn handle(message: &Message) {
+    if message.service != SYSTEM_SERVICE {
+        return;
+    }
+
+    let request = match message.kind.as_deref().and_then(SystemRequest::from_kind) {
+        Some(request) => request,
+        None => return,
+    };
+
+    log::info!("received system request: {request:?}");
+
+    match &*HANDLER.read().expect("poisoned system handler lock") {
+        Some(handler) => handler(request),
+        None => exit(request),
+    }
+}
+
+/// Shuts the connection down and exits as requested by the system.
+fn exit(request: SystemRequest) -> ! {
+    if let Err(error) = crate::shutdown(None) {
+        log::error!("failed to shut down: {error}");
+    }
+
+    std::process::exit(match request {
+        SystemRequest::Die => 0,
+        SystemRequest::Restart => crate::RESTART_EXIT_CODE,
+    })
+}
+
+/// A handler of system requests.
+type Handler = Box<dyn Fn(SystemRequest) + Send + Sync>;
+
+static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn request_from_kind() {
+        assert_eq!(SystemRequest::from_kind("Die"), Some(SystemRequest::Die));
+        assert_eq!(SystemRequest::from_kind("RestartService"), Some(SystemRequest::Restart));
+        assert_eq!(SystemRequest::from_kind("Heartbeat"), None);
+    }
+}
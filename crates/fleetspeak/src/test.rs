@@ -0,0 +1,120 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Utilities for testing services that use the global connection.
+//!
+//! Normally, the global connection talks to the Fleetspeak client that started
+//! the service, so code using the free functions of this library can only be
+//! tested in a subprocess with a properly set-up environment. With the
+//! [`install_pipes`] function, the connection talks to in-memory pipes instead
+//! and the test plays the part of Fleetspeak through the returned [`ServerEnd`].
+
+use std::io::{PipeReader, PipeWriter};
+
+use crate::Message;
+
+/// Establishes the global connection over in-memory pipes.
+///
+/// Returns the end of the pipes that Fleetspeak would normally hold. The
+/// handshake is already done once this function returns, so messages can be
+/// exchanged right away.
+///
+/// Because the global connection is established only once per process, this
+/// function has to be called before any other function of this library and
+/// at most once per process.
+///
+/// # Panics
+///
+/// Panics if the connection has been established before or the pipes cannot
+/// be created.
+///
+/// # Examples
+///
+/// ```
+/// let mut server = fleetspeak::test::install_pipes();
+///
+/// fleetspeak::send(fleetspeak::Message {
+///     service: String::from("foo"),
+///     data: b"ping".to_vec(),
+///     ..Default::default()
+/// });
+///
+/// let message = server.recv().unwrap();
+/// assert_eq!(message.data, b"ping");
+/// ```
+pub fn install_pipes() -> ServerEnd {
+    try_install_pipes()
+        .unwrap_or_else(|error| panic!("failed to install pipes: {error}"))
+}
+
+fn try_install_pipes() -> std::io::Result<ServerEnd> {
+    let (service_input, mut output) = std::io::pipe()?;
+    let (mut input, service_output) = std::io::pipe()?;
+
+    // Our side of the handshake is written upfront (the pipe buffers it), so
+    // that establishing the connection below does not block.
+    crate::io::write_magic(&mut output)?;
+
+    crate::init::init_transport(crate::transport::Transport::Channels {
+        input: crate::CommsInRaw::from(service_input),
+        output: crate::CommsOutRaw::from(service_output),
+    }).map_err(std::io::Error::other)?;
+
+    crate::io::read_magic(&mut input)?;
+
+    Ok(ServerEnd { input, output })
+}
+
+/// The Fleetspeak side of the global connection established over pipes.
+///
+/// See [`install_pipes`] for more details.
+#[derive(Debug)]
+pub struct ServerEnd {
+    /// Pipe with data written by the service.
+    input: PipeReader,
+    /// Pipe with data for the service.
+    output: PipeWriter,
+}
+
+impl ServerEnd {
+
+    /// Sends a message to the service.
+    ///
+    /// The `service` of the message is used as the source address, i.e. the
+    /// server-side service the message appears to come from.
+    pub fn send(&mut self, message: Message) -> std::io::Result<()> {
+        let mut proto = crate::io::encode_message(message);
+        let address = proto.take_destination();
+        proto.set_source(address);
+
+        crate::io::write_proto(&mut self.output, proto)
+    }
+
+    /// Receives the next message sent by the service.
+    ///
+    /// System records (heartbeats and startup information) are skipped, use
+    /// [`ServerEnd::recv_proto`] to inspect them. The data is returned exactly
+    /// as sent, i.e. it is not decompressed or decrypted.
+    pub fn recv(&mut self) -> std::io::Result<Message> {
+        loop {
+            let mut proto = self.recv_proto()?;
+            if proto.destination.service_name == "system" {
+                continue;
+            }
+
+            let address = proto.take_destination();
+            proto.set_source(address);
+
+            // With the `Empty` policy the message is never skipped.
+            let message = crate::io::decode_message(proto, crate::MissingData::Empty)?;
+            return Ok(message.unwrap_or_default());
+        }
+    }
+
+    /// Receives the next raw record written by the service.
+    pub fn recv_proto(&mut self) -> std::io::Result<fleetspeak_proto::common::Message> {
+        crate::io::read_proto(&mut self.input)
+    }
+}
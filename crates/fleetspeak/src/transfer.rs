@@ -0,0 +1,309 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Uploads of files to server-side services.
+//!
+//! Files are usually too big to fit into a single Fleetspeak message, so an
+//! [`Upload`] streams them in chunks, one message per chunk. Every chunk is
+//! marked with the following annotations:
+//!
+//!   * [`ID_ANNOTATION`] with an identifier unique to the upload,
+//!   * [`OFFSET_ANNOTATION`] with the offset of the chunk within the file,
+//!   * [`SIZE_ANNOTATION`] with the size of the whole file,
+//!   * [`SHA256_ANNOTATION`] with the SHA-256 digest of the chunk data (in
+//!     hexadecimal form), so that the server can verify it.
+//!
+//! Offsets and sizes are written in decimal form. The upload is complete once
+//! the server received all the bytes up to the size of the file. A heartbeat
+//! is sent after every chunk, so long uploads do not get the service killed.
+//!
+//! Interrupted uploads can be [resumed](Upload::resume): given the identifier
+//! of the upload and the offset up to which the server already has the data,
+//! only the rest of the file is sent.
+//!
+//! Chunks are sent with [low priority](crate::Priority::Low), so they do not
+//! delay more urgent messages of the service.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::{Message, Priority};
+
+/// Default message kind of file chunks.
+pub const CHUNK_KIND: &str = "FileChunk";
+
+/// Key of the annotation with the identifier of the upload.
+pub const ID_ANNOTATION: &str = "fleetspeak-rs/transfer-id";
+
+/// Key of the annotation with the offset of the chunk within the file.
+pub const OFFSET_ANNOTATION: &str = "fleetspeak-rs/transfer-offset";
+
+/// Key of the annotation with the size of the whole file.
+pub const SIZE_ANNOTATION: &str = "fleetspeak-rs/transfer-size";
+
+/// Key of the annotation with the SHA-256 digest of the chunk data.
+pub const SHA256_ANNOTATION: &str = "fleetspeak-rs/transfer-sha256";
+
+/// Default size (in bytes) of the data of a single chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = crate::chunking::DEFAULT_CHUNK_SIZE;
+
+/// An upload of a file to a server-side service.
+///
+/// # Examples
+///
+/// ```no_run
+/// let mut upload = fleetspeak::transfer::Upload::open("example", "/var/log/syslog")
+///     .unwrap();
+///
+/// if let Err(error) = upload.run() {
+///     // The identifier and the offset can be stored to resume the upload later.
+///     eprintln!("upload {} failed at {}: {error}", upload.id(), upload.offset());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Upload<R> {
+    /// The file (or any other seekable source) being uploaded.
+    reader: R,
+    /// Name of the server-side service to upload the file to.
+    service: String,
+    /// Message kind of the chunks.
+    kind: String,
+    /// Identifier of the upload.
+    id: String,
+    /// Size of the file (at the time the upload was created).
+    size: u64,
+    /// Offset of the first byte that has not been sent yet.
+    offset: u64,
+    /// Maximum size of the data of a single chunk.
+    chunk_size: usize,
+    /// Whether all the chunks have been sent.
+    done: bool,
+}
+
+impl Upload<std::fs::File> {
+
+    /// Creates an upload of the file at the given path.
+    ///
+    /// See [`Upload::new`] for more details.
+    pub fn open<P>(service: &str, path: P) -> std::io::Result<Upload<std::fs::File>>
+    where
+        P: AsRef<Path>,
+    {
+        Upload::new(service, std::fs::File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> Upload<R> {
+
+    /// Creates an upload of the data of the given reader to the `service`.
+    ///
+    /// Nothing is sent until [`run`](Upload::run) or [`send_chunk`] is called.
+    /// The size of the upload is determined upfront: data appended to the
+    /// reader afterwards is not uploaded.
+    ///
+    /// [`send_chunk`]: Upload::send_chunk
+    pub fn new(service: &str, mut reader: R) -> std::io::Result<Upload<R>> {
+        let size = reader.seek(SeekFrom::End(0))?;
+
+        Ok(Upload {
+            reader,
+            service: String::from(service),
+            kind: String::from(CHUNK_KIND),
+            id: crate::chunking::transfer_id(),
+            size,
+            offset: 0,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            done: false,
+        })
+    }
+
+    /// Continues an interrupted upload with the given identifier.
+    ///
+    /// The `offset` is the number of bytes the server has already received.
+    /// Only the data after it is sent.
+    ///
+    /// An error is returned if the offset is past the end of the file.
+    pub fn resume(mut self, id: &str, offset: u64) -> std::io::Result<Upload<R>> {
+        if offset > self.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, {
+                format!("offset {offset} past the end of the file of size {}", self.size)
+            }));
+        }
+
+        self.id = String::from(id);
+        self.offset = offset;
+        self.done = offset == self.size;
+
+        Ok(self)
+    }
+
+    /// Sets the message kind of the chunks (instead of [`CHUNK_KIND`]).
+    pub fn with_kind(mut self, kind: &str) -> Upload<R> {
+        self.kind = String::from(kind);
+        self
+    }
+
+    /// Sets the maximum size of the data of a single chunk (instead of
+    /// [`DEFAULT_CHUNK_SIZE`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Upload<R> {
+        assert!(chunk_size > 0, "chunk size must be positive");
+
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Returns the identifier of the upload.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the number of bytes sent so far (including those sent before
+    /// the upload was resumed).
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the size of the file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns whether all the chunks have been sent.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Sends all the remaining chunks.
+    ///
+    /// In case of an error, the upload stops at the chunk that failed and can
+    /// be continued later (see [`Upload::resume`]).
+    pub fn run(&mut self) -> std::io::Result<()> {
+        while !self.done {
+            self.send_chunk()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the next chunk followed by a heartbeat.
+    ///
+    /// This allows the service to interleave the upload with other work. It
+    /// does nothing if the upload [is done](Upload::is_done).
+    pub fn send_chunk(&mut self) -> std::io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+
+        let message = self.next_chunk()?;
+        let len = message.data.len() as u64;
+
+        crate::deliver(message).inspect_err(|error| {
+            // Oversized messages are refused before anything is written, so
+            // the connection is still usable (e.g. with smaller chunks).
+            if error.kind() != std::io::ErrorKind::InvalidInput {
+                crate::close(error);
+            }
+        })?;
+
+        self.offset += len;
+        self.done = self.offset == self.size;
+
+        crate::request_heartbeat()
+    }
+
+    /// Reads the next chunk of the file and builds a message out of it.
+    fn next_chunk(&mut self) -> std::io::Result<Message> {
+        use sha2::Digest as _;
+
+        let len = std::cmp::min(self.size - self.offset, self.chunk_size as u64);
+
+        // The position of the reader is restored every time, as a failed read
+        // might have left it anywhere.
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+
+        let mut data = vec![0; len as usize];
+        self.reader.read_exact(&mut data)?;
+
+        let digest = crate::hex::encode(&sha2::Sha256::digest(&data));
+
+        Ok(Message {
+            service: self.service.clone(),
+            kind: Some(self.kind.clone()),
+            data,
+            priority: Priority::Low,
+            annotations: vec![
+                (String::from(ID_ANNOTATION), self.id.clone()),
+                (String::from(OFFSET_ANNOTATION), self.offset.to_string()),
+                (String::from(SIZE_ANNOTATION), self.size.to_string()),
+                (String::from(SHA256_ANNOTATION), digest),
+            ],
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn annotation<'a>(message: &'a Message, key: &str) -> &'a str {
+        message.annotations.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+            .unwrap()
+    }
+
+    #[test]
+    fn next_chunk_offsets_and_digests() {
+        let mut upload = Upload::new("foo", Cursor::new(b"0123456789".to_vec()))
+            .unwrap()
+            .with_chunk_size(4);
+
+        let chunk = upload.next_chunk().unwrap();
+        assert_eq!(chunk.service, "foo");
+        assert_eq!(chunk.kind.as_deref(), Some(CHUNK_KIND));
+        assert_eq!(chunk.data, b"0123");
+        assert_eq!(annotation(&chunk, ID_ANNOTATION), upload.id());
+        assert_eq!(annotation(&chunk, OFFSET_ANNOTATION), "0");
+        assert_eq!(annotation(&chunk, SIZE_ANNOTATION), "10");
+        assert_eq! {
+            annotation(&chunk, SHA256_ANNOTATION),
+            "1be2e452b46d7a0d9656bbb1f768e8248eba1b75baed65f5d99eafa948899a6a",
+        };
+
+        upload.offset = 8;
+
+        let chunk = upload.next_chunk().unwrap();
+        assert_eq!(chunk.data, b"89");
+        assert_eq!(annotation(&chunk, OFFSET_ANNOTATION), "8");
+    }
+
+    #[test]
+    fn resume() {
+        let upload = Upload::new("foo", Cursor::new(b"0123456789".to_vec()))
+            .unwrap();
+
+        let mut upload = upload.resume("bar", 6).unwrap();
+        assert_eq!(upload.id(), "bar");
+        assert!(!upload.is_done());
+
+        let chunk = upload.next_chunk().unwrap();
+        assert_eq!(chunk.data, b"6789");
+        assert_eq!(annotation(&chunk, ID_ANNOTATION), "bar");
+        assert_eq!(annotation(&chunk, OFFSET_ANNOTATION), "6");
+
+        let upload = upload.resume("bar", 10).unwrap();
+        assert!(upload.is_done());
+
+        assert!(upload.resume("bar", 11).is_err());
+    }
+}
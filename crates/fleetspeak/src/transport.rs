@@ -0,0 +1,103 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! Connections over transports supplied by the application.
+//!
+//! The connection always works with a pair of operating system channels. To
+//! support arbitrary readers and writers, the library speaks the protocol over
+//! a pair of pipes, the other ends of which are served by background threads
+//! copying the data to and from the supplied transport.
+
+use std::io::{Read, Write};
+
+use crate::io::{CommsInRaw, CommsOutRaw};
+
+/// A transport to establish the connection over.
+pub(crate) enum Transport {
+    /// Arbitrary streams served by background threads.
+    Streams {
+        /// Reader of data sent by the Fleetspeak client.
+        input: Box<dyn Read + Send>,
+        /// Writer of data for the Fleetspeak client.
+        output: Box<dyn Write + Send>,
+    },
+    /// Channels the connection can use directly.
+    #[cfg(feature = "test-util")]
+    Channels {
+        /// Channel with data sent by the Fleetspeak client.
+        input: CommsInRaw,
+        /// Channel for data for the Fleetspeak client.
+        output: CommsOutRaw,
+    },
+}
+
+impl Transport {
+
+    /// Creates a transport from the given reader and writer.
+    pub(crate) fn new<R, W>(input: R, output: W) -> Transport
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        Transport::Streams {
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+
+    /// Creates the channels to use for the connection.
+    ///
+    /// For arbitrary streams, the channels are served by background threads
+    /// copying the data to and from the streams.
+    pub(crate) fn channels(self) -> std::io::Result<(CommsInRaw, CommsOutRaw)> {
+        let (mut input, mut output) = match self {
+            Transport::Streams { input, output } => (input, output),
+            #[cfg(feature = "test-util")]
+            Transport::Channels { input, output } => return Ok((input, output)),
+        };
+
+        let (input_end, mut input_peer) = std::io::pipe()?;
+        let (mut output_peer, output_end) = std::io::pipe()?;
+
+        std::thread::Builder::new()
+            .name(String::from("fleetspeak-transport-in"))
+            .spawn(move || {
+                if let Err(error) = std::io::copy(&mut input, &mut input_peer) {
+                    log::error!("transport input failure: {error}");
+                }
+            })?;
+
+        std::thread::Builder::new()
+            .name(String::from("fleetspeak-transport-out"))
+            .spawn(move || {
+                if let Err(error) = serve_output(&mut output_peer, &mut output) {
+                    log::error!("transport output failure: {error}");
+                }
+            })?;
+
+        Ok((CommsInRaw::from(input_end), CommsOutRaw::from(output_end)))
+    }
+}
+
+/// Forwards data written by the library to the output of the transport.
+///
+/// Unlike [`std::io::copy`], this flushes the output after every chunk, as the
+/// library expects the data to reach the Fleetspeak client once it flushes its
+/// own buffers.
+fn serve_output(peer: &mut std::io::PipeReader, output: &mut dyn Write) -> std::io::Result<()> {
+    let mut buf = vec![0; 8 * 1024];
+
+    loop {
+        let len = match peer.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+
+        output.write_all(&buf[..len])?;
+        output.flush()?;
+    }
+}
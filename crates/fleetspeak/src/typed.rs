@@ -0,0 +1,287 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+/// A Fleetspeak message with a typed Protocol Buffers payload.
+///
+/// This is a counterpart of [`Message`] for services that exchange Protocol
+/// Buffers messages with their server-side part. Unlike with raw messages, the
+/// type URL of the payload is filled in automatically when sending and verified
+/// when receiving.
+///
+/// [`Message`]: crate::Message
+#[derive(Debug)]
+pub struct Packet<M> {
+    /// A name of the server-side service that sent or should receive the data.
+    pub service: String,
+    /// An optional message type that can be used by the server-side service.
+    pub kind: Option<String>,
+    /// The payload to send to the specified service.
+    pub data: M,
+}
+
+/// Sends the packet with a [rust-protobuf] payload to the Fleetspeak server.
+///
+/// The payload is serialized and its type URL is derived from the fully
+/// qualified name of the message type (as [`any::pack`] would do).
+///
+/// [`any::pack`]: crate::any::pack
+///
+/// In case of any I/O failure, this function will panic (see [`send`] for more
+/// details).
+///
+/// [rust-protobuf]: https://github.com/stepancheg/rust-protobuf
+/// [`send`]: crate::send
+///
+/// # Examples
+///
+/// ```no_run
+/// use protobuf::well_known_types::timestamp::Timestamp;
+///
+/// fleetspeak::send_msg(fleetspeak::Packet {
+///     service: String::from("example"),
+///     kind: Some(String::from("time")),
+///     data: Timestamp::now(),
+/// });
+/// ```
+pub fn send_msg<M>(packet: Packet<M>)
+where
+    M: protobuf::MessageFull,
+{
+    // Serializing to a vector fails only if the message is too big to be
+    // represented at all, which Fleetspeak would reject anyway.
+    let any = match crate::any::pack(&packet.data) {
+        Ok(any) => any,
+        Err(error) => crate::fail(error),
+    };
+
+    let message = crate::Message {
+        service: packet.service,
+        kind: packet.kind,
+        data: any.value,
+        data_type_url: Some(any.type_url),
+        ..Default::default()
+    };
+
+    crate::send(message);
+}
+
+/// Receives a packet with a [rust-protobuf] payload from the Fleetspeak
+/// server.
+///
+/// An error is returned if the type URL of the received payload does not name
+/// the expected message type or if the payload cannot be parsed. The connection
+/// can still be used afterwards.
+///
+/// In case of any I/O failure, this function will panic (see [`receive`] for
+/// more details).
+///
+/// [rust-protobuf]: https://github.com/stepancheg/rust-protobuf
+/// [`receive`]: crate::receive
+///
+/// # Examples
+///
+/// ```no_run
+/// use protobuf::well_known_types::timestamp::Timestamp;
+///
+/// match fleetspeak::receive_msg::<Timestamp>() {
+///     Ok(packet) => println!("server time: {}", packet.data.seconds),
+///     Err(error) => eprintln!("invalid message: {error}"),
+/// }
+/// ```
+pub fn receive_msg<M>() -> std::io::Result<Packet<M>>
+where
+    M: protobuf::MessageFull,
+{
+    decode_msg(crate::receive())
+}
+
+/// Sends the packet with a [prost] payload to the Fleetspeak server.
+///
+/// The payload is encoded and its type URL is derived from the fully qualified
+/// name given by the [`prost::Name`] implementation of the message type.
+///
+/// In case of any I/O failure, this function will panic (see [`send`] for more
+/// details).
+///
+/// [prost]: https://github.com/tokio-rs/prost
+/// [`send`]: crate::send
+///
+/// # Examples
+///
+/// ```no_run
+/// # fn example<M: prost::Name>(status: M) {
+/// fleetspeak::send_proto(fleetspeak::Packet {
+///     service: String::from("example"),
+///     kind: Some(String::from("status")),
+///     data: status,
+/// });
+/// # }
+/// ```
+#[cfg(feature = "prost")]
+pub fn send_proto<M>(packet: Packet<M>)
+where
+    M: prost::Name,
+{
+    let message = crate::Message {
+        service: packet.service,
+        kind: packet.kind,
+        data: packet.data.encode_to_vec(),
+        data_type_url: Some(crate::any::type_url(&M::full_name())),
+        ..Default::default()
+    };
+
+    crate::send(message);
+}
+
+/// Receives a packet with a [prost] payload from the Fleetspeak server.
+///
+/// An error is returned if the type URL of the received payload does not name
+/// the expected message type (as given by its [`prost::Name`] implementation)
+/// or if the payload cannot be decoded. The connection can still be used afterwards.
+///
+/// In case of any I/O failure, this function will panic (see [`receive`] for
+/// more details).
+///
+/// [prost]: https://github.com/tokio-rs/prost
+/// [`receive`]: crate::receive
+#[cfg(feature = "prost")]
+pub fn receive_proto<M>() -> std::io::Result<Packet<M>>
+where
+    M: prost::Name + Default,
+{
+    decode_proto(crate::receive())
+}
+
+/// Parses a [rust-protobuf] payload of a raw message.
+///
+/// [rust-protobuf]: https://github.com/stepancheg/rust-protobuf
+fn decode_msg<M>(message: crate::Message) -> std::io::Result<Packet<M>>
+where
+    M: protobuf::MessageFull,
+{
+    let type_url = message.data_type_url.as_deref().unwrap_or_default();
+    crate::any::verify_type_url(type_url, M::descriptor().full_name())?;
+
+    let data = M::parse_from_bytes(&message.data[..])?;
+
+    Ok(Packet {
+        service: message.service,
+        kind: message.kind,
+        data,
+    })
+}
+
+/// Decodes a [prost] payload of a raw message.
+///
+/// [prost]: https://github.com/tokio-rs/prost
+#[cfg(feature = "prost")]
+fn decode_proto<M>(message: crate::Message) -> std::io::Result<Packet<M>>
+where
+    M: prost::Name + Default,
+{
+    let type_url = message.data_type_url.as_deref().unwrap_or_default();
+    crate::any::verify_type_url(type_url, &M::full_name())?;
+
+    let data = M::decode(&message.data[..])
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+    Ok(Packet {
+        service: message.service,
+        kind: message.kind,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use protobuf::well_known_types::timestamp::Timestamp;
+
+    use super::*;
+
+    #[test]
+    fn decode_msg_ok() {
+        use protobuf::Message as _;
+
+        let mut timestamp = Timestamp::new();
+        timestamp.seconds = 1337;
+
+        let message = crate::Message {
+            service: String::from("foo"),
+            kind: None,
+            data: timestamp.write_to_bytes().unwrap(),
+            data_type_url: Some(String::from("type.googleapis.com/google.protobuf.Timestamp")),
+            ..Default::default()
+        };
+
+        let packet = decode_msg::<Timestamp>(message).unwrap();
+        assert_eq!(packet.service, "foo");
+        assert_eq!(packet.data, timestamp);
+    }
+
+    #[test]
+    fn decode_msg_type_mismatch() {
+        let message = crate::Message {
+            service: String::from("foo"),
+            kind: None,
+            data: Vec::new(),
+            data_type_url: Some(String::from("type.googleapis.com/google.protobuf.Duration")),
+            ..Default::default()
+        };
+
+        assert!(decode_msg::<Timestamp>(message).is_err());
+    }
+
+    #[cfg(feature = "prost")]
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Sample {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(uint64, tag = "2")]
+        value: u64,
+    }
+
+    #[cfg(feature = "prost")]
+    impl prost::Name for Sample {
+        const NAME: &'static str = "Sample";
+        const PACKAGE: &'static str = "fleetspeak.test";
+    }
+
+    #[cfg(feature = "prost")]
+    #[test]
+    fn decode_proto_ok() {
+        use prost::Message as _;
+
+        let sample = Sample {
+            name: String::from("foo"),
+            value: 42,
+        };
+        let message = crate::Message {
+            service: String::from("bar"),
+            kind: None,
+            data: sample.encode_to_vec(),
+            data_type_url: Some(String::from("type.googleapis.com/fleetspeak.test.Sample")),
+            ..Default::default()
+        };
+
+        let packet = decode_proto::<Sample>(message).unwrap();
+        assert_eq!(packet.service, "bar");
+        assert_eq!(packet.data, sample);
+    }
+
+    #[cfg(feature = "prost")]
+    #[test]
+    fn decode_proto_type_mismatch() {
+        let message = crate::Message {
+            service: String::from("bar"),
+            kind: None,
+            data: Vec::new(),
+            data_type_url: Some(String::from("type.googleapis.com/fleetspeak.test.Other")),
+            ..Default::default()
+        };
+
+        assert!(decode_proto::<Sample>(message).is_err());
+    }
+}
@@ -0,0 +1,739 @@
+// Copyright 2024 Google LLC
+//
+// Use of this source code is governed by an MIT-style license that can be found
+// in the LICENSE file or at https://opensource.org/licenses/MIT.
+
+//! A queue of outgoing messages serviced by a background writer thread.
+//!
+//! Messages submitted to the queue are written to the output channel by the
+//! writer thread one by one. A batch of messages (see [`send_all`]) is
+//! submitted as a single payload, so nothing is written in between them. This is also the path of regular sends: senders
+//! only ever contend on the queue (which is held briefly) rather than on the
+//! output channel, so a sender stuck on a full channel does not make other
+//! threads wait on the channel mutex behind it. Because only the writer thread
+//! writes messages, a caller can stop waiting for the message to be sent at
+//! any time without leaving a partially written frame behind: the message is
+//! either withdrawn from the queue before the writer gets to it or it is
+//! written in full.
+//!
+//! Messages are not necessarily written in the order of submission: messages
+//! of higher class (see [`SendClass`]) jump ahead of the ones of lower class.
+//! Within a single class, messages are written in order.
+//!
+//! Messages can have a deadline attached. If the writer does not get to such
+//! a message before its deadline passes, the message is dropped instead.
+//!
+//! Messages submitted without waiting for them to be written can be limited
+//! in number (see [`limit_send_queue`]), so that the queue does not grow
+//! without bounds while the Fleetspeak client is slow to drain the channel.
+//!
+//! [`send_all`]: crate::send_all
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, LazyLock, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::Message;
+
+/// Class of an outgoing message determining its order in the writer queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SendClass {
+    /// Small control messages (e.g. acknowledgements or interactive responses)
+    /// that should be written as soon as possible.
+    Control,
+    /// Regular messages.
+    #[default]
+    Normal,
+    /// Large messages (e.g. chunks of uploaded files) that can wait for the
+    /// messages of other classes to be written first.
+    Bulk,
+}
+
+/// What happens to messages sent with [`send_queued`] when the queue is full.
+///
+/// [`send_queued`]: crate::send_queued
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// The sender waits until there is space in the queue.
+    #[default]
+    Block,
+    /// The sender gets an error of the [`WouldBlock`] kind and the message is
+    /// not queued.
+    ///
+    /// [`WouldBlock`]: std::io::ErrorKind::WouldBlock
+    WouldBlock,
+    /// The oldest message in the queue is dropped to make space for the new
+    /// one.
+    DropOldest,
+}
+
+/// Options for sending messages through the writer queue.
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// Class of the message determining its order in the writer queue.
+    pub class: SendClass,
+    /// Time after which the message is no longer worth sending.
+    ///
+    /// If the writer does not start writing the message before the deadline,
+    /// the message is dropped and the hook registered with [`on_send_expired`]
+    /// is called. Unlike the timeout given to [`send_timeout_with`], this does
+    /// not depend on how long the caller is willing to wait.
+    ///
+    /// [`send_timeout_with`]: crate::send_timeout_with
+    pub deadline: Option<Instant>,
+}
+
+/// An error returned when a message could not be sent within a given time.
+#[derive(Debug)]
+pub struct SendTimeoutError {
+    repr: SendTimeoutErrorRepr,
+}
+
+#[derive(Debug)]
+enum SendTimeoutErrorRepr {
+    /// The message was withdrawn from the queue once the timeout elapsed.
+    ///
+    /// The message is boxed to keep the error (and thus results) small.
+    Withdrawn(Box<Message>),
+    /// The message was dropped because its deadline passed.
+    Expired(Box<Message>),
+    /// The message was still being written once the timeout elapsed.
+    InFlight,
+    /// The message was refused before anything was written.
+    Rejected(std::io::Error),
+}
+
+impl SendTimeoutError {
+
+    /// Returns whether the message was dropped because its deadline (see
+    /// [`SendOptions::deadline`]) passed before it could be written.
+    pub fn is_expired(&self) -> bool {
+        matches!(self.repr, SendTimeoutErrorRepr::Expired(_))
+    }
+
+    /// Returns whether the message was refused before anything was written.
+    ///
+    /// This happens for the same reasons as for [`WriteError::Rejected`] and
+    /// does not indicate a connection failure.
+    ///
+    /// [`WriteError::Rejected`]: crate::WriteError::Rejected
+    pub fn is_rejected(&self) -> bool {
+        matches!(self.repr, SendTimeoutErrorRepr::Rejected(_))
+    }
+
+    /// Returns the message that was not sent.
+    ///
+    /// The message is available only if it was withdrawn from the queue before
+    /// being written. If writing the message has already started by the time
+    /// the timeout elapsed, `None` is returned: the message will be delivered
+    /// once the Fleetspeak client drains the channel. Messages that have been
+    /// refused are not available either.
+    pub fn into_message(self) -> Option<Message> {
+        match self.repr {
+            SendTimeoutErrorRepr::Withdrawn(message) |
+            SendTimeoutErrorRepr::Expired(message) => Some(*message),
+            SendTimeoutErrorRepr::InFlight |
+            SendTimeoutErrorRepr::Rejected(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SendTimeoutError {
+
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.repr {
+            SendTimeoutErrorRepr::Withdrawn(_) => {
+                write!(fmt, "message not sent within the timeout")
+            }
+            SendTimeoutErrorRepr::Expired(_) => {
+                write!(fmt, "message not sent before its deadline")
+            }
+            SendTimeoutErrorRepr::InFlight => {
+                write!(fmt, "message still being sent after the timeout")
+            }
+            SendTimeoutErrorRepr::Rejected(error) => {
+                write!(fmt, "message rejected: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendTimeoutError {
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.repr {
+            SendTimeoutErrorRepr::Rejected(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Registers a hook called for every message dropped because its deadline
+/// passed before it could be written.
+///
+/// Only one hook can be registered at a time, registering a new one replaces
+/// the previous one. The hook is called on the background writer thread, so it
+/// should return quickly (e.g. just bump a metric or log the message).
+///
+/// See documentation for [`SendOptions::deadline`] for more details.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::on_send_expired(|message| {
+///     eprintln!("dropped expired message: {}", message.preview());
+/// });
+/// ```
+pub fn on_send_expired<F>(hook: F)
+where
+    F: Fn(&Message) + Send + Sync + 'static,
+{
+    *EXPIRED_HOOK.write().expect("poisoned expiry hook lock") = Some(Box::new(hook));
+}
+
+/// Limits the number of messages sent with [`send_queued`] that can wait in
+/// the queue at the same time.
+///
+/// Once the queue holds `capacity` such messages, sending another one behaves
+/// according to the given `policy`. This keeps the memory usage bounded for
+/// services that produce messages faster than the Fleetspeak client drains
+/// them. By default, the queue is unbounded.
+///
+/// Messages sent using other functions do not count towards the limit and are
+/// never dropped by it.
+///
+/// [`send_queued`]: crate::send_queued
+///
+/// # Panics
+///
+/// This function panics if `capacity` is zero.
+///
+/// # Examples
+///
+/// ```no_run
+/// fleetspeak::limit_send_queue(1024, fleetspeak::QueueFullPolicy::DropOldest);
+/// ```
+pub fn limit_send_queue(capacity: usize, policy: QueueFullPolicy) {
+    assert!(capacity > 0, "zero send queue capacity");
+
+    let mut queue = QUEUE.jobs.lock().expect("poisoned writer queue mutex");
+    queue.limit = Some((capacity, policy));
+    drop(queue);
+
+    // Blocked senders have to re-check the limit.
+    QUEUE.space.notify_all();
+}
+
+/// Submits the message to the queue and waits until it is written.
+///
+/// If the message is not written within the given `timeout`, it is withdrawn
+/// from the queue (if possible) and an error is returned. The same happens if
+/// the message is refused before anything is written. I/O errors that occurred
+/// while writing the message are returned as-is.
+pub(crate) fn send(message: Message, options: SendOptions, timeout: Duration) -> Result<std::io::Result<()>, SendTimeoutError> {
+    let job = submit(Payload::Message(message), options.class, options.deadline);
+
+    // Timeouts too large to be represented are effectively infinite.
+    let repr = match job.wait(Instant::now().checked_add(timeout)) {
+        Outcome::Done(Ok(())) => return Ok(Ok(())),
+        Outcome::Done(Err(crate::WriteError::Output(error))) => return Ok(Err(error)),
+        Outcome::Done(Err(crate::WriteError::Rejected(error))) => {
+            SendTimeoutErrorRepr::Rejected(error)
+        }
+        Outcome::Withdrawn(Payload::Message(message)) => {
+            SendTimeoutErrorRepr::Withdrawn(Box::new(message))
+        }
+        Outcome::Expired(Payload::Message(message)) => {
+            SendTimeoutErrorRepr::Expired(Box::new(message))
+        }
+        Outcome::Withdrawn(Payload::Heartbeat | Payload::Batch(_)) |
+        Outcome::Expired(Payload::Heartbeat | Payload::Batch(_)) => unreachable!(),
+        Outcome::InFlight => SendTimeoutErrorRepr::InFlight,
+    };
+
+    Err(SendTimeoutError { repr })
+}
+
+/// Submits the message to the queue and waits until it is written, no matter
+/// how long it takes.
+///
+/// The message is queued as a regular message. Unlike [`send`], the error of a
+/// message refused before anything was written is told apart from a write
+/// error (see [`WriteError`]).
+///
+/// [`WriteError`]: crate::WriteError
+pub(crate) fn send_blocking(message: Message) -> Result<(), crate::WriteError> {
+    send_payload(Payload::Message(message))
+}
+
+/// Submits the messages to the queue as a single batch and waits until all of
+/// them are written, no matter how long it takes.
+///
+/// The batch is queued as a regular message (see [`send_blocking`]).
+pub(crate) fn send_all(messages: Vec<Message>) -> Result<(), crate::WriteError> {
+    send_payload(Payload::Batch(messages))
+}
+
+/// Submits the payload to the queue as a regular message and waits until it
+/// is written.
+fn send_payload(payload: Payload) -> Result<(), crate::WriteError> {
+    // The writer thread cannot wait for itself (e.g. if a hook called on it
+    // sends a message), so it writes the payload directly instead.
+    if IS_WRITER.get() {
+        return write(payload).inspect_err(|error| {
+            if let crate::WriteError::Output(error) = error {
+                crate::close(error);
+            }
+        });
+    }
+
+    let job = submit(payload, SendClass::Normal, None);
+
+    match job.wait(None) {
+        Outcome::Done(result) => result,
+        Outcome::Withdrawn(_) | Outcome::Expired(_) | Outcome::InFlight => {
+            unreachable!("job without deadline not done")
+        }
+    }
+}
+
+/// Submits the message to the queue without waiting for it to be written.
+///
+/// The message is queued as a regular message, so it is written after all the
+/// regular messages submitted before. Write errors close the connection. If
+/// the queue is full, the configured [`QueueFullPolicy`] applies.
+pub(crate) fn send_queued(message: Message) -> std::io::Result<()> {
+    let mut queue = QUEUE.jobs.lock().expect("poisoned writer queue mutex");
+    while let Some((capacity, policy)) = queue.limit {
+        if queue.detached < capacity {
+            break;
+        }
+
+        match policy {
+            QueueFullPolicy::Block => {
+                queue = QUEUE.space.wait(queue)
+                    .expect("poisoned writer queue mutex");
+            }
+            QueueFullPolicy::WouldBlock => {
+                return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+                    "send queue full"
+                }));
+            }
+            QueueFullPolicy::DropOldest => {
+                let pending = &mut queue.pending[SendClass::Normal as usize];
+                if let Some(index) = pending.iter().position(|job| job.detached) {
+                    let job = pending.remove(index)
+                        .expect("invalid job index");
+                    queue.detached -= 1;
+
+                    let state = job.state.lock().expect("poisoned writer job mutex");
+                    if let JobState::Pending(Payload::Message(message)) = &*state {
+                        log::warn!("send queue full, dropping message: {}", message.preview());
+                    }
+                    drop(state);
+                }
+                break;
+            }
+        }
+    }
+
+    // The job is added while the queue is still locked, so that concurrent
+    // senders cannot exceed the limit.
+    push(&mut queue, Arc::new(Job {
+        state: Mutex::new(JobState::Pending(Payload::Message(message))),
+        done: Condvar::new(),
+        deadline: None,
+        detached: true,
+    }), SendClass::Normal);
+    drop(queue);
+
+    QUEUE.ready.notify_one();
+
+    Ok(())
+}
+
+/// Submits a heartbeat signal to the queue and waits until it is written.
+///
+/// The heartbeat signal is submitted as a control message, so that it is not
+/// delayed by other queued messages. If it is not written within the given
+/// `timeout`, `None` is returned.
+pub(crate) fn heartbeat(timeout: Duration) -> Option<std::io::Result<()>> {
+    let job = submit(Payload::Heartbeat, SendClass::Control, None);
+
+    match job.wait(Instant::now().checked_add(timeout)) {
+        Outcome::Done(result) => Some(result.map_err(std::io::Error::from)),
+        Outcome::Withdrawn(_) | Outcome::Expired(_) | Outcome::InFlight => None,
+    }
+}
+
+/// Waits until all the payloads submitted to the queue are written.
+///
+/// Returns `false` if there are still payloads pending or being written when
+/// the `deadline` passes.
+pub(crate) fn drain(deadline: Instant) -> bool {
+    let mut queue = QUEUE.jobs.lock().expect("poisoned writer queue mutex");
+    while queue.writing || queue.pending.iter().any(|jobs| !jobs.is_empty()) {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return false;
+        }
+
+        queue = QUEUE.idle.wait_timeout(queue, timeout)
+            .expect("poisoned writer queue mutex")
+            .0;
+    }
+
+    true
+}
+
+/// Resets the queue in a child process after `fork`.
+///
+/// The writer thread does not survive `fork`, so it has to be spawned again
+/// once there is something to write. Payloads pending at the time of the fork
+/// belong to the parent process and are discarded (otherwise they would be
+/// written twice).
+#[cfg(target_family = "unix")]
+pub(crate) fn after_fork() -> std::io::Result<()> {
+    let mut queue = match QUEUE.jobs.try_lock() {
+        Ok(queue) => queue,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "writer queue locked during fork"
+        })),
+    };
+
+    if queue.writing {
+        return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, {
+            "writer busy during fork"
+        }));
+    }
+
+    queue.pending.iter_mut().for_each(VecDeque::clear);
+    queue.detached = 0;
+    queue.running = false;
+    queue.stopping = false;
+    // The writer thread does not exist in the child process, so its handle
+    // must not be joined nor detached.
+    std::mem::forget(queue.thread.take());
+
+    Ok(())
+}
+
+/// Spawns the writer thread unless it is already running.
+pub(crate) fn start() {
+    let mut queue = QUEUE.jobs.lock().expect("poisoned writer queue mutex");
+    spawn(&mut queue);
+}
+
+/// Stops the writer thread and waits for it to exit.
+///
+/// Payloads that are already queued are written before the thread exits. The
+/// writer thread is spawned again once something new is submitted.
+pub(crate) fn stop() {
+    let mut queue = QUEUE.jobs.lock().expect("poisoned writer queue mutex");
+    let thread = match queue.thread.take() {
+        Some(thread) => thread,
+        None => return,
+    };
+    queue.stopping = true;
+    drop(queue);
+
+    QUEUE.ready.notify_all();
+
+    if thread.join().is_err() {
+        log::error!("writer thread panicked");
+    }
+}
+
+/// Adds the payload to the queue, spawning the writer thread if needed.
+fn submit(payload: Payload, class: SendClass, deadline: Option<Instant>) -> Arc<Job> {
+    let job = Arc::new(Job {
+        state: Mutex::new(JobState::Pending(payload)),
+        done: Condvar::new(),
+        deadline,
+        detached: false,
+    });
+
+    let mut queue = QUEUE.jobs.lock().expect("poisoned writer queue mutex");
+    push(&mut queue, job.clone(), class);
+    drop(queue);
+
+    QUEUE.ready.notify_one();
+
+    job
+}
+
+/// Adds the job to the locked queue, spawning the writer thread if needed.
+fn push(queue: &mut Jobs, job: Arc<Job>, class: SendClass) {
+    spawn(queue);
+    if job.detached {
+        queue.detached += 1;
+    }
+    queue.pending[class as usize].push_back(job);
+}
+
+/// Spawns the writer thread on the locked queue unless it is already running.
+fn spawn(queue: &mut Jobs) {
+    if !queue.running {
+        queue.thread = Some(std::thread::spawn(run));
+        queue.running = true;
+    }
+}
+
+/// Body of the writer thread.
+fn run() {
+    IS_WRITER.set(true);
+
+    loop {
+        let mut queue = QUEUE.jobs.lock().expect("poisoned writer queue mutex");
+        let job = loop {
+            // Queues are ordered by class, so the first non-empty one is the
+            // one with the highest priority.
+            match queue.pending.iter_mut().find_map(VecDeque::pop_front) {
+                Some(job) => break job,
+                None => {
+                    queue.writing = false;
+                    QUEUE.idle.notify_all();
+
+                    if queue.stopping {
+                        queue.stopping = false;
+                        queue.running = false;
+                        return;
+                    }
+
+                    queue = QUEUE.ready.wait(queue)
+                        .expect("poisoned writer queue mutex");
+                }
+            }
+        };
+        queue.writing = true;
+        if job.detached {
+            queue.detached -= 1;
+            QUEUE.space.notify_one();
+        }
+        drop(queue);
+
+        let mut state = job.state.lock().expect("poisoned writer job mutex");
+        let payload = match std::mem::replace(&mut *state, JobState::Writing) {
+            JobState::Pending(payload) => payload,
+            // The message has been withdrawn by the submitter in the meantime,
+            // there is nothing to do.
+            _ => {
+                *state = JobState::Withdrawn;
+                continue;
+            }
+        };
+        drop(state);
+
+        if job.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            if let Payload::Message(message) = &payload {
+                log::warn!("dropping expired message: {}", message.preview());
+
+                if let Some(hook) = &*EXPIRED_HOOK.read().expect("poisoned expiry hook lock") {
+                    hook(message);
+                }
+            }
+
+            *job.state.lock().expect("poisoned writer job mutex") = JobState::Expired(payload);
+            job.done.notify_all();
+            continue;
+        }
+
+        let result = write(payload);
+        match &result {
+            Ok(()) => (),
+            // The connection is fine, the error is up to the submitter.
+            Err(crate::WriteError::Rejected(error)) => {
+                log::error!("refused to write queued payload: {error}");
+            }
+            Err(crate::WriteError::Output(error)) => {
+                log::error!("failed to write queued payload: {error}");
+                crate::close(error);
+            }
+        }
+
+        *job.state.lock().expect("poisoned writer job mutex") = JobState::Done(result);
+        job.done.notify_all();
+    }
+}
+
+/// Writes the payload to the output channel of the connection.
+fn write(payload: Payload) -> Result<(), crate::WriteError> {
+    match payload {
+        Payload::Message(message) => crate::try_deliver(message),
+        Payload::Batch(messages) => crate::try_deliver_all(messages),
+        Payload::Heartbeat => crate::deliver_heartbeat().map_err(crate::WriteError::Output),
+    }
+}
+
+/// A single payload submitted to the writer queue.
+struct Job {
+    state: Mutex<JobState>,
+    done: Condvar,
+    /// Time after which the payload should be dropped instead of written.
+    deadline: Option<Instant>,
+    /// Whether nobody waits for the payload to be written.
+    detached: bool,
+}
+
+impl Job {
+
+    /// Waits until the payload is written or the `deadline` (if any) passes.
+    ///
+    /// If the deadline passes before the writer thread picks the payload up,
+    /// the payload is withdrawn from the queue and returned.
+    fn wait(&self, deadline: Option<Instant>) -> Outcome {
+        let passed = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+        let mut state = self.state.lock().expect("poisoned writer job mutex");
+        loop {
+            match std::mem::replace(&mut *state, JobState::Withdrawn) {
+                JobState::Done(result) => return Outcome::Done(result),
+                JobState::Expired(payload) => return Outcome::Expired(payload),
+                JobState::Pending(payload) if passed() => {
+                    return Outcome::Withdrawn(payload);
+                }
+                JobState::Writing if passed() => {
+                    *state = JobState::Writing;
+                    return Outcome::InFlight;
+                }
+                JobState::Withdrawn => unreachable!("job withdrawn by another party"),
+                pending => *state = pending,
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    self.done.wait_timeout(state, timeout)
+                        .expect("poisoned writer job mutex")
+                        .0
+                }
+                None => self.done.wait(state).expect("poisoned writer job mutex"),
+            };
+        }
+    }
+}
+
+/// Data to be written by the writer thread.
+enum Payload {
+    Message(Message),
+    /// Messages written one after another with a single flush.
+    Batch(Vec<Message>),
+    Heartbeat,
+}
+
+/// State of a payload submitted to the writer queue.
+enum JobState {
+    /// The payload waits in the queue.
+    Pending(Payload),
+    /// The payload is being written by the writer thread.
+    Writing,
+    /// The payload has been written (or writing it failed).
+    Done(Result<(), crate::WriteError>),
+    /// The payload has been dropped by the writer thread because its deadline
+    /// passed.
+    Expired(Payload),
+    /// The payload has been withdrawn from the queue by the submitter.
+    Withdrawn,
+}
+
+/// Result of waiting for a payload to be written.
+enum Outcome {
+    /// The writer thread finished writing the payload.
+    Done(Result<(), crate::WriteError>),
+    /// The payload has not been written and was withdrawn from the queue.
+    Withdrawn(Payload),
+    /// The payload has not been written because its deadline passed.
+    Expired(Payload),
+    /// The payload is still being written by the writer thread.
+    InFlight,
+}
+
+/// The queue of jobs shared between the submitters and the writer thread.
+struct Queue {
+    jobs: Mutex<Jobs>,
+    /// Notified when new jobs are submitted.
+    ready: Condvar,
+    /// Notified when the writer thread runs out of jobs.
+    idle: Condvar,
+    /// Notified when detached jobs are taken from the queue.
+    space: Condvar,
+}
+
+struct Jobs {
+    /// Pending jobs, a separate queue for each class.
+    pending: [VecDeque<Arc<Job>>; 3],
+    running: bool,
+    /// Whether the writer thread is busy with a job taken from the queue.
+    writing: bool,
+    /// Whether the writer thread should exit once the queue is empty.
+    stopping: bool,
+    /// Handle of the writer thread (unless it is being stopped).
+    thread: Option<std::thread::JoinHandle<()>>,
+    /// Number of pending detached jobs.
+    detached: usize,
+    /// Maximum number of pending detached jobs and what to do once reached.
+    limit: Option<(usize, QueueFullPolicy)>,
+}
+
+/// A hook called for messages dropped because of their deadline.
+type ExpiredHook = Box<dyn Fn(&Message) + Send + Sync>;
+
+thread_local! {
+    /// Whether the current thread is the writer thread.
+    static IS_WRITER: std::cell::Cell<bool> = const {
+        std::cell::Cell::new(false)
+    };
+}
+
+static EXPIRED_HOOK: RwLock<Option<ExpiredHook>> = RwLock::new(None);
+
+static QUEUE: LazyLock<Queue> = LazyLock::new(|| Queue {
+    jobs: Mutex::new(Jobs {
+        pending: Default::default(),
+        running: false,
+        writing: false,
+        stopping: false,
+        thread: None,
+        detached: 0,
+        limit: None,
+    }),
+    ready: Condvar::new(),
+    idle: Condvar::new(),
+    space: Condvar::new(),
+});
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn send_expired() {
+        let message = Message {
+            service: String::from("foo"),
+            kind: None,
+            data: b"bar".to_vec(),
+            ..Default::default()
+        };
+
+        let options = SendOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+
+        let error = send(message, options, Duration::from_secs(60)).unwrap_err();
+        assert!(error.is_expired());
+        assert_eq!(error.into_message().unwrap().data, b"bar");
+    }
+
+    #[test]
+    fn send_timeout_error_rejected() {
+        let error = SendTimeoutError {
+            repr: SendTimeoutErrorRepr::Rejected(std::io::ErrorKind::InvalidInput.into()),
+        };
+        assert!(error.is_rejected());
+        assert!(!error.is_expired());
+        assert!(std::error::Error::source(&error).is_some());
+        assert!(error.into_message().is_none());
+    }
+}